@@ -0,0 +1,136 @@
+// Compare the read paths of the file based archive.
+//
+// Run with `cargo bench --features io-uring,mmap` to include io_uring and memory mapped reads.
+use bitcoinsv::bitcoin::{BlockHash, FromHex};
+use bsvlake_blockarchive::{BlockArchiveReader, BlockArchiveWriter, SimpleFileBasedBlockArchive};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::try_join_all;
+use std::io::Cursor;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::runtime::Runtime;
+
+const BLOCK_COUNT: usize = 64;
+const BLOCK_SIZE: usize = 1024 * 1024;
+const RANGE_COUNT: u64 = 256;
+const RANGE_SIZE: u64 = 64;
+
+fn block_hash(i: usize) -> BlockHash {
+    BlockHash::from_hex(format!("{i:064x}")).unwrap()
+}
+
+// Fill an archive with blocks of random looking data. The blocks are not valid, but the read
+// paths do not parse them apart from the header and transaction count.
+async fn make_archive(root: &str) {
+    let archive = SimpleFileBasedBlockArchive::new(root.to_string())
+        .await
+        .unwrap();
+    for i in 0..BLOCK_COUNT {
+        let mut state = i as u64 + 1;
+        let mut raw = (0..BLOCK_SIZE)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect::<Vec<_>>();
+        raw[80] = 0xfd;
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(raw));
+        archive
+            .store_block(&block_hash(i), &mut reader)
+            .await
+            .unwrap();
+    }
+}
+
+// The archives to compare, all reading the same files.
+async fn archives(root: &str) -> Vec<(&'static str, SimpleFileBasedBlockArchive)> {
+    let open = || SimpleFileBasedBlockArchive::new(root.to_string());
+    #[allow(unused_mut)]
+    let mut archives = vec![("tokio", open().await.unwrap())];
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    archives.push((
+        "io_uring",
+        open().await.unwrap().with_io_uring(256).unwrap(),
+    ));
+    #[cfg(feature = "mmap")]
+    archives.push(("mmap", open().await.unwrap().with_mmap(BLOCK_COUNT)));
+    archives
+}
+
+fn bench_reads(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap().to_string();
+    runtime.block_on(make_archive(&root));
+    let archives = runtime.block_on(archives(&root));
+    let hashes = (0..BLOCK_COUNT).map(block_hash).collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("get_block");
+    group.throughput(Throughput::Bytes((BLOCK_COUNT * BLOCK_SIZE) as u64));
+    for (name, archive) in archives.iter() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(try_join_all(hashes.iter().map(|h| async move {
+                        let mut reader = archive.get_block(h).await?;
+                        let mut buf = Vec::with_capacity(BLOCK_SIZE);
+                        reader.read_to_end(&mut buf).await?;
+                        Ok::<_, bsvlake_blockarchive::Error>(buf.len())
+                    })))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("block_header");
+    group.throughput(Throughput::Elements(BLOCK_COUNT as u64));
+    for (name, archive) in archives.iter() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(try_join_all(hashes.iter().map(|h| archive.block_header(h))))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("block_tx_count");
+    group.throughput(Throughput::Elements(BLOCK_COUNT as u64));
+    for (name, archive) in archives.iter() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(try_join_all(
+                        hashes.iter().map(|h| archive.block_tx_count(h)),
+                    ))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let step = BLOCK_SIZE as u64 / RANGE_COUNT;
+    let ranges = (0..RANGE_COUNT)
+        .map(|i| (i * step, RANGE_SIZE))
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("get_ranges_from_block");
+    group.throughput(Throughput::Elements(BLOCK_COUNT as u64 * RANGE_COUNT));
+    for (name, archive) in archives.iter() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(try_join_all(
+                        hashes
+                            .iter()
+                            .map(|h| archive.get_ranges_from_block(h, &ranges)),
+                    ))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_reads);
+criterion_main!(benches);
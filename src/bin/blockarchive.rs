@@ -0,0 +1,280 @@
+//! Command line tool for managing block archives.
+//!
+//! Usage:
+//!     blockarchive diff <source> <destination>
+//!     blockarchive sync [--concurrency N] [--dry-run] [--no-verify] <source> <destination>
+//!     blockarchive migrate <root> <layout>
+//!     blockarchive stats <root>
+//!     blockarchive headers [--blockchain main|test|stn|regtest] <root>
+//!     blockarchive utxo-snapshot [--blockchain B] [--height N] --db <utxo db> <root> <output>
+use bitcoinsv::bitcoin::BlockchainId;
+#[cfg(feature = "index")]
+use bsvlake_blockarchive::UtxoSet;
+use bsvlake_blockarchive::{
+    diff_archives, sync_archives, validate_headers, ArchiveDiff, ArchiveStats, BlockArchiveReader,
+    Layout, Result, SimpleFileBasedBlockArchive, SyncOptions, DEFAULT_SYNC_CONCURRENCY,
+};
+use clap::{Parser, Subcommand};
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(version, about = "Manage Bitcoin SV block archives")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compare the blocks in two archives.
+    Diff {
+        /// Root path of the source archive.
+        source: String,
+        /// Root path of the destination archive.
+        destination: String,
+        /// List every block that differs, not just the totals.
+        #[arg(long, short)]
+        verbose: bool,
+    },
+    /// Copy the blocks that are missing from the destination archive from the source archive.
+    ///
+    /// An interrupted sync can be resumed by running it again.
+    Sync {
+        /// Root path of the source archive.
+        source: String,
+        /// Root path of the destination archive.
+        destination: String,
+        /// The number of blocks to copy at the same time.
+        #[arg(long, default_value_t = DEFAULT_SYNC_CONCURRENCY)]
+        concurrency: usize,
+        /// Report the blocks that would be copied without copying them.
+        #[arg(long)]
+        dry_run: bool,
+        /// Do not read the copied blocks back to check them.
+        #[arg(long)]
+        no_verify: bool,
+    },
+    /// Convert an archive to a different directory layout, in place.
+    ///
+    /// An interrupted migration can be resumed by running it again.
+    Migrate {
+        /// Root path of the archive.
+        root: String,
+        /// The new layout: fanout:<depth>:<width>, flat or height:<blocks per directory>.
+        layout: Layout,
+    },
+    /// Show statistics about the blocks in an archive.
+    Stats {
+        /// Root path of the archive.
+        root: String,
+    },
+    /// Check the block headers in an archive against the proof of work, difficulty adjustment
+    /// and median time past rules of a blockchain.
+    Headers {
+        /// Root path of the archive.
+        root: String,
+        /// The blockchain: main, test, stn or regtest.
+        #[arg(long, default_value = "main", value_parser = parse_blockchain)]
+        blockchain: BlockchainId,
+    },
+    /// Replay the best chain of an archive into a UTXO set and write a snapshot of the set.
+    ///
+    /// The set is kept in the database between runs, so only the blocks that changed since the
+    /// last run are applied or undone.
+    #[cfg(feature = "index")]
+    UtxoSnapshot {
+        /// Root path of the archive.
+        root: String,
+        /// The file to write the snapshot to.
+        output: std::path::PathBuf,
+        /// The database file of the UTXO set, which is created if it does not exist.
+        #[arg(long)]
+        db: std::path::PathBuf,
+        /// The height of the snapshot, the tip of the best chain if not given.
+        #[arg(long)]
+        height: Option<u64>,
+        /// The blockchain: main, test, stn or regtest.
+        #[arg(long, default_value = "main", value_parser = parse_blockchain)]
+        blockchain: BlockchainId,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli.command).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(command: Command) -> Result<ExitCode> {
+    match command {
+        Command::Diff {
+            source,
+            destination,
+            verbose,
+        } => {
+            let source = SimpleFileBasedBlockArchive::new(source).await?;
+            let destination = SimpleFileBasedBlockArchive::new(destination).await?;
+            let diff = diff_archives(&source, &destination).await?;
+            print_diff(&diff, verbose);
+            // like diff(1), exit with 1 if the archives differ
+            Ok(match diff.is_in_sync() {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::from(1),
+            })
+        }
+        Command::Sync {
+            source,
+            destination,
+            concurrency,
+            dry_run,
+            no_verify,
+        } => {
+            let source = SimpleFileBasedBlockArchive::new(source).await?;
+            let destination = SimpleFileBasedBlockArchive::new(destination).await?;
+            let options = SyncOptions {
+                concurrency,
+                dry_run,
+                verify: !no_verify,
+            };
+            let report = sync_archives(&source, &destination, &options).await?;
+            for block_hash in report.copied.iter() {
+                match dry_run {
+                    true => println!("would copy {block_hash}"),
+                    false => println!("copied {block_hash}"),
+                }
+            }
+            for (block_hash, source_size, destination_size) in report.diff.size_mismatch.iter() {
+                eprintln!(
+                    "size mismatch {block_hash}: source {source_size} bytes, destination {destination_size} bytes"
+                );
+            }
+            for (block_hash, e) in report.errors.iter() {
+                eprintln!("failed {block_hash}: {e}");
+            }
+            match dry_run {
+                true => println!("{} blocks would be copied", report.copied.len()),
+                false => println!(
+                    "{} blocks copied, {} bytes, {} errors",
+                    report.copied.len(),
+                    report.bytes_copied,
+                    report.errors.len()
+                ),
+            }
+            Ok(match report.errors.is_empty() {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            })
+        }
+        Command::Migrate { root, layout } => {
+            let mut archive = SimpleFileBasedBlockArchive::new(root).await?;
+            let old_layout = archive.layout();
+            let moved = archive.migrate_layout(layout).await?;
+            println!("migrated from {old_layout} to {layout}, {moved} block files moved");
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Stats { root } => {
+            let archive = SimpleFileBasedBlockArchive::new(root).await?;
+            print_stats(&archive.archive_stats().await?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Headers { root, blockchain } => {
+            let archive = SimpleFileBasedBlockArchive::new(root).await?;
+            let report = validate_headers(&archive, blockchain).await?;
+            for (block_hash, problems) in report.failed.iter() {
+                for problem in problems.iter() {
+                    println!("failed {block_hash}: {problem}");
+                }
+            }
+            if !report.unconnected.is_empty() {
+                println!(
+                    "{} blocks do not connect to the {blockchain} genesis block or a block with a known height",
+                    report.unconnected.len()
+                );
+            }
+            println!(
+                "{} headers checked, {} failed",
+                report.headers_checked,
+                report.failed.len()
+            );
+            Ok(match report.is_valid() {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            })
+        }
+        #[cfg(feature = "index")]
+        Command::UtxoSnapshot {
+            root,
+            output,
+            db,
+            height,
+            blockchain,
+        } => {
+            let archive = SimpleFileBasedBlockArchive::new(root).await?;
+            let utxos = UtxoSet::open(db)?;
+            let report = utxos.replay(&archive, blockchain, height).await?;
+            println!(
+                "{} blocks undone, {} blocks applied",
+                report.rolled_back, report.applied
+            );
+            let snapshot = utxos.write_snapshot(&output).await?;
+            println!(
+                "{} outputs at block {} height {}, commitment {}",
+                snapshot.coins, snapshot.block_hash, snapshot.height, snapshot.commitment
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+fn parse_blockchain(value: &str) -> std::result::Result<BlockchainId, String> {
+    BlockchainId::try_from(value).map_err(|e| e.to_string())
+}
+
+fn print_diff(diff: &ArchiveDiff, verbose: bool) {
+    if verbose {
+        for block_hash in diff.missing.iter() {
+            println!("missing {block_hash}");
+        }
+        for (block_hash, source_size, destination_size) in diff.size_mismatch.iter() {
+            println!("size mismatch {block_hash}: {source_size} != {destination_size}");
+        }
+        for block_hash in diff.extra.iter() {
+            println!("extra {block_hash}");
+        }
+    }
+    println!(
+        "{} matching, {} missing, {} size mismatch, {} extra",
+        diff.matching,
+        diff.missing.len(),
+        diff.size_mismatch.len(),
+        diff.extra.len()
+    );
+}
+
+fn print_stats(stats: &ArchiveStats) {
+    println!("blocks:        {}", stats.block_count);
+    println!("total size:    {} bytes", stats.total_size);
+    if let Some(average) = stats.average_size() {
+        println!("average size:  {average} bytes");
+    }
+    if let Some(block_hash) = stats.max_size_block {
+        println!("largest block: {block_hash} ({} bytes)", stats.max_size);
+    }
+    println!("transactions:  {}", stats.total_tx_count);
+    if let (Some(earliest), Some(latest)) = (stats.earliest_time, stats.latest_time) {
+        println!("header times:  {earliest} to {latest}");
+    }
+    if !stats.size_histogram.is_empty() {
+        println!("block sizes:");
+        for (bucket, count) in stats.size_histogram.iter() {
+            let low = 1u64 << bucket;
+            println!("  {low:>12} to {:>12} bytes: {count}", (low << 1) - 1);
+        }
+    }
+}
@@ -1,10 +1,16 @@
-use crate::Result;
+use crate::checksum::verify_reader;
+use crate::events::{BlockEvent, BlockEventStream};
+use crate::filters::BlockFilter;
+use crate::metadata::BlockMetadata;
+use crate::stats::{scan_archive_stats, ArchiveStats};
+use crate::validate::{validate_reader, BlockValidation};
+use crate::{Error, Result};
 use async_trait::async_trait;
 use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader};
 use bytes::Bytes;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
 use tokio_stream::Stream;
@@ -14,8 +20,19 @@ use tokio_stream::Stream;
 ///
 /// The BlockArchive has very little knowledge of the structure of block, it only knows how to
 /// store and retrieve blocks.
+///
+/// It is the combination of [BlockArchiveReader] and [BlockArchiveWriter], and is implemented
+/// for every type that implements both. Code that only reads from an archive should use
+/// [BlockArchiveReader], see also [ReadOnly](crate::ReadOnly).
+pub trait BlockArchive: BlockArchiveReader + BlockArchiveWriter {}
+
+impl<T: BlockArchiveReader + BlockArchiveWriter + ?Sized> BlockArchive for T {}
+
+/// The read operations of a [BlockArchive].
+///
+/// The trait is object safe, so a reader can be shared as an `Arc<dyn BlockArchiveReader>`.
 #[async_trait]
-pub trait BlockArchive {
+pub trait BlockArchiveReader: Send + Sync {
     /// Get a reader to a block from the archive.
     ///
     /// Returns a reader for the encoded block.
@@ -30,20 +47,6 @@ pub trait BlockArchive {
     /// Check if a block exists in the archive.
     async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool>;
 
-    /// Store a block in the archive.
-    ///
-    /// Expects a reader for the encoded block.
-    ///
-    /// This function does not do any checking of the block, it stores the bytes of the block as is.
-    async fn store_block(
-        &self,
-        block_hash: &BlockHash,
-        block: &mut Box<dyn AsyncRead + Unpin + Send>,
-    ) -> Result<()>;
-
-    /// Store a full block in the archive.
-    async fn store_block_full(&self, block: &Block) -> Result<()>;
-
     /// Get the size of a block in the archive.
     async fn block_size(&self, block_hash: &BlockHash) -> Result<usize>;
 
@@ -57,7 +60,8 @@ pub trait BlockArchive {
     ///
     /// Returns a vector of bytes.
     ///
-    /// Can be used to retrieve a transaction if the location is known.
+    /// Can be used to retrieve a transaction if the location is known. Returns
+    /// [Error::InvalidRange](crate::Error::InvalidRange) if the range is not within the block.
     async fn get_bytes_from_block(
         &self,
         block_hash: &BlockHash,
@@ -65,6 +69,29 @@ pub trait BlockArchive {
         length: u64,
     ) -> Result<Bytes>;
 
+    /// Get a reader for a specific number of bytes from an offset in the block.
+    ///
+    /// This is the streaming version of [BlockArchiveReader::get_bytes_from_block], use it for
+    /// large ranges. Returns [Error::InvalidRange](crate::Error::InvalidRange) if the range is
+    /// not within the block.
+    async fn get_block_range(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>>;
+
+    /// Get several ranges of bytes from the block, each range is an (offset, length) pair.
+    ///
+    /// Returns the bytes for each range, in the same order as the ranges. The ranges are all
+    /// checked before any data is read, if any range is not within the block then
+    /// [Error::InvalidRange](crate::Error::InvalidRange) is returned.
+    async fn get_ranges_from_block(
+        &self,
+        block_hash: &BlockHash,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<Bytes>>;
+
     /// Get a list of all the blocks in the archive.
     ///
     /// It returns a stream of block hashes.
@@ -74,13 +101,157 @@ pub trait BlockArchive {
     ///     while let Some(block_hash) = results.next().await {
     ///       println!("{}", block_hash);
     ///     }
-    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>>;
+    async fn block_list(&self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>>;
+
+    /// Subscribe to changes to the archive.
+    ///
+    /// It returns a stream of [BlockEvent]s, starting with the first change made after the call.
+    /// Each subscriber has a bounded buffer, a subscriber that does not keep up will receive a
+    /// [BlockEvent::Lagged] event which reports the number of events that were missed.
+    ///
+    /// Example code:
+    ///     let mut events = archive.subscribe().await.unwrap();
+    ///     while let Some(event) = events.next().await {
+    ///       println!("{:?}", event);
+    ///     }
+    async fn subscribe(&self) -> Result<Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>>;
+
+    /// Get statistics about the blocks in the archive.
+    ///
+    /// The default implementation reads the size, transaction count and header of every block
+    /// in [BlockArchiveReader::block_list]. Archives that can keep the statistics up to date as
+    /// blocks are stored and deleted override it.
+    async fn archive_stats(&self) -> Result<ArchiveStats> {
+        scan_archive_stats(self).await
+    }
+
+    /// Get the metadata recorded for a block.
+    ///
+    /// Returns empty metadata if nothing has been recorded for the block, or
+    /// [Error::BlockNotFound](crate::Error::BlockNotFound) if the block is not in the archive.
+    ///
+    /// The default implementation, for archives which cannot record metadata, always returns
+    /// empty metadata.
+    async fn block_metadata(&self, block_hash: &BlockHash) -> Result<BlockMetadata> {
+        match self.block_exists(block_hash).await? {
+            true => Ok(BlockMetadata::default()),
+            false => Err(Error::BlockNotFound(*block_hash)),
+        }
+    }
+
+    /// Check the bytes of a block against the checksum recorded in its metadata, see
+    /// [BlockMetadata::checksum].
+    ///
+    /// Returns true if the block matches its checksum, false if no checksum has been recorded
+    /// for the block, or [Error::CorruptData](crate::Error::CorruptData) if the block does not
+    /// match.
+    async fn verify_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        let metadata = self.block_metadata(block_hash).await?;
+        if metadata.checksum.is_none() {
+            return Ok(false);
+        }
+        let mut reader = self.get_block(block_hash).await?;
+        verify_reader(block_hash, &metadata, &mut reader).await
+    }
+
+    /// Check that a block is well formed: the header matches the hash and meets its difficulty
+    /// target, the transaction count matches the transactions, every transaction parses, the
+    /// first and only the first transaction is a coinbase, the merkle root matches the header
+    /// and there is nothing after the last transaction.
+    ///
+    /// The block is streamed from [BlockArchiveReader::get_block] so it is not loaded into
+    /// memory. The problems that are found are returned in the [BlockValidation], errors are
+    /// only returned if the block cannot be read.
+    async fn validate_block(&self, block_hash: &BlockHash) -> Result<BlockValidation> {
+        let reader = self.get_block(block_hash).await?;
+        validate_reader(block_hash, reader).await
+    }
+
+    /// Get the filter stored for a block, see [BlockFilter].
+    ///
+    /// Returns None if no filter has been stored for the block, or
+    /// [Error::BlockNotFound](crate::Error::BlockNotFound) if the block is not in the archive.
+    ///
+    /// The default implementation, for archives which cannot store filters, always returns None.
+    async fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        match self.block_exists(block_hash).await? {
+            true => Ok(None),
+            false => Err(Error::BlockNotFound(*block_hash)),
+        }
+    }
+}
+
+/// The write operations of a [BlockArchive].
+#[async_trait]
+pub trait BlockArchiveWriter: Send + Sync {
+    /// Store a block in the archive.
+    ///
+    /// Expects a reader for the encoded block.
+    ///
+    /// This function does not do any checking of the block, it stores the bytes of the block as is.
+    async fn store_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()>;
+
+    /// Store a full block in the archive.
+    async fn store_block_full(&self, block: &Block) -> Result<()>;
+
+    /// Remove a block from the archive.
+    ///
+    /// Returns [Error::BlockNotFound](crate::Error::BlockNotFound) if the block is not in the archive.
+    async fn delete_block(&self, block_hash: &BlockHash) -> Result<()>;
+
+    /// Store a block, replacing the copy in the archive if there is one, for example to repair a
+    /// copy that is corrupt.
+    ///
+    /// The default implementation reads the new copy into memory before deleting the old copy
+    /// and storing the new one, so the block is missing from the archive if the store fails.
+    /// Archives that can replace a block in one step override it.
+    async fn replace_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        block.read_to_end(&mut buf).await?;
+        match self.delete_block(block_hash).await {
+            Ok(_) | Err(Error::BlockNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(std::io::Cursor::new(buf));
+        self.store_block(block_hash, &mut reader).await
+    }
+
+    /// Record metadata for a block, replacing any metadata that was recorded before.
+    ///
+    /// Returns [Error::BlockNotFound](crate::Error::BlockNotFound) if the block is not in the
+    /// archive. The default implementation, for archives which cannot record metadata, returns
+    /// [Error::Unsupported](crate::Error::Unsupported).
+    async fn set_block_metadata(
+        &self,
+        _block_hash: &BlockHash,
+        _metadata: &BlockMetadata,
+    ) -> Result<()> {
+        Err(Error::Unsupported("block metadata"))
+    }
+
+    /// Store the filter of a block, replacing any filter that was stored before. Filters are
+    /// usually built and stored by a [FilterIndex](crate::FilterIndex).
+    ///
+    /// Returns [Error::BlockNotFound](crate::Error::BlockNotFound) if the block is not in the
+    /// archive. The default implementation, for archives which cannot store filters, returns
+    /// [Error::Unsupported](crate::Error::Unsupported).
+    async fn set_block_filter(&self, _block_hash: &BlockHash, _filter: &BlockFilter) -> Result<()> {
+        Err(Error::Unsupported("block filters"))
+    }
 }
 
-/// A stream of block hashes, returned by [BlockArchive::block_list].
+/// A stream of block hashes, returned by [BlockArchiveReader::block_list].
 ///
 /// Implemented as a trait for future extensibility.
-pub trait BlockHashListStream: Stream<Item = BlockHash> {}
+pub trait BlockHashListStream: Stream<Item = BlockHash> + Send {}
 
 /// An implementation of the [BlockHashListStream] trait.
 ///
@@ -0,0 +1,402 @@
+//! A synchronous interface to a block archive, for code that does not use async.
+//!
+//! [BlockingArchive] wraps any archive and runs each operation to completion on a Tokio runtime,
+//! either its own or one that it is given a handle to.
+//!
+//! Example code:
+//!     let archive = BlockingArchive::open(String::from("/mnt/blockstore/mainnet"))?;
+//!     let header = archive.block_header(&hash)?;
+//!     for block_hash in archive.block_list()? {
+//!         println!("{}", block_hash);
+//!     }
+
+use crate::block_archive::BlockHashListStream;
+use crate::events::{BlockEvent, BlockEventStream};
+use crate::{
+    ArchiveStats, BlockArchiveReader, BlockArchiveWriter, BlockFilter, BlockMetadata,
+    BlockValidation, Result, SimpleFileBasedBlockArchive,
+};
+use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader};
+use bytes::Bytes;
+use std::future::Future;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::runtime::{Handle, Runtime};
+use tokio_stream::StreamExt;
+
+/// A synchronous wrapper around an archive.
+///
+/// Each method blocks the calling thread until the operation is complete. The methods must not
+/// be called from within an async context, such as a task running on the runtime.
+pub struct BlockingArchive<A> {
+    inner: A,
+    runtime: RuntimeRef,
+}
+
+// The runtime that operations are run on. The runtime is shared with the readers and iterators
+// returned by the archive, so that it outlives them.
+#[derive(Clone)]
+struct RuntimeRef {
+    handle: Handle,
+    _owned: Option<Arc<Runtime>>,
+}
+
+impl RuntimeRef {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+}
+
+impl BlockingArchive<SimpleFileBasedBlockArchive> {
+    /// Open a [SimpleFileBasedBlockArchive] with a runtime owned by the wrapper.
+    pub fn open(root_path: String) -> Result<BlockingArchive<SimpleFileBasedBlockArchive>> {
+        let runtime = Self::new_runtime()?;
+        let archive = runtime.block_on(SimpleFileBasedBlockArchive::new(root_path))?;
+        Ok(BlockingArchive {
+            inner: archive,
+            runtime,
+        })
+    }
+}
+
+impl<A> BlockingArchive<A> {
+    /// Wrap an archive, creating a runtime which is owned by the wrapper.
+    pub fn new(inner: A) -> Result<BlockingArchive<A>> {
+        Ok(BlockingArchive {
+            inner,
+            runtime: Self::new_runtime()?,
+        })
+    }
+
+    /// Wrap an archive, running operations on an existing runtime.
+    pub fn with_handle(inner: A, handle: Handle) -> BlockingArchive<A> {
+        BlockingArchive {
+            inner,
+            runtime: RuntimeRef {
+                handle,
+                _owned: None,
+            },
+        }
+    }
+
+    /// The wrapped archive.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Unwrap the archive.
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    /// Run a future to completion on the runtime used by the archive.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    // Create a runtime for the wrapper. The runtime has its own worker thread so that the IO
+    // and timer drivers run while the calling thread is blocked.
+    fn new_runtime() -> Result<RuntimeRef> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        Ok(RuntimeRef {
+            handle: runtime.handle().clone(),
+            _owned: Some(Arc::new(runtime)),
+        })
+    }
+}
+
+impl<A: BlockArchiveReader> BlockingArchive<A> {
+    /// See [BlockArchiveReader::get_block].
+    pub fn get_block(&self, block_hash: &BlockHash) -> Result<BlockReader> {
+        let reader = self.block_on(self.inner.get_block(block_hash))?;
+        Ok(BlockReader::new(reader, &self.runtime))
+    }
+
+    /// See [BlockArchiveReader::get_block_full].
+    pub fn get_block_full(&self, block_hash: &BlockHash) -> Result<Block> {
+        self.block_on(self.inner.get_block_full(block_hash))
+    }
+
+    /// See [BlockArchiveReader::block_exists].
+    pub fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.block_on(self.inner.block_exists(block_hash))
+    }
+
+    /// See [BlockArchiveReader::block_size].
+    pub fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.block_on(self.inner.block_size(block_hash))
+    }
+
+    /// See [BlockArchiveReader::block_tx_count].
+    pub fn block_tx_count(&self, block_hash: &BlockHash) -> Result<i64> {
+        self.block_on(self.inner.block_tx_count(block_hash))
+    }
+
+    /// See [BlockArchiveReader::block_header].
+    pub fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.block_on(self.inner.block_header(block_hash))
+    }
+
+    /// See [BlockArchiveReader::get_bytes_from_block].
+    pub fn get_bytes_from_block(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Bytes> {
+        self.block_on(self.inner.get_bytes_from_block(block_hash, offset, length))
+    }
+
+    /// See [BlockArchiveReader::get_block_range].
+    pub fn get_block_range(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<BlockReader> {
+        let reader = self.block_on(self.inner.get_block_range(block_hash, offset, length))?;
+        Ok(BlockReader::new(reader, &self.runtime))
+    }
+
+    /// See [BlockArchiveReader::get_ranges_from_block].
+    pub fn get_ranges_from_block(
+        &self,
+        block_hash: &BlockHash,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<Bytes>> {
+        self.block_on(self.inner.get_ranges_from_block(block_hash, ranges))
+    }
+
+    /// See [BlockArchiveReader::block_list].
+    pub fn block_list(&self) -> Result<BlockHashIter> {
+        let stream = self.block_on(self.inner.block_list())?;
+        Ok(BlockHashIter {
+            stream,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// See [BlockArchiveReader::subscribe]. The iterator blocks until the next event.
+    pub fn subscribe(&self) -> Result<BlockEventIter> {
+        let stream = self.block_on(self.inner.subscribe())?;
+        Ok(BlockEventIter {
+            stream,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// See [BlockArchiveReader::archive_stats].
+    pub fn archive_stats(&self) -> Result<ArchiveStats> {
+        self.block_on(self.inner.archive_stats())
+    }
+
+    /// See [BlockArchiveReader::block_metadata].
+    pub fn block_metadata(&self, block_hash: &BlockHash) -> Result<BlockMetadata> {
+        self.block_on(self.inner.block_metadata(block_hash))
+    }
+
+    /// See [BlockArchiveReader::verify_block].
+    pub fn verify_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.block_on(self.inner.verify_block(block_hash))
+    }
+
+    /// See [BlockArchiveReader::validate_block].
+    pub fn validate_block(&self, block_hash: &BlockHash) -> Result<BlockValidation> {
+        self.block_on(self.inner.validate_block(block_hash))
+    }
+
+    /// See [BlockArchiveReader::block_filter].
+    pub fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        self.block_on(self.inner.block_filter(block_hash))
+    }
+}
+
+impl<A: BlockArchiveWriter> BlockingArchive<A> {
+    /// See [BlockArchiveWriter::store_block]. The block is read from the reader on the calling
+    /// thread.
+    pub fn store_block<R>(&self, block_hash: &BlockHash, block: R) -> Result<()>
+    where
+        R: Read + Send + Unpin + 'static,
+    {
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(SyncReader(block));
+        self.block_on(self.inner.store_block(block_hash, &mut reader))
+    }
+
+    /// See [BlockArchiveWriter::store_block_full].
+    pub fn store_block_full(&self, block: &Block) -> Result<()> {
+        self.block_on(self.inner.store_block_full(block))
+    }
+
+    /// See [BlockArchiveWriter::delete_block].
+    pub fn delete_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.block_on(self.inner.delete_block(block_hash))
+    }
+
+    /// See [BlockArchiveWriter::replace_block]. The block is read from the reader on the calling
+    /// thread.
+    pub fn replace_block<R>(&self, block_hash: &BlockHash, block: R) -> Result<()>
+    where
+        R: Read + Send + Unpin + 'static,
+    {
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(SyncReader(block));
+        self.block_on(self.inner.replace_block(block_hash, &mut reader))
+    }
+
+    /// See [BlockArchiveWriter::set_block_metadata].
+    pub fn set_block_metadata(
+        &self,
+        block_hash: &BlockHash,
+        metadata: &BlockMetadata,
+    ) -> Result<()> {
+        self.block_on(self.inner.set_block_metadata(block_hash, metadata))
+    }
+
+    /// See [BlockArchiveWriter::set_block_filter].
+    pub fn set_block_filter(&self, block_hash: &BlockHash, filter: &BlockFilter) -> Result<()> {
+        self.block_on(self.inner.set_block_filter(block_hash, filter))
+    }
+}
+
+/// A reader for the bytes of a block, returned by [BlockingArchive::get_block] and
+/// [BlockingArchive::get_block_range].
+pub struct BlockReader {
+    inner: Box<dyn AsyncRead + Unpin + Send>,
+    runtime: RuntimeRef,
+}
+
+impl BlockReader {
+    fn new(inner: Box<dyn AsyncRead + Unpin + Send>, runtime: &RuntimeRef) -> BlockReader {
+        BlockReader {
+            inner,
+            runtime: runtime.clone(),
+        }
+    }
+}
+
+impl Read for BlockReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.runtime.block_on(self.inner.read(buf))
+    }
+}
+
+/// An iterator over the blocks in an archive, returned by [BlockingArchive::block_list].
+pub struct BlockHashIter {
+    stream: Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>,
+    runtime: RuntimeRef,
+}
+
+impl Iterator for BlockHashIter {
+    type Item = BlockHash;
+
+    fn next(&mut self) -> Option<BlockHash> {
+        self.runtime.block_on(self.stream.next())
+    }
+}
+
+/// An iterator over the changes to an archive, returned by [BlockingArchive::subscribe].
+pub struct BlockEventIter {
+    stream: Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>,
+    runtime: RuntimeRef,
+}
+
+impl Iterator for BlockEventIter {
+    type Item = BlockEvent;
+
+    fn next(&mut self) -> Option<BlockEvent> {
+        self.runtime.block_on(self.stream.next())
+    }
+}
+
+// Presents a synchronous reader as an async reader. The reads block, which is acceptable
+// because the future that uses it is run on the calling thread by Handle::block_on.
+struct SyncReader<R>(R);
+
+impl<R: Read + Unpin> AsyncRead for SyncReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let n = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::FromHex;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    fn get_block_hash() -> BlockHash {
+        BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+            .unwrap()
+    }
+
+    // Blocks can be read and listed without an async context
+    #[test]
+    fn test_blocking_read() {
+        let archive = BlockingArchive::open(String::from("testdata/blockarchive")).unwrap();
+        let h = get_block_hash();
+        assert!(archive.block_exists(&h).unwrap());
+        assert_eq!(archive.block_header(&h).unwrap().timestamp(), 1364004432);
+        let mut buf = Vec::new();
+        archive
+            .get_block(&h)
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf.len(), 227);
+        assert_eq!(buf, archive.get_block_full(&h).unwrap().raw);
+        let mut range = Vec::new();
+        archive
+            .get_block_range(&h, 4, 32)
+            .unwrap()
+            .read_to_end(&mut range)
+            .unwrap();
+        assert_eq!(range, buf[4..36]);
+        assert_eq!(archive.block_list().unwrap().count(), 3);
+        assert!(archive
+            .block_size(&BlockHash::ZERO)
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    // Blocks can be stored from a synchronous reader, using a runtime that is not owned by the
+    // archive
+    #[test]
+    fn test_blocking_store() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let archive = runtime
+            .block_on(SimpleFileBasedBlockArchive::new(
+                dir.path().to_str().unwrap().to_string(),
+            ))
+            .unwrap();
+        let archive = BlockingArchive::with_handle(archive, runtime.handle().clone());
+        let source = BlockingArchive::open(String::from("testdata/blockarchive")).unwrap();
+        let h = get_block_hash();
+        let raw = source.get_block_full(&h).unwrap().raw;
+        let mut events = archive.subscribe().unwrap();
+        archive.store_block(&h, Cursor::new(raw.to_vec())).unwrap();
+        assert_eq!(
+            events.next(),
+            Some(BlockEvent::Stored {
+                hash: h,
+                size: raw.len()
+            })
+        );
+        assert_eq!(archive.get_block_full(&h).unwrap().raw, raw);
+        assert!(archive.verify_block(&h).unwrap());
+        archive.delete_block(&h).unwrap();
+        assert!(!archive.block_exists(&h).unwrap());
+    }
+}
@@ -0,0 +1,773 @@
+use crate::block_archive::BlockHashListStream;
+use crate::events::{BlockEvent, BlockEventStream};
+use crate::validate::has_header_and_tx_count;
+use crate::{
+    ArchiveStats, BlockArchiveReader, BlockArchiveWriter, BlockFilter, BlockMetadata, Error, Result,
+};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader};
+use bytes::Bytes;
+use lru::LruCache;
+use std::io::Cursor;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+// The approximate memory used by an entry in the metadata cache, including the header, the
+// hash key and the overhead of the cache itself.
+const METADATA_ENTRY_SIZE: usize = 256;
+
+/// Configuration for a [CachedBlockArchive].
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Memory budget, in bytes, for caching block headers, sizes and transaction counts.
+    pub metadata_budget: usize,
+    /// Memory budget, in bytes, for caching whole blocks.
+    pub block_budget: usize,
+    /// Blocks larger than this are never cached.
+    pub max_cached_block_size: usize,
+    /// The maximum number of blocks that are remembered as not being in the archive.
+    pub not_found_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            metadata_budget: 64 * 1024 * 1024,
+            block_budget: 256 * 1024 * 1024,
+            max_cached_block_size: 1024 * 1024,
+            not_found_entries: 100_000,
+        }
+    }
+}
+
+/// Hit and miss statistics for a [CachedBlockArchive].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Requests for headers, sizes and transaction counts that were answered from the cache.
+    pub metadata_hits: u64,
+    /// Requests for headers, sizes and transaction counts that were passed to the archive.
+    pub metadata_misses: u64,
+    /// Requests for block data that were answered from the cache.
+    pub block_hits: u64,
+    /// Requests for block data that were passed to the archive.
+    pub block_misses: u64,
+    /// Requests that were answered from the cache of blocks that are not in the archive.
+    pub not_found_hits: u64,
+    /// Entries that have been evicted from the cache to stay within the memory budget.
+    pub evictions: u64,
+}
+
+/// A wrapper around a [BlockArchive](crate::BlockArchive) which caches headers, sizes and transaction counts, small
+/// blocks, and blocks which are not in the archive.
+///
+/// The cache is invalidated when blocks are stored or deleted through the wrapper, and when
+/// the wrapped archive publishes a change (see [BlockArchiveReader::subscribe]). If the
+/// subscription lags then the whole cache is cleared.
+///
+/// Example code:
+///     let archive = SimpleFileBasedBlockArchive::new(root_path).await?;
+///     let cached = CachedBlockArchive::new(archive, CacheConfig::default()).await?;
+///     let header = cached.block_header(&hash).await?;
+///     println!("{:?}", cached.stats());
+pub struct CachedBlockArchive<A: BlockArchiveReader> {
+    inner: A,
+    config: CacheConfig,
+    state: Arc<Mutex<CacheState>>,
+    // Handle to the background task that invalidates the cache when the archive changes.
+    invalidator: JoinHandle<()>,
+}
+
+impl<A: BlockArchiveReader> CachedBlockArchive<A> {
+    /// Create a new cache around the given archive.
+    pub async fn new(inner: A, config: CacheConfig) -> Result<CachedBlockArchive<A>> {
+        let state = Arc::new(Mutex::new(CacheState::new(&config)));
+        let events = inner.subscribe().await?;
+        let invalidator = tokio::spawn(CacheState::invalidate_bgrnd(state.clone(), events));
+        Ok(CachedBlockArchive {
+            inner,
+            config,
+            state,
+            invalidator,
+        })
+    }
+
+    /// The wrapped archive.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Get the hit and miss statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Remove everything from the cache.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().clear();
+    }
+
+    // Check whether the block is known to be missing, recording a hit if it is.
+    fn is_known_missing(&self, block_hash: &BlockHash) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.not_found.get(block_hash).is_some() {
+            state.stats.not_found_hits += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Get the block from the cache, recording a hit or a miss.
+    fn cached_block(&self, block_hash: &BlockHash) -> Option<Bytes> {
+        let mut state = self.state.lock().unwrap();
+        match state.blocks.get(block_hash).cloned() {
+            Some(raw) => {
+                state.stats.block_hits += 1;
+                Some(raw)
+            }
+            None => {
+                state.stats.block_misses += 1;
+                None
+            }
+        }
+    }
+
+    // Get the metadata from the cache, recording a hit or a miss.
+    fn cached_metadata<T, F>(&self, block_hash: &BlockHash, field: F) -> Option<T>
+    where
+        F: Fn(&CachedMetadata) -> Option<T>,
+    {
+        let mut state = self.state.lock().unwrap();
+        match state.metadata.get(block_hash).and_then(field) {
+            Some(value) => {
+                state.stats.metadata_hits += 1;
+                Some(value)
+            }
+            None => {
+                state.stats.metadata_misses += 1;
+                None
+            }
+        }
+    }
+
+    // The generation of the cache, to pass to the functions that add entries.
+    fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    // Update the metadata in the cache, unless the cache has been invalidated since the
+    // generation was read.
+    fn update_metadata<F>(&self, block_hash: &BlockHash, generation: u64, update: F)
+    where
+        F: FnOnce(&mut CachedMetadata),
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        if let Some(m) = state.metadata.get_mut(block_hash) {
+            update(m);
+            return;
+        }
+        let mut m = CachedMetadata::default();
+        update(&mut m);
+        state.metadata.put(*block_hash, m);
+        state.metadata_bytes += METADATA_ENTRY_SIZE;
+        state.evict(&self.config);
+    }
+
+    // Add a block to the cache, if it is small enough and the cache has not been invalidated
+    // since the generation was read.
+    fn cache_block(&self, block_hash: &BlockHash, generation: u64, raw: &Bytes) {
+        if raw.len() > self.config.max_cached_block_size {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        if let Some(old) = state.blocks.put(*block_hash, raw.clone()) {
+            state.block_bytes -= old.len();
+        }
+        state.block_bytes += raw.len();
+        state.evict(&self.config);
+    }
+
+    // Remember a block which is not in the archive, unless the cache has been invalidated
+    // since the generation was read. A lookup that overlaps a store must not hide the block.
+    fn note_missing(&self, block_hash: &BlockHash, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            state.not_found.put(*block_hash, ());
+        }
+    }
+
+    // Remember blocks which are not in the archive.
+    fn note_result<T>(&self, block_hash: &BlockHash, generation: u64, result: &Result<T>) {
+        if let Err(Error::BlockNotFound(_)) = result {
+            self.note_missing(block_hash, generation);
+        }
+    }
+
+    // Load a small block into the cache. Returns None if the block is too large to cache.
+    async fn load_block(&self, block_hash: &BlockHash, generation: u64) -> Result<Option<Bytes>> {
+        if self.block_size(block_hash).await? > self.config.max_cached_block_size {
+            return Ok(None);
+        }
+        let mut reader = self.inner.get_block(block_hash).await?;
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.map_err(Error::from)?;
+        let raw = Bytes::from(buf);
+        self.cache_block(block_hash, generation, &raw);
+        Ok(Some(raw))
+    }
+
+    // Check that a range of bytes is within a cached block.
+    fn check_range(block_hash: &BlockHash, raw: &Bytes, offset: u64, length: u64) -> Result<()> {
+        match offset.checked_add(length) {
+            Some(end) if end <= raw.len() as u64 => Ok(()),
+            _ => Err(Error::InvalidRange {
+                hash: *block_hash,
+                offset,
+                length,
+                size: raw.len() as u64,
+            }),
+        }
+    }
+}
+
+impl<A: BlockArchiveReader> Drop for CachedBlockArchive<A> {
+    // stop the background task when the cache is dropped
+    fn drop(&mut self) {
+        self.invalidator.abort();
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchiveReader> BlockArchiveReader for CachedBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(raw) = self.cached_block(block_hash) {
+            return Ok(Box::new(Cursor::new(raw)));
+        }
+        let result = match self.load_block(block_hash, generation).await {
+            Ok(Some(raw)) => Ok(Box::new(Cursor::new(raw)) as Box<dyn AsyncRead + Unpin + Send>),
+            Ok(None) => self.inner.get_block(block_hash).await,
+            Err(e) => Err(e),
+        };
+        self.note_result(block_hash, generation, &result);
+        result
+    }
+
+    async fn get_block_full(&self, block_hash: &BlockHash) -> Result<Block> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(raw) = self.cached_block(block_hash) {
+            if !has_header_and_tx_count(&raw) {
+                return Err(Error::CorruptData {
+                    hash: *block_hash,
+                    reason: String::from("block is too short to contain a header and tx count"),
+                });
+            }
+            return Ok(Block::new(raw)?);
+        }
+        let result = self.inner.get_block_full(block_hash).await;
+        self.note_result(block_hash, generation, &result);
+        if let Ok(block) = &result {
+            self.update_metadata(block_hash, generation, |m| {
+                m.size = Some(block.raw.len());
+                m.tx_count = Some(block.num_tx as i64);
+            });
+            self.cache_block(block_hash, generation, &block.raw);
+        }
+        result
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        if self.is_known_missing(block_hash) {
+            return Ok(false);
+        }
+        let generation = self.generation();
+        if self.cached_metadata(block_hash, |m| m.size).is_some() {
+            return Ok(true);
+        }
+        let exists = self.inner.block_exists(block_hash).await?;
+        if !exists {
+            self.note_missing(block_hash, generation);
+        }
+        Ok(exists)
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(size) = self.cached_metadata(block_hash, |m| m.size) {
+            return Ok(size);
+        }
+        let result = self.inner.block_size(block_hash).await;
+        self.note_result(block_hash, generation, &result);
+        if let Ok(size) = result {
+            self.update_metadata(block_hash, generation, |m| m.size = Some(size));
+        }
+        result
+    }
+
+    async fn block_tx_count(&self, block_hash: &BlockHash) -> Result<i64> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(count) = self.cached_metadata(block_hash, |m| m.tx_count) {
+            return Ok(count);
+        }
+        let result = self.inner.block_tx_count(block_hash).await;
+        self.note_result(block_hash, generation, &result);
+        if let Ok(count) = result {
+            self.update_metadata(block_hash, generation, |m| m.tx_count = Some(count));
+        }
+        result
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(header) = self.cached_metadata(block_hash, |m| m.header.clone()) {
+            return Ok(header);
+        }
+        let result = self.inner.block_header(block_hash).await;
+        self.note_result(block_hash, generation, &result);
+        if let Ok(header) = &result {
+            self.update_metadata(block_hash, generation, |m| m.header = Some(header.clone()));
+        }
+        result
+    }
+
+    async fn get_bytes_from_block(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Bytes> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(raw) = self.cached_block(block_hash) {
+            Self::check_range(block_hash, &raw, offset, length)?;
+            return Ok(raw.slice(offset as usize..(offset + length) as usize));
+        }
+        let result = self
+            .inner
+            .get_bytes_from_block(block_hash, offset, length)
+            .await;
+        self.note_result(block_hash, generation, &result);
+        result
+    }
+
+    async fn get_block_range(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(raw) = self.cached_block(block_hash) {
+            Self::check_range(block_hash, &raw, offset, length)?;
+            let range = raw.slice(offset as usize..(offset + length) as usize);
+            return Ok(Box::new(Cursor::new(range)));
+        }
+        let result = self.inner.get_block_range(block_hash, offset, length).await;
+        self.note_result(block_hash, generation, &result);
+        result
+    }
+
+    async fn get_ranges_from_block(
+        &self,
+        block_hash: &BlockHash,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<Bytes>> {
+        if self.is_known_missing(block_hash) {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let generation = self.generation();
+        if let Some(raw) = self.cached_block(block_hash) {
+            for (offset, length) in ranges.iter() {
+                Self::check_range(block_hash, &raw, *offset, *length)?;
+            }
+            return Ok(ranges
+                .iter()
+                .map(|(offset, length)| raw.slice(*offset as usize..(offset + length) as usize))
+                .collect());
+        }
+        let result = self.inner.get_ranges_from_block(block_hash, ranges).await;
+        self.note_result(block_hash, generation, &result);
+        result
+    }
+
+    async fn block_list(&self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>> {
+        self.inner.block_list().await
+    }
+
+    async fn subscribe(&self) -> Result<Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>> {
+        self.inner.subscribe().await
+    }
+
+    async fn archive_stats(&self) -> Result<ArchiveStats> {
+        self.inner.archive_stats().await
+    }
+
+    async fn block_metadata(&self, block_hash: &BlockHash) -> Result<BlockMetadata> {
+        self.inner.block_metadata(block_hash).await
+    }
+
+    async fn verify_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.inner.verify_block(block_hash).await
+    }
+
+    async fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        self.inner.block_filter(block_hash).await
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchiveWriter + BlockArchiveReader> BlockArchiveWriter for CachedBlockArchive<A> {
+    async fn store_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        let result = self.inner.store_block(block_hash, block).await;
+        self.state.lock().unwrap().invalidate(block_hash);
+        result
+    }
+
+    async fn store_block_full(&self, block: &Block) -> Result<()> {
+        let block_hash = block.header()?.hash();
+        let result = self.inner.store_block_full(block).await;
+        self.state.lock().unwrap().invalidate(&block_hash);
+        result
+    }
+
+    async fn delete_block(&self, block_hash: &BlockHash) -> Result<()> {
+        let result = self.inner.delete_block(block_hash).await;
+        self.state.lock().unwrap().invalidate(block_hash);
+        result
+    }
+
+    async fn replace_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        let result = self.inner.replace_block(block_hash, block).await;
+        self.state.lock().unwrap().invalidate(block_hash);
+        result
+    }
+
+    async fn set_block_metadata(
+        &self,
+        block_hash: &BlockHash,
+        metadata: &BlockMetadata,
+    ) -> Result<()> {
+        self.inner.set_block_metadata(block_hash, metadata).await
+    }
+
+    async fn set_block_filter(&self, block_hash: &BlockHash, filter: &BlockFilter) -> Result<()> {
+        self.inner.set_block_filter(block_hash, filter).await
+    }
+}
+
+// The cached information about a block, each field is filled in when it is first requested.
+#[derive(Debug, Default)]
+struct CachedMetadata {
+    header: Option<BlockHeader>,
+    size: Option<usize>,
+    tx_count: Option<i64>,
+}
+
+// The contents of the cache, protected by a mutex. The mutex is never held across an await.
+struct CacheState {
+    metadata: LruCache<BlockHash, CachedMetadata>,
+    metadata_bytes: usize,
+    blocks: LruCache<BlockHash, Bytes>,
+    block_bytes: usize,
+    not_found: LruCache<BlockHash, ()>,
+    // Changes whenever entries are invalidated, so that a lookup which overlaps a change does
+    // not add a stale entry.
+    generation: u64,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn new(config: &CacheConfig) -> CacheState {
+        let not_found_entries =
+            NonZeroUsize::new(config.not_found_entries).unwrap_or(NonZeroUsize::MIN);
+        CacheState {
+            metadata: LruCache::unbounded(),
+            metadata_bytes: 0,
+            blocks: LruCache::unbounded(),
+            block_bytes: 0,
+            not_found: LruCache::new(not_found_entries),
+            generation: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // Invalidate cache entries when the archive publishes a change.
+    async fn invalidate_bgrnd(
+        state: Arc<Mutex<CacheState>>,
+        mut events: Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>,
+    ) {
+        while let Some(event) = events.next().await {
+            let mut state = state.lock().unwrap();
+            match event {
+                BlockEvent::Stored { hash, .. } | BlockEvent::Deleted { hash } => {
+                    state.invalidate(&hash)
+                }
+                // we don't know what we've missed
+                BlockEvent::Lagged { .. } => state.clear(),
+            }
+        }
+    }
+
+    // Remove everything about a block from the cache.
+    fn invalidate(&mut self, block_hash: &BlockHash) {
+        self.generation += 1;
+        if self.metadata.pop(block_hash).is_some() {
+            self.metadata_bytes -= METADATA_ENTRY_SIZE;
+        }
+        if let Some(raw) = self.blocks.pop(block_hash) {
+            self.block_bytes -= raw.len();
+        }
+        self.not_found.pop(block_hash);
+    }
+
+    // Remove everything from the cache, but keep the statistics.
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.metadata.clear();
+        self.metadata_bytes = 0;
+        self.blocks.clear();
+        self.block_bytes = 0;
+        self.not_found.clear();
+    }
+
+    // Evict the least recently used entries until the cache is within its memory budgets.
+    fn evict(&mut self, config: &CacheConfig) {
+        while self.metadata_bytes > config.metadata_budget {
+            if self.metadata.pop_lru().is_none() {
+                break;
+            }
+            self.metadata_bytes -= METADATA_ENTRY_SIZE;
+            self.stats.evictions += 1;
+        }
+        while self.block_bytes > config.block_budget {
+            match self.blocks.pop_lru() {
+                Some((_, raw)) => self.block_bytes -= raw.len(),
+                None => break,
+            }
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleFileBasedBlockArchive;
+    use hex::FromHex;
+    use tempfile::tempdir;
+
+    fn get_testdata_path() -> String {
+        String::from("testdata/blockarchive")
+    }
+
+    // Repeated requests for metadata are answered from the cache
+    #[tokio::test]
+    async fn test_metadata_cache() {
+        let archive = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let cached = CachedBlockArchive::new(archive, CacheConfig::default())
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        for _ in 0..3 {
+            assert_eq!(cached.block_size(&h).await.unwrap(), 227);
+            assert_eq!(cached.block_header(&h).await.unwrap().version(), 2);
+            assert_eq!(cached.block_tx_count(&h).await.unwrap(), 1);
+        }
+        let stats = cached.stats();
+        assert_eq!(stats.metadata_misses, 3);
+        assert_eq!(stats.metadata_hits, 6);
+    }
+
+    // Small blocks are cached and served from memory
+    #[tokio::test]
+    async fn test_block_cache() {
+        let archive = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let cached = CachedBlockArchive::new(archive, CacheConfig::default())
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block = cached.get_block_full(&h).await.unwrap();
+        let mut reader = cached.get_block(&h).await.unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, block.raw);
+        let bytes = cached.get_bytes_from_block(&h, 0, 80).await.unwrap();
+        assert_eq!(bytes, block.header().unwrap().raw);
+        assert!(cached.get_bytes_from_block(&h, 200, 28).await.is_err());
+        let stats = cached.stats();
+        assert_eq!(stats.block_misses, 1);
+        assert_eq!(stats.block_hits, 3);
+    }
+
+    // The memory budget for blocks is respected
+    #[tokio::test]
+    async fn test_block_budget() {
+        let archive = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let config = CacheConfig {
+            block_budget: 300,
+            ..CacheConfig::default()
+        };
+        let cached = CachedBlockArchive::new(archive, config).await.unwrap();
+        let h1 =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let h2 =
+            BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048")
+                .unwrap();
+        cached.get_block_full(&h1).await.unwrap();
+        cached.get_block_full(&h2).await.unwrap();
+        cached.get_block_full(&h1).await.unwrap();
+        let stats = cached.stats();
+        assert_eq!(stats.block_misses, 3);
+        assert_eq!(stats.evictions, 2);
+    }
+
+    // Missing blocks are remembered until they are stored
+    #[tokio::test]
+    async fn test_not_found_cache() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let cached = CachedBlockArchive::new(archive, CacheConfig::default())
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        assert!(!cached.block_exists(&h).await.unwrap());
+        assert!(cached.block_size(&h).await.unwrap_err().is_not_found());
+        assert_eq!(cached.stats().not_found_hits, 1);
+        let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+        cached
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+            .unwrap();
+        assert!(cached.block_exists(&h).await.unwrap());
+        assert_eq!(cached.block_size(&h).await.unwrap(), 15);
+        // a cached block that is too short is reported as corrupt
+        cached.get_block(&h).await.unwrap();
+        assert!(matches!(
+            cached.get_block_full(&h).await,
+            Err(Error::CorruptData { .. })
+        ));
+        cached.delete_block(&h).await.unwrap();
+        assert!(!cached.block_exists(&h).await.unwrap());
+
+        // a lookup which overlaps a change to the block is not remembered
+        let generation = cached.generation();
+        cached.state.lock().unwrap().invalidate(&h);
+        cached.note_missing(&h, generation);
+        assert!(!cached.is_known_missing(&h));
+    }
+
+    // A cached block without a complete tx count is reported as corrupt
+    #[tokio::test]
+    async fn test_short_cached_block() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h1 =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let h2 =
+            BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048")
+                .unwrap();
+        let mut cut_varint = vec![0u8; 80];
+        cut_varint.push(0xfd);
+        for (h, raw) in [(h1, vec![0u8; 80]), (h2, cut_varint)] {
+            let block_cursor = Box::new(Cursor::new(raw));
+            archive
+                .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+                .await
+                .unwrap();
+        }
+        let cached = CachedBlockArchive::new(archive, CacheConfig::default())
+            .await
+            .unwrap();
+        for h in [h1, h2] {
+            cached.get_block(&h).await.unwrap();
+            assert!(cached.cached_block(&h).is_some());
+            assert!(matches!(
+                cached.get_block_full(&h).await,
+                Err(Error::CorruptData { .. })
+            ));
+        }
+    }
+
+    // Changes made directly to the wrapped archive invalidate the cache
+    #[tokio::test]
+    async fn test_invalidate_from_events() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let cached = CachedBlockArchive::new(archive, CacheConfig::default())
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        assert!(!cached.block_exists(&h).await.unwrap());
+        let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+        cached
+            .inner()
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+            .unwrap();
+        // the invalidation happens in the background
+        for _ in 0..100 {
+            if cached.block_exists(&h).await.unwrap() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("cache was not invalidated");
+    }
+}
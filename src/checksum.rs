@@ -0,0 +1,211 @@
+use crate::{BlockMetadata, Error, Result};
+use bitcoinsv::bitcoin::BlockHash;
+use hex::{FromHex, ToHex};
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+/// Calculate the checksum of the bytes of a block, as recorded in [BlockMetadata::checksum]: the
+/// hex encoded SHA-256 digest.
+pub fn block_checksum(data: &[u8]) -> String {
+    Sha256::digest(data).encode_hex()
+}
+
+// Parse a checksum recorded in the metadata of a block.
+pub(crate) fn parse_checksum(block_hash: &BlockHash, checksum: &str) -> Result<[u8; 32]> {
+    <[u8; 32]>::from_hex(checksum).map_err(|_| Error::CorruptData {
+        hash: *block_hash,
+        reason: format!("invalid checksum {checksum:?}"),
+    })
+}
+
+// The error for a block whose bytes do not match its recorded checksum.
+pub(crate) fn checksum_mismatch(block_hash: &BlockHash) -> Error {
+    Error::CorruptData {
+        hash: *block_hash,
+        reason: String::from("checksum mismatch"),
+    }
+}
+
+// Calculate the sha256 digest and size of the data in a reader.
+pub(crate) async fn digest_reader<R>(reader: &mut R) -> std::io::Result<([u8; 32], u64)>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((hasher.finalize().into(), size))
+}
+
+// Check the bytes in a reader against the checksum in the metadata of a block. Returns false if
+// no checksum has been recorded, or CorruptData if the bytes do not match.
+pub(crate) async fn verify_reader<R>(
+    block_hash: &BlockHash,
+    metadata: &BlockMetadata,
+    reader: &mut R,
+) -> Result<bool>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let expected = match metadata.checksum.as_deref() {
+        Some(checksum) => parse_checksum(block_hash, checksum)?,
+        None => return Ok(false),
+    };
+    let (digest, _) = digest_reader(reader).await?;
+    match digest == expected {
+        true => Ok(true),
+        false => Err(checksum_mismatch(block_hash)),
+    }
+}
+
+// A reader which calculates the digest and size of the bytes that are read from it. The digest
+// is kept in a HashingState, which can be shared with handle() to get the digest after the
+// reader has been handed to a function that consumes it, such as store_block.
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    state: Arc<Mutex<HashingState>>,
+}
+
+// The digest and size of the bytes that have passed through a HashingReader.
+#[derive(Default)]
+pub(crate) struct HashingState {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl HashingState {
+    // The digest and size of the bytes read so far.
+    pub fn finish(&mut self) -> ([u8; 32], u64) {
+        (
+            std::mem::take(&mut self.hasher).finalize().into(),
+            self.size,
+        )
+    }
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            state: Arc::new(Mutex::new(HashingState::default())),
+        }
+    }
+
+    // A handle on the digest, which stays valid when the reader is dropped.
+    pub fn handle(&self) -> Arc<Mutex<HashingState>> {
+        self.state.clone()
+    }
+
+    // The digest of the bytes read so far.
+    pub fn finish(&mut self) -> [u8; 32] {
+        self.state.lock().unwrap().finish().0
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let data = &buf.filled()[start..];
+            let mut state = self.state.lock().unwrap();
+            state.hasher.update(data);
+            state.size += data.len() as u64;
+        }
+        result
+    }
+}
+
+/// A reader which checks the bytes of a block against its recorded checksum.
+///
+/// The bytes are passed through as they are read. When the end of the block is reached the
+/// digest is compared with the checksum, and if they differ the read fails with an error of
+/// kind [InvalidData](std::io::ErrorKind::InvalidData), so a reader that reaches the end without
+/// an error has seen the correct bytes.
+pub struct VerifyingReader<R> {
+    inner: HashingReader<R>,
+    block_hash: BlockHash,
+    expected: [u8; 32],
+    verified: bool,
+}
+
+impl<R> VerifyingReader<R> {
+    /// Create a reader which checks the bytes read from `inner` against the SHA-256 digest
+    /// `expected`.
+    pub fn new(inner: R, block_hash: BlockHash, expected: [u8; 32]) -> VerifyingReader<R> {
+        VerifyingReader {
+            inner: HashingReader::new(inner),
+            block_hash,
+            expected,
+            verified: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for VerifyingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        // the end of the data is reached when a read with space in the buffer returns nothing
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() == start && buf.remaining() > 0 && !self.verified {
+                self.verified = true;
+                if self.inner.finish() != self.expected {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        checksum_mismatch(&self.block_hash).to_string(),
+                    )));
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_block_hash() -> BlockHash {
+        BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531")
+            .unwrap()
+    }
+
+    // The verifying reader passes correct data through and fails at the end on a mismatch
+    #[tokio::test]
+    async fn test_verifying_reader() {
+        let data = vec![7u8; 100_000];
+        let expected = <[u8; 32]>::from_hex(block_checksum(&data)).unwrap();
+        let mut reader = VerifyingReader::new(&data[..], get_block_hash(), expected);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+
+        let mut corrupt = data.clone();
+        corrupt[50_000] = 8;
+        let mut reader = VerifyingReader::new(&corrupt[..], get_block_hash(), expected);
+        let mut buf = Vec::new();
+        let e = reader.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(buf.len(), corrupt.len());
+    }
+}
@@ -0,0 +1,106 @@
+use bitcoinsv::bitcoin::BlockHash;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::Stream;
+
+/// The default number of events that are buffered for each subscriber.
+pub const DEFAULT_EVENT_BUFFER: usize = 1024;
+
+/// A change to the contents of a [BlockArchive](crate::BlockArchive).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockEvent {
+    /// A block has been stored in the archive.
+    Stored { hash: BlockHash, size: usize },
+    /// A block has been removed from the archive.
+    Deleted { hash: BlockHash },
+    /// The subscriber did not keep up and `missed` events were dropped.
+    ///
+    /// After receiving this event, a subscriber that needs a complete view of the archive should
+    /// re-read it using [BlockArchiveReader::block_list](crate::BlockArchiveReader::block_list).
+    Lagged { missed: u64 },
+}
+
+/// A stream of block events, returned by
+/// [BlockArchiveReader::subscribe](crate::BlockArchiveReader::subscribe).
+///
+/// Implemented as a trait for future extensibility.
+pub trait BlockEventStream: Stream<Item = BlockEvent> + Send {}
+
+/// Publishes [BlockEvent]s to any number of subscribers.
+///
+/// Each subscriber has a bounded buffer. If a subscriber falls too far behind then the oldest
+/// events are dropped and the subscriber receives a [BlockEvent::Lagged] event instead. Clones
+/// of a publisher share its subscribers.
+#[derive(Debug, Clone)]
+pub struct BlockEventPublisher {
+    sender: Arc<RwLock<broadcast::Sender<BlockEvent>>>,
+}
+
+impl BlockEventPublisher {
+    /// Create a new publisher which buffers up to `buffer_size` events for each subscriber.
+    pub fn new(buffer_size: usize) -> BlockEventPublisher {
+        let (sender, _) = broadcast::channel(buffer_size.max(1));
+        BlockEventPublisher {
+            sender: Arc::new(RwLock::new(sender)),
+        }
+    }
+
+    /// Change the number of events that are buffered for each subscriber, for this publisher
+    /// and all its clones. Existing subscriptions are closed.
+    pub fn set_buffer_size(&self, buffer_size: usize) {
+        let (sender, _) = broadcast::channel(buffer_size.max(1));
+        *self.sender.write().unwrap() = sender;
+    }
+
+    /// Send an event to all current subscribers.
+    pub fn publish(&self, event: BlockEvent) {
+        // an error only means that there are no subscribers, which is fine
+        let _ = self.sender.read().unwrap().send(event);
+    }
+
+    /// Create a new subscription. Only events published after this call are received.
+    pub fn subscribe(&self) -> BlockEventStreamFromBroadcast {
+        BlockEventStreamFromBroadcast::new(self.sender.read().unwrap().subscribe())
+    }
+}
+
+impl Default for BlockEventPublisher {
+    fn default() -> Self {
+        BlockEventPublisher::new(DEFAULT_EVENT_BUFFER)
+    }
+}
+
+/// An implementation of the [BlockEventStream] trait which reads events from a broadcast channel.
+pub struct BlockEventStreamFromBroadcast {
+    inner: BroadcastStream<BlockEvent>,
+}
+
+impl BlockEventStreamFromBroadcast {
+    /// Create a new stream from the receiving end of a broadcast channel.
+    pub fn new(receiver: broadcast::Receiver<BlockEvent>) -> BlockEventStreamFromBroadcast {
+        BlockEventStreamFromBroadcast {
+            inner: BroadcastStream::new(receiver),
+        }
+    }
+}
+
+impl Stream for BlockEventStreamFromBroadcast {
+    type Item = BlockEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(event))) => Poll::Ready(Some(event)),
+            Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(missed)))) => {
+                Poll::Ready(Some(BlockEvent::Lagged { missed }))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl BlockEventStream for BlockEventStreamFromBroadcast {}
@@ -0,0 +1,623 @@
+use crate::tx_reader::BlockTxReader;
+use crate::{BlockArchiveReader, BlockArchiveWriter, Error, Result};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, Hash, Outpoint, TxHash};
+use bytes::{BufMut, Bytes, BytesMut};
+use siphasher::sip::SipHasher24;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// The number of bits in the remainder of each element of a filter, the P parameter of the basic
+/// filter type in BIP158.
+pub const FILTER_P: u8 = 19;
+
+/// The inverse of the false positive rate of a filter, the M parameter of the basic filter type
+/// in BIP158.
+pub const FILTER_M: u64 = 784_931;
+
+pub(crate) const OP_RETURN: u8 = 0x6a;
+
+/// A Golomb-coded set filter of the scripts used by a block, in the format of the basic filter
+/// type from BIP158.
+///
+/// The filter contains the output scripts of the transactions in the block, apart from those
+/// that start with OP_RETURN, and the scripts of the outputs spent by the block. A client can
+/// check whether a block uses any of its scripts without downloading the block, with a false
+/// positive rate of 1 in [FILTER_M] for each script.
+///
+/// Example code:
+///     if let Some(filter) = archive.block_filter(&hash).await? {
+///         if filter.matches_any(scripts.iter().map(|s| &s[..]))? {
+///             let block = archive.get_block_full(&hash).await?;
+///         }
+///     }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    block_hash: BlockHash,
+    element_count: u64,
+    raw: Bytes,
+}
+
+impl BlockFilter {
+    /// Create a filter from its serialized form: the number of elements as a varint followed by
+    /// the Golomb-coded set.
+    ///
+    /// Returns [Error::CorruptData] if the number of elements cannot be read.
+    pub fn new(block_hash: BlockHash, raw: Bytes) -> Result<BlockFilter> {
+        let mut reader = BitReader::new(&raw);
+        let element_count = reader.read_varint().ok_or_else(|| Error::CorruptData {
+            hash: block_hash,
+            reason: String::from("the filter is too short to contain the number of elements"),
+        })?;
+        Ok(BlockFilter {
+            block_hash,
+            element_count,
+            raw,
+        })
+    }
+
+    /// Build the filter of a block from its elements. Duplicate elements are only included once.
+    pub fn build<'a, I>(block_hash: BlockHash, elements: I) -> BlockFilter
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let elements = elements.into_iter().collect::<HashSet<_>>();
+        let element_count = elements.len() as u64;
+        let mut values = hash_elements(&block_hash, element_count, elements);
+        values.sort_unstable();
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            for _ in 0..delta >> FILTER_P {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, FILTER_P);
+            last = value;
+        }
+        let mut raw = BytesMut::new();
+        put_varint(&mut raw, element_count);
+        raw.put_slice(&writer.finish());
+        BlockFilter {
+            block_hash,
+            element_count,
+            raw: raw.freeze(),
+        }
+    }
+
+    /// The hash of the block that the filter is for, which is the key for the hashes of the
+    /// elements.
+    pub fn block_hash(&self) -> BlockHash {
+        self.block_hash
+    }
+
+    /// The serialized filter.
+    pub fn raw(&self) -> &Bytes {
+        &self.raw
+    }
+
+    /// The number of elements in the filter.
+    pub fn len(&self) -> u64 {
+        self.element_count
+    }
+
+    /// Returns true if the filter has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.element_count == 0
+    }
+
+    /// Returns true if the script may be used by the block, and false if it is not.
+    pub fn matches(&self, script: &[u8]) -> Result<bool> {
+        self.matches_any([script])
+    }
+
+    /// Returns true if any of the scripts may be used by the block, and false if none of them
+    /// are.
+    ///
+    /// Returns [Error::CorruptData] if the filter cannot be decoded.
+    pub fn matches_any<'a, I>(&self, scripts: I) -> Result<bool>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut queries = hash_elements(&self.block_hash, self.element_count, scripts);
+        if queries.is_empty() || self.is_empty() {
+            return Ok(false);
+        }
+        queries.sort_unstable();
+        let corrupt = || Error::CorruptData {
+            hash: self.block_hash,
+            reason: String::from("the filter ends before all its elements"),
+        };
+        let mut reader = BitReader::new(&self.raw);
+        reader.read_varint().ok_or_else(corrupt)?;
+        let mut queries = queries.into_iter().peekable();
+        let mut value = 0;
+        for _ in 0..self.element_count {
+            let mut quotient = 0;
+            while reader.read_bit().ok_or_else(corrupt)? {
+                quotient += 1;
+            }
+            let remainder = reader.read_bits(FILTER_P).ok_or_else(corrupt)?;
+            value += (quotient << FILTER_P) + remainder;
+            while let Some(query) = queries.peek() {
+                match *query {
+                    q if q == value => return Ok(true),
+                    q if q < value => {
+                        queries.next();
+                    }
+                    _ => break,
+                }
+            }
+            if queries.peek().is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The double SHA-256 hash of the serialized filter.
+    pub fn filter_hash(&self) -> Hash {
+        Hash::sha256d(&self.raw)
+    }
+
+    /// The filter header of the block, which commits to the filter and to the filter header of
+    /// the parent block. The filter header before the genesis block is [Hash::ZERO].
+    pub fn header(&self, prev_header: &Hash) -> Hash {
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(&self.filter_hash().raw);
+        data.extend_from_slice(&prev_header.raw);
+        Hash::sha256d(&data)
+    }
+}
+
+// Hash the elements of a filter with N elements to the range 0 to N * M, using SipHash keyed by
+// the first 16 bytes of the block hash.
+fn hash_elements<'a, I>(block_hash: &BlockHash, element_count: u64, elements: I) -> Vec<u64>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let k0 = u64::from_le_bytes(block_hash.raw[0..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(block_hash.raw[8..16].try_into().unwrap());
+    let hasher = SipHasher24::new_with_keys(k0, k1);
+    let range = element_count as u128 * FILTER_M as u128;
+    elements
+        .into_iter()
+        .map(|e| ((hasher.hash(e) as u128 * range) >> 64) as u64)
+        .collect()
+}
+
+fn put_varint(buf: &mut BytesMut, value: u64) {
+    match value {
+        0..=0xfc => buf.put_u8(value as u8),
+        0xfd..=0xffff => {
+            buf.put_u8(0xfd);
+            buf.put_u16_le(value as u16);
+        }
+        0x10000..=0xffff_ffff => {
+            buf.put_u8(0xfe);
+            buf.put_u32_le(value as u32);
+        }
+        _ => {
+            buf.put_u8(0xff);
+            buf.put_u64_le(value);
+        }
+    }
+}
+
+// Writes bits, most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.bits == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.bits;
+        }
+        self.bits = (self.bits + 1) % 8;
+    }
+
+    // Write the low bits of a value, most significant first.
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// Reads bits, most significant first, and varints from the start of the data.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    fn read_varint(&mut self) -> Option<u64> {
+        let mut read = |n: usize| {
+            let start = self.position / 8;
+            let bytes = self.data.get(start..start + n)?;
+            self.position += n * 8;
+            Some(bytes.iter().rev().fold(0u64, |v, b| (v << 8) | *b as u64))
+        };
+        match read(1)? {
+            0xfd => read(2),
+            0xfe => read(4),
+            0xff => read(8),
+            n => Some(n),
+        }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1 == 1;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+/// Provides the scripts of the outputs spent by a block, which are part of its filter.
+///
+/// Blocks are given to [PrevoutScripts::connect_block] in chain order as their filters are
+/// built, so an implementation can keep track of the unspent outputs, like [MemoryPrevouts], or
+/// it can look them up in an index.
+#[async_trait]
+pub trait PrevoutScripts: Send + Sync {
+    /// Get the script of the output at an outpoint, or None if it is not known.
+    async fn prevout_script(&self, outpoint: &Outpoint) -> Result<Option<Bytes>>;
+
+    /// Called after the filter of a block has been built, with the outputs that it created and
+    /// the outputs from earlier blocks that it spent. Outputs which are created and spent in
+    /// the same block, and outputs whose script starts with OP_FALSE OP_RETURN, which can never
+    /// be spent, are left out. The default implementation does nothing.
+    async fn connect_block(
+        &self,
+        _block_hash: &BlockHash,
+        _created: HashMap<Outpoint, Bytes>,
+        _spent: Vec<Outpoint>,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the scripts of the unspent outputs in memory.
+///
+/// The memory needed grows with the number of unspent outputs, so this is suitable for test
+/// networks and for building the filters of a range of blocks, but not for building the filters
+/// of the whole of mainnet.
+#[derive(Debug, Default)]
+pub struct MemoryPrevouts {
+    scripts: Mutex<HashMap<Outpoint, Bytes>>,
+}
+
+impl MemoryPrevouts {
+    /// The number of unspent outputs.
+    pub fn len(&self) -> usize {
+        self.scripts.lock().unwrap().len()
+    }
+
+    /// Returns true if there are no unspent outputs.
+    pub fn is_empty(&self) -> bool {
+        self.scripts.lock().unwrap().is_empty()
+    }
+}
+
+#[async_trait]
+impl PrevoutScripts for MemoryPrevouts {
+    async fn prevout_script(&self, outpoint: &Outpoint) -> Result<Option<Bytes>> {
+        Ok(self.scripts.lock().unwrap().get(outpoint).cloned())
+    }
+
+    async fn connect_block(
+        &self,
+        _block_hash: &BlockHash,
+        created: HashMap<Outpoint, Bytes>,
+        spent: Vec<Outpoint>,
+    ) -> Result<()> {
+        let mut scripts = self.scripts.lock().unwrap();
+        for outpoint in spent.iter() {
+            scripts.remove(outpoint);
+        }
+        scripts.extend(created);
+        Ok(())
+    }
+}
+
+// Returns true if an output can never be spent: its script starts with OP_FALSE OP_RETURN, or
+// before the Genesis upgrade, with OP_RETURN.
+pub(crate) fn is_unspendable(script: &[u8], before_genesis: bool) -> bool {
+    script.starts_with(&[0, OP_RETURN]) || (before_genesis && script.first() == Some(&OP_RETURN))
+}
+
+// The outpoint of an output of a transaction.
+pub(crate) fn outpoint(tx_hash: &TxHash, index: u32) -> Outpoint {
+    let mut raw = BytesMut::with_capacity(Outpoint::SIZE as usize);
+    raw.put_slice(&tx_hash.raw);
+    raw.put_u32_le(index);
+    Outpoint { raw: raw.freeze() }
+}
+
+/// Build the filter of a block, streaming the transactions from
+/// [BlockArchiveReader::get_block].
+///
+/// The scripts of outputs spent from earlier blocks are found with `prevouts`, which is then
+/// given the outputs that the block created and spent, see [PrevoutScripts::connect_block].
+/// Returns [Error::PrevoutNotFound] if the script of a spent output is not known.
+pub async fn build_block_filter<A, P>(
+    archive: &A,
+    block_hash: &BlockHash,
+    prevouts: &P,
+) -> Result<BlockFilter>
+where
+    A: BlockArchiveReader + ?Sized,
+    P: PrevoutScripts + ?Sized,
+{
+    let mut txs = BlockTxReader::new(archive.get_block(block_hash).await?, *block_hash).await?;
+    let mut elements = HashSet::new();
+    let mut created = HashMap::new();
+    let mut spent = Vec::new();
+    while let Some(tx) = txs.next_tx().await? {
+        if !tx.is_coinbase() {
+            for input in tx.tx.inputs.iter() {
+                let script = match created.remove(&input.outpoint) {
+                    Some(script) => script,
+                    None => {
+                        let script = prevouts.prevout_script(&input.outpoint).await?;
+                        spent.push(input.outpoint.clone());
+                        script.ok_or_else(|| Error::PrevoutNotFound {
+                            hash: *block_hash,
+                            tx: input.outpoint.tx_hash(),
+                            index: input.outpoint.index(),
+                        })?
+                    }
+                };
+                if !script.is_empty() {
+                    elements.insert(script);
+                }
+            }
+        }
+        for (index, output) in tx.tx.outputs.iter().enumerate() {
+            let script = &output.script.raw;
+            if script.first().is_some_and(|op| *op != OP_RETURN) {
+                elements.insert(script.clone());
+            }
+            // the height of the block is not known, so only the outputs that cannot be spent
+            // after the Genesis upgrade are left out
+            if is_unspendable(script, false) {
+                continue;
+            }
+            // copy the script so that the rest of the transaction can be freed
+            created.insert(
+                outpoint(&tx.hash, index as u32),
+                Bytes::copy_from_slice(script),
+            );
+        }
+    }
+    let filter = BlockFilter::build(*block_hash, elements.iter().map(|e| &e[..]));
+    prevouts.connect_block(block_hash, created, spent).await?;
+    Ok(filter)
+}
+
+/// Builds the filters of blocks in chain order, stores them in an archive with
+/// [BlockArchiveWriter::set_block_filter] and keeps track of the filter header chain.
+///
+/// Example code:
+///     let mut index = FilterIndex::new(MemoryPrevouts::default());
+///     for hash in main_chain.iter() {
+///         let filter_header = index.add_block(&archive, hash).await?;
+///     }
+#[derive(Debug)]
+pub struct FilterIndex<P> {
+    prevouts: P,
+    tip: Option<(BlockHash, Hash)>,
+}
+
+impl<P: PrevoutScripts> FilterIndex<P> {
+    /// Start an index before the genesis block.
+    pub fn new(prevouts: P) -> FilterIndex<P> {
+        FilterIndex {
+            prevouts,
+            tip: None,
+        }
+    }
+
+    /// Continue an index after a block whose filter header is known. The prevouts must know the
+    /// outputs that are unspent after the block.
+    pub fn resume(prevouts: P, block_hash: BlockHash, filter_header: Hash) -> FilterIndex<P> {
+        FilterIndex {
+            prevouts,
+            tip: Some((block_hash, filter_header)),
+        }
+    }
+
+    /// The last block added and its filter header.
+    pub fn tip(&self) -> Option<(BlockHash, Hash)> {
+        self.tip
+    }
+
+    /// The source of the scripts of spent outputs.
+    pub fn prevouts(&self) -> &P {
+        &self.prevouts
+    }
+
+    /// Build the filter of the next block in the chain, store it and return its filter header.
+    ///
+    /// Returns [Error::ParentNotFound] if the parent of the block is not the last block added,
+    /// or if the index is new and the block is not a genesis block.
+    pub async fn add_block<A>(&mut self, archive: &A, block_hash: &BlockHash) -> Result<Hash>
+    where
+        A: BlockArchiveReader + BlockArchiveWriter + ?Sized,
+    {
+        let (tip_hash, prev_header) = self.tip.unwrap_or((BlockHash::ZERO, Hash::ZERO));
+        let parent = archive.block_header(block_hash).await?.prev_hash();
+        if parent != tip_hash {
+            return Err(Error::ParentNotFound {
+                hash: *block_hash,
+                parent,
+            });
+        }
+        let filter = build_block_filter(archive, block_hash, &self.prevouts).await?;
+        archive.set_block_filter(block_hash, &filter).await?;
+        let header = filter.header(&prev_header);
+        self.tip = Some((*block_hash, header));
+        Ok(header)
+    }
+}
+
+/// Calculate the filter headers of a chain of blocks from their stored filters, starting from
+/// the filter header of the parent of the first block, which is [Hash::ZERO] for the genesis
+/// block.
+///
+/// Returns [Error::FilterNotFound] if a block does not have a filter.
+pub async fn filter_headers<A>(
+    archive: &A,
+    prev_header: &Hash,
+    blocks: &[BlockHash],
+) -> Result<Vec<Hash>>
+where
+    A: BlockArchiveReader + ?Sized,
+{
+    let mut headers = Vec::with_capacity(blocks.len());
+    let mut header = *prev_header;
+    for block_hash in blocks {
+        let filter = archive
+            .block_filter(block_hash)
+            .await?
+            .ok_or(Error::FilterNotFound(*block_hash))?;
+        header = filter.header(&header);
+        headers.push(header);
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{make_block, make_tx};
+    use crate::SimpleFileBasedBlockArchive;
+    use bitcoinsv::bitcoin::{Block, BlockchainId, FromHex};
+    use tempfile::tempdir;
+
+    // The filter of the testnet genesis block matches the BIP158 test vector
+    #[tokio::test]
+    async fn test_genesis_filter() {
+        let root = tempdir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let genesis = Block::get_genesis(BlockchainId::Test).unwrap();
+        let h = genesis.header().unwrap().hash();
+        archive.store_block_full(&genesis).await.unwrap();
+        let mut index = FilterIndex::new(MemoryPrevouts::default());
+        let header = index.add_block(&archive, &h).await.unwrap();
+        assert_eq!(
+            header,
+            Hash::from_hex("21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750")
+                .unwrap()
+        );
+        let filter = archive.block_filter(&h).await.unwrap().unwrap();
+        assert_eq!(hex::encode(filter.raw()), "019dfca8");
+        let script = genesis.tx_iter().next().unwrap().outputs[0]
+            .script
+            .raw
+            .clone();
+        assert!(filter.matches(&script).unwrap());
+        assert!(!filter.matches(b"not a script").unwrap());
+        assert_eq!(
+            filter_headers(&archive, &Hash::ZERO, &[h]).await.unwrap(),
+            vec![header]
+        );
+    }
+
+    // Spent scripts are included, and found in earlier blocks or the same block
+    #[tokio::test]
+    async fn test_spent_scripts() {
+        let root = tempdir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let null = outpoint(&Hash::ZERO, u32::MAX);
+        let coinbase1 = make_tx(
+            std::slice::from_ref(&null),
+            &[b"script one", &[OP_RETURN, 1]],
+        );
+        let block1 = make_block(&BlockHash::ZERO, std::slice::from_ref(&coinbase1));
+        let h1 = block1.header().unwrap().hash();
+        let spend = make_tx(&[outpoint(&Hash::sha256d(&coinbase1), 0)], &[b"script two"]);
+        let spend2 = make_tx(&[outpoint(&Hash::sha256d(&spend), 0)], &[b""]);
+        let coinbase2 = make_tx(std::slice::from_ref(&null), &[b"script three"]);
+        let spend2_hash = Hash::sha256d(&spend2);
+        let block2 = make_block(&h1, &[coinbase2, spend, spend2]);
+        let h2 = block2.header().unwrap().hash();
+        // the output with an empty script can be spent
+        let coinbase3 = make_tx(std::slice::from_ref(&null), &[b"script four"]);
+        let spend3 = make_tx(&[outpoint(&spend2_hash, 0)], &[&[0, OP_RETURN]]);
+        let block3 = make_block(&h2, &[coinbase3, spend3]);
+        let h3 = block3.header().unwrap().hash();
+        archive.store_block_full(&block1).await.unwrap();
+        archive.store_block_full(&block2).await.unwrap();
+        archive.store_block_full(&block3).await.unwrap();
+
+        let mut index = FilterIndex::new(MemoryPrevouts::default());
+        assert!(matches!(
+            index.add_block(&archive, &h2).await,
+            Err(Error::ParentNotFound { .. })
+        ));
+        let header1 = index.add_block(&archive, &h1).await.unwrap();
+        assert_eq!(index.prevouts().len(), 2);
+        let header2 = index.add_block(&archive, &h2).await.unwrap();
+        assert_eq!(index.prevouts().len(), 3);
+        let filter = archive.block_filter(&h2).await.unwrap().unwrap();
+        assert_eq!(filter.len(), 3);
+        for script in [&b"script one"[..], b"script two", b"script three"] {
+            assert!(filter.matches(script).unwrap());
+        }
+        assert!(!filter.matches_any([&[OP_RETURN, 1][..], b""]).unwrap());
+        assert_eq!(
+            filter_headers(&archive, &Hash::ZERO, &[h1, h2])
+                .await
+                .unwrap(),
+            vec![header1, header2]
+        );
+        index.add_block(&archive, &h3).await.unwrap();
+        assert_eq!(index.prevouts().len(), 3);
+        let filter = archive.block_filter(&h3).await.unwrap().unwrap();
+        assert_eq!(filter.len(), 2);
+        assert!(filter.matches(b"script four").unwrap());
+        assert!(!filter.matches(b"").unwrap());
+
+        // the filter is removed with the block
+        archive.delete_block(&h2).await.unwrap();
+        assert!(archive.block_filter(&h2).await.unwrap_err().is_not_found());
+        archive.store_block_full(&block2).await.unwrap();
+        assert_eq!(archive.block_filter(&h2).await.unwrap(), None);
+
+        // without the outputs of the first block the spent script is not known
+        let mut index = FilterIndex::resume(MemoryPrevouts::default(), h1, header1);
+        assert!(matches!(
+            index.add_block(&archive, &h2).await,
+            Err(Error::PrevoutNotFound { index: 0, .. })
+        ));
+    }
+}
@@ -0,0 +1,944 @@
+use crate::pow::{block_proof, meets_target, target_from_bits, U256};
+use crate::scan::DEFAULT_SCAN_CONCURRENCY;
+use crate::{BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, BlockchainId};
+use futures::stream::{self, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// The time between blocks that the difficulty is adjusted for, in seconds.
+const TARGET_SPACING: i64 = 600;
+// The time that the original difficulty adjustment aims for each interval to take.
+const TARGET_TIMESPAN: i64 = 14 * 24 * 60 * 60;
+// The number of blocks between adjustments of the original difficulty adjustment.
+const ADJUSTMENT_INTERVAL: u64 = 2016;
+// The number of blocks over which the difficulty adjustment algorithm measures work.
+const DAA_WINDOW: u64 = 144;
+// The number of blocks used to calculate the median time past.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// The rules for the difficulty of a blockchain, from the chain parameters of the node.
+#[derive(Debug, Clone)]
+struct Params {
+    genesis: BlockHeader,
+    pow_limit: U256,
+    // a block may use the minimum difficulty if it is more than 20 minutes after its parent
+    allow_min_difficulty: bool,
+    no_retargeting: bool,
+    // the first height at which the difficulty adjustment algorithm from November 2017 applies,
+    // before it the original adjustment and the emergency difficulty adjustment apply
+    daa_height: u64,
+}
+
+impl Params {
+    fn new(blockchain: BlockchainId) -> Params {
+        let genesis = BlockHeader::get_genesis(blockchain);
+        match blockchain {
+            BlockchainId::Main => Params {
+                genesis,
+                pow_limit: U256::MAX.shr(32),
+                allow_min_difficulty: false,
+                no_retargeting: false,
+                daa_height: 504_031,
+            },
+            BlockchainId::Test => Params {
+                genesis,
+                pow_limit: U256::MAX.shr(32),
+                allow_min_difficulty: true,
+                no_retargeting: false,
+                daa_height: 1_188_697,
+            },
+            BlockchainId::Stn => Params {
+                genesis,
+                pow_limit: U256::MAX.shr(32),
+                allow_min_difficulty: true,
+                no_retargeting: false,
+                daa_height: 2_200,
+            },
+            BlockchainId::Regtest => Params {
+                genesis,
+                pow_limit: U256::MAX.shr(1),
+                allow_min_difficulty: true,
+                no_retargeting: true,
+                daa_height: 0,
+            },
+        }
+    }
+}
+
+// The blockchains whose rules are known.
+const BLOCKCHAINS: [BlockchainId; 4] = [
+    BlockchainId::Main,
+    BlockchainId::Test,
+    BlockchainId::Stn,
+    BlockchainId::Regtest,
+];
+
+// Returns false if a header cannot belong to the blockchain with the given genesis block: it has
+// no parent but is not the genesis block, its parent is the genesis block of another blockchain,
+// or its difficulty target is easier than the blockchain allows. Whether other headers belong to
+// the blockchain cannot be checked without their ancestors.
+pub(crate) fn may_belong_to(genesis: &BlockHash, header: &BlockHeader) -> bool {
+    let parent = header.prev_hash();
+    if parent == BlockHash::ZERO {
+        return header.hash() == *genesis;
+    }
+    let mut params = BLOCKCHAINS.iter().map(|b| Params::new(*b));
+    if parent != *genesis && params.clone().any(|p| p.genesis.hash() == parent) {
+        return false;
+    }
+    // testnet and STN share a genesis block and a minimum difficulty
+    match params.find(|p| p.genesis.hash() == *genesis) {
+        Some(params) => target_from_bits(header.bits()).is_none_or(|t| t <= params.pow_limit),
+        None => true,
+    }
+}
+
+/// A problem found when checking a block header against the rules of a blockchain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderProblem {
+    /// The header is not the header of the block it is stored as.
+    HashMismatch { actual: BlockHash },
+    /// The difficulty target in the header is not valid, or is easier than the blockchain allows.
+    InvalidBits { bits: u32 },
+    /// The hash of the header is above its difficulty target.
+    InsufficientWork { bits: u32 },
+    /// The difficulty target is not the one given by the difficulty adjustment rules.
+    UnexpectedBits { expected: u32, actual: u32 },
+    /// The timestamp is not after the median time of the previous 11 blocks.
+    TimeTooOld {
+        timestamp: u32,
+        median_time_past: u32,
+    },
+    /// The parent of the header is not known.
+    UnknownParent { parent: BlockHash },
+    /// The parent of the header failed the checks.
+    InvalidParent { parent: BlockHash },
+    /// The header has no parent but is not the genesis block of the blockchain.
+    WrongGenesis { genesis: BlockHash },
+}
+
+impl fmt::Display for HeaderProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderProblem::HashMismatch { actual } => {
+                write!(f, "the header is for block {actual}")
+            }
+            HeaderProblem::InvalidBits { bits } => write!(f, "invalid bits {bits:#010x}"),
+            HeaderProblem::InsufficientWork { bits } => {
+                write!(f, "the hash is above the target of bits {bits:#010x}")
+            }
+            HeaderProblem::UnexpectedBits { expected, actual } => {
+                write!(f, "bits {actual:#010x} but expected {expected:#010x}")
+            }
+            HeaderProblem::TimeTooOld {
+                timestamp,
+                median_time_past,
+            } => write!(
+                f,
+                "timestamp {timestamp} is not after the median time past {median_time_past}"
+            ),
+            HeaderProblem::UnknownParent { parent } => write!(f, "unknown parent {parent}"),
+            HeaderProblem::InvalidParent { parent } => write!(f, "invalid parent {parent}"),
+            HeaderProblem::WrongGenesis { genesis } => {
+                write!(f, "no parent, but the genesis block is {genesis}")
+            }
+        }
+    }
+}
+
+// A header in the chain, with the fields that the rules need.
+#[derive(Debug, Clone)]
+struct Entry {
+    hash: BlockHash,
+    // None for the genesis block and for checkpoints
+    parent: Option<usize>,
+    height: u64,
+    bits: u32,
+    timestamp: u32,
+    chain_work: U256,
+}
+
+/// A tree of block headers which have been checked against the proof of work, difficulty
+/// adjustment and median time past rules of a blockchain.
+///
+/// The chain starts with the genesis block. Headers are added with [HeaderChain::add], which
+/// only accepts a header whose parent is already in the chain and which passes the checks.
+/// Forks are kept, the tip is the header with the most work.
+///
+/// A chain can also start from a header at a known height, see [HeaderChain::add_checkpoint].
+/// The rules that need earlier headers are not checked until there are enough headers after
+/// the checkpoint.
+///
+/// Example code:
+///     let mut chain = HeaderChain::new(BlockchainId::Main);
+///     for header in headers.iter() {
+///         let problems = chain.add(header);
+///         if !problems.is_empty() {
+///             println!("{}: {:?}", header.hash(), problems);
+///         }
+///     }
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    blockchain: BlockchainId,
+    params: Params,
+    entries: Vec<Entry>,
+    index: HashMap<BlockHash, usize>,
+    // headers that failed the checks, to report their children
+    failed: HashSet<BlockHash>,
+    tip: usize,
+}
+
+impl HeaderChain {
+    /// Create a chain containing the genesis block of the blockchain.
+    pub fn new(blockchain: BlockchainId) -> HeaderChain {
+        let params = Params::new(blockchain);
+        let genesis = Entry {
+            hash: params.genesis.hash(),
+            parent: None,
+            height: 0,
+            bits: params.genesis.bits(),
+            timestamp: params.genesis.timestamp(),
+            chain_work: block_proof(params.genesis.bits()),
+        };
+        HeaderChain {
+            blockchain,
+            index: HashMap::from([(genesis.hash, 0)]),
+            entries: vec![genesis],
+            params,
+            failed: HashSet::new(),
+            tip: 0,
+        }
+    }
+
+    /// The blockchain whose rules are checked.
+    pub fn blockchain(&self) -> BlockchainId {
+        self.blockchain
+    }
+
+    /// The hash of the genesis block of the blockchain.
+    pub fn genesis_hash(&self) -> BlockHash {
+        self.entries[0].hash
+    }
+
+    /// The number of headers in the chain, including the genesis block.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Always false, the chain contains at least the genesis block.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns true if the header is in the chain.
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.index.contains_key(block_hash)
+    }
+
+    /// The height of a header in the chain.
+    pub fn height(&self, block_hash: &BlockHash) -> Option<u64> {
+        self.index.get(block_hash).map(|i| self.entries[*i].height)
+    }
+
+    /// The hash and height of the header with the most work, the first one added if several
+    /// have the same work. Work before a checkpoint is not counted.
+    pub fn tip(&self) -> (BlockHash, u64) {
+        let tip = &self.entries[self.tip];
+        (tip.hash, tip.height)
+    }
+
+    /// The hashes of the headers in the chain that ends at the tip, in height order, starting
+    /// with the genesis block or the checkpoint that the chain starts from.
+    pub fn best_chain(&self) -> Vec<BlockHash> {
+        let mut chain = Vec::new();
+        let mut i = Some(self.tip);
+        while let Some(entry) = i.map(|i| &self.entries[i]) {
+            chain.push(entry.hash);
+            i = entry.parent;
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Check a header against the rules of the blockchain without adding it to the chain.
+    ///
+    /// Returns the problems found, empty if the header passes or is already in the chain.
+    pub fn check(&self, header: &BlockHeader) -> Vec<HeaderProblem> {
+        self.check_header(header).0
+    }
+
+    /// Check a header and add it to the chain if it passes.
+    ///
+    /// Returns the problems found, empty if the header was added or is already in the chain.
+    pub fn add(&mut self, header: &BlockHeader) -> Vec<HeaderProblem> {
+        let (problems, parent) = self.check_header(header);
+        match (problems.is_empty(), parent) {
+            (true, Some(parent)) => self.insert(header, Some(parent), None),
+            (false, _) => {
+                self.failed.insert(header.hash());
+            }
+            (true, None) => {}
+        }
+        problems
+    }
+
+    /// Add a header at a known height, for a chain that does not start at the genesis block.
+    /// Only its proof of work is checked.
+    ///
+    /// Returns the problems found, empty if the header was added or is already in the chain.
+    pub fn add_checkpoint(&mut self, header: &BlockHeader, height: u64) -> Vec<HeaderProblem> {
+        if self.contains(&header.hash()) {
+            return Vec::new();
+        }
+        let problems = self.check_proof_of_work(header);
+        match problems.is_empty() {
+            true => self.insert(header, None, Some(height)),
+            false => {
+                self.failed.insert(header.hash());
+            }
+        }
+        problems
+    }
+
+    fn insert(&mut self, header: &BlockHeader, parent: Option<usize>, height: Option<u64>) {
+        let (height, chain_work) = match parent {
+            Some(parent) => (
+                self.entries[parent].height + 1,
+                self.entries[parent].chain_work,
+            ),
+            None => (height.unwrap_or_default(), U256::ZERO),
+        };
+        let entry = Entry {
+            hash: header.hash(),
+            parent,
+            height,
+            bits: header.bits(),
+            timestamp: header.timestamp(),
+            chain_work: chain_work.wrapping_add(block_proof(header.bits())),
+        };
+        if entry.chain_work > self.entries[self.tip].chain_work {
+            self.tip = self.entries.len();
+        }
+        self.index.insert(entry.hash, self.entries.len());
+        self.entries.push(entry);
+    }
+
+    // Check a header, also returning the position of its parent.
+    fn check_header(&self, header: &BlockHeader) -> (Vec<HeaderProblem>, Option<usize>) {
+        let hash = header.hash();
+        if self.contains(&hash) {
+            return (Vec::new(), None);
+        }
+        let mut problems = self.check_proof_of_work(header);
+        let parent_hash = header.prev_hash();
+        let parent = match self.index.get(&parent_hash) {
+            Some(parent) => *parent,
+            None => {
+                problems.push(match parent_hash {
+                    _ if self.failed.contains(&parent_hash) => HeaderProblem::InvalidParent {
+                        parent: parent_hash,
+                    },
+                    BlockHash::ZERO => HeaderProblem::WrongGenesis {
+                        genesis: self.genesis_hash(),
+                    },
+                    _ => HeaderProblem::UnknownParent {
+                        parent: parent_hash,
+                    },
+                });
+                return (problems, None);
+            }
+        };
+        if let Some(median_time_past) = self.median_time_past(parent) {
+            if header.timestamp() <= median_time_past {
+                problems.push(HeaderProblem::TimeTooOld {
+                    timestamp: header.timestamp(),
+                    median_time_past,
+                });
+            }
+        }
+        if let Some(expected) = self.next_bits(parent, header) {
+            if expected != header.bits() {
+                problems.push(HeaderProblem::UnexpectedBits {
+                    expected,
+                    actual: header.bits(),
+                });
+            }
+        }
+        (problems, Some(parent))
+    }
+
+    fn check_proof_of_work(&self, header: &BlockHeader) -> Vec<HeaderProblem> {
+        let bits = header.bits();
+        match target_from_bits(bits) {
+            Some(target) if target <= self.params.pow_limit => {
+                match meets_target(&header.hash(), bits) {
+                    true => Vec::new(),
+                    false => vec![HeaderProblem::InsufficientWork { bits }],
+                }
+            }
+            _ => vec![HeaderProblem::InvalidBits { bits }],
+        }
+    }
+
+    // Find the ancestor of an entry at a height, if the headers back to it are in the chain.
+    fn ancestor(&self, mut i: usize, height: u64) -> Option<usize> {
+        while self.entries[i].height > height {
+            i = self.entries[i].parent?;
+        }
+        match self.entries[i].height == height {
+            true => Some(i),
+            false => None,
+        }
+    }
+
+    // The median timestamp of an entry and the ten before it, or fewer near the genesis block.
+    // Returns None if the headers are not in the chain.
+    fn median_time_past(&self, mut i: usize) -> Option<u32> {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        loop {
+            times.push(self.entries[i].timestamp);
+            if times.len() == MEDIAN_TIME_SPAN || self.entries[i].height == 0 {
+                break;
+            }
+            i = self.entries[i].parent?;
+        }
+        times.sort_unstable();
+        Some(times[times.len() / 2])
+    }
+
+    // The bits required for the child of an entry, see GetNextWorkRequired in the node. Returns
+    // None if the headers that are needed are not in the chain.
+    fn next_bits(&self, parent: usize, header: &BlockHeader) -> Option<u32> {
+        let entry = &self.entries[parent];
+        if self.params.no_retargeting {
+            return Some(entry.bits);
+        }
+        match entry.height >= self.params.daa_height {
+            true => self.next_daa_bits(parent, header),
+            false => self.next_eda_bits(parent, header),
+        }
+    }
+
+    // The original difficulty adjustment every 2016 blocks, and the emergency difficulty
+    // adjustment between August and November 2017.
+    fn next_eda_bits(&self, parent: usize, header: &BlockHeader) -> Option<u32> {
+        let entry = &self.entries[parent];
+        let pow_limit_bits = self.params.pow_limit.to_compact();
+        let height = entry.height + 1;
+        if height.is_multiple_of(ADJUSTMENT_INTERVAL) {
+            let first = self.ancestor(parent, height - ADJUSTMENT_INTERVAL)?;
+            let timespan = (entry.timestamp as i64 - self.entries[first].timestamp as i64)
+                .clamp(TARGET_TIMESPAN / 4, TARGET_TIMESPAN * 4);
+            let target = U256::from_compact(entry.bits)
+                .wrapping_mul_u64(timespan as u64)
+                .div(U256::from_u64(TARGET_TIMESPAN as u64));
+            return Some(target.min(self.params.pow_limit).to_compact());
+        }
+        if self.params.allow_min_difficulty {
+            if header.timestamp() as i64 > entry.timestamp as i64 + 2 * TARGET_SPACING {
+                return Some(pow_limit_bits);
+            }
+            // the bits of the last block that did not use the minimum difficulty
+            let mut i = parent;
+            while !self.entries[i].height.is_multiple_of(ADJUSTMENT_INTERVAL)
+                && self.entries[i].bits == pow_limit_bits
+            {
+                match self.entries[i].parent {
+                    Some(p) => i = p,
+                    None if self.entries[i].height == 0 => break,
+                    None => return None,
+                }
+            }
+            return Some(self.entries[i].bits);
+        }
+        if entry.bits == pow_limit_bits {
+            return Some(entry.bits);
+        }
+        // if the last 6 blocks took more than 12 hours, reduce the difficulty by 20%
+        let first = self.ancestor(parent, height.checked_sub(7)?)?;
+        let mtp_span = self.median_time_past(parent)? as i64 - self.median_time_past(first)? as i64;
+        if mtp_span < 12 * 3600 {
+            return Some(entry.bits);
+        }
+        let target = U256::from_compact(entry.bits);
+        let target = target.wrapping_add(target.shr(2));
+        Some(target.min(self.params.pow_limit).to_compact())
+    }
+
+    // The difficulty adjustment algorithm from November 2017, which adjusts every block based
+    // on the work and time of the last 144 blocks.
+    fn next_daa_bits(&self, parent: usize, header: &BlockHeader) -> Option<u32> {
+        let entry = &self.entries[parent];
+        if self.params.allow_min_difficulty
+            && header.timestamp() as i64 > entry.timestamp as i64 + 2 * TARGET_SPACING
+        {
+            return Some(self.params.pow_limit.to_compact());
+        }
+        let last = self.suitable_block(parent)?;
+        let first = self.ancestor(parent, entry.height.checked_sub(DAA_WINDOW)?)?;
+        let first = self.suitable_block(first)?;
+        let (first, last) = (&self.entries[first], &self.entries[last]);
+        let work = last
+            .chain_work
+            .wrapping_sub(first.chain_work)
+            .wrapping_mul_u64(TARGET_SPACING as u64);
+        let timespan = (last.timestamp as i64 - first.timestamp as i64)
+            .clamp(72 * TARGET_SPACING, 288 * TARGET_SPACING);
+        let work = work.div(U256::from_u64(timespan as u64));
+        if work == U256::ZERO {
+            return None;
+        }
+        // the target is 2^256 / work - 1, which is (2^256 - work) / work
+        let target = U256::ZERO.wrapping_sub(work).div(work);
+        Some(target.min(self.params.pow_limit).to_compact())
+    }
+
+    // The entry with the median timestamp of an entry and its parent and grandparent. The
+    // entries are ordered with the same three swaps as the node, which decide between entries
+    // with equal timestamps.
+    fn suitable_block(&self, i: usize) -> Option<usize> {
+        let parent = self.entries[i].parent?;
+        let grandparent = self.entries[parent].parent?;
+        let mut blocks = [grandparent, parent, i];
+        let time = |b: usize| self.entries[b].timestamp;
+        if time(blocks[0]) > time(blocks[2]) {
+            blocks.swap(0, 2);
+        }
+        if time(blocks[0]) > time(blocks[1]) {
+            blocks.swap(0, 1);
+        }
+        if time(blocks[1]) > time(blocks[2]) {
+            blocks.swap(1, 2);
+        }
+        Some(blocks[1])
+    }
+}
+
+/// The result of [validate_headers].
+#[derive(Debug, Default)]
+pub struct HeaderReport {
+    /// The number of headers that were checked against the rules of the blockchain.
+    pub headers_checked: usize,
+    /// Blocks whose chain does not reach the genesis block or a block with a height recorded in
+    /// its metadata. Only their proof of work was checked.
+    pub unconnected: Vec<BlockHash>,
+    /// The headers that failed the checks, with the problems found. The children of a header
+    /// that fails also fail, with [HeaderProblem::InvalidParent].
+    pub failed: Vec<(BlockHash, Vec<HeaderProblem>)>,
+}
+
+impl HeaderReport {
+    /// Returns true if no headers failed the checks.
+    pub fn is_valid(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Check the header of every block in an archive against the proof of work, difficulty
+/// adjustment and median time past rules of a blockchain.
+///
+/// The headers are checked in chain order starting from the genesis block. A block whose parent
+/// is not in the archive is checked from its height if one is recorded in its metadata, with
+/// the rules that need earlier headers checked once there are enough headers after it.
+///
+/// Returns [Error::CorruptData] if the parents of the blocks in the archive form a cycle, which
+/// can only happen if blocks are stored under hashes that do not match their headers.
+///
+/// Example code:
+///     let report = validate_headers(&archive, BlockchainId::Main).await?;
+///     for (block_hash, problems) in report.failed.iter() {
+///         println!("{block_hash}: {problems:?}");
+///     }
+pub async fn validate_headers<A>(archive: &A, blockchain: BlockchainId) -> Result<HeaderReport>
+where
+    A: BlockArchiveReader + ?Sized,
+{
+    Ok(load_header_chain(archive, blockchain).await?.1)
+}
+
+// Check the headers of the blocks in an archive, returning the chain of the headers that passed
+// along with the report.
+pub(crate) async fn load_header_chain<A>(
+    archive: &A,
+    blockchain: BlockchainId,
+) -> Result<(HeaderChain, HeaderReport)>
+where
+    A: BlockArchiveReader + ?Sized,
+{
+    let mut hashes = Vec::new();
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        hashes.push(block_hash);
+    }
+    drop(results);
+
+    let mut headers = HashMap::with_capacity(hashes.len());
+    let mut reads = stream::iter(hashes.iter())
+        .map(|block_hash| async move { (*block_hash, archive.block_header(block_hash).await) })
+        .buffered(DEFAULT_SCAN_CONCURRENCY);
+    while let Some((block_hash, header)) = reads.next().await {
+        match header {
+            Ok(header) => {
+                headers.insert(block_hash, header);
+            }
+            // removed since it was listed
+            Err(Error::BlockNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    drop(reads);
+    hashes.retain(|h| headers.contains_key(h));
+
+    let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+    for block_hash in hashes.iter() {
+        children
+            .entry(headers[block_hash].prev_hash())
+            .or_default()
+            .push(*block_hash);
+    }
+    let mut chain = HeaderChain::new(blockchain);
+    let mut report = HeaderReport::default();
+    let mut checked = HashSet::with_capacity(hashes.len());
+    for root in hashes.iter() {
+        let header = &headers[root];
+        if headers.contains_key(&header.prev_hash()) {
+            continue;
+        }
+        let connects = *root == chain.genesis_hash() || chain.contains(&header.prev_hash());
+        let checkpoint = match connects {
+            true => None,
+            false => match archive.block_metadata(root).await {
+                Ok(metadata) => metadata.height,
+                Err(Error::BlockNotFound(_)) => None,
+                Err(e) => return Err(e),
+            },
+        };
+        let connected = connects || checkpoint.is_some();
+        let mut pending = vec![*root];
+        while let Some(block_hash) = pending.pop() {
+            let header = &headers[&block_hash];
+            let mut problems = match header.hash() == block_hash {
+                true => Vec::new(),
+                false => vec![HeaderProblem::HashMismatch {
+                    actual: header.hash(),
+                }],
+            };
+            if !connected {
+                problems.extend(chain.check_proof_of_work(header));
+                report.unconnected.push(block_hash);
+            } else if problems.is_empty() {
+                problems = match (block_hash == *root, checkpoint) {
+                    (true, Some(height)) => chain.add_checkpoint(header, height),
+                    _ => chain.add(header),
+                };
+            } else {
+                chain.failed.insert(block_hash);
+            }
+            report.headers_checked += 1;
+            checked.insert(block_hash);
+            if !problems.is_empty() {
+                report.failed.push((block_hash, problems));
+            }
+            if let Some(c) = children.get(&block_hash) {
+                pending.extend(c.iter().rev());
+            }
+        }
+    }
+    // every chain of parents in the archive ends at a root unless it has a cycle
+    if let Some(start) = hashes.iter().find(|h| !checked.contains(*h)) {
+        let mut seen = HashSet::new();
+        let mut hash = *start;
+        while seen.insert(hash) {
+            hash = headers[&hash].prev_hash();
+        }
+        return Err(Error::CorruptData {
+            hash,
+            reason: String::from("the parents of the block form a cycle"),
+        });
+    }
+    Ok((chain, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mine_header;
+    use crate::{BlockArchiveWriter, BlockMetadata, SimpleFileBasedBlockArchive};
+    use bitcoinsv::bitcoin::Block;
+    use bytes::Bytes;
+    use tempfile::tempdir;
+    use tokio::io::AsyncRead;
+
+    // Add an entry without any checks, to test the difficulty rules without mining.
+    fn push(chain: &mut HeaderChain, timestamp: u32, bits: u32) {
+        let parent = chain.entries.len() - 1;
+        let mut hash = BlockHash::ZERO;
+        hash.raw[..8].copy_from_slice(&(parent as u64 + 1).to_le_bytes());
+        let entry = Entry {
+            hash,
+            parent: Some(parent),
+            height: chain.entries[parent].height + 1,
+            bits,
+            timestamp,
+            chain_work: chain.entries[parent]
+                .chain_work
+                .wrapping_add(block_proof(bits)),
+        };
+        chain.index.insert(hash, chain.entries.len());
+        chain.entries.push(entry);
+    }
+
+    fn header_at(timestamp: u32) -> BlockHeader {
+        mine_header(&BlockHash::ZERO, timestamp, 0x207fffff, true)
+    }
+
+    // Headers are checked for proof of work, median time past and connection to the genesis block
+    #[test]
+    fn test_header_chain() {
+        let mut chain = HeaderChain::new(BlockchainId::Regtest);
+        let genesis = BlockHeader::get_genesis(BlockchainId::Regtest);
+        assert!(chain.add(&genesis).is_empty());
+        assert_eq!(chain.len(), 1);
+        let mut parent = genesis.hash();
+        let mut time = genesis.timestamp();
+        for _ in 0..12 {
+            time += 600;
+            let header = mine_header(&parent, time, 0x207fffff, true);
+            assert_eq!(chain.add(&header), vec![]);
+            parent = header.hash();
+        }
+        assert_eq!(chain.tip(), (parent, 12));
+        let best = chain.best_chain();
+        assert_eq!(
+            (best.len(), best[0], best[12]),
+            (13, genesis.hash(), parent)
+        );
+
+        // the median of the last 11 timestamps is 6 blocks back
+        let header = mine_header(&parent, time - 5 * 600, 0x207fffff, true);
+        assert_eq!(
+            chain.add(&header),
+            vec![HeaderProblem::TimeTooOld {
+                timestamp: time - 5 * 600,
+                median_time_past: time - 5 * 600
+            }]
+        );
+        assert!(chain
+            .check(&mine_header(&parent, time - 4 * 600, 0x207fffff, true))
+            .is_empty());
+
+        let header = mine_header(&parent, time + 600, 0x207fffff, false);
+        assert_eq!(
+            chain.add(&header),
+            vec![HeaderProblem::InsufficientWork { bits: 0x207fffff }]
+        );
+        let child = mine_header(&header.hash(), time + 1200, 0x207fffff, true);
+        assert_eq!(
+            chain.add(&child),
+            vec![HeaderProblem::InvalidParent {
+                parent: header.hash()
+            }]
+        );
+        // regtest does not retarget
+        let header = mine_header(&parent, time + 600, 0x1f7fffff, true);
+        assert_eq!(
+            chain.add(&header),
+            vec![HeaderProblem::UnexpectedBits {
+                expected: 0x207fffff,
+                actual: 0x1f7fffff
+            }]
+        );
+        assert_eq!(
+            chain.add(&mine_header(&parent, time + 600, 0x217fffff, false)),
+            vec![
+                HeaderProblem::InvalidBits { bits: 0x217fffff },
+                HeaderProblem::UnexpectedBits {
+                    expected: 0x207fffff,
+                    actual: 0x217fffff
+                }
+            ]
+        );
+        let header = mine_header(&BlockHash::ZERO, time, 0x207fffff, true);
+        assert_eq!(
+            chain.add(&header),
+            vec![HeaderProblem::WrongGenesis {
+                genesis: genesis.hash()
+            }]
+        );
+        let header = mine_header(&BlockHash { raw: [9; 32] }, time, 0x207fffff, true);
+        assert!(matches!(
+            chain.add(&header)[..],
+            [HeaderProblem::UnknownParent { .. }]
+        ));
+
+        // a checkpoint is only checked for proof of work
+        let checkpoint = header_at(1);
+        assert!(chain.add_checkpoint(&checkpoint, 1000).is_empty());
+        let header = mine_header(&checkpoint.hash(), 2, 0x207fffff, true);
+        assert!(chain.add(&header).is_empty());
+        assert_eq!(chain.height(&header.hash()), Some(1001));
+        assert_eq!(chain.tip().1, 12);
+    }
+
+    // The difficulty adjustment rules of mainnet and testnet
+    #[test]
+    fn test_difficulty_rules() {
+        let genesis = BlockHeader::get_genesis(BlockchainId::Main);
+        let next = |timestamp: u32| mine_header(&BlockHash::ZERO, timestamp, 0x207fffff, true);
+
+        // blocks which took half of the target time double the difficulty
+        let mut chain = HeaderChain::new(BlockchainId::Main);
+        let mut time = genesis.timestamp();
+        for _ in 1..2015 {
+            time += 300;
+            push(&mut chain, time, 0x1d00ffff);
+        }
+        // the timespan is measured over 2015 blocks
+        time += 600;
+        push(&mut chain, time, 0x1d00ffff);
+        assert_eq!(chain.next_bits(2015, &next(time + 300)), Some(0x1c7fff80));
+        time += 300;
+        push(&mut chain, time, 0x1c7fff80);
+        assert_eq!(chain.next_bits(2016, &next(time + 300)), Some(0x1c7fff80));
+        // the emergency adjustment when 6 blocks take more than 12 hours by median time past
+        for _ in 0..12 {
+            time += 3 * 3600;
+            push(&mut chain, time, 0x1c7fff80);
+        }
+        assert_eq!(chain.next_bits(2028, &next(time)), Some(0x1d009fff));
+
+        // testnet allows the minimum difficulty after 20 minutes, then returns to the last bits
+        let mut chain = HeaderChain::new(BlockchainId::Test);
+        push(&mut chain, genesis.timestamp() + 600, 0x1c7fff80);
+        push(&mut chain, genesis.timestamp() + 1800, 0x1d00ffff);
+        let time = genesis.timestamp() + 2400;
+        assert_eq!(chain.next_bits(2, &next(time)), Some(0x1c7fff80));
+        assert_eq!(chain.next_bits(2, &next(time + 1000)), Some(0x1d00ffff));
+
+        // the difficulty adjustment algorithm keeps the difficulty when blocks are on time
+        let mut chain = HeaderChain::new(BlockchainId::Stn);
+        let mut time = genesis.timestamp();
+        for _ in 1..2300 {
+            time += 600;
+            push(&mut chain, time, 0x1c7fff80);
+        }
+        assert_eq!(chain.next_bits(2299, &next(time + 600)), Some(0x1c7fff80));
+        for _ in 0..144 {
+            time += 300;
+            push(&mut chain, time, 0x1c7fff80);
+        }
+        let bits = chain.next_bits(2443, &next(time + 300)).unwrap();
+        assert!(target_from_bits(bits).unwrap() < target_from_bits(0x1c7fff80).unwrap());
+    }
+
+    // The suitable block is chosen between equal timestamps in the same way as the node
+    #[test]
+    fn test_suitable_block() {
+        let mut chain = HeaderChain::new(BlockchainId::Stn);
+        push(&mut chain, 5, 0x1c7fff80);
+        push(&mut chain, 5, 0x1c7fff80);
+        push(&mut chain, 3, 0x1c7fff80);
+        assert_eq!(chain.suitable_block(3), Some(2));
+        push(&mut chain, 4, 0x1c7fff80);
+        assert_eq!(chain.suitable_block(4), Some(4));
+    }
+
+    // The headers in an archive are checked from the genesis block or a block with a height
+    #[tokio::test]
+    async fn test_validate_headers() {
+        let root = tempdir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let genesis = BlockHeader::get_genesis(BlockchainId::Regtest);
+        let mut parent = genesis.hash();
+        let mut headers = Vec::new();
+        for i in 1..6 {
+            let header = match i {
+                3 => mine_header(&parent, genesis.timestamp() + i * 600, 0x207fffff, false),
+                _ => mine_header(&parent, genesis.timestamp() + i * 600, 0x207fffff, true),
+            };
+            parent = header.hash();
+            headers.push(header);
+        }
+        for header in headers.iter().skip(1) {
+            let mut raw = header.raw.to_vec();
+            raw.push(0);
+            archive
+                .store_block_full(&Block::new(Bytes::from(raw)).unwrap())
+                .await
+                .unwrap();
+        }
+        // the first block is missing, so the rest are not connected without a height
+        let report = validate_headers(&archive, BlockchainId::Regtest)
+            .await
+            .unwrap();
+        assert_eq!(report.unconnected.len(), 4);
+        assert_eq!(report.headers_checked, 4);
+        assert_eq!(
+            report.failed,
+            vec![(
+                headers[2].hash(),
+                vec![HeaderProblem::InsufficientWork { bits: 0x207fffff }]
+            )]
+        );
+
+        let metadata = BlockMetadata {
+            height: Some(2),
+            ..BlockMetadata::default()
+        };
+        archive
+            .set_block_metadata(&headers[1].hash(), &metadata)
+            .await
+            .unwrap();
+        let report = validate_headers(&archive, BlockchainId::Regtest)
+            .await
+            .unwrap();
+        assert!(report.unconnected.is_empty());
+        assert_eq!(report.headers_checked, 4);
+        assert_eq!(report.failed.len(), 3);
+        assert_eq!(
+            report.failed[2],
+            (
+                headers[4].hash(),
+                vec![HeaderProblem::InvalidParent {
+                    parent: headers[3].hash()
+                }]
+            )
+        );
+
+        // the mainnet genesis block is not the parent of the regtest blocks
+        let mut raw = headers[0].raw.to_vec();
+        raw.push(0);
+        archive
+            .store_block_full(&Block::new(Bytes::from(raw)).unwrap())
+            .await
+            .unwrap();
+        let report = validate_headers(&archive, BlockchainId::Regtest)
+            .await
+            .unwrap();
+        assert_eq!(report.headers_checked, 5);
+        assert_eq!(report.failed.len(), 3);
+        let report = validate_headers(&archive, BlockchainId::Main)
+            .await
+            .unwrap();
+        assert_eq!(report.unconnected.len(), 5);
+
+        // blocks stored under hashes that make their parents a cycle
+        let first = BlockHash { raw: [1; 32] };
+        let second = BlockHash { raw: [2; 32] };
+        for (hash, parent) in [(first, second), (second, first)] {
+            let mut raw = mine_header(&parent, genesis.timestamp(), 0x207fffff, true)
+                .raw
+                .to_vec();
+            raw.push(0);
+            let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(std::io::Cursor::new(raw));
+            archive.store_block(&hash, &mut reader).await.unwrap();
+        }
+        assert!(matches!(
+            validate_headers(&archive, BlockchainId::Regtest).await,
+            Err(Error::CorruptData { hash, .. }) if hash == first || hash == second
+        ));
+    }
+}
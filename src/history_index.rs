@@ -0,0 +1,679 @@
+use crate::events::BlockEvent;
+use crate::filters::{is_unspendable, outpoint};
+use crate::output_index::{index_error, write_transaction};
+use crate::tx_reader::BlockTxReader;
+use crate::{BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::{BlockHash, Hash, Outpoint, TxHash};
+use bytes::Bytes;
+use redb::{
+    Database, MultimapTableDefinition, ReadableDatabase, ReadableMultimapTable, TableDefinition,
+    WriteTransaction,
+};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+// script hash -> the funding and spending events of the script
+const HISTORY: MultimapTableDefinition<&[u8], &[u8]> = MultimapTableDefinition::new("history");
+// outpoint -> the script hash of the output and the hash of each block that creates it
+const FUNDED: MultimapTableDefinition<&[u8], &[u8]> = MultimapTableDefinition::new("funded");
+// outpoint -> the spending events, one for each block, of an output which has not been indexed
+const PENDING: MultimapTableDefinition<&[u8], &[u8]> = MultimapTableDefinition::new("pending");
+// the hashes of the blocks that have been indexed
+const BLOCKS: TableDefinition<&[u8], ()> = TableDefinition::new("blocks");
+
+const FUNDING: u8 = 0;
+const SPENDING: u8 = 1;
+
+/// The hash of an output script used to look up its history, the single SHA-256 hash of the
+/// script. It is displayed in reverse byte order, like the script hashes of the Electrum
+/// protocol.
+pub fn script_hash(script: &[u8]) -> Hash {
+    Hash {
+        raw: Sha256::digest(script).into(),
+    }
+}
+
+/// A transaction that funded or spent an output of a script, see [ScriptHashIndex::history].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// The block that contains the transaction.
+    pub block_hash: BlockHash,
+    /// The position of the transaction in the block, the coinbase is 0.
+    pub tx_index: u64,
+    /// The hash of the transaction.
+    pub tx_hash: TxHash,
+    /// What the transaction did.
+    pub event: HistoryEvent,
+}
+
+/// What a transaction in the history of a script did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryEvent {
+    /// An output of the transaction pays to the script.
+    Funding { output: u32, value: u64 },
+    /// An input of the transaction spends an output which paid to the script.
+    Spending { input: u32, outpoint: Outpoint },
+}
+
+impl HistoryEntry {
+    // Encoded so that the entries of a script sort by block, then by position in the block.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(117);
+        buf.extend_from_slice(&self.block_hash.raw);
+        buf.extend_from_slice(&self.tx_index.to_be_bytes());
+        let (kind, index) = match &self.event {
+            HistoryEvent::Funding { output, .. } => (FUNDING, output),
+            HistoryEvent::Spending { input, .. } => (SPENDING, input),
+        };
+        buf.push(kind);
+        buf.extend_from_slice(&index.to_be_bytes());
+        buf.extend_from_slice(&self.tx_hash.raw);
+        match &self.event {
+            HistoryEvent::Funding { value, .. } => buf.extend_from_slice(&value.to_le_bytes()),
+            HistoryEvent::Spending { outpoint, .. } => buf.extend_from_slice(&outpoint.raw),
+        }
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<HistoryEntry> {
+        let index = u32::from_be_bytes(data.get(41..45)?.try_into().ok()?);
+        let event = match (data.get(40)?, data.get(77..)?) {
+            (&FUNDING, value) => HistoryEvent::Funding {
+                output: index,
+                value: u64::from_le_bytes(value.try_into().ok()?),
+            },
+            (&SPENDING, raw) if raw.len() == Outpoint::SIZE as usize => HistoryEvent::Spending {
+                input: index,
+                outpoint: Outpoint {
+                    raw: Bytes::copy_from_slice(raw),
+                },
+            },
+            _ => return None,
+        };
+        Some(HistoryEntry {
+            block_hash: BlockHash {
+                raw: data.get(0..32)?.try_into().ok()?,
+            },
+            tx_index: u64::from_be_bytes(data.get(32..40)?.try_into().ok()?),
+            tx_hash: TxHash {
+                raw: data.get(45..77)?.try_into().ok()?,
+            },
+            event,
+        })
+    }
+}
+
+// A change made to the index by a block, in the order of the transactions in the block.
+enum Change {
+    Fund {
+        outpoint: Bytes,
+        script_hash: Hash,
+        entry: Vec<u8>,
+    },
+    Spend {
+        outpoint: Bytes,
+        entry: Vec<u8>,
+    },
+}
+
+// The value of an entry in the funded table: the script hash of the output and the block that
+// creates it.
+fn funded_value(script_hash: &Hash, block_hash: &BlockHash) -> Vec<u8> {
+    let mut value = Vec::with_capacity(64);
+    value.extend_from_slice(&script_hash.raw);
+    value.extend_from_slice(&block_hash.raw);
+    value
+}
+
+// The script hash of an output, if a block that creates it has been indexed.
+fn funded_script_hash<T>(
+    funded: &T,
+    outpoint: &[u8],
+) -> std::result::Result<Option<Vec<u8>>, redb::Error>
+where
+    T: ReadableMultimapTable<&'static [u8], &'static [u8]>,
+{
+    match funded.get(outpoint)?.next() {
+        Some(value) => Ok(value?.value().get(..32).map(|v| v.to_vec())),
+        None => Ok(None),
+    }
+}
+
+/// An index from output script hashes to the transactions that funded and spent their outputs,
+/// so that the history of an address can be found without a separate copy of the blocks.
+///
+/// The index is built from the blocks of an archive, see [ScriptHashIndex::index_archive], and
+/// can be kept up to date as blocks are stored with [ScriptHashIndex::start_updates]. It is
+/// stored in a [redb](https://docs.rs/redb) database file, and is available with the "index"
+/// feature. Outputs whose script starts with OP_FALSE OP_RETURN can never be spent and are not
+/// indexed. The heights of the blocks are not known, so outputs whose script starts with
+/// OP_RETURN are indexed, as they can be spent after the Genesis upgrade.
+///
+/// Blocks can be indexed in any order. A spend is added to the history of a script once the
+/// block that created the output has been indexed, until then it is kept aside.
+///
+/// Example code:
+///     let index = ScriptHashIndex::open("history.redb")?;
+///     let updater = index.start_updates(archive.clone());
+///     for entry in index.history(&script_hash(&script))? {
+///         println!("{} {:?}", entry.tx_hash, entry.event);
+///     }
+#[derive(Clone)]
+pub struct ScriptHashIndex {
+    path: PathBuf,
+    db: Arc<Database>,
+}
+
+impl ScriptHashIndex {
+    /// Open the index in the file at the given path, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<ScriptHashIndex> {
+        let path = path.as_ref().to_path_buf();
+        let db = Database::create(&path).map_err(|e| index_error(&path, e))?;
+        // create the tables, so that reads do not fail before the first block is indexed
+        write_transaction(&db, |txn| {
+            txn.open_multimap_table(HISTORY)?;
+            txn.open_multimap_table(FUNDED)?;
+            txn.open_multimap_table(PENDING)?;
+            txn.open_table(BLOCKS)?;
+            Ok(())
+        })
+        .map_err(|e| index_error(&path, e))?;
+        Ok(ScriptHashIndex {
+            path,
+            db: Arc::new(db),
+        })
+    }
+
+    /// The path of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns true if the block has been indexed.
+    pub fn is_indexed(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.read(|txn| Ok(txn.open_table(BLOCKS)?.get(&block_hash.raw[..])?.is_some()))
+    }
+
+    /// Get the funding and spending transactions of the outputs of a script, ordered by block
+    /// hash and then by position in the block. Use the heights of the blocks to put them in
+    /// chain order.
+    pub fn history(&self, script_hash: &Hash) -> Result<Vec<HistoryEntry>> {
+        let values = self.read(|txn| {
+            let table = txn.open_multimap_table(HISTORY)?;
+            let mut values = Vec::new();
+            for value in table.get(&script_hash.raw[..])? {
+                values.push(value?.value().to_vec());
+            }
+            Ok(values)
+        })?;
+        values
+            .iter()
+            .map(|v| {
+                HistoryEntry::decode(v).ok_or_else(|| Error::IndexError {
+                    path: self.path.clone(),
+                    reason: format!("invalid history entry for script hash {script_hash}"),
+                })
+            })
+            .collect()
+    }
+
+    /// Add the outputs created and spent by a block to the index. The block is streamed from
+    /// [BlockArchiveReader::get_block].
+    ///
+    /// Returns false if the block has already been indexed.
+    pub async fn index_block<A>(&self, archive: &A, block_hash: &BlockHash) -> Result<bool>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        if self.is_indexed(block_hash)? {
+            return Ok(false);
+        }
+        let changes = Self::block_changes(archive, block_hash).await?;
+        let block_hash = *block_hash;
+        self.write_blocking(move |txn| {
+            let mut history = txn.open_multimap_table(HISTORY)?;
+            let mut funded = txn.open_multimap_table(FUNDED)?;
+            let mut pending = txn.open_multimap_table(PENDING)?;
+            for change in changes.iter() {
+                match change {
+                    Change::Fund {
+                        outpoint,
+                        script_hash,
+                        entry,
+                    } => {
+                        let value = funded_value(script_hash, &block_hash);
+                        funded.insert(&outpoint[..], &value[..])?;
+                        history.insert(&script_hash.raw[..], &entry[..])?;
+                        // the output was spent by blocks that were indexed before this one
+                        let mut spends = Vec::new();
+                        for spend in pending.remove_all(&outpoint[..])? {
+                            spends.push(spend?.value().to_vec());
+                        }
+                        for spend in spends {
+                            history.insert(&script_hash.raw[..], &spend[..])?;
+                        }
+                    }
+                    Change::Spend { outpoint, entry } => {
+                        match funded_script_hash(&funded, outpoint)? {
+                            Some(script_hash) => {
+                                history.insert(&script_hash[..], &entry[..])?;
+                            }
+                            None => {
+                                pending.insert(&outpoint[..], &entry[..])?;
+                            }
+                        }
+                    }
+                }
+            }
+            txn.open_table(BLOCKS)?.insert(&block_hash.raw[..], ())?;
+            Ok(())
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Index every block in the archive that has not been indexed. Returns the number of blocks
+    /// that were indexed.
+    pub async fn index_archive<A>(&self, archive: &A) -> Result<usize>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let mut blocks = archive.block_list().await?;
+        let mut indexed = 0;
+        while let Some(block_hash) = blocks.next().await {
+            match self.index_block(archive, &block_hash).await {
+                Ok(true) => indexed += 1,
+                Ok(false) => {}
+                // the block was removed since it was listed
+                Err(Error::BlockNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(indexed)
+    }
+
+    /// Remove the transactions of a block from the history, for example when the block has
+    /// been orphaned. Spends of its outputs by other blocks are kept aside until the outputs are
+    /// indexed again.
+    ///
+    /// Returns false if the block has not been indexed.
+    pub async fn remove_block<A>(&self, archive: &A, block_hash: &BlockHash) -> Result<bool>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        if !self.is_indexed(block_hash)? {
+            return Ok(false);
+        }
+        let changes = Self::block_changes(archive, block_hash).await?;
+        let block_hash = *block_hash;
+        self.write_blocking(move |txn| {
+            let mut history = txn.open_multimap_table(HISTORY)?;
+            let mut funded = txn.open_multimap_table(FUNDED)?;
+            let mut pending = txn.open_multimap_table(PENDING)?;
+            // undo the changes in reverse, so that the outputs spent within the block are
+            // still known when their spends are removed
+            for change in changes.iter().rev() {
+                match change {
+                    Change::Fund {
+                        outpoint,
+                        script_hash,
+                        entry,
+                    } => {
+                        let value = funded_value(script_hash, &block_hash);
+                        funded.remove(&outpoint[..], &value[..])?;
+                        history.remove(&script_hash.raw[..], &entry[..])?;
+                        // the spends stay while another block creates the output
+                        if funded.get(&outpoint[..])?.next().is_some() {
+                            continue;
+                        }
+                        let mut spends = Vec::new();
+                        for value in history.get(&script_hash.raw[..])? {
+                            let value = value?.value().to_vec();
+                            if value.get(40) == Some(&SPENDING)
+                                && value.get(77..) == Some(&outpoint[..])
+                            {
+                                spends.push(value);
+                            }
+                        }
+                        for spend in spends {
+                            history.remove(&script_hash.raw[..], &spend[..])?;
+                            pending.insert(&outpoint[..], &spend[..])?;
+                        }
+                    }
+                    Change::Spend { outpoint, entry } => {
+                        match funded_script_hash(&funded, outpoint)? {
+                            Some(script_hash) => {
+                                history.remove(&script_hash[..], &entry[..])?;
+                            }
+                            None => {
+                                pending.remove(&outpoint[..], &entry[..])?;
+                            }
+                        }
+                    }
+                }
+            }
+            txn.open_table(BLOCKS)?.remove(&block_hash.raw[..])?;
+            Ok(())
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Keep the index up to date with an archive in the background. Blocks that have not been
+    /// indexed are indexed first, then each block is indexed when it is stored, using
+    /// [BlockArchiveReader::subscribe]. Blocks that are deleted from the archive cannot be read,
+    /// so their entries are kept; use [ScriptHashIndex::remove_block] before deleting a block.
+    ///
+    /// The updates stop when the [HistoryUpdater] is dropped.
+    pub fn start_updates<A>(&self, archive: Arc<A>) -> HistoryUpdater
+    where
+        A: BlockArchiveReader + ?Sized + 'static,
+    {
+        let counts = Arc::new(UpdaterCounts::default());
+        let handle = tokio::spawn(Self::update_bgrnd(self.clone(), archive, counts.clone()));
+        HistoryUpdater { counts, handle }
+    }
+
+    // Index the blocks in the archive and then the blocks that are stored, until the task is
+    // aborted.
+    async fn update_bgrnd<A>(index: ScriptHashIndex, archive: Arc<A>, counts: Arc<UpdaterCounts>)
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        // subscribe before listing the blocks, so that no block is missed in between
+        let mut events = match archive.subscribe().await {
+            Ok(events) => Some(events),
+            Err(_) => {
+                counts.errors.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        };
+        counts.record(index.index_archive(archive.as_ref()).await);
+        let Some(events) = events.as_mut() else {
+            return;
+        };
+        while let Some(event) = events.next().await {
+            match event {
+                BlockEvent::Stored { hash, .. } => {
+                    let result = index.index_block(archive.as_ref(), &hash).await;
+                    counts.record(result.map(usize::from));
+                }
+                BlockEvent::Lagged { .. } => {
+                    counts.record(index.index_archive(archive.as_ref()).await);
+                }
+                // the block cannot be read to find its entries, so they are kept
+                BlockEvent::Deleted { .. } => {}
+            }
+        }
+    }
+
+    // Read the changes that a block makes to the index.
+    async fn block_changes<A>(archive: &A, block_hash: &BlockHash) -> Result<Vec<Change>>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let mut txs = BlockTxReader::new(archive.get_block(block_hash).await?, *block_hash).await?;
+        let mut changes = Vec::new();
+        while let Some(tx) = txs.next_tx().await? {
+            if !tx.is_coinbase() {
+                for (input, tx_input) in tx.tx.inputs.iter().enumerate() {
+                    let entry = HistoryEntry {
+                        block_hash: *block_hash,
+                        tx_index: tx.index,
+                        tx_hash: tx.hash,
+                        event: HistoryEvent::Spending {
+                            input: input as u32,
+                            outpoint: tx_input.outpoint.clone(),
+                        },
+                    };
+                    changes.push(Change::Spend {
+                        outpoint: tx_input.outpoint.raw.clone(),
+                        entry: entry.encode(),
+                    });
+                }
+            }
+            for (output, tx_output) in tx.tx.outputs.iter().enumerate() {
+                let script = &tx_output.script.raw;
+                if is_unspendable(script, false) {
+                    continue;
+                }
+                let entry = HistoryEntry {
+                    block_hash: *block_hash,
+                    tx_index: tx.index,
+                    tx_hash: tx.hash,
+                    event: HistoryEvent::Funding {
+                        output: output as u32,
+                        value: tx_output.value,
+                    },
+                };
+                changes.push(Change::Fund {
+                    outpoint: outpoint(&tx.hash, output as u32).raw,
+                    script_hash: script_hash(script),
+                    entry: entry.encode(),
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    fn read<T>(
+        &self,
+        f: impl FnOnce(&redb::ReadTransaction) -> std::result::Result<T, redb::Error>,
+    ) -> Result<T> {
+        let result = self
+            .db
+            .begin_read()
+            .map_err(redb::Error::from)
+            .and_then(|txn| f(&txn));
+        result.map_err(|e| index_error(&self.path, e))
+    }
+
+    // Perform a write on a blocking thread, writes can be large and wait for other writes.
+    async fn write_blocking<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&WriteTransaction) -> std::result::Result<(), redb::Error> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || write_transaction(&db, f))
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(|e| index_error(&self.path, e))
+    }
+}
+
+#[derive(Debug, Default)]
+struct UpdaterCounts {
+    indexed: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl UpdaterCounts {
+    fn record(&self, result: Result<usize>) {
+        match result {
+            Ok(indexed) => {
+                self.indexed.fetch_add(indexed as u64, Ordering::Relaxed);
+            }
+            // removed since it was stored
+            Err(Error::BlockNotFound(_)) => {}
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Keeps a [ScriptHashIndex] up to date with an archive in the background, see
+/// [ScriptHashIndex::start_updates].
+///
+/// Blocks that are deleted from the archive stay in the index, as they can no longer be read to
+/// find their entries. Use [ScriptHashIndex::remove_block] before deleting a block.
+pub struct HistoryUpdater {
+    counts: Arc<UpdaterCounts>,
+    // Handle to the background task that indexes the blocks.
+    handle: JoinHandle<()>,
+}
+
+impl HistoryUpdater {
+    /// The number of blocks that have been indexed.
+    pub fn blocks_indexed(&self) -> u64 {
+        self.counts.indexed.load(Ordering::Relaxed)
+    }
+
+    /// The number of times that a block could not be indexed or the archive could not be
+    /// listed.
+    pub fn errors(&self) -> u64 {
+        self.counts.errors.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for HistoryUpdater {
+    // stop the background task when the updater is dropped
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::OP_RETURN;
+    use crate::test_util::{make_block, make_tx};
+    use crate::{BlockArchiveWriter, SimpleFileBasedBlockArchive};
+    use bitcoinsv::bitcoin::FromHex;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    // Wait until the updater has indexed the given number of blocks.
+    async fn wait_for_blocks(updater: &HistoryUpdater, blocks: u64) {
+        for _ in 0..500 {
+            if updater.blocks_indexed() >= blocks {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("updater did not index {blocks} blocks");
+    }
+
+    // The script hash is displayed like the Electrum protocol
+    #[test]
+    fn test_script_hash() {
+        let script = hex::decode("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap();
+        assert_eq!(
+            script_hash(&script),
+            Hash::from_hex("8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161")
+                .unwrap()
+        );
+    }
+
+    // Funding and spending events are recorded as blocks are stored, in any order, and removed
+    // with their block
+    #[tokio::test]
+    async fn test_history() {
+        let dir = tempdir().unwrap();
+        let blocks = dir.path().join("blocks");
+        std::fs::create_dir(&blocks).unwrap();
+        let archive = Arc::new(
+            SimpleFileBasedBlockArchive::new(blocks.to_str().unwrap().into())
+                .await
+                .unwrap(),
+        );
+        let null = outpoint(&Hash::ZERO, u32::MAX);
+        let coinbase1 = make_tx(
+            std::slice::from_ref(&null),
+            &[b"alice", &[OP_RETURN], &[0, OP_RETURN]],
+        );
+        let block1 = make_block(&BlockHash::ZERO, std::slice::from_ref(&coinbase1));
+        let h1 = block1.header().unwrap().hash();
+        let paid = outpoint(&Hash::sha256d(&coinbase1), 0);
+        let spend = make_tx(std::slice::from_ref(&paid), &[b"bob", b"alice"]);
+        let coinbase2 = make_tx(&[null], &[b"carol"]);
+        let block2 = make_block(&h1, &[coinbase2, spend.clone()]);
+        let h2 = block2.header().unwrap().hash();
+
+        // the block that spends the output is stored first
+        archive.store_block_full(&block2).await.unwrap();
+        let index = ScriptHashIndex::open(dir.path().join("history.redb")).unwrap();
+        let updater = index.start_updates(archive.clone());
+        wait_for_blocks(&updater, 1).await;
+        assert_eq!(index.history(&script_hash(b"alice")).unwrap().len(), 1);
+        archive.store_block_full(&block1).await.unwrap();
+        wait_for_blocks(&updater, 2).await;
+        assert_eq!(updater.errors(), 0);
+        drop(updater);
+
+        let funding = HistoryEntry {
+            block_hash: h1,
+            tx_index: 0,
+            tx_hash: Hash::sha256d(&coinbase1),
+            event: HistoryEvent::Funding {
+                output: 0,
+                value: 1,
+            },
+        };
+        let spending = HistoryEntry {
+            block_hash: h2,
+            tx_index: 1,
+            tx_hash: Hash::sha256d(&spend),
+            event: HistoryEvent::Spending {
+                input: 0,
+                outpoint: paid.clone(),
+            },
+        };
+        let change = HistoryEntry {
+            event: HistoryEvent::Funding {
+                output: 1,
+                value: 1,
+            },
+            ..spending.clone()
+        };
+        let mut expected = vec![funding.clone(), spending.clone(), change.clone()];
+        expected.sort_by_key(|e| e.encode());
+        assert_eq!(index.history(&script_hash(b"alice")).unwrap(), expected);
+        assert_eq!(index.history(&script_hash(b"bob")).unwrap().len(), 1);
+        assert_eq!(index.history(&script_hash(&[OP_RETURN])).unwrap().len(), 1);
+        assert_eq!(
+            index.history(&script_hash(&[0, OP_RETURN])).unwrap(),
+            vec![]
+        );
+
+        // the spend is kept aside while the funding block is removed
+        assert!(index.remove_block(archive.as_ref(), &h1).await.unwrap());
+        assert_eq!(
+            index.history(&script_hash(b"alice")).unwrap(),
+            vec![change.clone()]
+        );
+        assert!(index.index_block(archive.as_ref(), &h1).await.unwrap());
+        assert_eq!(index.history(&script_hash(b"alice")).unwrap(), expected);
+        assert!(index.remove_block(archive.as_ref(), &h2).await.unwrap());
+        assert_eq!(
+            index.history(&script_hash(b"alice")).unwrap(),
+            vec![funding]
+        );
+        assert_eq!(index.history(&script_hash(b"bob")).unwrap(), vec![]);
+        assert!(index.is_indexed(&h1).unwrap());
+        assert!(!index.is_indexed(&h2).unwrap());
+
+        // forks which create and spend the same outputs are removed without the main chain
+        let fork1 = make_block(
+            &BlockHash { raw: [1; 32] },
+            std::slice::from_ref(&coinbase1),
+        );
+        let fork2 = make_block(&BlockHash { raw: [2; 32] }, std::slice::from_ref(&spend));
+        archive.store_block_full(&fork1).await.unwrap();
+        archive.store_block_full(&fork2).await.unwrap();
+        let fork1 = fork1.header().unwrap().hash();
+        let fork2 = fork2.header().unwrap().hash();
+        assert!(index.remove_block(archive.as_ref(), &h1).await.unwrap());
+        for h in [h2, fork2, h1, fork1] {
+            assert!(index.index_block(archive.as_ref(), &h).await.unwrap());
+        }
+        for h in [fork2, fork1] {
+            assert!(index.remove_block(archive.as_ref(), &h).await.unwrap());
+        }
+        assert_eq!(index.history(&script_hash(b"alice")).unwrap(), expected);
+    }
+}
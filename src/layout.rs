@@ -0,0 +1,282 @@
+use crate::{Error, Result};
+use bitcoinsv::bitcoin::BlockHash;
+use hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The name of the manifest file at the root of a [SimpleFileBasedBlockArchive].
+///
+/// [SimpleFileBasedBlockArchive]: crate::SimpleFileBasedBlockArchive
+pub const MANIFEST_FILE: &str = "blockarchive.json";
+
+// The current version of the manifest format.
+const MANIFEST_VERSION: u32 = 1;
+
+/// The directory layout of a [SimpleFileBasedBlockArchive], which determines where the file for
+/// each block is stored.
+///
+/// The layout is recorded in the archive manifest, see [MANIFEST_FILE]. An archive without a
+/// manifest uses the default layout, `FanOut { depth: 2, width: 2 }`.
+///
+/// [SimpleFileBasedBlockArchive]: crate::SimpleFileBasedBlockArchive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Layout {
+    /// Blocks are stored in `depth` levels of directories, each named after the next `width`
+    /// characters of the hex encoded hash, starting from the end of the hash.
+    ///
+    /// Example with depth 2 and width 2:
+    /// /31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin
+    FanOut { depth: usize, width: usize },
+    /// All blocks are stored in the root directory.
+    Flat,
+    /// Blocks are stored in directories of consecutive heights, with the height in the file name.
+    ///
+    /// Example with 1000 blocks per directory:
+    /// /504000/504031_0000000000000000000e1be8e8b08ee55cbbd8c3f0f8a3c1d8fdc8b5d2a0a1c3.bin
+    ///
+    /// The height of a block is found from its parent, so a block can only be stored if its
+    /// parent is already in the archive. The archive keeps the height of every block in memory.
+    Height { blocks_per_dir: u64 },
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::FanOut { depth: 2, width: 2 }
+    }
+}
+
+impl Layout {
+    /// Check that the parameters of the layout are valid.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match *self {
+            Layout::FanOut { depth, width }
+                if width == 0 || depth.checked_mul(width).is_none_or(|chars| chars > 64) =>
+            {
+                Err(format!(
+                    "fan out of depth {depth} and width {width} does not fit in a block hash"
+                ))
+            }
+            Layout::Height { blocks_per_dir: 0 } => {
+                Err(String::from("blocks per directory must be at least one"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if the layout needs the height of a block to find its file.
+    pub fn uses_height(&self) -> bool {
+        matches!(self, Layout::Height { .. })
+    }
+
+    /// The path of the file for a block. Returns None if the layout uses heights and the height
+    /// is not given.
+    pub fn path(&self, root: &Path, hash: &BlockHash, height: Option<u64>) -> Option<PathBuf> {
+        let s: String = hash.encode_hex();
+        let mut path = root.to_path_buf();
+        match *self {
+            Layout::FanOut { depth, width } => {
+                for level in 0..depth {
+                    let end = s.len() - level * width;
+                    path.push(&s[end - width..end]);
+                }
+                path.push(s);
+            }
+            Layout::Flat => path.push(s),
+            Layout::Height { blocks_per_dir } => {
+                let height = height?;
+                path.push((height - height % blocks_per_dir).to_string());
+                path.push(format!("{height}_{s}"));
+            }
+        }
+        path.set_extension("bin");
+        Some(path)
+    }
+
+    /// Get the block hash, and the height if the layout uses heights, from the path of a block
+    /// file. Returns None if the file is not a block file or if it is not stored in the correct
+    /// location.
+    pub fn parse(&self, root: &Path, path: &Path) -> Option<(BlockHash, Option<u64>)> {
+        // ignore files which are not .bin files
+        if path.extension()? != "bin" {
+            return None;
+        }
+        let f_name = path.file_stem()?.to_str()?;
+        let (height, f_name) = match self {
+            Layout::Height { .. } => {
+                let (height, f_name) = f_name.split_once('_')?;
+                (Some(height.parse().ok()?), f_name)
+            }
+            _ => (None, f_name),
+        };
+        // ignore files which are not valid block hashes
+        let hash = BlockHash::from_hex(f_name).ok()?;
+        // ignore files that are not in the correct location
+        if self.path(root, &hash, height)? != path {
+            return None;
+        }
+        Some((hash, height))
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layout::FanOut { depth, width } => write!(f, "fanout:{depth}:{width}"),
+            Layout::Flat => write!(f, "flat"),
+            Layout::Height { blocks_per_dir } => write!(f, "height:{blocks_per_dir}"),
+        }
+    }
+}
+
+/// Parse a layout from the form used by [Display](fmt::Display): `fanout:<depth>:<width>`,
+/// `flat` or `height:<blocks per directory>`.
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let number = |p: &str| p.parse().map_err(|_| format!("invalid number {p:?}"));
+        let layout = match parts.as_slice() {
+            ["fanout", depth, width] => Layout::FanOut {
+                depth: number(depth)? as usize,
+                width: number(width)? as usize,
+            },
+            ["flat"] => Layout::Flat,
+            ["height", blocks_per_dir] => Layout::Height {
+                blocks_per_dir: number(blocks_per_dir)?,
+            },
+            _ => return Err(format!("unknown layout {s:?}")),
+        };
+        layout.validate()?;
+        Ok(layout)
+    }
+}
+
+// The contents of the manifest file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub version: u32,
+    pub layout: Layout,
+    // The previous layout, if a migration has not finished removing the old block files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cleanup: Option<Layout>,
+    // The genesis block of the network the archive belongs to, if one has been recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genesis: Option<BlockHash>,
+}
+
+impl Manifest {
+    pub fn new(layout: Layout) -> Manifest {
+        Manifest {
+            version: MANIFEST_VERSION,
+            layout,
+            cleanup: None,
+            genesis: None,
+        }
+    }
+
+    // Read the manifest of the archive at root, if it has one.
+    pub async fn read(root: &Path) -> Result<Option<Manifest>> {
+        let path = root.join(MANIFEST_FILE);
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::io(&path, e)),
+        };
+        let invalid = |reason: String| Error::InvalidManifest {
+            path: path.clone(),
+            reason,
+        };
+        let manifest: Manifest =
+            serde_json::from_slice(&data).map_err(|e| invalid(e.to_string()))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(invalid(format!("unsupported version {}", manifest.version)));
+        }
+        manifest.layout.validate().map_err(invalid)?;
+        Ok(Some(manifest))
+    }
+
+    // Write the manifest of the archive at root, replacing it atomically.
+    pub async fn write(&self, root: &Path) -> Result<()> {
+        let path = root.join(MANIFEST_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+        let data = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        tokio::fs::write(&tmp_path, data)
+            .await
+            .map_err(|e| Error::io(&tmp_path, e))?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .map_err(|e| Error::io(&path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_hash() -> BlockHash {
+        BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531")
+            .unwrap()
+    }
+
+    // The default layout matches the original layout of the archive
+    #[test]
+    fn test_default_layout() {
+        let root = Path::new("/root");
+        let path = Layout::default().path(root, &get_hash(), None).unwrap();
+        assert_eq!(
+            path,
+            Path::new(
+                "/root/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin"
+            )
+        );
+        assert_eq!(
+            Layout::default().parse(root, &path),
+            Some((get_hash(), None))
+        );
+    }
+
+    // Paths are parsed back to the hash and height, files in the wrong place are ignored
+    #[test]
+    fn test_paths() {
+        let root = Path::new("/root");
+        let h = get_hash();
+        let layouts = [
+            Layout::FanOut { depth: 3, width: 1 },
+            Layout::Flat,
+            Layout::Height {
+                blocks_per_dir: 1000,
+            },
+        ];
+        for layout in layouts {
+            let height = layout.uses_height().then_some(504031);
+            let path = layout.path(root, &h, height).unwrap();
+            assert_eq!(layout.parse(root, &path), Some((h, height)));
+            let misplaced = root.join("x").join(path.file_name().unwrap());
+            assert_eq!(layout.parse(root, &misplaced), None);
+        }
+        let layout = Layout::Height {
+            blocks_per_dir: 1000,
+        };
+        assert_eq!(layout.path(root, &h, None), None);
+        assert_eq!(
+            layout.path(root, &h, Some(504031)).unwrap(),
+            Path::new("/root/504000/504031_00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin")
+        );
+    }
+
+    // Layouts can be written as strings and read back
+    #[test]
+    fn test_from_str() {
+        for s in ["fanout:2:2", "fanout:4:1", "flat", "height:1000"] {
+            assert_eq!(Layout::from_str(s).unwrap().to_string(), s);
+        }
+        assert!(Layout::from_str("fanout:40:2").is_err());
+        assert!(Layout::from_str(&format!("fanout:{}:2", usize::MAX)).is_err());
+        assert!(Layout::from_str("height:0").is_err());
+        assert!(Layout::from_str("tree").is_err());
+    }
+}
@@ -1,8 +1,72 @@
 mod block_archive;
+pub mod blocking;
+mod cached_archive;
+mod checksum;
+mod events;
+mod filters;
+mod headers;
+#[cfg(feature = "index")]
+mod history_index;
+mod layout;
+mod metadata;
+#[cfg(feature = "index")]
+mod output_index;
+mod pow;
+mod read_only;
+mod replicated_archive;
+mod scan;
+mod scrub;
 mod sfb_archive;
+mod sharded_archive;
+mod stats;
+mod sync;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod tx_reader;
+mod upload;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[cfg(feature = "index")]
+mod utxo;
+mod validate;
 
-pub use block_archive::{BlockArchive, BlockHashListStream};
+pub use block_archive::{
+    BlockArchive, BlockArchiveReader, BlockArchiveWriter, BlockHashListStream,
+};
+pub use cached_archive::{CacheConfig, CacheStats, CachedBlockArchive};
+pub use checksum::{block_checksum, VerifyingReader};
+pub use events::{BlockEvent, BlockEventStream, DEFAULT_EVENT_BUFFER};
+pub use filters::{
+    build_block_filter, filter_headers, BlockFilter, FilterIndex, MemoryPrevouts, PrevoutScripts,
+    FILTER_M, FILTER_P,
+};
+pub use headers::{validate_headers, HeaderChain, HeaderProblem, HeaderReport};
+#[cfg(feature = "index")]
+pub use history_index::{script_hash, HistoryEntry, HistoryEvent, HistoryUpdater, ScriptHashIndex};
+pub use layout::{Layout, MANIFEST_FILE};
+pub use metadata::BlockMetadata;
+#[cfg(feature = "index")]
+pub use output_index::{OutputIndex, OutputLocation, SpendLocation, TxLocation};
+pub use read_only::ReadOnly;
+pub use replicated_archive::{RepairReport, Replica, ReplicatedBlockArchive};
+pub use scan::{
+    scan, ScanOptions, ScanProgress, ScanProgressFn, ScanReport, DEFAULT_SCAN_CONCURRENCY,
+    DEFAULT_SCAN_READ_AHEAD,
+};
+pub use scrub::{ScrubConfig, ScrubStats, Scrubber};
 pub use sfb_archive::SimpleFileBasedBlockArchive;
+pub use sharded_archive::{RebalanceReport, ShardPlacement, ShardedBlockArchive};
+pub use stats::{scan_archive_stats, ArchiveStats};
+pub use sync::{
+    diff_archives, sync_archives, ArchiveDiff, SyncOptions, SyncReport, DEFAULT_SYNC_CONCURRENCY,
+};
+pub use tx_reader::{BlockTx, BlockTxReader};
+pub use upload::{UploadSession, UploadStatus, UPLOADS_DIR};
+#[cfg(feature = "index")]
+pub use utxo::{
+    verify_snapshot, Coin, ReplayReport, UtxoSet, UtxoSnapshot, SNAPSHOT_MAGIC, SNAPSHOT_VERSION,
+};
+pub use validate::{BlockValidation, ValidationProblem};
 
 mod result;
 pub use result::{Error, Result};
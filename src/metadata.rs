@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Information about a block other than its bytes, such as where and when it was obtained.
+///
+/// See [BlockArchiveReader::block_metadata](crate::BlockArchiveReader::block_metadata). All
+/// fields are optional, a block for which nothing has been recorded has the default (empty)
+/// metadata.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockMetadata {
+    /// When the block was first seen, in seconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<u64>,
+    /// Where the block came from, for example the address of a peer or the name of an importer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The height of the block, if it is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u64>,
+    /// The hex encoded SHA-256 digest of the bytes of the block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// The compression used to store the block, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// Any other information.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom: BTreeMap<String, String>,
+}
+
+impl BlockMetadata {
+    /// Create metadata for a block which has just been seen, from the given source.
+    pub fn seen_now(source: impl Into<String>) -> BlockMetadata {
+        BlockMetadata {
+            first_seen: Some(now()),
+            source: Some(source.into()),
+            ..BlockMetadata::default()
+        }
+    }
+
+    /// Returns true if nothing has been recorded.
+    pub fn is_empty(&self) -> bool {
+        *self == BlockMetadata::default()
+    }
+}
+
+// The current time in seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,549 @@
+use crate::filters::outpoint;
+use crate::tx_reader::{BlockTx, BlockTxReader};
+use crate::{BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::{varint_size, BlockHash, Encodable, Outpoint, Tx, TxHash, TxOutput};
+use redb::{Database, ReadableDatabase, TableDefinition, WriteTransaction};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+// outpoint, block hash -> the location of the output
+const OUTPUTS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("outputs");
+// outpoint, block hash -> the location of the input that spends it
+const SPENDS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("spends");
+// the hashes of the blocks that have been indexed
+const BLOCKS: TableDefinition<&[u8], ()> = TableDefinition::new("blocks");
+
+/// The location of a transaction in an archived block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxLocation {
+    /// The hash of the block.
+    pub block_hash: BlockHash,
+    /// The hash of the transaction.
+    pub tx_hash: TxHash,
+    /// The offset of the transaction from the start of the block.
+    pub offset: u64,
+    /// The size of the transaction in bytes.
+    pub size: u64,
+}
+
+impl TxLocation {
+    const SIZE: usize = 80;
+
+    fn new(block_hash: &BlockHash, tx: &BlockTx) -> TxLocation {
+        TxLocation {
+            block_hash: *block_hash,
+            tx_hash: tx.hash,
+            offset: tx.offset,
+            size: tx.size,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.block_hash.raw);
+        buf.extend_from_slice(&self.tx_hash.raw);
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+    }
+
+    fn decode(data: &[u8]) -> Option<TxLocation> {
+        Some(TxLocation {
+            block_hash: BlockHash {
+                raw: data.get(0..32)?.try_into().ok()?,
+            },
+            tx_hash: TxHash {
+                raw: data.get(32..64)?.try_into().ok()?,
+            },
+            offset: read_u64(data, 64)?,
+            size: read_u64(data, 72)?,
+        })
+    }
+}
+
+/// Where an output was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLocation {
+    /// The transaction that created the output.
+    pub tx: TxLocation,
+    /// The offset of the output from the start of the block.
+    pub offset: u64,
+    /// The size of the output in bytes.
+    pub size: u64,
+}
+
+impl OutputLocation {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(TxLocation::SIZE + 16);
+        self.tx.encode(&mut buf);
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<OutputLocation> {
+        Some(OutputLocation {
+            tx: TxLocation::decode(data)?,
+            offset: read_u64(data, TxLocation::SIZE)?,
+            size: read_u64(data, TxLocation::SIZE + 8)?,
+        })
+    }
+}
+
+/// Where an output was spent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpendLocation {
+    /// The transaction that spent the output.
+    pub tx: TxLocation,
+    /// The input of the transaction that spent the output.
+    pub input: u32,
+}
+
+impl SpendLocation {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(TxLocation::SIZE + 4);
+        self.tx.encode(&mut buf);
+        buf.extend_from_slice(&self.input.to_le_bytes());
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<SpendLocation> {
+        let input = data.get(TxLocation::SIZE..TxLocation::SIZE + 4)?;
+        Some(SpendLocation {
+            tx: TxLocation::decode(data)?,
+            input: u32::from_le_bytes(input.try_into().ok()?),
+        })
+    }
+}
+
+// The key of an entry: the outpoint followed by the hash of the block the entry comes from, so
+// that each block has its own entry when a transaction is in more than one block.
+fn entry_key(outpoint: &[u8], block_hash: &BlockHash) -> Vec<u8> {
+    let mut key = Vec::with_capacity(outpoint.len() + 32);
+    key.extend_from_slice(outpoint);
+    key.extend_from_slice(&block_hash.raw);
+    key
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+// The offsets from the start of the block and the sizes of the outputs of a transaction.
+fn output_ranges(tx: &BlockTx) -> Vec<(u64, u64)> {
+    let mut offset = tx.offset
+        + 4
+        + varint_size(tx.tx.inputs.len() as u64)
+        + tx.tx.inputs.iter().map(|i| i.encoded_size()).sum::<u64>()
+        + varint_size(tx.tx.outputs.len() as u64);
+    let mut ranges = Vec::with_capacity(tx.tx.outputs.len());
+    for output in tx.tx.outputs.iter() {
+        let size = output.encoded_size();
+        ranges.push((offset, size));
+        offset += size;
+    }
+    ranges
+}
+
+/// An index from outpoints to the transactions that created and spent them, built by walking
+/// the blocks of an archive.
+///
+/// The index only holds the locations of the transactions and outputs in their blocks, so it
+/// is small compared to the archive. [OutputIndex::get_output] and [OutputIndex::get_spender]
+/// read the data with [BlockArchiveReader::get_bytes_from_block]. The index is stored in a
+/// [redb](https://docs.rs/redb) database file, and is available with the "index" feature.
+///
+/// Blocks can be indexed in any order, and blocks that are not in the main chain are indexed
+/// like any other, so an output can be created or spent in more than one block. The location
+/// that is returned is then one of them, use [OutputIndex::remove_block] to remove the blocks
+/// of a fork.
+///
+/// Example code:
+///     let index = OutputIndex::open("outputs.redb")?;
+///     index.index_archive(&archive).await?;
+///     if let Some((location, tx)) = index.get_spender(&archive, &outpoint).await? {
+///         println!("spent by {} in block {}", location.tx.tx_hash, location.tx.block_hash);
+///     }
+#[derive(Clone)]
+pub struct OutputIndex {
+    path: PathBuf,
+    db: Arc<Database>,
+}
+
+impl OutputIndex {
+    /// Open the index in the file at the given path, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<OutputIndex> {
+        let path = path.as_ref().to_path_buf();
+        let db = Database::create(&path).map_err(|e| index_error(&path, e))?;
+        let index = OutputIndex {
+            path,
+            db: Arc::new(db),
+        };
+        // create the tables, so that reads do not fail before the first block is indexed
+        index.write(|txn| {
+            txn.open_table(OUTPUTS)?;
+            txn.open_table(SPENDS)?;
+            txn.open_table(BLOCKS)?;
+            Ok(())
+        })?;
+        Ok(index)
+    }
+
+    /// The path of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns true if the block has been indexed.
+    pub fn is_indexed(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.read(|txn| Ok(txn.open_table(BLOCKS)?.get(&block_hash.raw[..])?.is_some()))
+    }
+
+    /// Add the outputs created and spent by a block to the index. The block is streamed from
+    /// [BlockArchiveReader::get_block].
+    ///
+    /// Returns false if the block has already been indexed.
+    pub async fn index_block<A>(&self, archive: &A, block_hash: &BlockHash) -> Result<bool>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        if self.is_indexed(block_hash)? {
+            return Ok(false);
+        }
+        let mut txs = BlockTxReader::new(archive.get_block(block_hash).await?, *block_hash).await?;
+        let mut outputs = Vec::new();
+        let mut spends = Vec::new();
+        while let Some(tx) = txs.next_tx().await? {
+            let location = TxLocation::new(block_hash, &tx);
+            if !tx.is_coinbase() {
+                for (input, tx_input) in tx.tx.inputs.iter().enumerate() {
+                    let spend = SpendLocation {
+                        tx: location,
+                        input: input as u32,
+                    };
+                    spends.push((
+                        entry_key(&tx_input.outpoint.raw, block_hash),
+                        spend.encode(),
+                    ));
+                }
+            }
+            for (index, (offset, size)) in output_ranges(&tx).into_iter().enumerate() {
+                let output = OutputLocation {
+                    tx: location,
+                    offset,
+                    size,
+                };
+                let key = entry_key(&outpoint(&tx.hash, index as u32).raw, block_hash);
+                outputs.push((key, output.encode()));
+            }
+        }
+        let block_hash = *block_hash;
+        self.write_blocking(move |txn| {
+            let mut table = txn.open_table(OUTPUTS)?;
+            for (key, value) in outputs.iter() {
+                table.insert(&key[..], &value[..])?;
+            }
+            let mut table = txn.open_table(SPENDS)?;
+            for (key, value) in spends.iter() {
+                table.insert(&key[..], &value[..])?;
+            }
+            txn.open_table(BLOCKS)?.insert(&block_hash.raw[..], ())?;
+            Ok(())
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Index every block in the archive that has not been indexed. Returns the number of blocks
+    /// that were indexed.
+    pub async fn index_archive<A>(&self, archive: &A) -> Result<usize>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let mut blocks = archive.block_list().await?;
+        let mut indexed = 0;
+        while let Some(block_hash) = blocks.next().await {
+            match self.index_block(archive, &block_hash).await {
+                Ok(true) => indexed += 1,
+                Ok(false) => {}
+                // the block was removed since it was listed
+                Err(Error::BlockNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(indexed)
+    }
+
+    /// Remove the outputs created and spent by a block from the index, for example when the
+    /// block has been orphaned. The entries of other blocks with the same transactions are kept.
+    ///
+    /// Returns false if the block has not been indexed.
+    pub async fn remove_block<A>(&self, archive: &A, block_hash: &BlockHash) -> Result<bool>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        if !self.is_indexed(block_hash)? {
+            return Ok(false);
+        }
+        let mut txs = BlockTxReader::new(archive.get_block(block_hash).await?, *block_hash).await?;
+        let mut outputs = Vec::new();
+        let mut spends = Vec::new();
+        while let Some(tx) = txs.next_tx().await? {
+            if !tx.is_coinbase() {
+                let keys = tx.tx.inputs.iter();
+                spends.extend(keys.map(|i| entry_key(&i.outpoint.raw, block_hash)));
+            }
+            let keys = (0..tx.tx.outputs.len()).map(|i| outpoint(&tx.hash, i as u32).raw);
+            outputs.extend(keys.map(|key| entry_key(&key, block_hash)));
+        }
+        let block_hash = *block_hash;
+        self.write_blocking(move |txn| {
+            for (definition, keys) in [(OUTPUTS, outputs), (SPENDS, spends)] {
+                let mut table = txn.open_table(definition)?;
+                for key in keys.iter() {
+                    table.remove(&key[..])?;
+                }
+            }
+            txn.open_table(BLOCKS)?.remove(&block_hash.raw[..])?;
+            Ok(())
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Get the location of the output at an outpoint, or None if it is not in an indexed block.
+    pub fn output_location(&self, outpoint: &Outpoint) -> Result<Option<OutputLocation>> {
+        self.lookup(OUTPUTS, outpoint, OutputLocation::decode)
+    }
+
+    /// Get the location of the input that spent an outpoint, or None if it has not been spent
+    /// in an indexed block.
+    pub fn spend_location(&self, outpoint: &Outpoint) -> Result<Option<SpendLocation>> {
+        self.lookup(SPENDS, outpoint, SpendLocation::decode)
+    }
+
+    /// Get the output at an outpoint, and its location, reading it from the archive.
+    ///
+    /// Returns None if the output is not in an indexed block.
+    pub async fn get_output<A>(
+        &self,
+        archive: &A,
+        outpoint: &Outpoint,
+    ) -> Result<Option<(OutputLocation, TxOutput)>>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let Some(location) = self.output_location(outpoint)? else {
+            return Ok(None);
+        };
+        let mut raw = archive
+            .get_bytes_from_block(&location.tx.block_hash, location.offset, location.size)
+            .await?;
+        Ok(Some((location, TxOutput::from_binary(&mut raw)?)))
+    }
+
+    /// Get the transaction that spent an outpoint, and the location of the input, reading it
+    /// from the archive.
+    ///
+    /// Returns None if the outpoint has not been spent in an indexed block.
+    pub async fn get_spender<A>(
+        &self,
+        archive: &A,
+        outpoint: &Outpoint,
+    ) -> Result<Option<(SpendLocation, Tx)>>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let Some(location) = self.spend_location(outpoint)? else {
+            return Ok(None);
+        };
+        let mut raw = archive
+            .get_bytes_from_block(
+                &location.tx.block_hash,
+                location.tx.offset,
+                location.tx.size,
+            )
+            .await?;
+        Ok(Some((location, Tx::from_binary(&mut raw)?)))
+    }
+
+    fn lookup<T>(
+        &self,
+        definition: TableDefinition<&[u8], &[u8]>,
+        outpoint: &Outpoint,
+        decode: fn(&[u8]) -> Option<T>,
+    ) -> Result<Option<T>> {
+        let start = entry_key(&outpoint.raw, &BlockHash { raw: [0; 32] });
+        let end = entry_key(&outpoint.raw, &BlockHash { raw: [0xff; 32] });
+        let value = self.read(|txn| {
+            let table = txn.open_table(definition)?;
+            let mut entries = table.range(&start[..]..=&end[..])?;
+            Ok(entries.next().transpose()?.map(|(_, v)| v.value().to_vec()))
+        })?;
+        match value {
+            Some(value) => decode(&value).map(Some).ok_or_else(|| Error::IndexError {
+                path: self.path.clone(),
+                reason: format!("invalid entry for outpoint {}", hex::encode(&outpoint.raw)),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    fn read<T>(
+        &self,
+        f: impl FnOnce(&redb::ReadTransaction) -> std::result::Result<T, redb::Error>,
+    ) -> Result<T> {
+        let result = self
+            .db
+            .begin_read()
+            .map_err(redb::Error::from)
+            .and_then(|txn| f(&txn));
+        result.map_err(|e| index_error(&self.path, e))
+    }
+
+    fn write(
+        &self,
+        f: impl FnOnce(&WriteTransaction) -> std::result::Result<(), redb::Error>,
+    ) -> Result<()> {
+        write_transaction(&self.db, f).map_err(|e| index_error(&self.path, e))
+    }
+
+    // Perform a write on a blocking thread, writes can be large and wait for other writes.
+    async fn write_blocking<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&WriteTransaction) -> std::result::Result<(), redb::Error> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || write_transaction(&db, f))
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(|e| index_error(&self.path, e))
+    }
+}
+
+// Perform a write transaction, committing it if the function succeeds.
+pub(crate) fn write_transaction(
+    db: &Database,
+    f: impl FnOnce(&WriteTransaction) -> std::result::Result<(), redb::Error>,
+) -> std::result::Result<(), redb::Error> {
+    let txn = db.begin_write()?;
+    f(&txn)?;
+    txn.commit()?;
+    Ok(())
+}
+
+// Convert an error from the database of an index.
+pub(crate) fn index_error(path: &Path, e: impl Into<redb::Error>) -> Error {
+    Error::IndexError {
+        path: path.to_path_buf(),
+        reason: e.into().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockArchiveWriter, SimpleFileBasedBlockArchive};
+    use bitcoinsv::bitcoin::Block;
+    use tempfile::tempdir;
+
+    // Outputs and spenders are found in the test data, and removed with their block
+    #[tokio::test]
+    async fn test_output_index() {
+        let dir = tempdir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let source = SimpleFileBasedBlockArchive::new(String::from("testdata/blockarchive"))
+            .await
+            .unwrap();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut list = source.block_list().await.unwrap();
+        while let Some(h) = list.next().await {
+            let block = source.get_block_full(&h).await.unwrap();
+            archive.store_block_full(&block).await.unwrap();
+            blocks.push(block);
+        }
+        // a block with transactions which spend outputs
+        let raw = std::fs::read(
+            "testdata/blockarchive/000000000000000006f0fc3708a93be758307b16ea39f57c7e62026355cb6bf4.bin",
+        )
+        .unwrap();
+        let block = Block::new(raw.into()).unwrap();
+        archive.store_block_full(&block).await.unwrap();
+        blocks.push(block);
+        let index = OutputIndex::open(dir.path().join("outputs.redb")).unwrap();
+        assert_eq!(index.index_archive(&archive).await.unwrap(), blocks.len());
+        assert_eq!(index.index_archive(&archive).await.unwrap(), 0);
+
+        let mut spends = 0;
+        for block in blocks.iter() {
+            let block_hash = block.header().unwrap().hash();
+            for tx in block.tx_iter() {
+                let tx_hash = tx.hash();
+                for (i, output) in tx.outputs.iter().enumerate() {
+                    let (location, found) = index
+                        .get_output(&archive, &outpoint(&tx_hash, i as u32))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(&found, output);
+                    assert_eq!(location.tx.block_hash, block_hash);
+                    assert_eq!(location.tx.tx_hash, tx_hash);
+                }
+                if tx.inputs[0].outpoint.tx_hash() == TxHash::ZERO {
+                    continue;
+                }
+                for (i, input) in tx.inputs.iter().enumerate() {
+                    let (location, spender) = index
+                        .get_spender(&archive, &input.outpoint)
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(spender, tx);
+                    assert_eq!(location.input, i as u32);
+                    spends += 1;
+                }
+            }
+        }
+        assert!(spends > 0);
+
+        // a fork with a transaction of the main chain, which creates and spends the same outputs
+        let spending_block = blocks.last().unwrap();
+        let tx = spending_block.tx_iter().nth(1).unwrap();
+        let spent = tx.inputs[0].outpoint.clone();
+        let created = outpoint(&tx.hash(), 0);
+        let main_spend = index.spend_location(&spent).unwrap().unwrap();
+        let main_output = index.output_location(&created).unwrap().unwrap();
+        let start = main_spend.tx.offset as usize;
+        let end = start + main_spend.tx.size as usize;
+        let mut raw = spending_block.raw[..80].to_vec();
+        raw[36] ^= 1;
+        raw.push(1);
+        raw.extend_from_slice(&spending_block.raw[start..end]);
+        let fork = Block::new(raw.into()).unwrap();
+        let fork_hash = fork.header().unwrap().hash();
+        archive.store_block_full(&fork).await.unwrap();
+        assert!(index.index_block(&archive, &fork_hash).await.unwrap());
+        assert!(index.remove_block(&archive, &fork_hash).await.unwrap());
+        assert_eq!(index.spend_location(&spent).unwrap(), Some(main_spend));
+        assert_eq!(index.output_location(&created).unwrap(), Some(main_output));
+        archive.delete_block(&fork_hash).await.unwrap();
+
+        let block = &blocks[0];
+        let block_hash = block.header().unwrap().hash();
+        let tx = block.tx_iter().last().unwrap();
+        let created = outpoint(&tx.hash(), 0);
+        assert!(index.remove_block(&archive, &block_hash).await.unwrap());
+        assert!(!index.remove_block(&archive, &block_hash).await.unwrap());
+        assert!(!index.is_indexed(&block_hash).unwrap());
+        assert_eq!(index.output_location(&created).unwrap(), None);
+        drop(index);
+
+        // the index is kept in the file
+        let index = OutputIndex::open(dir.path().join("outputs.redb")).unwrap();
+        assert_eq!(index.index_archive(&archive).await.unwrap(), 1);
+        assert!(index.output_location(&created).unwrap().is_some());
+    }
+}
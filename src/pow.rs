@@ -0,0 +1,249 @@
+use bitcoinsv::bitcoin::BlockHash;
+use std::cmp::Ordering;
+
+// An unsigned 256 bit integer, for difficulty targets and chain work.
+//
+// The limbs are stored least significant first. Arithmetic wraps, like arith_uint256 in the node.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> U256 {
+        U256([value, 0, 0, 0])
+    }
+
+    // Interpret a hash as a number, the bytes of a hash are little endian.
+    pub fn from_hash(hash: &BlockHash) -> U256 {
+        let mut limbs = [0; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_le_bytes(hash.raw[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    #[cfg(test)]
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, limb) in self.0.iter().rev().enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    // The number of bits needed to represent the value.
+    pub fn bits(self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    pub fn low_u64(self) -> u64 {
+        self.0[0]
+    }
+
+    pub fn shl(self, shift: u32) -> U256 {
+        let mut result = U256::ZERO;
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        for i in (limbs..4).rev() {
+            result.0[i] = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                result.0[i] |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        result
+    }
+
+    pub fn shr(self, shift: u32) -> U256 {
+        let mut result = U256::ZERO;
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        for i in 0..4usize.saturating_sub(limbs) {
+            result.0[i] = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                result.0[i] |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        result
+    }
+
+    pub fn wrapping_add(self, other: U256) -> U256 {
+        let mut result = U256::ZERO;
+        let mut carry = false;
+        for i in 0..4 {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            result.0[i] = sum;
+            carry = c1 || c2;
+        }
+        result
+    }
+
+    pub fn wrapping_sub(self, other: U256) -> U256 {
+        let mut result = U256::ZERO;
+        let mut borrow = false;
+        for i in 0..4 {
+            let (difference, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (difference, b2) = difference.overflowing_sub(borrow as u64);
+            result.0[i] = difference;
+            borrow = b1 || b2;
+        }
+        result
+    }
+
+    pub fn wrapping_mul_u64(self, other: u64) -> U256 {
+        let mut result = U256::ZERO;
+        let mut carry = 0u128;
+        for i in 0..4 {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            result.0[i] = product as u64;
+            carry = product >> 64;
+        }
+        result
+    }
+
+    // Divide by long division. Panics if the divisor is zero.
+    pub fn div(self, divisor: U256) -> U256 {
+        assert!(divisor != U256::ZERO, "division by zero");
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..self.bits()).rev() {
+            // the remainder is less than the divisor, so if the shift overflows the shifted
+            // remainder is larger than the divisor and the wrapping subtraction is correct
+            let overflow = remainder.0[3] >> 63 == 1;
+            remainder = remainder.shl(1);
+            remainder.0[0] |= (self.0[i as usize / 64] >> (i % 64)) & 1;
+            if overflow || remainder >= divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.0[i as usize / 64] |= 1 << (i % 64);
+            }
+        }
+        quotient
+    }
+
+    pub fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+
+    // Decode the compact form of a number used in the bits field of a header, ignoring the sign
+    // and any bits that overflow.
+    pub fn from_compact(bits: u32) -> U256 {
+        let size = bits >> 24;
+        let word = bits & 0x007f_ffff;
+        match size <= 3 {
+            true => U256::from_u64((word >> (8 * (3 - size))) as u64),
+            false => U256::from_u64(word as u64).shl(8 * (size - 3)),
+        }
+    }
+
+    // Encode the number in the compact form used in the bits field of a header.
+    pub fn to_compact(self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = match size <= 3 {
+            true => (self.low_u64() << (8 * (3 - size))) as u32,
+            false => self.shr(8 * (size - 3)).low_u64() as u32,
+        };
+        // the 0x00800000 bit is the sign, so use a larger exponent rather than set it
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size << 24)
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Calculate the difficulty target from the bits field of a header. Returns None if the bits are
+// negative, zero or overflow.
+pub(crate) fn target_from_bits(bits: u32) -> Option<U256> {
+    let size = bits >> 24;
+    let word = bits & 0x007f_ffff;
+    let negative = word != 0 && bits & 0x0080_0000 != 0;
+    let overflow =
+        word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32));
+    let target = U256::from_compact(bits);
+    match negative || overflow || target == U256::ZERO {
+        true => None,
+        false => Some(target),
+    }
+}
+
+// Returns true if the hash meets the difficulty target given by the bits.
+pub(crate) fn meets_target(hash: &BlockHash, bits: u32) -> bool {
+    target_from_bits(bits).is_some_and(|target| U256::from_hash(hash) <= target)
+}
+
+// The expected number of hashes needed to find a block with the difficulty target given by the
+// bits, this is the work that the block adds to the chain.
+pub(crate) fn block_proof(bits: u32) -> U256 {
+    match target_from_bits(bits) {
+        // 2^256 / (target + 1) does not fit, but it is the same as ~target / (target + 1) + 1
+        Some(target) => target
+            .not()
+            .div(target.wrapping_add(U256::from_u64(1)))
+            .wrapping_add(U256::from_u64(1)),
+        None => U256::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::FromHex;
+
+    // The target is calculated from the compact form and encoded back to it
+    #[test]
+    fn test_compact() {
+        let target = target_from_bits(0x1d00ffff).unwrap();
+        assert_eq!(
+            hex::encode(target.to_be_bytes()),
+            "00000000ffff0000000000000000000000000000000000000000000000000000"
+        );
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        let target = target_from_bits(0x03123456).unwrap();
+        assert_eq!(target, U256::from_u64(0x123456));
+        assert_eq!(target.to_compact(), 0x03123456);
+        assert_eq!(target_from_bits(0x02123456), Some(U256::from_u64(0x1234)));
+        assert_eq!(U256::from_u64(0x80).to_compact(), 0x02008000);
+        assert_eq!(target_from_bits(0x04923456), None);
+        assert_eq!(target_from_bits(0x1d000000), None);
+        assert_eq!(target_from_bits(0x23010000), None);
+
+        let genesis =
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        assert!(meets_target(&genesis, 0x1d00ffff));
+        assert!(!meets_target(&genesis, 0x1b00ffff));
+    }
+
+    // The arithmetic used for retargeting and chain work
+    #[test]
+    fn test_arithmetic() {
+        // the work of a block at the minimum difficulty
+        assert_eq!(block_proof(0x1d00ffff), U256::from_u64(0x1_0001_0001));
+        let a = U256::from_u64(u64::MAX).shl(100);
+        assert_eq!(a.shr(100), U256::from_u64(u64::MAX));
+        assert_eq!(a.bits(), 164);
+        assert_eq!(a.div(U256::from_u64(1).shl(100)), U256::from_u64(u64::MAX));
+        assert_eq!(a.wrapping_mul_u64(4).div(a), U256::from_u64(4));
+        assert_eq!(U256::MAX.div(U256::MAX.shr(1)), U256::from_u64(2));
+        assert_eq!(U256::ZERO.wrapping_sub(U256::from_u64(1)), U256::MAX);
+        assert_eq!(U256::MAX.wrapping_add(U256::from_u64(1)), U256::ZERO);
+        assert!(U256::from_u64(1).shl(64) > U256::from_u64(u64::MAX));
+    }
+}
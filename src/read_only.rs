@@ -0,0 +1,146 @@
+use crate::block_archive::BlockHashListStream;
+use crate::events::{BlockEvent, BlockEventStream};
+use crate::{
+    ArchiveStats, BlockArchiveReader, BlockFilter, BlockMetadata, BlockValidation, Result,
+};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader};
+use bytes::Bytes;
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+
+/// A wrapper around an archive which only exposes the read operations.
+///
+/// It implements [BlockArchiveReader] but not [BlockArchiveWriter](crate::BlockArchiveWriter),
+/// and does not give access to the wrapped archive, so code that is given a `ReadOnly` cannot
+/// change the archive. It can be shared as an `Arc<dyn BlockArchiveReader>`.
+///
+/// Example code:
+///     let archive = SimpleFileBasedBlockArchive::new(root_path).await?;
+///     let reader = Arc::new(ReadOnly::new(archive));
+///     let header = reader.block_header(&hash).await?;
+pub struct ReadOnly<A: BlockArchiveReader> {
+    inner: A,
+}
+
+impl<A: BlockArchiveReader> ReadOnly<A> {
+    /// Wrap an archive.
+    pub fn new(inner: A) -> ReadOnly<A> {
+        ReadOnly { inner }
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchiveReader> BlockArchiveReader for ReadOnly<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.inner.get_block(block_hash).await
+    }
+
+    async fn get_block_full(&self, block_hash: &BlockHash) -> Result<Block> {
+        self.inner.get_block_full(block_hash).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.inner.block_exists(block_hash).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.inner.block_size(block_hash).await
+    }
+
+    async fn block_tx_count(&self, block_hash: &BlockHash) -> Result<i64> {
+        self.inner.block_tx_count(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.inner.block_header(block_hash).await
+    }
+
+    async fn get_bytes_from_block(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Bytes> {
+        self.inner
+            .get_bytes_from_block(block_hash, offset, length)
+            .await
+    }
+
+    async fn get_block_range(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.inner.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn get_ranges_from_block(
+        &self,
+        block_hash: &BlockHash,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<Bytes>> {
+        self.inner.get_ranges_from_block(block_hash, ranges).await
+    }
+
+    async fn block_list(&self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>> {
+        self.inner.block_list().await
+    }
+
+    async fn subscribe(&self) -> Result<Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>> {
+        self.inner.subscribe().await
+    }
+
+    async fn archive_stats(&self) -> Result<ArchiveStats> {
+        self.inner.archive_stats().await
+    }
+
+    async fn block_metadata(&self, block_hash: &BlockHash) -> Result<BlockMetadata> {
+        self.inner.block_metadata(block_hash).await
+    }
+
+    async fn verify_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.inner.verify_block(block_hash).await
+    }
+
+    async fn validate_block(&self, block_hash: &BlockHash) -> Result<BlockValidation> {
+        self.inner.validate_block(block_hash).await
+    }
+
+    async fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        self.inner.block_filter(block_hash).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleFileBasedBlockArchive;
+    use hex::FromHex;
+    use std::sync::Arc;
+
+    // A read only archive can be shared as a trait object and reads the wrapped archive
+    #[tokio::test]
+    async fn test_read_only() {
+        let archive = SimpleFileBasedBlockArchive::new(String::from("testdata/blockarchive"))
+            .await
+            .unwrap();
+        let reader: Arc<dyn BlockArchiveReader> = Arc::new(ReadOnly::new(archive));
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        assert!(reader.block_exists(&h).await.unwrap());
+        assert_eq!(reader.block_size(&h).await.unwrap(), 227);
+        assert_eq!(
+            futures::StreamExt::count(reader.block_list().await.unwrap()).await,
+            3
+        );
+        let task_reader = reader.clone();
+        let size = tokio::spawn(async move { task_reader.block_size(&h).await.unwrap() })
+            .await
+            .unwrap();
+        assert_eq!(size, 227);
+        assert_eq!(reader.archive_stats().await.unwrap().block_count, 3);
+    }
+}
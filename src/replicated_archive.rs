@@ -0,0 +1,786 @@
+use crate::block_archive::BlockHashListStream;
+use crate::events::{BlockEvent, BlockEventPublisher, BlockEventStream};
+use crate::{
+    BlockArchive, BlockArchiveReader, BlockArchiveWriter, BlockFilter, BlockMetadata, Error, Result,
+};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader};
+use bytes::Bytes;
+use futures::future::{join_all, BoxFuture};
+use std::collections::{BTreeSet, HashMap};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+
+/// A [BlockArchive] which can be used as a replica by a [ReplicatedBlockArchive].
+pub type Replica = Box<dyn BlockArchive + Send + Sync>;
+
+/// A [BlockArchive] that keeps a copy of every block in each of several archives.
+///
+/// Blocks are written to all replicas, the write succeeds if at least `write_quorum` replicas
+/// have the block (by default all of them). Reads are served by the first healthy replica that
+/// has the block. A replica is marked unhealthy when it returns an IO error and healthy again
+/// when an operation on it succeeds.
+///
+/// A block that is stored with [BlockArchiveWriter::store_block] is streamed into the first healthy
+/// replica and then copied from that replica to the others. If that store fails, the block is
+/// copied from another replica that already has it, if there is one.
+///
+/// [BlockArchiveReader::block_list] lists the blocks in the first healthy replica. Use
+/// [ReplicatedBlockArchive::repair] to copy blocks that are missing from a replica, or that have
+/// the wrong size, from the other replicas.
+///
+/// Example code:
+///     let primary = SimpleFileBasedBlockArchive::new(primary_path).await?;
+///     let secondary = SimpleFileBasedBlockArchive::new(secondary_path).await?;
+///     let mut archive = ReplicatedBlockArchive::new(vec![Box::new(primary), Box::new(secondary)])
+///         .with_write_quorum(1);
+///     let report = archive.repair().await?;
+pub struct ReplicatedBlockArchive {
+    replicas: Vec<ReplicaState>,
+    write_quorum: usize,
+    events: BlockEventPublisher,
+}
+
+// A replica and its health.
+struct ReplicaState {
+    archive: Replica,
+    healthy: AtomicBool,
+}
+
+/// The result of [ReplicatedBlockArchive::repair].
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The number of distinct blocks found in the replicas.
+    pub blocks_checked: usize,
+    /// Blocks that were copied to a replica which did not have them, as (block, replica index).
+    pub copied: Vec<(BlockHash, usize)>,
+    /// Blocks that were replaced in a replica because they had the wrong size, as
+    /// (block, replica index).
+    pub replaced: Vec<(BlockHash, usize)>,
+    /// Failures to check or repair a block in a replica, as (block, replica index, error).
+    pub errors: Vec<(BlockHash, usize, Error)>,
+    /// Replicas whose blocks could not be listed, as (replica index, error). These replicas are
+    /// not repaired.
+    pub unlisted: Vec<(usize, Error)>,
+}
+
+impl ReplicatedBlockArchive {
+    /// Create a new replicated archive. The order of the replicas is the order in which they are
+    /// tried for reads.
+    ///
+    /// Panics if there are no replicas.
+    pub fn new(replicas: Vec<Replica>) -> ReplicatedBlockArchive {
+        assert!(!replicas.is_empty(), "at least one replica is required");
+        let write_quorum = replicas.len();
+        ReplicatedBlockArchive {
+            replicas: replicas
+                .into_iter()
+                .map(|archive| ReplicaState {
+                    archive,
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            write_quorum,
+            events: BlockEventPublisher::default(),
+        }
+    }
+
+    /// Set the number of replicas which must have a block for a write to succeed.
+    ///
+    /// The quorum is limited to between one and the number of replicas.
+    pub fn with_write_quorum(mut self, write_quorum: usize) -> ReplicatedBlockArchive {
+        self.write_quorum = write_quorum.clamp(1, self.replicas.len());
+        self
+    }
+
+    /// The health of each replica.
+    pub fn healthy(&self) -> Vec<bool> {
+        self.replicas
+            .iter()
+            .map(|r| r.healthy.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Compare the blocks in each of the replicas and copy blocks that are missing from a
+    /// replica, or that have a different size, from another replica.
+    ///
+    /// If the copies of a block have different sizes then the size held by the most replicas is
+    /// assumed to be correct, with ties going to the larger size because an incomplete copy is
+    /// shorter than the original.
+    pub async fn repair(&mut self) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+        // the blocks in each replica, or None if they could not be listed
+        let mut lists = Vec::with_capacity(self.replicas.len());
+        for (i, replica) in self.replicas.iter().enumerate() {
+            match Self::list_blocks(&replica.archive).await {
+                Ok(blocks) => lists.push(Some(blocks)),
+                Err(e) => {
+                    report.unlisted.push((i, e));
+                    lists.push(None);
+                }
+            }
+        }
+        let all_blocks: BTreeSet<BlockHash> = lists.iter().flatten().flatten().copied().collect();
+        report.blocks_checked = all_blocks.len();
+        for block_hash in all_blocks {
+            // the size of the block in each replica, or None if the replica does not have it
+            let mut sizes = Vec::with_capacity(self.replicas.len());
+            // replicas which are not repaired because we don't know what they have
+            let mut skipped = vec![false; self.replicas.len()];
+            for (i, (replica, list)) in self.replicas.iter().zip(lists.iter()).enumerate() {
+                let size = match list {
+                    Some(list) if list.contains(&block_hash) => {
+                        match replica.archive.block_size(&block_hash).await {
+                            Ok(size) => Some(size),
+                            Err(e) => {
+                                report.errors.push((block_hash, i, e));
+                                skipped[i] = true;
+                                None
+                            }
+                        }
+                    }
+                    Some(_) => None,
+                    None => {
+                        skipped[i] = true;
+                        None
+                    }
+                };
+                sizes.push(size);
+            }
+            let mut counts: HashMap<usize, usize> = HashMap::new();
+            for size in sizes.iter().flatten() {
+                *counts.entry(*size).or_default() += 1;
+            }
+            let Some((correct_size, _)) =
+                counts.iter().max_by_key(|(size, count)| (**count, **size))
+            else {
+                continue;
+            };
+            let source = sizes
+                .iter()
+                .position(|s| *s == Some(*correct_size))
+                .unwrap();
+            for (i, size) in sizes.iter().enumerate() {
+                if skipped[i] || *size == Some(*correct_size) {
+                    continue;
+                }
+                let result = self.repair_block(&block_hash, source, i, size.is_some());
+                match result.await {
+                    Ok(_) if size.is_some() => report.replaced.push((block_hash, i)),
+                    Ok(_) => report.copied.push((block_hash, i)),
+                    Err(e) => report.errors.push((block_hash, i, e)),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // List the blocks in a replica.
+    async fn list_blocks(archive: &Replica) -> Result<BTreeSet<BlockHash>> {
+        let mut blocks = BTreeSet::new();
+        let mut results = archive.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            blocks.insert(block_hash);
+        }
+        Ok(blocks)
+    }
+
+    // Copy a block from one replica to another, replacing the existing copy if requested.
+    async fn repair_block(
+        &self,
+        block_hash: &BlockHash,
+        source: usize,
+        destination: usize,
+        replace: bool,
+    ) -> Result<()> {
+        let destination = &self.replicas[destination].archive;
+        let source = &self.replicas[source].archive;
+        let mut reader = source.get_block(block_hash).await?;
+        match replace {
+            true => destination.replace_block(block_hash, &mut reader).await?,
+            false => destination.store_block(block_hash, &mut reader).await?,
+        }
+        match source.block_metadata(block_hash).await? {
+            metadata if metadata.is_empty() => {}
+            metadata => match destination.set_block_metadata(block_hash, &metadata).await {
+                Err(Error::Unsupported(_)) => {}
+                result => result?,
+            },
+        }
+        match source.block_filter(block_hash).await? {
+            None => Ok(()),
+            Some(filter) => match destination.set_block_filter(block_hash, &filter).await {
+                Err(Error::Unsupported(_)) => Ok(()),
+                result => result,
+            },
+        }
+    }
+
+    // Combine the results of a write of metadata or a filter to every replica. The write
+    // succeeds if at least `write_quorum` replicas succeeded.
+    fn quorum_result(&self, block_hash: &BlockHash, results: Vec<Result<()>>) -> Result<()> {
+        let mut succeeded = 0;
+        let mut missing = 0;
+        let mut error = None;
+        for (replica, result) in self.replicas.iter().zip(results) {
+            Self::record_health(replica, &result);
+            match result {
+                Ok(_) => succeeded += 1,
+                Err(Error::BlockNotFound(_)) => missing += 1,
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if missing == self.replicas.len() {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        if succeeded < self.write_quorum {
+            return Err(Error::QuorumNotReached {
+                hash: *block_hash,
+                required: self.write_quorum,
+                succeeded,
+                source: error.map(Box::new),
+            });
+        }
+        Ok(())
+    }
+
+    // The indexes of the replicas in the order that they should be tried for reads, healthy
+    // ones first.
+    fn read_order(&self) -> Vec<usize> {
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) = (0..self.replicas.len())
+            .partition(|i| self.replicas[*i].healthy.load(Ordering::Relaxed));
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    // Update the health of a replica from the result of an operation.
+    fn record_health<T>(replica: &ReplicaState, result: &Result<T>) {
+        match result {
+            Ok(_) => replica.healthy.store(true, Ordering::Relaxed),
+            Err(Error::IoError { .. }) => replica.healthy.store(false, Ordering::Relaxed),
+            Err(_) => {}
+        }
+    }
+
+    // Perform a read on each replica in turn until one succeeds. If they all fail, return the
+    // first error that is not a BlockNotFound, or BlockNotFound if no replica has the block.
+    async fn read<'a, T, F>(&'a self, block_hash: &BlockHash, op: F) -> Result<T>
+    where
+        F: Fn(&'a Replica) -> BoxFuture<'a, Result<T>>,
+    {
+        let mut error = None;
+        for i in self.read_order() {
+            let replica = &self.replicas[i];
+            let result = op(&replica.archive).await;
+            Self::record_health(replica, &result);
+            match result {
+                Ok(value) => return Ok(value),
+                Err(Error::BlockNotFound(_)) => {}
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or(Error::BlockNotFound(*block_hash)))
+    }
+
+    // Find a replica other than `skip` which has the block.
+    async fn find_block(&self, block_hash: &BlockHash, skip: usize) -> Option<usize> {
+        for (i, replica) in self.replicas.iter().enumerate() {
+            if i != skip && matches!(replica.archive.block_exists(block_hash).await, Ok(true)) {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    // Check the results of a write to each replica against the quorum. A replica that already
+    // had the block counts towards the quorum, if every replica already had the block then the
+    // result is BlockExists.
+    fn check_quorum(&self, block_hash: &BlockHash, results: Vec<Result<()>>) -> Result<()> {
+        let mut succeeded = 0;
+        let mut existed = 0;
+        let mut error = None;
+        for (replica, result) in self.replicas.iter().zip(results) {
+            Self::record_health(replica, &result);
+            match result {
+                Ok(_) => succeeded += 1,
+                Err(Error::BlockExists(_)) => existed += 1,
+                // a replica that could not be given the block is reported if there is no other
+                // error
+                Err(e) => {
+                    if error.as_ref().is_none_or(Error::is_not_found) {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+        if existed == self.replicas.len() {
+            Err(Error::BlockExists(*block_hash))
+        } else if succeeded + existed >= self.write_quorum {
+            Ok(())
+        } else {
+            Err(Error::QuorumNotReached {
+                hash: *block_hash,
+                required: self.write_quorum,
+                succeeded: succeeded + existed,
+                source: error.map(Box::new),
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl BlockArchiveReader for ReplicatedBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.read(block_hash, |a| a.get_block(block_hash)).await
+    }
+
+    async fn get_block_full(&self, block_hash: &BlockHash) -> Result<Block> {
+        self.read(block_hash, |a| a.get_block_full(block_hash))
+            .await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let mut error = None;
+        for i in self.read_order() {
+            let replica = &self.replicas[i];
+            let result = replica.archive.block_exists(block_hash).await;
+            Self::record_health(replica, &result);
+            match result {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(false),
+        }
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.read(block_hash, |a| a.block_size(block_hash)).await
+    }
+
+    async fn block_tx_count(&self, block_hash: &BlockHash) -> Result<i64> {
+        self.read(block_hash, |a| a.block_tx_count(block_hash))
+            .await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.read(block_hash, |a| a.block_header(block_hash)).await
+    }
+
+    async fn get_bytes_from_block(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Bytes> {
+        self.read(block_hash, |a| {
+            a.get_bytes_from_block(block_hash, offset, length)
+        })
+        .await
+    }
+
+    async fn get_block_range(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.read(block_hash, |a| {
+            a.get_block_range(block_hash, offset, length)
+        })
+        .await
+    }
+
+    async fn get_ranges_from_block(
+        &self,
+        block_hash: &BlockHash,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<Bytes>> {
+        self.read(block_hash, |a| a.get_ranges_from_block(block_hash, ranges))
+            .await
+    }
+
+    async fn block_list(&self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>> {
+        let i = self.read_order()[0];
+        let replica = &self.replicas[i];
+        let result = replica.archive.block_list().await;
+        Self::record_health(replica, &result);
+        result
+    }
+
+    async fn block_metadata(&self, block_hash: &BlockHash) -> Result<BlockMetadata> {
+        self.read(block_hash, |a| a.block_metadata(block_hash))
+            .await
+    }
+
+    async fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        self.read(block_hash, |a| a.block_filter(block_hash)).await
+    }
+
+    /// Subscribe to changes made through the replicated archive.
+    ///
+    /// Changes made directly to the replicas are not reported.
+    async fn subscribe(&self) -> Result<Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>> {
+        Ok(Box::pin(self.events.subscribe()))
+    }
+}
+
+#[async_trait]
+impl BlockArchiveWriter for ReplicatedBlockArchive {
+    async fn store_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        // stream the block into the first healthy replica, the reader can only be read once
+        let source = self.read_order()[0];
+        let source_result = self.replicas[source]
+            .archive
+            .store_block(block_hash, block)
+            .await;
+        // then copy it to the others from a replica that has it, which is another replica if
+        // the first one failed and another replica already had the block
+        let copy_from = match &source_result {
+            Ok(_) | Err(Error::BlockExists(_)) => Some(source),
+            Err(_) => self.find_block(block_hash, source).await,
+        };
+        let mut source_result = Some(source_result);
+        let copies = self.replicas.iter().enumerate().map(|(i, replica)| {
+            let result = match i == source {
+                true => source_result.take(),
+                false => None,
+            };
+            async move {
+                if let Some(result) = result {
+                    return result;
+                }
+                match copy_from {
+                    Some(from) if from == i => Err(Error::BlockExists(*block_hash)),
+                    Some(from) => {
+                        let mut reader = self.replicas[from].archive.get_block(block_hash).await?;
+                        replica.archive.store_block(block_hash, &mut reader).await
+                    }
+                    None => Err(Error::BlockNotFound(*block_hash)),
+                }
+            }
+        });
+        let results = join_all(copies).await;
+        self.check_quorum(block_hash, results)?;
+        let size = self.block_size(block_hash).await?;
+        self.events.publish(BlockEvent::Stored {
+            hash: *block_hash,
+            size,
+        });
+        Ok(())
+    }
+
+    async fn store_block_full(&self, block: &Block) -> Result<()> {
+        let block_hash = block.header()?.hash();
+        let results = join_all(
+            self.replicas
+                .iter()
+                .map(|r| r.archive.store_block_full(block)),
+        )
+        .await;
+        self.check_quorum(&block_hash, results)?;
+        self.events.publish(BlockEvent::Stored {
+            hash: block_hash,
+            size: block.raw.len(),
+        });
+        Ok(())
+    }
+
+    async fn delete_block(&self, block_hash: &BlockHash) -> Result<()> {
+        let results = join_all(
+            self.replicas
+                .iter()
+                .map(|r| r.archive.delete_block(block_hash)),
+        )
+        .await;
+        let mut deleted = 0;
+        let mut missing = 0;
+        let mut error = None;
+        for (replica, result) in self.replicas.iter().zip(results) {
+            Self::record_health(replica, &result);
+            match result {
+                Ok(_) => deleted += 1,
+                Err(Error::BlockNotFound(_)) => missing += 1,
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if missing == self.replicas.len() {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        if deleted + missing < self.write_quorum {
+            return Err(Error::QuorumNotReached {
+                hash: *block_hash,
+                required: self.write_quorum,
+                succeeded: deleted + missing,
+                source: error.map(Box::new),
+            });
+        }
+        self.events
+            .publish(BlockEvent::Deleted { hash: *block_hash });
+        Ok(())
+    }
+
+    /// Record metadata for a block on every replica that has the block. The write succeeds if
+    /// at least `write_quorum` replicas record it.
+    async fn set_block_metadata(
+        &self,
+        block_hash: &BlockHash,
+        metadata: &BlockMetadata,
+    ) -> Result<()> {
+        let results = join_all(
+            self.replicas
+                .iter()
+                .map(|r| r.archive.set_block_metadata(block_hash, metadata)),
+        )
+        .await;
+        self.quorum_result(block_hash, results)
+    }
+
+    /// Store the filter of a block on every replica that has the block. The write succeeds if
+    /// at least `write_quorum` replicas store it.
+    async fn set_block_filter(&self, block_hash: &BlockHash, filter: &BlockFilter) -> Result<()> {
+        let results = join_all(
+            self.replicas
+                .iter()
+                .map(|r| r.archive.set_block_filter(block_hash, filter)),
+        )
+        .await;
+        self.quorum_result(block_hash, results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleFileBasedBlockArchive;
+    use hex::FromHex;
+    use std::path::Path;
+    use tempfile::{tempdir, TempDir};
+    use tokio::io::AsyncReadExt;
+
+    const BLOCK_PATH: &str =
+        "testdata/blockarchive/6f/e2/00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f.bin";
+
+    fn get_block_hash() -> BlockHash {
+        BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+            .unwrap()
+    }
+
+    async fn get_block() -> Block {
+        let raw = tokio::fs::read(BLOCK_PATH).await.unwrap();
+        Block::new(Bytes::from(raw)).unwrap()
+    }
+
+    async fn make_replica(dir: &Path) -> Replica {
+        let archive = SimpleFileBasedBlockArchive::new(dir.to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        Box::new(archive)
+    }
+
+    async fn make_archive(count: usize) -> (Vec<TempDir>, ReplicatedBlockArchive) {
+        let mut dirs = Vec::new();
+        let mut replicas = Vec::new();
+        for _ in 0..count {
+            let dir = tempdir().unwrap();
+            replicas.push(make_replica(dir.path()).await);
+            dirs.push(dir);
+        }
+        (dirs, ReplicatedBlockArchive::new(replicas))
+    }
+
+    // A stored block is written to every replica
+    #[tokio::test]
+    async fn test_store_block() {
+        let (dirs, archive) = make_archive(3).await;
+        let h = get_block_hash();
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> =
+            Box::new(tokio::fs::File::open(BLOCK_PATH).await.unwrap());
+        archive.store_block(&h, &mut reader).await.unwrap();
+        for dir in dirs.iter() {
+            let replica = make_replica(dir.path()).await;
+            assert_eq!(replica.block_size(&h).await.unwrap(), 227);
+        }
+        let block = get_block().await;
+        match archive.store_block_full(&block).await {
+            Err(Error::BlockExists(hash)) => assert_eq!(hash, h),
+            r => panic!("expected BlockExists, got {:?}", r.map(|_| ())),
+        }
+        archive.delete_block(&h).await.unwrap();
+        for dir in dirs.iter() {
+            let replica = make_replica(dir.path()).await;
+            assert!(!replica.block_exists(&h).await.unwrap());
+        }
+    }
+
+    // Reads fall back to a replica that has the block
+    #[tokio::test]
+    async fn test_read_fallback() {
+        let (dirs, archive) = make_archive(2).await;
+        let h = get_block_hash();
+        let block = get_block().await;
+        let replica = make_replica(dirs[1].path()).await;
+        replica.store_block_full(&block).await.unwrap();
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert_eq!(archive.block_tx_count(&h).await.unwrap(), 1);
+        let mut reader = archive.get_block(&h).await.unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, block.raw);
+        let unknown =
+            BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        assert!(archive
+            .block_size(&unknown)
+            .await
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    // Repair copies missing blocks and replaces short copies
+    #[tokio::test]
+    async fn test_repair() {
+        let (dirs, mut archive) = make_archive(3).await;
+        let h = get_block_hash();
+        let block = get_block().await;
+        make_replica(dirs[0].path())
+            .await
+            .store_block_full(&block)
+            .await
+            .unwrap();
+        make_replica(dirs[1].path())
+            .await
+            .store_block_full(&block)
+            .await
+            .unwrap();
+        // a short copy, as left by an interrupted copy
+        let mut short: Box<dyn AsyncRead + Unpin + Send> =
+            Box::new(std::io::Cursor::new(block.raw.slice(..100)));
+        make_replica(dirs[2].path())
+            .await
+            .store_block(&h, &mut short)
+            .await
+            .unwrap();
+        let genesis = Block::get_genesis(bitcoinsv::bitcoin::BlockchainId::Main).unwrap();
+        make_replica(dirs[2].path())
+            .await
+            .store_block_full(&genesis)
+            .await
+            .unwrap();
+        let report = archive.repair().await.unwrap();
+        let genesis_hash = genesis.header().unwrap().hash();
+        assert_eq!(report.blocks_checked, 2);
+        let mut copied = report.copied.clone();
+        copied.sort();
+        assert_eq!(copied, vec![(genesis_hash, 0), (genesis_hash, 1)]);
+        assert_eq!(report.replaced, vec![(h, 2)]);
+        assert!(report.errors.is_empty());
+        for dir in dirs.iter() {
+            let replica = make_replica(dir.path()).await;
+            assert_eq!(replica.block_size(&h).await.unwrap(), 227);
+            assert!(replica.block_exists(&genesis_hash).await.unwrap());
+        }
+        let report = archive.repair().await.unwrap();
+        assert!(report.copied.is_empty() && report.replaced.is_empty());
+    }
+
+    // A replica whose root is a file, so that every operation on it fails
+    async fn make_broken_replica() -> (TempDir, Replica) {
+        let broken_dir = tempdir().unwrap();
+        let broken_root = broken_dir.path().join("root");
+        tokio::fs::create_dir(&broken_root).await.unwrap();
+        let broken = make_replica(&broken_root).await;
+        tokio::fs::remove_dir(&broken_root).await.unwrap();
+        tokio::fs::write(&broken_root, b"not a directory")
+            .await
+            .unwrap();
+        (broken_dir, broken)
+    }
+
+    // A failed store in the first replica counts against the quorum, the other replicas are
+    // still written, and repair reports the replica it cannot repair instead of failing
+    #[tokio::test]
+    async fn test_store_after_source_failure() {
+        let (dirs, _) = make_archive(2).await;
+        let (_broken_dir, broken) = make_broken_replica().await;
+        let block = get_block().await;
+        let h = get_block_hash();
+        make_replica(dirs[0].path())
+            .await
+            .store_block_full(&block)
+            .await
+            .unwrap();
+        let replicas = vec![
+            broken,
+            make_replica(dirs[0].path()).await,
+            make_replica(dirs[1].path()).await,
+        ];
+        let mut archive = ReplicatedBlockArchive::new(replicas).with_write_quorum(2);
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> =
+            Box::new(tokio::fs::File::open(BLOCK_PATH).await.unwrap());
+        archive.store_block(&h, &mut reader).await.unwrap();
+        assert_eq!(archive.healthy(), vec![false, true, true]);
+        let replica = make_replica(dirs[1].path()).await;
+        assert_eq!(replica.block_size(&h).await.unwrap(), 227);
+
+        let report = archive.repair().await.unwrap();
+        assert_eq!(report.blocks_checked, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!((report.errors[0].0, report.errors[0].1), (h, 0));
+    }
+
+    // Writes succeed when the quorum is reached and failing replicas are marked unhealthy
+    #[tokio::test]
+    async fn test_write_quorum() {
+        let (dirs, _) = make_archive(2).await;
+        let (_broken_dir, broken) = make_broken_replica().await;
+        let replicas = vec![
+            broken,
+            make_replica(dirs[0].path()).await,
+            make_replica(dirs[1].path()).await,
+        ];
+        let archive = ReplicatedBlockArchive::new(replicas);
+        let block = get_block().await;
+        let h = get_block_hash();
+        match archive.store_block_full(&block).await {
+            Err(Error::QuorumNotReached {
+                hash,
+                required,
+                succeeded,
+                source,
+            }) => {
+                assert_eq!(hash, h);
+                assert_eq!(required, 3);
+                assert_eq!(succeeded, 2);
+                assert!(matches!(source.as_deref(), Some(Error::IoError { .. })));
+            }
+            r => panic!("expected QuorumNotReached, got {:?}", r.map(|_| ())),
+        }
+        assert_eq!(archive.healthy(), vec![false, true, true]);
+        let archive = archive.with_write_quorum(2);
+        archive.delete_block(&h).await.unwrap();
+        // the unhealthy replica is not used as the source of the copies
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> =
+            Box::new(tokio::fs::File::open(BLOCK_PATH).await.unwrap());
+        archive.store_block(&h, &mut reader).await.unwrap();
+        assert_eq!(archive.block_size(&h).await.unwrap(), 227);
+        assert_eq!(archive.healthy(), vec![false, true, true]);
+    }
+}
@@ -1,34 +1,234 @@
+use bitcoinsv::bitcoin::{BlockHash, TxHash};
+use std::path::{Path, PathBuf};
+
 /// Standard Result used in the library
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Standard error type used in the library
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// The block was not found in the archive.
-    BlockNotFound,
-    /// The block already exists in the archive. This error may be returned by [BlockArchive::store_block].
-    BlockExists,
-    /// Not enough data read.
-    NotEnoughData,
-    IoError(std::io::Error),
+    BlockNotFound(BlockHash),
+    /// The block already exists in the archive. This error may be returned by [BlockArchiveWriter::store_block].
+    ///
+    /// [BlockArchiveWriter::store_block]: crate::BlockArchiveWriter::store_block
+    BlockExists(BlockHash),
+    /// The data stored for a block is not valid, for example it is too short to contain a header.
+    CorruptData {
+        hash: BlockHash,
+        reason: String,
+    },
+    /// The hash of a block does not match the hash that was expected.
+    HashMismatch {
+        expected: BlockHash,
+        actual: BlockHash,
+    },
+    /// The archive does not allow blocks to be stored or removed.
+    ReadOnly,
+    /// The archive does not support the operation.
+    Unsupported(&'static str),
+    /// The requested range of bytes is not within the block.
+    InvalidRange {
+        hash: BlockHash,
+        offset: u64,
+        length: u64,
+        size: u64,
+    },
+    /// A write to a replicated archive did not reach the required number of replicas.
+    QuorumNotReached {
+        hash: BlockHash,
+        required: usize,
+        succeeded: usize,
+        /// The first error returned by a replica.
+        source: Option<Box<Error>>,
+    },
+    /// The parent of a block is not in the archive, but is needed to store the block, or a block
+    /// does not follow the last block given to a [FilterIndex](crate::FilterIndex).
+    ParentNotFound {
+        hash: BlockHash,
+        parent: BlockHash,
+    },
+    /// An upload session cannot accept the request, for example because a chunk does not
+    /// continue the data that has been received or the upload is not complete.
+    InvalidUpload {
+        hash: BlockHash,
+        reason: String,
+    },
+    /// An output spent by a block is not known, for example when building the filter of the
+    /// block or applying it to a UTXO set.
+    PrevoutNotFound {
+        hash: BlockHash,
+        tx: TxHash,
+        index: u32,
+    },
+    /// No filter has been stored for the block.
+    FilterNotFound(BlockHash),
+    /// The block does not belong to the network of the archive, which is identified by its
+    /// genesis block.
+    WrongNetwork {
+        hash: BlockHash,
+        genesis: BlockHash,
+    },
+    /// The best chain of the archive does not reach the height.
+    HeightNotFound(u64),
+    /// The manifest of an archive cannot be used.
+    InvalidManifest {
+        path: PathBuf,
+        reason: String,
+    },
+    /// The database of an index returned an error.
+    IndexError {
+        path: PathBuf,
+        reason: String,
+    },
+    /// An IO error, with the path of the file that was being accessed if it is known.
+    IoError {
+        path: Option<PathBuf>,
+        source: std::io::Error,
+    },
     BitcoinSVError(bitcoinsv::Error),
 }
 
+impl Error {
+    /// Create an IO error for the given path.
+    pub fn io(path: &Path, source: std::io::Error) -> Error {
+        Error::IoError {
+            path: Some(path.to_path_buf()),
+            source,
+        }
+    }
+
+    /// Returns true if the error is a [Error::BlockNotFound].
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::BlockNotFound(_))
+    }
+
+    /// Returns true if the error is a [Error::BlockExists].
+    pub fn is_exists(&self) -> bool {
+        matches!(self, Error::BlockExists(_))
+    }
+
+    /// Returns true if the error is caused by invalid data in the archive.
+    pub fn is_corrupt(&self) -> bool {
+        matches!(self, Error::CorruptData { .. } | Error::HashMismatch { .. })
+    }
+
+    /// The hash of the block that the error relates to, if known.
+    pub fn block_hash(&self) -> Option<BlockHash> {
+        match self {
+            Error::BlockNotFound(hash)
+            | Error::BlockExists(hash)
+            | Error::CorruptData { hash, .. }
+            | Error::InvalidRange { hash, .. }
+            | Error::QuorumNotReached { hash, .. }
+            | Error::ParentNotFound { hash, .. }
+            | Error::InvalidUpload { hash, .. }
+            | Error::PrevoutNotFound { hash, .. }
+            | Error::FilterNotFound(hash)
+            | Error::WrongNetwork { hash, .. } => Some(*hash),
+            Error::HashMismatch { expected, .. } => Some(*expected),
+            _ => None,
+        }
+    }
+
+    /// The path of the file that the error relates to, if known.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::IoError { path, .. } => path.as_deref(),
+            Error::InvalidManifest { path, .. } | Error::IndexError { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Error::BlockNotFound => write!(f, "Block not found"),
-            Error::BlockExists => write!(f, "Block exists"),
-            Error::NotEnoughData => write!(f, "Not enough data"),
-            Error::IoError(err) => write!(f, "IO error: {err}"),
+            Error::BlockNotFound(hash) => write!(f, "Block not found: {hash}"),
+            Error::BlockExists(hash) => write!(f, "Block exists: {hash}"),
+            Error::CorruptData { hash, reason } => write!(f, "Corrupt block {hash}: {reason}"),
+            Error::HashMismatch { expected, actual } => {
+                write!(f, "Hash mismatch: expected {expected}, got {actual}")
+            }
+            Error::ReadOnly => write!(f, "Archive is read-only"),
+            Error::Unsupported(operation) => write!(f, "Archive does not support {operation}"),
+            Error::InvalidRange {
+                hash,
+                offset,
+                length,
+                size,
+            } => write!(
+                f,
+                "Invalid range: {length} bytes at offset {offset} of block {hash} which has {size} bytes"
+            ),
+            Error::QuorumNotReached {
+                hash,
+                required,
+                succeeded,
+                source,
+            } => {
+                write!(
+                    f,
+                    "Quorum not reached for block {hash}: {succeeded} of {required} replicas succeeded"
+                )?;
+                match source {
+                    Some(e) => write!(f, ", first error: {e}"),
+                    None => Ok(()),
+                }
+            }
+            Error::ParentNotFound { hash, parent } => {
+                write!(f, "Parent {parent} of block {hash} is not in the archive")
+            }
+            Error::InvalidUpload { hash, reason } => {
+                write!(f, "Invalid upload of block {hash}: {reason}")
+            }
+            Error::PrevoutNotFound { hash, tx, index } => {
+                write!(f, "Output {tx}:{index} spent by block {hash} is not known")
+            }
+            Error::FilterNotFound(hash) => write!(f, "Filter not found for block {hash}"),
+            Error::WrongNetwork { hash, genesis } => write!(
+                f,
+                "Block {hash} does not belong to the network of the archive, whose genesis block is {genesis}"
+            ),
+            Error::HeightNotFound(height) => {
+                write!(f, "No block at height {height} in the best chain")
+            }
+            Error::InvalidManifest { path, reason } => {
+                write!(f, "Invalid manifest {}: {reason}", path.display())
+            }
+            Error::IndexError { path, reason } => {
+                write!(f, "Index error on {}: {reason}", path.display())
+            }
+            Error::IoError {
+                path: Some(path),
+                source,
+            } => write!(f, "IO error on {}: {source}", path.display()),
+            Error::IoError { path: None, source } => write!(f, "IO error: {source}"),
             Error::BitcoinSVError(err) => write!(f, "Bitcoin SV error: {err}"),
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError { source, .. } => Some(source),
+            Error::QuorumNotReached {
+                source: Some(e), ..
+            } => Some(e.as_ref()),
+            // bitcoinsv::Error does not implement std::error::Error
+            _ => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
-        Error::IoError(err)
+        Error::IoError {
+            path: None,
+            source: err,
+        }
     }
 }
 
@@ -0,0 +1,337 @@
+use crate::{BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::{Block, BlockHash};
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The default number of blocks that are processed concurrently by [scan].
+pub const DEFAULT_SCAN_CONCURRENCY: usize = 4;
+
+/// The default number of blocks that [scan] reads ahead of the blocks being processed.
+pub const DEFAULT_SCAN_READ_AHEAD: usize = 8;
+
+/// A function which is called with the progress of a [scan].
+pub type ScanProgressFn = Arc<dyn Fn(&ScanProgress) + Send + Sync>;
+
+/// Options for [scan].
+#[derive(Clone)]
+pub struct ScanOptions {
+    /// The maximum number of blocks that are processed at the same time.
+    pub concurrency: usize,
+    /// The maximum number of blocks that are read into memory before they are processed. The
+    /// blocks being processed are held as well, so up to `read_ahead + concurrency` blocks are in
+    /// memory at a time.
+    pub read_ahead: usize,
+    /// Process the blocks in order, by height if a height range is given and otherwise in the
+    /// order they are listed by the archive. Blocks are still processed concurrently, but each
+    /// block is started after the blocks before it and the results are collected in order.
+    pub ordered: bool,
+    /// Only scan the blocks with a height in this range.
+    ///
+    /// Heights are found by following the chain of parents back to the genesis block or to a
+    /// block whose height is recorded in its metadata. Blocks whose height cannot be found are
+    /// not scanned.
+    pub heights: Option<Range<u64>>,
+    /// Called after each block has been processed.
+    pub progress: Option<ScanProgressFn>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            concurrency: DEFAULT_SCAN_CONCURRENCY,
+            read_ahead: DEFAULT_SCAN_READ_AHEAD,
+            ordered: false,
+            heights: None,
+            progress: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for ScanOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScanOptions")
+            .field("concurrency", &self.concurrency)
+            .field("read_ahead", &self.read_ahead)
+            .field("ordered", &self.ordered)
+            .field("heights", &self.heights)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+/// The progress of a [scan].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScanProgress {
+    /// The number of blocks to be scanned.
+    pub blocks_total: usize,
+    /// The number of blocks that have been processed, including those that failed.
+    pub blocks_done: usize,
+    /// The number of bytes in the blocks that have been processed successfully.
+    pub bytes_scanned: u64,
+    /// The number of blocks that could not be read or processed.
+    pub errors: usize,
+}
+
+/// The result of [scan].
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// The number of blocks that were processed successfully.
+    pub blocks_scanned: usize,
+    /// The number of bytes in the blocks that were processed successfully.
+    pub bytes_scanned: u64,
+    /// Blocks that could not be read, or for which the function returned an error.
+    pub errors: Vec<(BlockHash, Error)>,
+}
+
+/// Run a function over every block in an archive, or every block in a height range.
+///
+/// The blocks are listed with [BlockArchiveReader::block_list] and read with
+/// [BlockArchiveReader::get_block_full], up to [ScanOptions::read_ahead] blocks ahead of the
+/// function, which is run on up to [ScanOptions::concurrency] blocks at a time. Blocks that are
+/// removed from the archive during the scan are skipped, an [Error::BlockNotFound] returned by
+/// the function is recorded like any other error.
+///
+/// Errors reading or processing individual blocks are recorded in [ScanReport::errors] and the
+/// scan continues, errors listing the archive are returned.
+///
+/// Example code:
+///     let report = scan(&archive, &ScanOptions::default(), |hash, block| async move {
+///         println!("{hash} has {} transactions", block.num_tx);
+///         Ok(())
+///     })
+///     .await?;
+pub async fn scan<A, F, Fut>(archive: &A, options: &ScanOptions, f: F) -> Result<ScanReport>
+where
+    A: BlockArchiveReader + ?Sized,
+    F: Fn(BlockHash, Block) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut hashes = Vec::new();
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        hashes.push(block_hash);
+    }
+    drop(results);
+    if let Some(range) = options.heights.as_ref() {
+        let heights = block_heights(archive, &hashes, options.concurrency).await?;
+        hashes.retain(|h| heights.get(h).is_some_and(|height| range.contains(height)));
+        hashes.sort_by_key(|h| heights[h]);
+    }
+
+    let mut progress = ScanProgress {
+        blocks_total: hashes.len(),
+        ..ScanProgress::default()
+    };
+    let read_ahead = options.read_ahead.max(1);
+    let concurrency = options.concurrency.max(1);
+    let f = &f;
+    let loads = stream::iter(hashes)
+        .map(|block_hash| async move { (block_hash, archive.get_block_full(&block_hash).await) });
+    let loads = match options.ordered {
+        true => loads.buffered(read_ahead).left_stream(),
+        false => loads.buffer_unordered(read_ahead).right_stream(),
+    };
+    // None for a block which has been removed since it was listed
+    let runs = loads.map(|(block_hash, block)| async move {
+        let result = match block {
+            Ok(block) => {
+                let size = block.raw.len() as u64;
+                Some(f(block_hash, block).await.map(|_| size))
+            }
+            Err(Error::BlockNotFound(_)) => None,
+            Err(e) => Some(Err(e)),
+        };
+        (block_hash, result)
+    });
+    let mut runs = match options.ordered {
+        true => runs.buffered(concurrency).left_stream(),
+        false => runs.buffer_unordered(concurrency).right_stream(),
+    };
+
+    let mut report = ScanReport::default();
+    while let Some((block_hash, result)) = runs.next().await {
+        match result {
+            Some(Ok(size)) => {
+                report.blocks_scanned += 1;
+                report.bytes_scanned += size;
+                progress.bytes_scanned += size;
+            }
+            None => {}
+            Some(Err(e)) => {
+                report.errors.push((block_hash, e));
+                progress.errors += 1;
+            }
+        }
+        progress.blocks_done += 1;
+        if let Some(callback) = options.progress.as_ref() {
+            callback(&progress);
+        }
+    }
+    Ok(report)
+}
+
+// Find the heights of blocks by following the chain of parents back to the genesis block, or to
+// a block with a height recorded in its metadata. Blocks whose height cannot be found are left
+// out.
+async fn block_heights<A>(
+    archive: &A,
+    hashes: &[BlockHash],
+    concurrency: usize,
+) -> Result<HashMap<BlockHash, u64>>
+where
+    A: BlockArchiveReader + ?Sized,
+{
+    let mut parents = HashMap::with_capacity(hashes.len());
+    let mut headers = stream::iter(hashes.iter())
+        .map(|block_hash| async move { (*block_hash, archive.block_header(block_hash).await) })
+        .buffer_unordered(concurrency.max(1));
+    while let Some((block_hash, header)) = headers.next().await {
+        match header {
+            Ok(header) => {
+                parents.insert(block_hash, header.prev_hash());
+            }
+            Err(Error::BlockNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    drop(headers);
+
+    // None records a block whose height cannot be found
+    let mut heights: HashMap<BlockHash, Option<u64>> = HashMap::with_capacity(parents.len());
+    for start in hashes.iter().filter(|h| parents.contains_key(h)) {
+        let mut chain = Vec::new();
+        let mut hash = *start;
+        let mut height = loop {
+            if let Some(height) = heights.get(&hash) {
+                break *height;
+            }
+            let parent = parents[&hash];
+            if parent == BlockHash::ZERO {
+                heights.insert(hash, Some(0));
+                break Some(0);
+            }
+            if !parents.contains_key(&parent) {
+                let height = match archive.block_metadata(&hash).await {
+                    Ok(metadata) => metadata.height,
+                    Err(Error::BlockNotFound(_)) => None,
+                    Err(e) => return Err(e),
+                };
+                heights.insert(hash, height);
+                break height;
+            }
+            chain.push(hash);
+            hash = parent;
+        };
+        for hash in chain.into_iter().rev() {
+            height = height.map(|h| h + 1);
+            heights.insert(hash, height);
+        }
+    }
+    Ok(heights
+        .into_iter()
+        .filter_map(|(hash, height)| Some((hash, height?)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::make_chain;
+    use crate::{BlockArchiveWriter, BlockMetadata, SimpleFileBasedBlockArchive};
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    // Every block is scanned, errors are collected and progress is reported
+    #[tokio::test]
+    async fn test_scan() {
+        let archive = SimpleFileBasedBlockArchive::new(String::from("testdata/blockarchive"))
+            .await
+            .unwrap();
+        let seen = Mutex::new(Vec::new());
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let recorded = progress.clone();
+        let options = ScanOptions {
+            concurrency: 2,
+            read_ahead: 1,
+            progress: Some(Arc::new(move |p: &ScanProgress| {
+                recorded.lock().unwrap().push(*p)
+            })),
+            ..ScanOptions::default()
+        };
+        let report = scan(&archive, &options, |hash, block| {
+            let seen = &seen;
+            async move {
+                seen.lock().unwrap().push(hash);
+                // not mistaken for a block removed during the scan
+                match block.raw.len() {
+                    227 => Err(Error::BlockNotFound(hash)),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        assert_eq!(report.blocks_scanned, 2);
+        assert_eq!(report.bytes_scanned, 285 + 215);
+        assert_eq!(report.errors.len(), 1);
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.len(), 3);
+        assert_eq!(
+            progress[2],
+            ScanProgress {
+                blocks_total: 3,
+                blocks_done: 3,
+                bytes_scanned: 285 + 215,
+                errors: 1,
+            }
+        );
+    }
+
+    // An ordered scan of a height range delivers the blocks in height order
+    #[tokio::test]
+    async fn test_scan_heights() {
+        let root = tempdir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let blocks = make_chain(10);
+        for block in blocks.iter().skip(1) {
+            archive.store_block_full(block).await.unwrap();
+        }
+        let first = blocks[1].header().unwrap().hash();
+        let options = ScanOptions {
+            ordered: true,
+            heights: Some(3..8),
+            ..ScanOptions::default()
+        };
+        let seen = Mutex::new(Vec::new());
+        let scan_blocks = || {
+            scan(&archive, &options, |hash, _| {
+                let seen = &seen;
+                async move {
+                    seen.lock().unwrap().push(hash);
+                    Ok(())
+                }
+            })
+        };
+        // without the genesis block the heights are not known
+        assert_eq!(scan_blocks().await.unwrap().blocks_scanned, 0);
+
+        let metadata = BlockMetadata {
+            height: Some(1),
+            ..BlockMetadata::default()
+        };
+        archive.set_block_metadata(&first, &metadata).await.unwrap();
+        let report = scan_blocks().await.unwrap();
+        assert_eq!(report.blocks_scanned, 5);
+        let expected = blocks[3..8]
+            .iter()
+            .map(|b| b.header().unwrap().hash())
+            .collect::<Vec<_>>();
+        assert_eq!(*seen.lock().unwrap(), expected);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::BlockHash;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+/// Configuration for a [Scrubber].
+#[derive(Debug, Clone)]
+pub struct ScrubConfig {
+    /// The maximum rate at which blocks are read, in bytes per second. None means no limit.
+    pub bytes_per_second: Option<u64>,
+    /// How long to wait after checking every block before starting again.
+    pub pass_interval: Duration,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            bytes_per_second: Some(16 * 1024 * 1024),
+            pass_interval: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// The results of a [Scrubber].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// The number of times that every block has been checked.
+    pub passes: u64,
+    /// The number of blocks that matched their checksums.
+    pub blocks_verified: u64,
+    /// The number of bytes in the blocks that matched their checksums.
+    pub bytes_verified: u64,
+    /// The number of blocks that could not be checked because they have no checksum.
+    pub blocks_without_checksum: u64,
+    /// The blocks that did not match their checksums when they were last checked.
+    pub corrupt: BTreeSet<BlockHash>,
+    /// The number of blocks that could not be read, or times the archive could not be listed.
+    pub errors: u64,
+}
+
+/// Checks the blocks in an archive against their recorded checksums in the background, at a
+/// limited rate so that it does not compete with other users of the archive.
+///
+/// Each pass checks every block using [BlockArchiveReader::verify_block] and then waits for
+/// [ScrubConfig::pass_interval] before starting the next. Blocks that do not match are recorded
+/// in [ScrubStats::corrupt] until they are checked and match again. The scrubber stops when it
+/// is dropped.
+///
+/// Example code:
+///     let archive = Arc::new(SimpleFileBasedBlockArchive::new(root_path).await?);
+///     let scrubber = Scrubber::start(archive.clone(), ScrubConfig::default());
+///     println!("{:?}", scrubber.stats().corrupt);
+pub struct Scrubber {
+    stats: Arc<Mutex<ScrubStats>>,
+    // Handle to the background task that checks the blocks.
+    handle: JoinHandle<()>,
+}
+
+impl Scrubber {
+    /// Start checking the blocks in the archive.
+    pub fn start<A>(archive: Arc<A>, config: ScrubConfig) -> Scrubber
+    where
+        A: BlockArchiveReader + ?Sized + 'static,
+    {
+        let stats = Arc::new(Mutex::new(ScrubStats::default()));
+        let handle = tokio::spawn(Self::scrub_bgrnd(archive, config, stats.clone()));
+        Scrubber { stats, handle }
+    }
+
+    /// Get the results so far.
+    pub fn stats(&self) -> ScrubStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // Check the blocks repeatedly, until the task is aborted.
+    async fn scrub_bgrnd<A>(archive: Arc<A>, config: ScrubConfig, stats: Arc<Mutex<ScrubStats>>)
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        loop {
+            match Self::list_blocks(archive.as_ref()).await {
+                Ok(blocks) => {
+                    for block_hash in blocks {
+                        Self::scrub_block(archive.as_ref(), &config, &stats, &block_hash).await;
+                    }
+                    stats.lock().unwrap().passes += 1;
+                }
+                Err(_) => stats.lock().unwrap().errors += 1,
+            }
+            tokio::time::sleep(config.pass_interval).await;
+        }
+    }
+
+    // List the blocks at the start of a pass.
+    async fn list_blocks<A>(archive: &A) -> Result<Vec<BlockHash>>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let mut blocks = Vec::new();
+        let mut results = archive.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            blocks.push(block_hash);
+        }
+        Ok(blocks)
+    }
+
+    // Check a single block and then wait long enough to keep to the rate limit.
+    async fn scrub_block<A>(
+        archive: &A,
+        config: &ScrubConfig,
+        stats: &Mutex<ScrubStats>,
+        block_hash: &BlockHash,
+    ) where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let size = match archive.block_size(block_hash).await {
+            Ok(size) => size as u64,
+            // removed since it was listed
+            Err(Error::BlockNotFound(_)) => return,
+            Err(_) => {
+                stats.lock().unwrap().errors += 1;
+                return;
+            }
+        };
+        let result = archive.verify_block(block_hash).await;
+        {
+            let mut stats = stats.lock().unwrap();
+            match result {
+                Ok(true) => {
+                    stats.blocks_verified += 1;
+                    stats.bytes_verified += size;
+                    stats.corrupt.remove(block_hash);
+                }
+                Ok(false) => stats.blocks_without_checksum += 1,
+                Err(Error::CorruptData { .. }) => {
+                    stats.corrupt.insert(*block_hash);
+                }
+                Err(Error::BlockNotFound(_)) => {}
+                Err(_) => stats.errors += 1,
+            }
+        }
+        if let Some(rate) = config.bytes_per_second {
+            tokio::time::sleep(Duration::from_secs_f64(size as f64 / rate.max(1) as f64)).await;
+        }
+    }
+}
+
+impl Drop for Scrubber {
+    // stop the background task when the scrubber is dropped
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockArchiveWriter, SimpleFileBasedBlockArchive};
+    use bitcoinsv::bitcoin::Block;
+    use bytes::Bytes;
+    use tempfile::tempdir;
+
+    // Wait until the scrubber has finished the given number of passes.
+    async fn wait_for_passes(scrubber: &Scrubber, passes: u64) -> ScrubStats {
+        for _ in 0..500 {
+            let stats = scrubber.stats();
+            if stats.passes >= passes {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("scrubber did not finish {passes} passes");
+    }
+
+    // The scrubber finds a corrupt block and stops reporting it once it is replaced
+    #[tokio::test]
+    async fn test_scrubber() {
+        let root_path = tempdir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root_path.path().to_str().unwrap().into())
+            .await
+            .unwrap();
+        let mut hashes = Vec::new();
+        for i in 0..3u8 {
+            let mut raw = vec![0u8; 81];
+            raw[0] = i;
+            let block = Block::new(Bytes::from(raw)).unwrap();
+            archive.store_block_full(&block).await.unwrap();
+            hashes.push(block.header().unwrap().hash());
+        }
+        let corrupt = hashes[1];
+        let raw = archive.get_block_full(&corrupt).await.unwrap().raw;
+        let block_path = crate::Layout::default()
+            .path(root_path.path(), &corrupt, None)
+            .unwrap();
+        let mut modified = raw.to_vec();
+        modified[0] ^= 0xff;
+        std::fs::write(&block_path, &modified).unwrap();
+
+        let archive: Arc<dyn BlockArchiveReader> = Arc::new(archive);
+        let config = ScrubConfig {
+            bytes_per_second: Some(1_000_000),
+            pass_interval: Duration::from_millis(10),
+        };
+        let scrubber = Scrubber::start(archive.clone(), config);
+        let stats = wait_for_passes(&scrubber, 1).await;
+        assert_eq!(stats.corrupt, BTreeSet::from([corrupt]));
+        assert!(stats.blocks_verified >= 2);
+        assert_eq!(stats.bytes_verified, 81 * stats.blocks_verified);
+        assert_eq!(stats.errors, 0);
+
+        std::fs::write(&block_path, &raw).unwrap();
+        let passes = scrubber.stats().passes;
+        let stats = wait_for_passes(&scrubber, passes + 2).await;
+        assert!(stats.corrupt.is_empty());
+    }
+}
@@ -1,14 +1,32 @@
 use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromChannel};
-use crate::{BlockArchive, Error, Result};
+use crate::checksum::{
+    checksum_mismatch, digest_reader, parse_checksum, verify_reader, HashingReader, VerifyingReader,
+};
+use crate::events::{BlockEvent, BlockEventPublisher, BlockEventStream};
+use crate::filters::BlockFilter;
+use crate::headers::may_belong_to;
+use crate::layout::{Layout, Manifest};
+use crate::metadata::BlockMetadata;
+use crate::stats::{scan_archive_stats, ArchiveStats};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::{Uring, UringReader};
+use crate::validate::has_header_and_tx_count;
+use crate::{BlockArchiveReader, BlockArchiveWriter, Error, Result};
 use async_trait::async_trait;
-use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader, Encodable};
+use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader, BlockchainId, Encodable};
 use bytes::Bytes;
-use hex::{FromHex, ToHex};
+use hex::ToHex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+#[cfg(feature = "watch")]
+use std::collections::HashSet;
 use std::io::SeekFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 use tokio_stream::wrappers::ReadDirStream;
 use tokio_stream::StreamExt;
 
@@ -16,18 +34,33 @@ use tokio_stream::StreamExt;
 // this is used to limit the size of the channel used to send block hashes
 // at the time of writing, testnet had about 1.2 million blocks
 // if this is too small, the background process will wait for the channel to be read
-const MAX_BLOCKS: usize = 2_000_000;
+pub(crate) const MAX_BLOCKS: usize = 2_000_000;
+
+// Used to give temporary files unique names.
+static NEXT_TMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+// The extensions of the files that are kept next to a block file: its metadata and its filter.
+const SIDE_FILE_EXTENSIONS: [&str; 2] = ["meta", "filter"];
+
+// The number of locks shared by the blocks, see SimpleFileBasedBlockArchive::lock_block().
+const BLOCK_LOCKS: usize = 64;
 
 /// A simple file-based block archive.
 ///
-/// Blocks are stored in a directory structure based on the block hash. The first level of directories
-/// is based on the last two characters of the hex encoded hash, the second level is based on the
-/// third and fourth last characters, and the block is stored in a file named after the hash with a
-/// "bin" extension.
+/// Blocks are stored in a directory structure based on the block hash. By default the first level
+/// of directories is based on the last two characters of the hex encoded hash, the second level is
+/// based on the third and fourth last characters, and the block is stored in a file named after
+/// the hash with a "bin" extension.
 ///
 ///
 /// Example: /31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin
 ///
+/// Other layouts can be used, see [Layout]. The layout is recorded in a manifest file at the root
+/// of the archive and is detected when the archive is opened. An archive can be converted to a
+/// different layout with [SimpleFileBasedBlockArchive::migrate_layout]. The manifest can also
+/// record the network that the archive belongs to, see
+/// [SimpleFileBasedBlockArchive::new_with_network].
+///
 /// This is simplistic to get started. It is not efficient for large numbers of small blocks.
 ///
 /// Example code:
@@ -36,10 +69,75 @@ const MAX_BLOCKS: usize = 2_000_000;
 ///
 /// Note that if block files are stored in the wrong location then they are not recognised by the
 /// archive.
+///
+/// Several processes can safely store blocks in the same archive. A block is written to a locked
+/// "partial" file next to its final location and is then linked into place, so a block file is
+/// never seen partially written and only one writer can store a given block. The other writers
+/// wait for it to finish and receive [Error::BlockExists], or store the block themselves if it
+/// failed.
+///
+/// The checksum of each block is recorded in its metadata when it is stored, see
+/// [BlockArchiveReader::verify_block] and [SimpleFileBasedBlockArchive::with_verify_on_read].
+///
+/// Changes made through the archive are published to subscribers, see
+/// [BlockArchiveReader::subscribe]. With the `watch` feature enabled,
+/// [SimpleFileBasedBlockArchive::watch] also publishes changes made by other processes.
 #[derive(Debug)]
 pub struct SimpleFileBasedBlockArchive {
     /// The root of the file store
     pub root_path: PathBuf,
+    // The directory layout.
+    layout: Layout,
+    // The genesis block of the network the archive belongs to, if one is recorded.
+    genesis: Option<BlockHash>,
+    // The height of each block, only used by layouts that use heights.
+    heights: Arc<RwLock<HashMap<BlockHash, u64>>>,
+    // Publishes changes to subscribers.
+    events: BlockEventPublisher,
+    // The statistics of the archive, once they have been calculated.
+    stats: Arc<Mutex<StatsState>>,
+    // Whether blocks are checked against their checksums when they are read.
+    verify_on_read: bool,
+    // Whether changes to the archive are refused.
+    read_only: bool,
+    // The source recorded in the metadata of the blocks stored by this instance.
+    source: Option<String>,
+    // Locks which keep the side files of a block from being written while it is deleted.
+    block_locks: Box<[tokio::sync::Mutex<()>]>,
+    // The mapped files of recently read blocks, if memory mapped reads are enabled.
+    #[cfg(feature = "mmap")]
+    mappings: Option<Arc<Mutex<lru::LruCache<BlockHash, Bytes>>>>,
+    // The io_uring instance used for reads, if io_uring reads are enabled.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<Arc<Uring>>,
+    // The file system watcher, if watching has been started.
+    #[cfg(feature = "watch")]
+    watcher: Option<notify::RecommendedWatcher>,
+    // Changes made by this instance which the watcher should not report a second time.
+    #[cfg(feature = "watch")]
+    local_changes: Arc<Mutex<HashSet<(BlockHash, LocalChange)>>>,
+}
+
+// The kind of change made to a block file by this instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum LocalChange {
+    Stored,
+    Deleted,
+}
+
+// The cached statistics of an archive. The generation changes whenever the statistics are
+// invalidated, so that a scan which overlaps a change is not cached.
+#[derive(Debug, Default)]
+struct StatsState {
+    stats: Option<ArchiveStats>,
+    generation: u64,
+}
+
+impl StatsState {
+    fn invalidate(&mut self) {
+        self.stats = None;
+        self.generation += 1;
+    }
 }
 
 impl SimpleFileBasedBlockArchive {
@@ -47,492 +145,2855 @@ impl SimpleFileBasedBlockArchive {
     pub async fn new(root_path: String) -> Result<SimpleFileBasedBlockArchive> {
         let root_path = PathBuf::from(root_path);
         // Check if the root_path is accessible
-        match tokio::fs::metadata(&root_path).await {
-            Ok(_) => Ok(SimpleFileBasedBlockArchive { root_path }),
-            Err(e) => {
-                Err(e.into()) // Convert the error into your custom error type
-            }
+        if let Err(e) = tokio::fs::metadata(&root_path).await {
+            return Err(Error::io(&root_path, e));
         }
+        let (layout, genesis) = match Manifest::read(&root_path).await? {
+            Some(manifest) => (manifest.layout, manifest.genesis),
+            // archives created before the manifest was introduced use the default layout
+            None => (Layout::default(), None),
+        };
+        let archive = SimpleFileBasedBlockArchive {
+            root_path,
+            layout,
+            genesis,
+            heights: Arc::new(RwLock::new(HashMap::new())),
+            events: BlockEventPublisher::default(),
+            stats: Arc::new(Mutex::new(StatsState::default())),
+            verify_on_read: false,
+            read_only: false,
+            source: None,
+            block_locks: (0..BLOCK_LOCKS)
+                .map(|_| tokio::sync::Mutex::new(()))
+                .collect(),
+            #[cfg(feature = "mmap")]
+            mappings: None,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: None,
+            #[cfg(feature = "watch")]
+            watcher: None,
+            #[cfg(feature = "watch")]
+            local_changes: Arc::new(Mutex::new(HashSet::new())),
+        };
+        archive.load_heights().await?;
+        Ok(archive)
     }
 
-    // Get the path for a block.
-    fn get_path_from_hash(&self, hash: &BlockHash) -> PathBuf {
-        let mut path = self.root_path.clone();
-        let s: String = hash.encode_hex();
-        path.push(&s[62..]);
-        path.push(&s[60..62]);
-        path.push(s);
-        path.set_extension("bin");
-        path
+    /// Open an archive with the given layout, creating the manifest if the archive is new.
+    ///
+    /// Returns [Error::InvalidManifest] if the archive already uses a different layout. An
+    /// existing archive without a manifest uses the default layout.
+    pub async fn new_with_layout(
+        root_path: String,
+        layout: Layout,
+    ) -> Result<SimpleFileBasedBlockArchive> {
+        let manifest_path = PathBuf::from(&root_path).join(crate::MANIFEST_FILE);
+        layout.validate().map_err(|reason| Error::InvalidManifest {
+            path: manifest_path.clone(),
+            reason,
+        })?;
+        let mut archive = Self::new(root_path).await?;
+        if archive.layout == layout {
+            if Manifest::read(&archive.root_path).await?.is_none() {
+                archive.manifest(layout).write(&archive.root_path).await?;
+            }
+            return Ok(archive);
+        }
+        let is_empty = tokio::fs::read_dir(&archive.root_path)
+            .await
+            .map_err(|e| Error::io(&archive.root_path, e))?
+            .next_entry()
+            .await
+            .map_err(|e| Error::io(&archive.root_path, e))?
+            .is_none();
+        if !is_empty {
+            return Err(Error::InvalidManifest {
+                path: manifest_path,
+                reason: format!(
+                    "the archive uses the {} layout, not {layout}",
+                    archive.layout
+                ),
+            });
+        }
+        archive.manifest(layout).write(&archive.root_path).await?;
+        archive.layout = layout;
+        Ok(archive)
     }
 
-    // Get a list of all blocks in the background, sending results to the channel.
-    // Do not return blocks that are stored in the wrong location because these
-    // won't be retrievable by get_block().
-    async fn block_list_bgrnd(
-        root_path: PathBuf,
-        transmit: tokio::sync::mpsc::Sender<BlockHash>,
-    ) -> Result<()> {
-        let mut stack = Vec::new();
-        stack.push(root_path.clone());
-        while let Some(path) = stack.pop() {
-            let dir = tokio::fs::read_dir(path).await?;
-            let mut stream = ReadDirStream::new(dir);
-            // it would be fun to spawn a new task for each directory, but that would be a bit daft
-            while let Some(entry) = stream.next().await {
-                let entry = entry?;
-                let path = entry.path();
-                if path.is_dir() {
-                    stack.push(path);
-                } else {
-                    // ignore files which are not .bin files
-                    if path.extension().is_none() || path.extension().unwrap() != "bin" {
-                        continue;
-                    }
-                    let f_name = path.file_stem().unwrap().to_str().unwrap();
-                    match BlockHash::from_hex(f_name) {
-                        Ok(h) => {
-                            // ignore files that are not in the correct location
-                            let correct_path = root_path
-                                .join(&f_name[62..])
-                                .join(&f_name[60..62])
-                                .join(f_name)
-                                .with_extension("bin");
-                            if path != correct_path {
-                                continue;
-                            }
-                            match transmit.send(h).await {
-                                Ok(_) => {}
-                                Err(_) => return Ok(()), // this is not an error, the receiver has merely dropped
-                            }
-                        }
-                        // ignore files which are not valid block hashes
-                        Err(_) => continue,
-                    };
-                }
+    /// Open an archive for the blockchain with the given id, recording its genesis block in the
+    /// manifest if the archive does not yet belong to a network.
+    ///
+    /// Blocks which cannot belong to the network are then rejected with [Error::WrongNetwork]
+    /// when they are stored: a block without a parent which is not the genesis block, a block
+    /// whose parent is the genesis block of another network, and a block whose difficulty target
+    /// is easier than the network allows. Blocks which are too short to contain a header are
+    /// rejected with [Error::CorruptData]. Other blocks are accepted, their ancestors are not
+    /// checked, so a block of another network is only caught when it is a direct child of a
+    /// genesis block or by its difficulty target. Note that testnet and STN share a genesis
+    /// block, so they are the same network for the archive.
+    ///
+    /// Returns [Error::InvalidManifest] if the archive belongs to a different network.
+    pub async fn new_with_network(
+        root_path: String,
+        blockchain: BlockchainId,
+    ) -> Result<SimpleFileBasedBlockArchive> {
+        let mut archive = Self::new(root_path).await?;
+        let genesis = BlockHeader::get_genesis(blockchain).hash();
+        match archive.genesis {
+            Some(recorded) if recorded == genesis => {}
+            Some(recorded) => {
+                return Err(Error::InvalidManifest {
+                    path: archive.root_path.join(crate::MANIFEST_FILE),
+                    reason: format!(
+                        "the archive belongs to the network with genesis block {recorded}, not {blockchain}"
+                    ),
+                })
+            }
+            None => {
+                archive.genesis = Some(genesis);
+                let manifest = match Manifest::read(&archive.root_path).await? {
+                    Some(manifest) => Manifest {
+                        genesis: Some(genesis),
+                        ..manifest
+                    },
+                    None => archive.manifest(archive.layout),
+                };
+                manifest.write(&archive.root_path).await?;
             }
         }
-        Ok(())
+        Ok(archive)
     }
-}
 
-#[async_trait]
-impl BlockArchive for SimpleFileBasedBlockArchive {
-    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
-        let path = self.get_path_from_hash(block_hash);
-        match File::open(path).await {
-            Ok(f) => Ok(Box::new(f)),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into()),
-            },
+    /// Open the archive for a blockchain in a root which holds archives for several networks.
+    ///
+    /// Each network has its own archive in a directory under the root named after the
+    /// blockchain id: `main`, `test`, `stn` or `regtest`. The directory is created if it does not
+    /// exist and the archive is opened with [SimpleFileBasedBlockArchive::new_with_network].
+    ///
+    /// Example code:
+    ///     let mainnet = SimpleFileBasedBlockArchive::open_network(root.clone(), BlockchainId::Main).await?;
+    ///     let testnet = SimpleFileBasedBlockArchive::open_network(root, BlockchainId::Test).await?;
+    pub async fn open_network(
+        root_path: String,
+        blockchain: BlockchainId,
+    ) -> Result<SimpleFileBasedBlockArchive> {
+        let path = PathBuf::from(root_path).join(blockchain.to_string());
+        tokio::fs::create_dir_all(&path)
+            .await
+            .map_err(|e| Error::io(&path, e))?;
+        Self::new_with_network(path.to_string_lossy().into_owned(), blockchain).await
+    }
+
+    /// The hash of the genesis block of the network the archive belongs to, if it is recorded in
+    /// the manifest.
+    pub fn genesis(&self) -> Option<BlockHash> {
+        self.genesis
+    }
+
+    // The manifest of the archive with the given layout.
+    fn manifest(&self, layout: Layout) -> Manifest {
+        Manifest {
+            genesis: self.genesis,
+            ..Manifest::new(layout)
         }
     }
 
-    /// Load a full block into memory
-    async fn get_block_full(&self, block_hash: &BlockHash) -> Result<Block> {
-        let path = self.get_path_from_hash(block_hash);
-        match tokio::fs::read(path).await {
-            Ok(raw) => Block::new(Bytes::from(raw)).map_err(Error::from),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into()),
-            },
+    // Check that a block may belong to the network of the archive.
+    fn check_network(&self, block_hash: &BlockHash, header: Option<&BlockHeader>) -> Result<()> {
+        match (self.genesis, header) {
+            (Some(genesis), Some(header)) if !may_belong_to(&genesis, header) => {
+                Err(Error::WrongNetwork {
+                    hash: *block_hash,
+                    genesis,
+                })
+            }
+            _ => Ok(()),
         }
     }
 
-    /// Check if a block exists in the archive.
-    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
-        let path = self.get_path_from_hash(block_hash);
-        match tokio::fs::metadata(path).await {
-            Ok(_) => Ok(true),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return false
-                std::io::ErrorKind::NotFound => Ok(false),
-                _ => Err(e.into()),
-            },
+    /// The directory layout of the archive.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Convert the archive to a different layout, in place. Returns the number of block files
+    /// that were moved.
+    ///
+    /// Each block file is first linked into its location in the new layout, then the manifest is
+    /// updated, and then the files in the old locations are removed. If the migration is
+    /// interrupted then the archive can still be used with whichever layout the manifest records,
+    /// and the migration can be resumed by calling this function again.
+    ///
+    /// Converting to a layout that uses heights requires the parent of every block to be in the
+    /// archive, apart from blocks that follow the zero hash, otherwise [Error::ParentNotFound] is
+    /// returned before any files are changed.
+    ///
+    /// Other processes should not use the archive during the migration.
+    pub async fn migrate_layout(&mut self, layout: Layout) -> Result<usize> {
+        self.check_writable()?;
+        let manifest_path = self.root_path.join(crate::MANIFEST_FILE);
+        layout.validate().map_err(|reason| Error::InvalidManifest {
+            path: manifest_path,
+            reason,
+        })?;
+        // finish an earlier migration first
+        let mut moved = 0;
+        if let Some(Manifest {
+            cleanup: Some(old_layout),
+            ..
+        }) = Manifest::read(&self.root_path).await?
+        {
+            moved += self.remove_old_block_files(old_layout).await?;
+            self.manifest(self.layout).write(&self.root_path).await?;
+        }
+        if layout == self.layout {
+            return Ok(moved);
+        }
+        // link every block file into its new location
+        let blocks = Self::list_block_files(self.root_path.clone(), self.layout).await?;
+        let heights = match layout.uses_height() {
+            true => self.chain_heights(&blocks).await?,
+            false => HashMap::new(),
+        };
+        for (hash, old_height) in blocks.iter() {
+            let old_path = self
+                .layout
+                .path(&self.root_path, hash, *old_height)
+                .unwrap();
+            let new_path = layout
+                .path(&self.root_path, hash, heights.get(hash).copied())
+                .unwrap();
+            if old_path == new_path {
+                continue;
+            }
+            let dir = new_path.parent().unwrap();
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| Error::io(dir, e))?;
+            Self::link_file(&old_path, &new_path).await?;
+            // the metadata and filter files move with the block file
+            for extension in SIDE_FILE_EXTENSIONS {
+                let old_side_path = old_path.with_extension(extension);
+                match Self::link_file(&old_side_path, &new_path.with_extension(extension)).await {
+                    Err(e) if e.path() == Some(&old_side_path) => {}
+                    result => result?,
+                }
+            }
         }
+        // switch to the new layout, then remove the old files
+        let old_layout = self.layout;
+        Manifest {
+            cleanup: Some(old_layout),
+            ..self.manifest(layout)
+        }
+        .write(&self.root_path)
+        .await?;
+        self.layout = layout;
+        *self.heights.write().unwrap() = heights;
+        moved += self.remove_old_block_files(old_layout).await?;
+        self.manifest(layout).write(&self.root_path).await?;
+        Ok(moved)
     }
 
-    async fn store_block(
-        &self,
-        block_hash: &BlockHash,
-        block: &mut Box<dyn AsyncRead + Unpin + Send>,
-    ) -> Result<()> {
-        if self.block_exists(block_hash).await? {
-            return Err(Error::BlockExists);
+    // Link a file into a new location. A file that is already there was linked by an earlier
+    // attempt.
+    async fn link_file(old_path: &Path, new_path: &Path) -> Result<()> {
+        match tokio::fs::hard_link(old_path, new_path).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::io(old_path, e)),
+            Err(e) => Err(Error::io(new_path, e)),
         }
-        let path = self.get_path_from_hash(block_hash);
-        // create the directory structure if it does not exist
-        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
-        // store the block in a file
-        let mut file = File::create(path).await?;
-        tokio::io::copy(block, &mut file).await?;
-        Ok(())
     }
 
-    async fn store_block_full(&self, block: &Block) -> Result<()> {
-        let h = block.header()?.hash();
-        if self.block_exists(&h).await? {
-            return Err(Error::BlockExists);
+    // The path of the metadata file for the block file at the given path.
+    fn metadata_path(path: &Path) -> PathBuf {
+        path.with_extension("meta")
+    }
+
+    // The path of the filter file for the block file at the given path.
+    fn filter_path(path: &Path) -> PathBuf {
+        path.with_extension("filter")
+    }
+
+    // Remove the metadata and filter files for the block file at the given path, if there are
+    // any.
+    async fn remove_side_files(path: &Path) -> Result<()> {
+        for extension in SIDE_FILE_EXTENSIONS {
+            let side_path = path.with_extension(extension);
+            match tokio::fs::remove_file(&side_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::io(&side_path, e))
+                }
+                _ => {}
+            }
         }
-        let path = self.get_path_from_hash(&h);
-        // create the directory structure if it does not exist
-        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
-        // store the block in a file
-        let mut file = File::create(path).await?;
-        let _ = file.write_all(&block.raw[..]).await?;
         Ok(())
     }
 
-    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
-        let path = self.get_path_from_hash(block_hash);
-        match tokio::fs::metadata(path).await {
-            Ok(m) => Ok(m.len() as usize),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into()),
-            },
+    // Write a file next to the block file at the given path. The data is written to a temporary
+    // file which is unique to this write and then renamed, so that readers never see a partially
+    // written file.
+    async fn write_side_file(path: &Path, extension: &str, data: &[u8]) -> Result<()> {
+        let side_path = path.with_extension(extension);
+        let tmp_path = path.with_extension(format!(
+            "{extension}.{}.{}.tmp",
+            std::process::id(),
+            NEXT_TMP_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let write = async {
+            tokio::fs::write(&tmp_path, data).await?;
+            tokio::fs::rename(&tmp_path, &side_path).await
+        };
+        if let Err(e) = write.await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(Error::io(&side_path, e));
         }
+        Ok(())
     }
 
-    async fn block_tx_count(&self, block_hash: &BlockHash) -> Result<i64> {
-        let path = self.get_path_from_hash(block_hash);
-        match File::open(path).await {
-            Ok(mut file) => {
-                file.seek(SeekFrom::Start(BlockHeader::SIZE)).await?;
-                let n0 = file.read_u8().await?;
-                let v = match n0 {
-                    0xff => file.read_u64_le().await? as i64,
-                    0xfe => file.read_u32_le().await? as i64,
-                    0xfd => file.read_u16_le().await? as i64,
-                    _ => n0 as i64,
-                };
-                Ok(v)
-            }
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into()),
-            },
+    // Write the metadata file for the block file at the given path.
+    async fn write_metadata_file(path: &Path, metadata: &BlockMetadata) -> Result<()> {
+        let data = serde_json::to_vec(metadata).map_err(std::io::Error::other)?;
+        Self::write_side_file(path, "meta", &data).await
+    }
+
+    // Record the metadata of a block that has just been stored: when it was first seen, where
+    // it came from and its checksum. The block is already in the archive, so a failure is
+    // ignored rather than reported as a failed store; the block is then stored without
+    // metadata, like the blocks of an older archive, and verify_block finds no checksum.
+    async fn write_stored_metadata(&self, block_hash: &BlockHash, path: &Path, digest: &[u8; 32]) {
+        let metadata = BlockMetadata {
+            first_seen: Some(crate::metadata::now()),
+            source: self.source.clone(),
+            checksum: Some(digest.encode_hex()),
+            ..BlockMetadata::default()
+        };
+        let _lock = self.lock_block(block_hash).await;
+        // the block may have been deleted again
+        if tokio::fs::try_exists(path).await.unwrap_or(false) {
+            let _ = Self::write_metadata_file(path, &metadata).await;
         }
     }
 
-    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
-        let path = self.get_path_from_hash(block_hash);
-        match File::open(path).await {
-            Ok(mut file) => {
-                let mut buf = vec![0; BlockHeader::SIZE as usize];
-                let t = file.read_exact(&mut buf).await?;
-                if t < BlockHeader::SIZE as usize {
-                    Err(Error::NotEnoughData)
-                } else {
-                    Ok(BlockHeader::from_binary(&mut Bytes::from(buf))?)
+    // Lock a block against writes to its side files, so that a file written next to a block is
+    // not left behind when the block is deleted at the same time. Each lock is shared by several
+    // blocks.
+    async fn lock_block(&self, block_hash: &BlockHash) -> tokio::sync::MutexGuard<'_, ()> {
+        let index = block_hash.raw[0] as usize % self.block_locks.len();
+        self.block_locks[index].lock().await
+    }
+
+    // Remove the block files of the old layout that have been linked into the current layout,
+    // and any directories that are left empty. Returns the number of files removed.
+    async fn remove_old_block_files(&self, old_layout: Layout) -> Result<usize> {
+        let blocks = Self::list_block_files(self.root_path.clone(), old_layout).await?;
+        let mut removed = 0;
+        for (hash, old_height) in blocks {
+            let old_path = old_layout.path(&self.root_path, &hash, old_height).unwrap();
+            match self.get_path_from_hash(&hash) {
+                Some(path) if path != old_path && self.block_exists(&hash).await? => {}
+                // the file is in the right place for both layouts, or was not linked
+                _ => continue,
+            }
+            Self::remove_side_files(&old_path).await?;
+            tokio::fs::remove_file(&old_path)
+                .await
+                .map_err(|e| Error::io(&old_path, e))?;
+            removed += 1;
+            // remove empty directories, removing a directory that is not empty fails
+            let mut dir = old_path.parent();
+            while let Some(d) = dir.filter(|d| *d != self.root_path) {
+                if tokio::fs::remove_dir(d).await.is_err() {
+                    break;
                 }
+                dir = d.parent();
             }
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into()),
-            },
         }
+        Ok(removed)
     }
 
-    async fn get_bytes_from_block(
+    // Calculate the height of each block by following the chain of parents. Every parent must be
+    // in the archive, apart from the zero hash.
+    async fn chain_heights(
         &self,
-        block_hash: &BlockHash,
-        offset: u64,
-        length: u64,
-    ) -> Result<Bytes> {
-        let path = self.get_path_from_hash(block_hash);
-        match File::open(path).await {
-            Ok(mut file) => {
-                file.seek(SeekFrom::Start(offset)).await?;
-                let mut buf = vec![0; length as usize];
-                file.read_exact(&mut buf).await?;
-                Ok(Bytes::from_owner(buf))
+        blocks: &[(BlockHash, Option<u64>)],
+    ) -> Result<HashMap<BlockHash, u64>> {
+        let mut parents = HashMap::with_capacity(blocks.len());
+        for (hash, _) in blocks.iter() {
+            parents.insert(*hash, self.block_header(hash).await?.prev_hash());
+        }
+        let mut heights = HashMap::with_capacity(blocks.len());
+        for (start, _) in blocks.iter() {
+            let mut chain = Vec::new();
+            let mut hash = *start;
+            let mut height = loop {
+                if let Some(height) = heights.get(&hash) {
+                    break *height;
+                }
+                let parent = parents[&hash];
+                if parent == BlockHash::ZERO {
+                    heights.insert(hash, 0);
+                    break 0;
+                }
+                if !parents.contains_key(&parent) {
+                    return Err(Error::ParentNotFound { hash, parent });
+                }
+                chain.push(hash);
+                hash = parent;
+            };
+            for hash in chain.into_iter().rev() {
+                height += 1;
+                heights.insert(hash, height);
             }
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into()),
-            },
         }
+        Ok(heights)
     }
 
-    /// Get a list of all the blocks in the archive.
+    /// Set the number of events that are buffered for each subscriber.
     ///
-    /// It returns a stream of block hashes.
+    /// The default is [DEFAULT_EVENT_BUFFER](crate::DEFAULT_EVENT_BUFFER). This should be set
+    /// before calling [BlockArchiveReader::subscribe], existing subscriptions are closed. A
+    /// watcher that has already been started publishes to the new subscriptions.
+    pub fn with_event_buffer(self, buffer_size: usize) -> SimpleFileBasedBlockArchive {
+        self.events.set_buffer_size(buffer_size);
+        self
+    }
+
+    /// Check blocks against their recorded checksums when they are read.
     ///
-    /// Example code:
-    ///     let mut results = archive.block_list().await.unwrap();
-    ///     while let Some(block_hash) = results.next().await {
-    ///       println!("{}", block_hash);
-    ///     }
+    /// The reader returned by [BlockArchiveReader::get_block] is a [VerifyingReader], which fails
+    /// at the end of the block if the bytes do not match, and
+    /// [BlockArchiveReader::get_block_full] returns [Error::CorruptData]. Reads of ranges of a
+    /// block are not checked. Blocks without a checksum, such as blocks stored before checksums
+    /// were recorded, are not checked.
+    pub fn with_verify_on_read(mut self, verify: bool) -> SimpleFileBasedBlockArchive {
+        self.verify_on_read = verify;
+        self
+    }
+
+    /// Record the given source, for example the name of an importer, in the metadata of the
+    /// blocks that are stored through this instance. The time the block was stored is always
+    /// recorded as the time it was first seen.
+    pub fn with_source(mut self, source: impl Into<String>) -> SimpleFileBasedBlockArchive {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Refuse changes to the archive, for example for an exported snapshot or an archive on
+    /// read-only media.
     ///
-    /// This function does not return blocks that are stored in the wrong location because these
-    /// won't be retrievable by get_block().
-    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>> {
-        // make the channel large enough to buffer all hashes, including testnet
-        // so that the background task can collect all buffer hashes despite how slow the consumer is
-        let (tx, rx) = tokio::sync::mpsc::channel(MAX_BLOCKS);
-        let handle = tokio::spawn(Self::block_list_bgrnd(self.root_path.clone(), tx));
-        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    /// Storing, deleting and migrating blocks, recording metadata and filters, and starting
+    /// uploads return [Error::ReadOnly].
+    pub fn with_read_only(mut self, read_only: bool) -> SimpleFileBasedBlockArchive {
+        self.read_only = read_only;
+        self
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use hex::FromHex;
-    use std::io::Cursor;
-    use tempfile::tempdir;
-    use tokio::io::AsyncReadExt;
+    // Returns an error if the archive refuses changes.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
 
-    fn get_testdata_path() -> String {
-        String::from("testdata/blockarchive")
+    /// Read blocks through memory mapped files, keeping up to `max_mappings` files mapped.
+    ///
+    /// [BlockArchiveReader::get_block_full], [BlockArchiveReader::block_header],
+    /// [BlockArchiveReader::get_bytes_from_block] and [BlockArchiveReader::get_ranges_from_block]
+    /// return data that refers to the mapped file instead of a copy. The least recently used
+    /// mapping is closed when the limit is reached, but a mapping stays open while any data
+    /// returned from it is in use.
+    ///
+    /// Block files are never changed after they have been stored. Another process that truncates
+    /// or rewrites a block file while it is mapped can cause reads of the mapped data to crash the
+    /// process.
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap(mut self, max_mappings: usize) -> SimpleFileBasedBlockArchive {
+        let capacity =
+            std::num::NonZeroUsize::new(max_mappings).unwrap_or(std::num::NonZeroUsize::MIN);
+        self.mappings = Some(Arc::new(Mutex::new(lru::LruCache::new(capacity))));
+        self
     }
 
-    // Test the path generation from a block hash.
-    #[tokio::test]
-    async fn check_path_from_hash() {
-        let path = get_testdata_path();
-        let s = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let h =
-            BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531")
-                .unwrap();
-        let path = s.get_path_from_hash(&h);
-        assert_eq!(path, PathBuf::from("testdata/blockarchive/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin"));
+    /// Read blocks through io_uring, with up to `queue_depth` operations in progress at a time.
+    ///
+    /// [BlockArchiveReader::get_block], [BlockArchiveReader::get_block_range],
+    /// [BlockArchiveReader::get_bytes_from_block], [BlockArchiveReader::get_ranges_from_block],
+    /// [BlockArchiveReader::block_header] and [BlockArchiveReader::block_tx_count] open and read
+    /// block files with io_uring instead of on Tokio's blocking thread pool. The reads of all the
+    /// ranges requested in one call, and of concurrent calls, are submitted in batches. Memory
+    /// mapped reads are used instead where both are enabled.
+    ///
+    /// Returns an error if io_uring is not available, for example because the kernel is too old.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn with_io_uring(mut self, queue_depth: u32) -> Result<SimpleFileBasedBlockArchive> {
+        self.uring = Some(Arc::new(Uring::new(queue_depth)?));
+        Ok(self)
     }
 
-    // Test the block list function.
-    // two of the potentially ok block files are stored in the wrong location, so they shouldnt be returned
-    #[tokio::test]
-    async fn test_block_list() {
-        let path = get_testdata_path();
-        let mut archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let mut results = archive.block_list().await.unwrap();
-        let mut count = 0;
-        while (results.next().await).is_some() {
-            count += 1;
+    /// Watch the file system for blocks that are stored or deleted by other processes and publish
+    /// them to subscribers.
+    ///
+    /// Changes made through this instance are published immediately and are not reported again
+    /// by the watcher. Changes made by other processes are published when the file system
+    /// notification arrives. If the operating system drops notifications then subscribers receive
+    /// a [BlockEvent::Lagged] event.
+    #[cfg(feature = "watch")]
+    pub fn watch(&mut self) -> Result<()> {
+        use notify::event::{CreateKind, ModifyKind, RenameMode};
+        use notify::{EventKind, RecursiveMode, Watcher};
+
+        // notifications use absolute paths
+        let watch_path = std::fs::canonicalize(&self.root_path)?;
+        let root_path = watch_path.clone();
+        let layout = self.layout;
+        let heights = self.heights.clone();
+        let stats = self.stats.clone();
+        let events = self.events.clone();
+        let local_changes = self.local_changes.clone();
+        // blocks found by scanning a new directory, whose own notification may still arrive
+        let mut scanned = HashSet::new();
+        let handler = move |result: notify::Result<notify::Event>| {
+            let event = match result {
+                Ok(event) if !event.need_rescan() => event,
+                // we don't know what has been missed
+                _ => {
+                    stats.lock().unwrap().invalidate();
+                    events.publish(BlockEvent::Lagged { missed: 0 });
+                    return;
+                }
+            };
+            let (stored, paths) = match event.kind {
+                // block files are created complete, see write_block_file()
+                EventKind::Create(CreateKind::File | CreateKind::Any)
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => (true, event.paths),
+                // files can be created in a new directory before it is watched, so they are
+                // only seen by looking in the directory
+                EventKind::Create(CreateKind::Folder) => {
+                    let mut files = Vec::new();
+                    for dir in event.paths.iter() {
+                        list_files(dir, &mut files);
+                    }
+                    (true, files)
+                }
+                EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                    (false, event.paths)
+                }
+                _ => return,
+            };
+            let from_scan = matches!(event.kind, EventKind::Create(CreateKind::Folder));
+            for path in paths.iter() {
+                let (hash, height) = match layout.parse(&root_path, path) {
+                    Some(parsed) => parsed,
+                    None => continue,
+                };
+                // report each block once when it is seen both by a scan and by its notification
+                let seen = match (stored, from_scan) {
+                    (true, true) => !scanned.insert(hash),
+                    (true, false) => scanned.remove(&hash),
+                    (false, _) => {
+                        scanned.remove(&hash);
+                        false
+                    }
+                };
+                if seen {
+                    continue;
+                }
+                if let Some(height) = height {
+                    let mut heights = heights.write().unwrap();
+                    match stored {
+                        true => heights.insert(hash, height),
+                        false => heights.remove(&hash),
+                    };
+                }
+                let kind = match stored {
+                    true => LocalChange::Stored,
+                    false => LocalChange::Deleted,
+                };
+                if local_changes.lock().unwrap().remove(&(hash, kind)) {
+                    continue;
+                }
+                stats.lock().unwrap().invalidate();
+                if stored {
+                    // the file may already have been removed again
+                    if let Ok(m) = std::fs::metadata(path) {
+                        events.publish(BlockEvent::Stored {
+                            hash,
+                            size: m.len() as usize,
+                        });
+                    }
+                } else {
+                    events.publish(BlockEvent::Deleted { hash });
+                }
+            }
+        };
+        let mut watcher = notify::recommended_watcher(handler).map_err(std::io::Error::other)?;
+        watcher
+            .watch(&watch_path, RecursiveMode::Recursive)
+            .map_err(std::io::Error::other)?;
+        self.watcher = Some(watcher);
+        Ok(())
+    }
+
+    // Record that this instance is about to change a block file, so that the watcher does not
+    // report the change a second time. Must be called before the file is changed.
+    fn start_local_change(&self, _hash: &BlockHash, _kind: LocalChange) {
+        #[cfg(feature = "watch")]
+        if self.watcher.is_some() {
+            self.local_changes.lock().unwrap().insert((*_hash, _kind));
         }
-        assert_eq!(count, 3);
     }
 
-    // Test the block list function with no blocks.
-    #[tokio::test]
-    async fn test_empty_block_list() {
-        // calling a blocking function from tokio is bad, but this is a test
-        let root = tempdir().unwrap();
-        let path = String::from(root.path().to_str().unwrap());
-        let mut archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let mut results = archive.block_list().await.unwrap();
-        let mut count = 0;
-        while (results.next().await).is_some() {
-            count += 1;
+    // Forget a change that was started with start_local_change() but which failed.
+    fn abandon_local_change(&self, _hash: &BlockHash, _kind: LocalChange) {
+        #[cfg(feature = "watch")]
+        self.local_changes.lock().unwrap().remove(&(*_hash, _kind));
+    }
+
+    // Add a stored block to the statistics, if they have been calculated.
+    async fn stats_add_block(&self, hash: &BlockHash, size: u64) {
+        if self.stats.lock().unwrap().stats.is_none() {
+            return;
+        }
+        let header = self.block_header(hash).await;
+        let tx_count = self.block_tx_count(hash).await;
+        let mut state = self.stats.lock().unwrap();
+        match (header, tx_count, state.stats.as_mut()) {
+            (Ok(header), Ok(tx_count), Some(stats)) => {
+                stats.add_block(hash, size, tx_count as u64, header.timestamp())
+            }
+            _ => state.invalidate(),
+        }
+    }
+
+    // Get the details of a block that are needed to remove it from the statistics, if they have
+    // been calculated.
+    async fn stats_block_details(&self, hash: &BlockHash) -> Option<Result<(u64, u64, u32)>> {
+        self.stats.lock().unwrap().stats.as_ref()?;
+        let details = async {
+            let size = self.block_size(hash).await?;
+            let tx_count = self.block_tx_count(hash).await?;
+            let header = self.block_header(hash).await?;
+            Ok((size as u64, tx_count as u64, header.timestamp()))
+        };
+        Some(details.await)
+    }
+
+    // Get the path for a block. Returns None if the layout uses heights and the block is not in
+    // the archive.
+    fn get_path_from_hash(&self, hash: &BlockHash) -> Option<PathBuf> {
+        let height = match self.layout.uses_height() {
+            true => Some(*self.heights.read().unwrap().get(hash)?),
+            false => None,
+        };
+        self.layout.path(&self.root_path, hash, height)
+    }
+
+    // Get the path for a block that is in the archive.
+    fn block_path(&self, hash: &BlockHash) -> Result<PathBuf> {
+        self.get_path_from_hash(hash)
+            .ok_or(Error::BlockNotFound(*hash))
+    }
+
+    // Get the path for a new block, and its height if the layout uses heights. The height is one
+    // more than the height of the parent, which must be in the archive.
+    fn new_block_path(
+        &self,
+        hash: &BlockHash,
+        header: Option<&BlockHeader>,
+    ) -> Result<(PathBuf, Option<u64>)> {
+        let height = match (self.layout.uses_height(), header) {
+            (false, _) => None,
+            (true, None) => {
+                return Err(Error::CorruptData {
+                    hash: *hash,
+                    reason: String::from("block is too short to contain a header"),
+                })
+            }
+            (true, Some(header)) => {
+                let parent = header.prev_hash();
+                if parent == BlockHash::ZERO {
+                    Some(0)
+                } else {
+                    match self.heights.read().unwrap().get(&parent) {
+                        Some(height) => Some(height + 1),
+                        None => {
+                            return Err(Error::ParentNotFound {
+                                hash: *hash,
+                                parent,
+                            })
+                        }
+                    }
+                }
+            }
+        };
+        // the layout has a path for every block once the height is known
+        let path = self.layout.path(&self.root_path, hash, height).unwrap();
+        Ok((path, height))
+    }
+
+    // Load the heights of the blocks in the archive, if the layout uses heights.
+    async fn load_heights(&self) -> Result<()> {
+        if !self.layout.uses_height() {
+            return Ok(());
+        }
+        let blocks = Self::list_block_files(self.root_path.clone(), self.layout).await?;
+        *self.heights.write().unwrap() = blocks
+            .into_iter()
+            .map(|(hash, height)| (hash, height.unwrap()))
+            .collect();
+        Ok(())
+    }
+
+    // Write a block file, returning the number of bytes written.
+    //
+    // The block is written to a partial file, which is locked while it is being written, and is
+    // then linked into place. Linking fails if the block file already exists, so only one writer
+    // can store a block, even if the writers are in different processes, and readers never see a
+    // partially written block. A writer that finds the partial file locked waits for the other
+    // writer to finish, and then either finds the block file or writes the block itself.
+    //
+    // Returns the size and the sha256 digest of the block.
+    //
+    // If `replace` is true then an existing block file is replaced by renaming the partial file
+    // over it, so the block file is never missing.
+    async fn write_block_file<R>(
+        block_hash: &BlockHash,
+        path: &Path,
+        block: &mut R,
+        replace: bool,
+    ) -> Result<(u64, [u8; 32])>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let partial_path = path.with_extension("partial");
+        let mut file = Self::lock_partial_file(&partial_path).await?;
+        let result = Self::write_locked_block_file(
+            block_hash,
+            path,
+            &partial_path,
+            &mut file,
+            block,
+            replace,
+        )
+        .await;
+        // remove the partial file while we still hold the lock, a replacement has already moved it
+        if result.is_err() || !replace {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+        }
+        result
+    }
+
+    // Open and lock the partial file of a block, waiting for any other writer to release it.
+    async fn lock_partial_file(partial_path: &Path) -> Result<File> {
+        let partial_error = |e| Error::io(partial_path, e);
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(partial_path)
+                .await
+                .map_err(partial_error)?
+                .into_std()
+                .await;
+            let locked_path = partial_path.to_path_buf();
+            let (file, same) = tokio::task::spawn_blocking(move || {
+                file.lock()?;
+                // the writer that held the lock removes the partial file before releasing it, in
+                // which case the lock is on a file that is no longer at the path
+                let same = is_same_file(&file, &locked_path)?;
+                Ok::<_, std::io::Error>((file, same))
+            })
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(partial_error)?;
+            if same {
+                return Ok(File::from_std(file));
+            }
+        }
+    }
+
+    // Write a block file using a partial file which is locked by the caller.
+    async fn write_locked_block_file<R>(
+        block_hash: &BlockHash,
+        path: &Path,
+        partial_path: &Path,
+        file: &mut File,
+        block: &mut R,
+        replace: bool,
+    ) -> Result<(u64, [u8; 32])>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        // another writer may have finished storing the block before we got the lock
+        if !replace
+            && tokio::fs::try_exists(path)
+                .await
+                .map_err(|e| Error::io(path, e))?
+        {
+            return Err(Error::BlockExists(*block_hash));
+        }
+        let partial_error = |e| Error::io(partial_path, e);
+        // the partial file may have been left behind by a writer that failed
+        file.set_len(0).await.map_err(partial_error)?;
+        let mut block = HashingReader::new(block);
+        let size = tokio::io::copy(&mut block, file)
+            .await
+            .map_err(partial_error)?;
+        file.sync_all().await.map_err(partial_error)?;
+        if replace {
+            // the partial file is ours while we hold its lock
+            return match tokio::fs::rename(partial_path, path).await {
+                Ok(_) => Ok((size, block.finish())),
+                Err(e) => Err(Error::io(path, e)),
+            };
+        }
+        match link_locked_file(file, partial_path, path).await {
+            Ok(_) => Ok((size, block.finish())),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::AlreadyExists => Err(Error::BlockExists(*block_hash)),
+                _ => Err(Error::io(path, e)),
+            },
+        }
+    }
+
+    // Link the data file of a completed upload into place as a block file. The data file is left
+    // in place for the caller to remove.
+    pub(crate) async fn store_uploaded_block(
+        &self,
+        block_hash: &BlockHash,
+        header: &BlockHeader,
+        data_path: &Path,
+    ) -> Result<()> {
+        if self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists(*block_hash));
+        }
+        self.check_network(block_hash, Some(header))?;
+        let (path, height) = self.new_block_path(block_hash, Some(header))?;
+        let dir = path.parent().unwrap();
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| Error::io(dir, e))?;
+        let mut file = File::open(data_path)
+            .await
+            .map_err(|e| Error::io(data_path, e))?;
+        let (digest, size) = digest_reader(&mut file)
+            .await
+            .map_err(|e| Error::io(data_path, e))?;
+        self.start_local_change(block_hash, LocalChange::Stored);
+        if let Err(e) = tokio::fs::hard_link(data_path, &path).await {
+            self.abandon_local_change(block_hash, LocalChange::Stored);
+            return Err(match e.kind() {
+                std::io::ErrorKind::AlreadyExists => Error::BlockExists(*block_hash),
+                _ => Error::io(&path, e),
+            });
+        }
+        self.write_stored_metadata(block_hash, &path, &digest).await;
+        if let Some(height) = height {
+            self.heights.write().unwrap().insert(*block_hash, height);
+        }
+        self.stats_add_block(block_hash, size).await;
+        self.events.publish(BlockEvent::Stored {
+            hash: *block_hash,
+            size: size as usize,
+        });
+        Ok(())
+    }
+
+    // Check that a range of bytes is within a block of the given size.
+    fn check_range(block_hash: &BlockHash, offset: u64, length: u64, size: u64) -> Result<()> {
+        match offset.checked_add(length) {
+            Some(end) if end <= size => Ok(()),
+            _ => Err(Error::InvalidRange {
+                hash: *block_hash,
+                offset,
+                length,
+                size,
+            }),
+        }
+    }
+
+    // Convert an IO error on a block file into an Error. If the file does not exist then the
+    // block is not in the archive, if the file is too short then the block is corrupt.
+    fn block_file_error(block_hash: &BlockHash, path: &Path, e: std::io::Error) -> Error {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => Error::BlockNotFound(*block_hash),
+            std::io::ErrorKind::UnexpectedEof => Error::CorruptData {
+                hash: *block_hash,
+                reason: String::from("block file is too short"),
+            },
+            _ => Error::io(path, e),
+        }
+    }
+
+    // Get the contents of a block file from a memory mapping, or None if memory mapped reads are
+    // not enabled.
+    #[cfg(feature = "mmap")]
+    async fn mapped_block(&self, block_hash: &BlockHash) -> Result<Option<Bytes>> {
+        let Some(mappings) = self.mappings.as_ref() else {
+            return Ok(None);
+        };
+        if let Some(bytes) = mappings.lock().unwrap().get(block_hash) {
+            return Ok(Some(bytes.clone()));
+        }
+        let path = self.block_path(block_hash)?;
+        let map_path = path.clone();
+        let map = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(map_path)?;
+            // SAFETY: block files are not changed after they have been stored, see with_mmap()
+            unsafe { memmap2::Mmap::map(&file) }
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|result| result)
+        .map_err(|e| Self::block_file_error(block_hash, &path, e))?;
+        let bytes = Bytes::from_owner(map);
+        mappings.lock().unwrap().put(*block_hash, bytes.clone());
+        Ok(Some(bytes))
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    async fn mapped_block(&self, _block_hash: &BlockHash) -> Result<Option<Bytes>> {
+        Ok(None)
+    }
+
+    // Open a block file with io_uring and get its size, or None if io_uring reads are not
+    // enabled.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    async fn uring_open(
+        &self,
+        block_hash: &BlockHash,
+        path: &Path,
+    ) -> Result<Option<(Arc<Uring>, std::fs::File, u64)>> {
+        let Some(uring) = self.uring.as_ref() else {
+            return Ok(None);
+        };
+        let open = async {
+            let file = uring.open(path).await?;
+            let size = file.metadata()?.len();
+            Ok((file, size))
+        };
+        match open.await {
+            Ok((file, size)) => Ok(Some((uring.clone(), file, size))),
+            Err(e) => Err(Self::block_file_error(block_hash, path, e)),
+        }
+    }
+
+    // Wrap a reader for a block in a VerifyingReader if the archive verifies reads and the block
+    // has a checksum.
+    async fn verified_reader<R>(
+        &self,
+        block_hash: &BlockHash,
+        reader: R,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        if self.verify_on_read {
+            if let Some(checksum) = self.block_metadata(block_hash).await?.checksum {
+                let expected = parse_checksum(block_hash, &checksum)?;
+                return Ok(Box::new(VerifyingReader::new(
+                    reader,
+                    *block_hash,
+                    expected,
+                )));
+            }
+        }
+        Ok(Box::new(reader))
+    }
+
+    // Parse the contents of a block file, checking them against the checksum if the archive
+    // verifies reads.
+    async fn parse_block(&self, block_hash: &BlockHash, path: &Path, raw: Bytes) -> Result<Block> {
+        if !has_header_and_tx_count(&raw) {
+            return Err(Self::block_file_error(
+                block_hash,
+                path,
+                std::io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+        if self.verify_on_read {
+            if let Some(checksum) = self.block_metadata(block_hash).await?.checksum {
+                let expected = parse_checksum(block_hash, &checksum)?;
+                if <[u8; 32]>::from(Sha256::digest(&raw)) != expected {
+                    return Err(checksum_mismatch(block_hash));
+                }
+            }
+        }
+        Block::new(raw).map_err(|e| Error::CorruptData {
+            hash: *block_hash,
+            reason: e.to_string(),
+        })
+    }
+
+    // List the blocks in the archive.
+    pub(crate) async fn list_blocks(&self) -> Result<Vec<BlockHash>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(MAX_BLOCKS);
+        let list = Self::block_list_bgrnd(self.root_path.clone(), self.layout, tx);
+        let collect = async {
+            let mut blocks = Vec::new();
+            while let Some(block_hash) = rx.recv().await {
+                blocks.push(block_hash);
+            }
+            blocks
+        };
+        let (result, blocks) = tokio::join!(list, collect);
+        result.map(|_| blocks)
+    }
+
+    // Get a list of all blocks in the background, sending results to the channel.
+    // Do not return blocks that are stored in the wrong location because these
+    // won't be retrievable by get_block().
+    pub(crate) async fn block_list_bgrnd(
+        root_path: PathBuf,
+        layout: Layout,
+        transmit: tokio::sync::mpsc::Sender<BlockHash>,
+    ) -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+        let list = Self::block_files_bgrnd(root_path, layout, tx);
+        let forward = async {
+            while let Some((hash, _)) = rx.recv().await {
+                if transmit.send(hash).await.is_err() {
+                    // this is not an error, the receiver has merely dropped
+                    return;
+                }
+            }
+        };
+        tokio::join!(list, forward).0
+    }
+
+    // Get the hash and height of every block file.
+    async fn list_block_files(
+        root_path: PathBuf,
+        layout: Layout,
+    ) -> Result<Vec<(BlockHash, Option<u64>)>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+        let list = Self::block_files_bgrnd(root_path, layout, tx);
+        let collect = async {
+            let mut blocks = Vec::new();
+            while let Some(block) = rx.recv().await {
+                blocks.push(block);
+            }
+            blocks
+        };
+        let (result, blocks) = tokio::join!(list, collect);
+        result.map(|_| blocks)
+    }
+
+    // Find all block files in the background, sending the hash and height of each block to the
+    // channel.
+    async fn block_files_bgrnd(
+        root_path: PathBuf,
+        layout: Layout,
+        transmit: tokio::sync::mpsc::Sender<(BlockHash, Option<u64>)>,
+    ) -> Result<()> {
+        let mut stack = Vec::new();
+        stack.push(root_path.clone());
+        while let Some(path) = stack.pop() {
+            let dir = tokio::fs::read_dir(path).await?;
+            let mut stream = ReadDirStream::new(dir);
+            // it would be fun to spawn a new task for each directory, but that would be a bit daft
+            while let Some(entry) = stream.next().await {
+                let entry = entry?;
+                let path = entry.path();
+                if path.is_dir() {
+                    stack.push(path);
+                } else if let Some(block) = layout.parse(&root_path, &path) {
+                    match transmit.send(block).await {
+                        Ok(_) => {}
+                        Err(_) => return Ok(()), // this is not an error, the receiver has merely dropped
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockArchiveReader for SimpleFileBasedBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = self.block_path(block_hash)?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some((uring, file, size)) = self.uring_open(block_hash, &path).await? {
+            let reader = UringReader::new(uring, file, 0, size);
+            return self.verified_reader(block_hash, reader).await;
+        }
+        let file = match File::open(&path).await {
+            Ok(f) => f,
+            Err(e) => return Err(Self::block_file_error(block_hash, &path, e)),
+        };
+        self.verified_reader(block_hash, file).await
+    }
+
+    /// Load a full block into memory
+    async fn get_block_full(&self, block_hash: &BlockHash) -> Result<Block> {
+        let path = self.block_path(block_hash)?;
+        let raw = match self.mapped_block(block_hash).await? {
+            Some(raw) => raw,
+            None => match tokio::fs::read(&path).await {
+                Ok(raw) => Bytes::from(raw),
+                Err(e) => return Err(Self::block_file_error(block_hash, &path, e)),
+            },
+        };
+        self.parse_block(block_hash, &path, raw).await
+    }
+
+    /// Check if a block exists in the archive.
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let path = match self.get_path_from_hash(block_hash) {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        match tokio::fs::metadata(&path).await {
+            Ok(_) => Ok(true),
+            Err(e) => match e.kind() {
+                // if the file does not exist, return false
+                std::io::ErrorKind::NotFound => Ok(false),
+                _ => Err(Error::io(&path, e)),
+            },
+        }
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let path = self.block_path(block_hash)?;
+        match tokio::fs::metadata(&path).await {
+            Ok(m) => Ok(m.len() as usize),
+            Err(e) => Err(Self::block_file_error(block_hash, &path, e)),
+        }
+    }
+
+    async fn block_tx_count(&self, block_hash: &BlockHash) -> Result<i64> {
+        let path = self.block_path(block_hash)?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some((uring, file, size)) = self.uring_open(block_hash, &path).await? {
+            // the tx count is a varint of up to 9 bytes after the header
+            let length = size.saturating_sub(BlockHeader::SIZE).clamp(1, 9);
+            let read_tx_count = async {
+                let data = uring
+                    .read(Arc::new(file), &[(BlockHeader::SIZE, length)])
+                    .await?
+                    .remove(0);
+                let value_size = match data[0] {
+                    0xff => 8,
+                    0xfe => 4,
+                    0xfd => 2,
+                    n0 => return Ok(n0 as i64),
+                };
+                let mut value = [0u8; 8];
+                match data.get(1..1 + value_size) {
+                    Some(bytes) => value[..value_size].copy_from_slice(bytes),
+                    None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                }
+                Ok(u64::from_le_bytes(value) as i64)
+            };
+            return read_tx_count
+                .await
+                .map_err(|e| Self::block_file_error(block_hash, &path, e));
+        }
+        let read_tx_count = async {
+            let mut file = File::open(&path).await?;
+            file.seek(SeekFrom::Start(BlockHeader::SIZE)).await?;
+            let n0 = file.read_u8().await?;
+            let v = match n0 {
+                0xff => file.read_u64_le().await? as i64,
+                0xfe => file.read_u32_le().await? as i64,
+                0xfd => file.read_u16_le().await? as i64,
+                _ => n0 as i64,
+            };
+            Ok(v)
+        };
+        read_tx_count
+            .await
+            .map_err(|e| Self::block_file_error(block_hash, &path, e))
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let path = self.block_path(block_hash)?;
+        if let Some(raw) = self.mapped_block(block_hash).await? {
+            if raw.len() < BlockHeader::SIZE as usize {
+                return Err(Self::block_file_error(
+                    block_hash,
+                    &path,
+                    std::io::ErrorKind::UnexpectedEof.into(),
+                ));
+            }
+            return Ok(BlockHeader::from_binary(
+                &mut raw.slice(..BlockHeader::SIZE as usize),
+            )?);
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some((uring, file, _)) = self.uring_open(block_hash, &path).await? {
+            return match uring.read(Arc::new(file), &[(0, BlockHeader::SIZE)]).await {
+                Ok(mut data) => Ok(BlockHeader::from_binary(&mut data.remove(0))?),
+                Err(e) => Err(Self::block_file_error(block_hash, &path, e)),
+            };
+        }
+        let read_header = async {
+            let mut file = File::open(&path).await?;
+            let mut buf = vec![0; BlockHeader::SIZE as usize];
+            file.read_exact(&mut buf).await?;
+            Ok(buf)
+        };
+        match read_header.await {
+            Ok(buf) => Ok(BlockHeader::from_binary(&mut Bytes::from(buf))?),
+            Err(e) => Err(Self::block_file_error(block_hash, &path, e)),
+        }
+    }
+
+    async fn get_bytes_from_block(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Bytes> {
+        let mut ranges = self
+            .get_ranges_from_block(block_hash, &[(offset, length)])
+            .await?;
+        Ok(ranges.remove(0))
+    }
+
+    async fn get_block_range(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = self.block_path(block_hash)?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some((uring, file, size)) = self.uring_open(block_hash, &path).await? {
+            Self::check_range(block_hash, offset, length, size)?;
+            return Ok(Box::new(UringReader::new(
+                uring,
+                file,
+                offset,
+                offset + length,
+            )));
+        }
+        let mut file = match File::open(&path).await {
+            Ok(f) => f,
+            Err(e) => return Err(Self::block_file_error(block_hash, &path, e)),
+        };
+        let size = match file.metadata().await {
+            Ok(m) => m.len(),
+            Err(e) => return Err(Self::block_file_error(block_hash, &path, e)),
+        };
+        Self::check_range(block_hash, offset, length, size)?;
+        if let Err(e) = file.seek(SeekFrom::Start(offset)).await {
+            return Err(Self::block_file_error(block_hash, &path, e));
+        }
+        Ok(Box::new(file.take(length)))
+    }
+
+    async fn get_ranges_from_block(
+        &self,
+        block_hash: &BlockHash,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<Bytes>> {
+        if let Some(raw) = self.mapped_block(block_hash).await? {
+            for (offset, length) in ranges.iter() {
+                Self::check_range(block_hash, *offset, *length, raw.len() as u64)?;
+            }
+            return Ok(ranges
+                .iter()
+                .map(|(offset, length)| raw.slice(*offset as usize..(*offset + *length) as usize))
+                .collect());
+        }
+        let path = self.block_path(block_hash)?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some((uring, file, size)) = self.uring_open(block_hash, &path).await? {
+            for (offset, length) in ranges.iter() {
+                Self::check_range(block_hash, *offset, *length, size)?;
+            }
+            return uring
+                .read(Arc::new(file), ranges)
+                .await
+                .map_err(|e| Self::block_file_error(block_hash, &path, e));
+        }
+        let mut file = match File::open(&path).await {
+            Ok(f) => f,
+            Err(e) => return Err(Self::block_file_error(block_hash, &path, e)),
+        };
+        let size = match file.metadata().await {
+            Ok(m) => m.len(),
+            Err(e) => return Err(Self::block_file_error(block_hash, &path, e)),
+        };
+        // check all of the ranges before allocating any memory
+        for (offset, length) in ranges.iter() {
+            Self::check_range(block_hash, *offset, *length, size)?;
+        }
+        let mut results = Vec::with_capacity(ranges.len());
+        for (offset, length) in ranges.iter() {
+            let read_range = async {
+                file.seek(SeekFrom::Start(*offset)).await?;
+                let mut buf = vec![0; *length as usize];
+                file.read_exact(&mut buf).await?;
+                Ok(Bytes::from_owner(buf))
+            };
+            let bytes = read_range
+                .await
+                .map_err(|e| Self::block_file_error(block_hash, &path, e))?;
+            results.push(bytes);
+        }
+        Ok(results)
+    }
+
+    /// Get a list of all the blocks in the archive.
+    ///
+    /// It returns a stream of block hashes.
+    ///
+    /// Example code:
+    ///     let mut results = archive.block_list().await.unwrap();
+    ///     while let Some(block_hash) = results.next().await {
+    ///       println!("{}", block_hash);
+    ///     }
+    ///
+    /// This function does not return blocks that are stored in the wrong location because these
+    /// won't be retrievable by get_block().
+    async fn block_list(&self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>> {
+        // make the channel large enough to buffer all hashes, including testnet
+        // so that the background task can collect all buffer hashes despite how slow the consumer is
+        let (tx, rx) = tokio::sync::mpsc::channel(MAX_BLOCKS);
+        let handle = tokio::spawn(Self::block_list_bgrnd(
+            self.root_path.clone(),
+            self.layout,
+            tx,
+        ));
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn subscribe(&self) -> Result<Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>> {
+        Ok(Box::pin(self.events.subscribe()))
+    }
+
+    /// Get the metadata recorded for a block.
+    ///
+    /// The metadata is stored as JSON in a file next to the block file, with a "meta" extension.
+    async fn block_metadata(&self, block_hash: &BlockHash) -> Result<BlockMetadata> {
+        let meta_path = Self::metadata_path(&self.block_path(block_hash)?);
+        match tokio::fs::read(&meta_path).await {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| Error::CorruptData {
+                hash: *block_hash,
+                reason: format!("invalid metadata: {e}"),
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match self.block_exists(block_hash).await? {
+                    true => Ok(BlockMetadata::default()),
+                    false => Err(Error::BlockNotFound(*block_hash)),
+                }
+            }
+            Err(e) => Err(Error::io(&meta_path, e)),
+        }
+    }
+
+    /// Get the filter stored for a block.
+    ///
+    /// The filter is stored in a file next to the block file, with a "filter" extension.
+    async fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        let filter_path = Self::filter_path(&self.block_path(block_hash)?);
+        match tokio::fs::read(&filter_path).await {
+            Ok(data) => BlockFilter::new(*block_hash, Bytes::from(data)).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match self.block_exists(block_hash).await? {
+                    true => Ok(None),
+                    false => Err(Error::BlockNotFound(*block_hash)),
+                }
+            }
+            Err(e) => Err(Error::io(&filter_path, e)),
+        }
+    }
+
+    // Read the block file directly, so that a mismatch is reported as CorruptData even if the
+    // archive verifies reads.
+    async fn verify_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        let metadata = self.block_metadata(block_hash).await?;
+        if metadata.checksum.is_none() {
+            return Ok(false);
+        }
+        let path = self.block_path(block_hash)?;
+        let mut file = File::open(&path)
+            .await
+            .map_err(|e| Self::block_file_error(block_hash, &path, e))?;
+        verify_reader(block_hash, &metadata, &mut file).await
+    }
+
+    /// Get statistics about the blocks in the archive.
+    ///
+    /// The statistics are calculated by a scan the first time, and are then kept up to date as
+    /// blocks are stored and deleted through this instance. Changes made by other processes are
+    /// only seen if the archive is being watched, see [SimpleFileBasedBlockArchive::watch], in
+    /// which case the statistics are calculated again on the next call.
+    async fn archive_stats(&self) -> Result<ArchiveStats> {
+        let generation = {
+            let state = self.stats.lock().unwrap();
+            if let Some(stats) = state.stats.as_ref() {
+                return Ok(stats.clone());
+            }
+            state.generation
+        };
+        let stats = scan_archive_stats(self).await?;
+        let mut state = self.stats.lock().unwrap();
+        if state.generation == generation {
+            state.stats = Some(stats.clone());
+        }
+        Ok(stats)
+    }
+}
+
+#[async_trait]
+impl BlockArchiveWriter for SimpleFileBasedBlockArchive {
+    async fn store_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        if self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists(*block_hash));
+        }
+        // the header is needed to find the path if the layout uses heights, and to check the
+        // network
+        let mut header = Vec::new();
+        if self.layout.uses_height() || self.genesis.is_some() {
+            block
+                .take(BlockHeader::SIZE)
+                .read_to_end(&mut header)
+                .await?;
+        }
+        let parsed_header = match header.len() as u64 {
+            BlockHeader::SIZE => Some(BlockHeader::from_binary(&mut &header[..])?),
+            // a block which cannot be checked is not stored
+            _ if self.genesis.is_some() => {
+                return Err(Error::CorruptData {
+                    hash: *block_hash,
+                    reason: String::from("block is too short to contain a header"),
+                })
+            }
+            _ => None,
+        };
+        self.check_network(block_hash, parsed_header.as_ref())?;
+        let (path, height) = self.new_block_path(block_hash, parsed_header.as_ref())?;
+        // create the directory structure if it does not exist
+        let dir = path.parent().unwrap();
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| Error::io(dir, e))?;
+        // store the block in a file
+        self.start_local_change(block_hash, LocalChange::Stored);
+        let mut reader = (&header[..]).chain(block);
+        let (size, digest) =
+            match Self::write_block_file(block_hash, &path, &mut reader, false).await {
+                Ok(written) => written,
+                Err(e) => {
+                    self.abandon_local_change(block_hash, LocalChange::Stored);
+                    return Err(e);
+                }
+            };
+        self.write_stored_metadata(block_hash, &path, &digest).await;
+        if let Some(height) = height {
+            self.heights.write().unwrap().insert(*block_hash, height);
+        }
+        self.stats_add_block(block_hash, size).await;
+        self.events.publish(BlockEvent::Stored {
+            hash: *block_hash,
+            size: size as usize,
+        });
+        Ok(())
+    }
+
+    async fn store_block_full(&self, block: &Block) -> Result<()> {
+        self.check_writable()?;
+        let h = block.header()?.hash();
+        if self.block_exists(&h).await? {
+            return Err(Error::BlockExists(h));
+        }
+        self.check_network(&h, Some(&block.header()?))?;
+        let (path, height) = self.new_block_path(&h, Some(&block.header()?))?;
+        // create the directory structure if it does not exist
+        let dir = path.parent().unwrap();
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| Error::io(dir, e))?;
+        // store the block in a file
+        self.start_local_change(&h, LocalChange::Stored);
+        let digest = match Self::write_block_file(&h, &path, &mut &block.raw[..], false).await {
+            Ok((_, digest)) => digest,
+            Err(e) => {
+                self.abandon_local_change(&h, LocalChange::Stored);
+                return Err(e);
+            }
+        };
+        self.write_stored_metadata(&h, &path, &digest).await;
+        if let Some(height) = height {
+            self.heights.write().unwrap().insert(h, height);
+        }
+        if let Some(stats) = self.stats.lock().unwrap().stats.as_mut() {
+            let timestamp = block.header()?.timestamp();
+            stats.add_block(&h, block.raw.len() as u64, block.num_tx, timestamp);
+        }
+        self.events.publish(BlockEvent::Stored {
+            hash: h,
+            size: block.raw.len(),
+        });
+        Ok(())
+    }
+
+    /// Replace a block by writing the new copy to a partial file and renaming it over the block
+    /// file, so the block is never missing. The block keeps its location, and the recorded
+    /// checksum is replaced. A block that is not in the archive is stored.
+    async fn replace_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        if !self.block_exists(block_hash).await? {
+            return self.store_block(block_hash, block).await;
+        }
+        let path = self.block_path(block_hash)?;
+        self.start_local_change(block_hash, LocalChange::Stored);
+        let (size, digest) = match Self::write_block_file(block_hash, &path, block, true).await {
+            Ok(written) => written,
+            Err(e) => {
+                self.abandon_local_change(block_hash, LocalChange::Stored);
+                return Err(e);
+            }
+        };
+        #[cfg(feature = "mmap")]
+        if let Some(mappings) = self.mappings.as_ref() {
+            mappings.lock().unwrap().pop(block_hash);
+        }
+        self.stats.lock().unwrap().invalidate();
+        let _lock = self.lock_block(block_hash).await;
+        // the rest of the metadata describes the block, not the copy
+        let mut metadata = self.block_metadata(block_hash).await?;
+        metadata.checksum = Some(digest.encode_hex());
+        Self::write_metadata_file(&path, &metadata).await?;
+        self.events.publish(BlockEvent::Stored {
+            hash: *block_hash,
+            size: size as usize,
+        });
+        Ok(())
+    }
+
+    async fn delete_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.check_writable()?;
+        let path = self.block_path(block_hash)?;
+        let details = self.stats_block_details(block_hash).await;
+        let _lock = self.lock_block(block_hash).await;
+        self.start_local_change(block_hash, LocalChange::Deleted);
+        match tokio::fs::remove_file(&path).await {
+            Ok(_) => {
+                self.heights.write().unwrap().remove(block_hash);
+                #[cfg(feature = "mmap")]
+                if let Some(mappings) = self.mappings.as_ref() {
+                    mappings.lock().unwrap().pop(block_hash);
+                }
+                {
+                    let mut state = self.stats.lock().unwrap();
+                    if let Some(stats) = state.stats.as_mut() {
+                        let removed = match details {
+                            Some(Ok((size, tx_count, timestamp))) => {
+                                stats.remove_block(block_hash, size, tx_count, timestamp)
+                            }
+                            _ => false,
+                        };
+                        if !removed {
+                            state.invalidate();
+                        }
+                    }
+                }
+                Self::remove_side_files(&path).await?;
+                self.events
+                    .publish(BlockEvent::Deleted { hash: *block_hash });
+                Ok(())
+            }
+            Err(e) => {
+                self.abandon_local_change(block_hash, LocalChange::Deleted);
+                Err(Self::block_file_error(block_hash, &path, e))
+            }
+        }
+    }
+
+    /// Record metadata for a block.
+    ///
+    /// The checksum recorded when the block was stored is kept if the new metadata does not
+    /// have a checksum.
+    async fn set_block_metadata(
+        &self,
+        block_hash: &BlockHash,
+        metadata: &BlockMetadata,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let path = self.block_path(block_hash)?;
+        let _lock = self.lock_block(block_hash).await;
+        let mut metadata = metadata.clone();
+        if metadata.checksum.is_none() {
+            metadata.checksum = self.block_metadata(block_hash).await?.checksum;
+        } else if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        Self::write_metadata_file(&path, &metadata).await
+    }
+
+    /// Store the filter of a block.
+    ///
+    /// The filter is stored in a file next to the block file, with a "filter" extension, and is
+    /// removed with the block.
+    async fn set_block_filter(&self, block_hash: &BlockHash, filter: &BlockFilter) -> Result<()> {
+        self.check_writable()?;
+        if filter.block_hash() != *block_hash {
+            return Err(Error::HashMismatch {
+                expected: *block_hash,
+                actual: filter.block_hash(),
+            });
+        }
+        let _lock = self.lock_block(block_hash).await;
+        if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        let path = self.block_path(block_hash)?;
+        Self::write_side_file(&path, "filter", filter.raw()).await
+    }
+}
+
+// Add the paths of the files in a directory and its subdirectories. Directories that cannot be
+// read, for example because they have already been removed again, are skipped.
+#[cfg(feature = "watch")]
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => list_files(&entry.path(), files),
+            Ok(_) => files.push(entry.path()),
+            Err(_) => {}
+        }
+    }
+}
+
+// Returns true if an open file is the file at a path.
+#[cfg(unix)]
+fn is_same_file(file: &std::fs::File, path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let open = file.metadata()?;
+    match std::fs::metadata(path) {
+        Ok(m) => Ok(m.dev() == open.dev() && m.ino() == open.ino()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Files that are open cannot be removed on other platforms.
+#[cfg(not(unix))]
+fn is_same_file(_file: &std::fs::File, _path: &Path) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+// Link a locked partial file into place. On Linux the link is made from the open file, through
+// /proc, so that it is the file we hold the lock on. Elsewhere, and if /proc is not mounted, the
+// partial path is linked, which is the same file as only the holder of the lock removes it.
+async fn link_locked_file(file: &File, partial_path: &Path, path: &Path) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        let source = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+        if tokio::fs::try_exists(&source).await.unwrap_or(false) {
+            let path = path.to_path_buf();
+            return tokio::task::spawn_blocking(move || link_following(&source, &path))
+                .await
+                .map_err(std::io::Error::other)?;
+        }
+    }
+    let _ = file;
+    tokio::fs::hard_link(partial_path, path).await
+}
+
+// Create a hard link to the file that a symbolic link points to.
+#[cfg(target_os = "linux")]
+fn link_following(source: &Path, path: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let source = CString::new(source.as_os_str().as_bytes()).map_err(std::io::Error::other)?;
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(std::io::Error::other)?;
+    // SAFETY: both paths are valid nul terminated strings
+    let result = unsafe {
+        libc::linkat(
+            libc::AT_FDCWD,
+            source.as_ptr(),
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::AT_SYMLINK_FOLLOW,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::make_chain;
+    use hex::FromHex;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+    use tokio::io::AsyncReadExt;
+
+    fn get_testdata_path() -> String {
+        String::from("testdata/blockarchive")
+    }
+
+    // Test the path generation from a block hash.
+    #[tokio::test]
+    async fn check_path_from_hash() {
+        let path = get_testdata_path();
+        let s = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531")
+                .unwrap();
+        let path = s.get_path_from_hash(&h).unwrap();
+        assert_eq!(path, PathBuf::from("testdata/blockarchive/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin"));
+    }
+
+    // Test the block list function.
+    // two of the potentially ok block files are stored in the wrong location, so they shouldnt be returned
+    #[tokio::test]
+    async fn test_block_list() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let mut results = archive.block_list().await.unwrap();
+        let mut count = 0;
+        while (results.next().await).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 3);
+    }
+
+    // Test the block list function with no blocks.
+    #[tokio::test]
+    async fn test_empty_block_list() {
+        // calling a blocking function from tokio is bad, but this is a test
+        let root = tempdir().unwrap();
+        let path = String::from(root.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let mut results = archive.block_list().await.unwrap();
+        let mut count = 0;
+        while (results.next().await).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 0);
+    }
+
+    // Test the archive with a non-existent root directory.
+    #[tokio::test]
+    async fn test_non_existent_root_dir() {
+        let path = String::from("../testdata/nonexistent");
+        let archive = SimpleFileBasedBlockArchive::new(path).await;
+        assert!(archive.is_err());
+    }
+
+    // Test getting a block
+    #[tokio::test]
+    async fn test_get_block() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let mut block = archive.get_block(&h).await.unwrap();
+        let mut buf = Vec::new();
+        block.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 227);
+    }
+
+    // Test unknown block, should return Error:BlockNotFound
+    #[tokio::test]
+    async fn test_unknown_block() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+                .unwrap();
+        let block = archive.get_block(&h).await;
+        match block {
+            Ok(_) => panic!("Expected error but got Ok"),
+            Err(e) => match e {
+                Error::BlockNotFound(hash) => assert_eq!(hash, h), // Expected error
+                _ => panic!("Unexpected error type: {e:?}"),
+            },
+        }
+    }
+
+    // Test block exists
+    #[tokio::test]
+    async fn test_block_exists() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let exists = archive.block_exists(&h).await.unwrap();
+        assert!(exists);
+    }
+
+    // Test unknown block does not exist
+    #[tokio::test]
+    async fn test_unknown_block_exists() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+                .unwrap();
+        let exists = archive.block_exists(&h).await.unwrap();
+        assert!(!exists);
+    }
+
+    // A block that is stored in the wrong location wont exist
+    #[tokio::test]
+    async fn test_wrong_location_block_exists() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("000000001ee3392a6b6ba0bf2480a0f6bf9cdaaefa331bc0dfb243523af41a44")
+                .unwrap();
+        let exists = archive.block_exists(&h).await.unwrap();
+        assert!(!exists);
+    }
+
+    // Test storing a block by storing on in a temporary location and then checking it is stored correctly
+    #[tokio::test]
+    async fn test_store_block() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block = "This is a block".as_bytes().to_vec();
+        let block_cursor = Box::new(Cursor::new(block.clone()));
+        archive
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+            .unwrap();
+        let exists = archive.block_exists(&h).await.unwrap();
+        assert!(exists);
+        let mut stored_block = archive.get_block(&h).await.unwrap();
+        let mut buf = Vec::new();
+        stored_block.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, block);
+    }
+
+    // Test storing a block that already exists
+    #[tokio::test]
+    async fn test_store_existing_block() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block = "This is a block".as_bytes().to_vec();
+        let block_cursor = Box::new(Cursor::new(block.clone()));
+        archive
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+            .unwrap();
+        let exists = archive.block_exists(&h).await.unwrap();
+        assert!(exists);
+        let block = "This is a new block".as_bytes().to_vec();
+        let block_cursor = Box::new(Cursor::new(block.clone()));
+        let store = archive
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await;
+        match store {
+            Ok(_) => panic!("Expected error but got Ok"),
+            Err(e) => match e {
+                Error::BlockExists(hash) => assert_eq!(hash, h), // Expected error
+                _ => panic!("Unexpected error type: {e:?}"),
+            },
+        }
+    }
+
+    // Test getting the size of a block
+    #[tokio::test]
+    async fn test_block_size() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let size = archive.block_size(&h).await.unwrap();
+        assert_eq!(size, 227);
+    }
+
+    // Test getting the size of an unknown block
+    #[tokio::test]
+    async fn test_unknown_block_size() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+                .unwrap();
+        let size = archive.block_size(&h).await;
+        match size {
+            Ok(_) => panic!("Expected error but got Ok"),
+            Err(e) => match e {
+                Error::BlockNotFound(hash) => assert_eq!(hash, h), // Expected error
+                _ => panic!("Unexpected error type: {e:?}"),
+            },
+        }
+    }
+
+    // Testing getting a header
+    #[tokio::test]
+    async fn test_block_header() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let header = archive.block_header(&h).await.unwrap();
+        assert_eq!(header.version(), 2);
+        assert_eq!(
+            header.prev_hash(),
+            BlockHash::from_hex("0000000000000135aeabf9666fc9f1d5b8573685db070a5f1dfdd78f728a167a")
+                .unwrap()
+        );
+        let m_root =
+            BlockHash::from_hex("949904a56c861ecde4b43c9fc4ad612b82d10e38bdd164ea820b8cd0e6a39178")
+                .unwrap();
+        assert_eq!(header.merkle_root(), m_root);
+    }
+
+    // test getting a header for an unknown block
+    #[tokio::test]
+    async fn test_unknown_block_header() {
+        let path = get_testdata_path();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+                .unwrap();
+        let header = archive.block_header(&h).await;
+        match header {
+            Ok(_) => panic!("Expected error but got Ok"),
+            Err(e) => match e {
+                Error::BlockNotFound(hash) => assert_eq!(hash, h), // Expected error
+                _ => panic!("Unexpected error type: {e:?}"),
+            },
+        }
+    }
+
+    // Test that subscribers receive events when blocks are stored and deleted
+    #[tokio::test]
+    async fn test_subscribe() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let mut events = archive.subscribe().await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block = "This is a block".as_bytes().to_vec();
+        let block_cursor = Box::new(Cursor::new(block.clone()));
+        archive
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+            .unwrap();
+        archive.delete_block(&h).await.unwrap();
+        assert_eq!(
+            events.next().await.unwrap(),
+            BlockEvent::Stored {
+                hash: h,
+                size: block.len()
+            }
+        );
+        assert_eq!(
+            events.next().await.unwrap(),
+            BlockEvent::Deleted { hash: h }
+        );
+        assert!(!archive.block_exists(&h).await.unwrap());
+    }
+
+    // A subscriber that doesn't keep up is told how many events it missed
+    #[tokio::test]
+    async fn test_subscribe_lagged() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path)
+            .await
+            .unwrap()
+            .with_event_buffer(2);
+        let mut events = archive.subscribe().await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        for _ in 0..3 {
+            let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+            archive
+                .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+                .await
+                .unwrap();
+            archive.delete_block(&h).await.unwrap();
+        }
+        assert_eq!(
+            events.next().await.unwrap(),
+            BlockEvent::Lagged { missed: 4 }
+        );
+        assert_eq!(
+            events.next().await.unwrap(),
+            BlockEvent::Stored { hash: h, size: 15 }
+        );
+        assert_eq!(
+            events.next().await.unwrap(),
+            BlockEvent::Deleted { hash: h }
+        );
+    }
+
+    // Test deleting an unknown block
+    #[tokio::test]
+    async fn test_delete_unknown_block() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+                .unwrap();
+        match archive.delete_block(&h).await {
+            Ok(_) => panic!("Expected error but got Ok"),
+            Err(e) => match e {
+                Error::BlockNotFound(hash) => assert_eq!(hash, h), // Expected error
+                _ => panic!("Unexpected error type: {e:?}"),
+            },
+        }
+    }
+
+    // A read-only archive refuses changes but can still be read
+    #[tokio::test]
+    async fn test_read_only() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path.clone())
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+        archive
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+            .unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(path)
+            .await
+            .unwrap()
+            .with_read_only(true);
+        assert!(matches!(
+            archive.delete_block(&h).await,
+            Err(Error::ReadOnly)
+        ));
+        let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+        assert!(matches!(
+            archive
+                .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+                .await,
+            Err(Error::ReadOnly)
+        ));
+        assert_eq!(archive.block_size(&h).await.unwrap(), 15);
+    }
+
+    // Blocks stored by another instance are picked up by the file system watcher, blocks stored
+    // by the watching instance are only reported once
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn test_watch() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let mut archive = SimpleFileBasedBlockArchive::new(path.clone())
+            .await
+            .unwrap();
+        archive.watch().unwrap();
+        // the running watcher publishes to subscriptions made after the buffer is changed
+        let archive = archive.with_event_buffer(16);
+        let mut events = archive.subscribe().await.unwrap();
+        let other = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h1 =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let h2 =
+            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+                .unwrap();
+        let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+        archive
+            .store_block(
+                &h1,
+                &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>),
+            )
+            .await
+            .unwrap();
+        let block_cursor = Box::new(Cursor::new(b"This is another block".to_vec()));
+        other
+            .store_block(
+                &h2,
+                &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>),
+            )
+            .await
+            .unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let e1 = tokio::time::timeout(timeout, events.next()).await.unwrap();
+        assert_eq!(e1.unwrap(), BlockEvent::Stored { hash: h1, size: 15 });
+        let e2 = tokio::time::timeout(timeout, events.next()).await.unwrap();
+        assert_eq!(e2.unwrap(), BlockEvent::Stored { hash: h2, size: 21 });
+        other.delete_block(&h2).await.unwrap();
+        let e3 = tokio::time::timeout(timeout, events.next()).await.unwrap();
+        assert_eq!(e3.unwrap(), BlockEvent::Deleted { hash: h2 });
+    }
+
+    // Child process for test_multi_process_store(), it does nothing when run directly.
+    // Stores a large block filled with a single byte, and reports the result on stdout.
+    #[tokio::test]
+    #[ignore]
+    async fn multi_process_store_child() {
+        let path = match std::env::var("BLOCKARCHIVE_TEST_ROOT") {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        let fill: u8 = std::env::var("BLOCKARCHIVE_TEST_FILL")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block_cursor = Box::new(Cursor::new(vec![fill; 8_000_000]));
+        match archive
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+        {
+            Ok(_) => println!("RESULT=stored"),
+            Err(Error::BlockExists(_)) => println!("RESULT=exists"),
+            Err(e) => println!("RESULT={e}"),
         }
-        assert_eq!(count, 0);
     }
 
-    // Test the archive with a non-existent root directory.
+    // Several processes store the same block at the same time, exactly one succeeds and the
+    // stored block is not corrupted.
     #[tokio::test]
-    async fn test_non_existent_root_dir() {
-        let path = String::from("../testdata/nonexistent");
-        let archive = SimpleFileBasedBlockArchive::new(path).await;
-        assert!(archive.is_err());
+    async fn test_multi_process_store() {
+        let root_path = tempdir().unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let children: Vec<_> = (1..=4u8)
+            .map(|fill| {
+                std::process::Command::new(&exe)
+                    .args([
+                        "--exact",
+                        "sfb_archive::tests::multi_process_store_child",
+                        "--ignored",
+                        "--nocapture",
+                    ])
+                    .env("BLOCKARCHIVE_TEST_ROOT", root_path.path())
+                    .env("BLOCKARCHIVE_TEST_FILL", fill.to_string())
+                    .stdout(std::process::Stdio::piped())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        let mut stored = 0;
+        let mut exists = 0;
+        for child in children {
+            let output = child.wait_with_output().unwrap();
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            if stdout.contains("RESULT=stored") {
+                stored += 1;
+            } else if stdout.contains("RESULT=exists") {
+                exists += 1;
+            } else {
+                panic!("Unexpected child output: {stdout}");
+            }
+        }
+        assert_eq!(stored, 1);
+        assert_eq!(exists, 3);
+
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let mut block = archive.get_block(&h).await.unwrap();
+        let mut buf = Vec::new();
+        block.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 8_000_000);
+        assert!(buf.iter().all(|b| *b == buf[0]));
+        // no partial files are left behind
+        let partial = archive
+            .get_path_from_hash(&h)
+            .unwrap()
+            .with_extension("partial");
+        assert!(!partial.exists());
     }
 
-    // Test getting a block
+    // A writer that finds the partial file locked waits for the other writer, and stores the
+    // block itself if the other writer fails
     #[tokio::test]
-    async fn test_get_block() {
-        let path = get_testdata_path();
-        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+    async fn test_store_after_failed_writer() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = Arc::new(SimpleFileBasedBlockArchive::new(path).await.unwrap());
         let h =
             BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
                 .unwrap();
+        let partial_path = archive
+            .get_path_from_hash(&h)
+            .unwrap()
+            .with_extension("partial");
+        std::fs::create_dir_all(partial_path.parent().unwrap()).unwrap();
+        let other = std::fs::File::create(&partial_path).unwrap();
+        other.lock().unwrap();
+        std::fs::write(&partial_path, b"half a block").unwrap();
+
+        let store = tokio::spawn({
+            let archive = archive.clone();
+            async move {
+                let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+                archive
+                    .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+                    .await
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!store.is_finished());
+        // the other writer fails, removing its partial file
+        std::fs::remove_file(&partial_path).unwrap();
+        drop(other);
+        store.await.unwrap().unwrap();
         let mut block = archive.get_block(&h).await.unwrap();
         let mut buf = Vec::new();
         block.read_to_end(&mut buf).await.unwrap();
-        assert_eq!(buf.len(), 227);
+        assert_eq!(buf, b"This is a block");
+        assert!(!partial_path.exists());
     }
 
-    // Test unknown block, should return Error:BlockNotFound
+    // A block file that is too short is reported as corrupt
     #[tokio::test]
-    async fn test_unknown_block() {
+    async fn test_corrupt_block() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block_cursor = Box::new(Cursor::new(b"This is a block".to_vec()));
+        archive
+            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .await
+            .unwrap();
+        let e = archive.get_block_full(&h).await.unwrap_err();
+        assert!(e.is_corrupt());
+        assert_eq!(e.block_hash(), Some(h));
+        let e = archive.block_header(&h).await.unwrap_err();
+        assert!(e.is_corrupt());
+        let e = archive.block_tx_count(&h).await.unwrap_err();
+        assert!(e.is_corrupt());
+    }
+
+    // Errors include the block hash or the path of the file, and can be used as a std::error::Error
+    #[tokio::test]
+    async fn test_error_context() {
         let path = get_testdata_path();
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
         let h =
             BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
                 .unwrap();
-        let block = archive.get_block(&h).await;
-        match block {
-            Ok(_) => panic!("Expected error but got Ok"),
-            Err(e) => match e {
-                Error::BlockNotFound => {} // Expected error
-                _ => panic!("Unexpected error type: {e:?}"),
-            },
-        }
+        let e = archive.get_block(&h).await.err().unwrap();
+        assert!(e.is_not_found());
+        assert_eq!(e.block_hash(), Some(h));
+        assert!(e.to_string().contains(&h.to_string()));
+
+        let e = SimpleFileBasedBlockArchive::new(String::from("../testdata/nonexistent"))
+            .await
+            .unwrap_err();
+        let e: Box<dyn std::error::Error> = Box::new(e);
+        assert!(e.source().is_some());
+
+        let e = Error::io(
+            Path::new("/a/file"),
+            std::io::Error::other("something went wrong"),
+        );
+        assert_eq!(e.path(), Some(Path::new("/a/file")));
+        assert_eq!(e.to_string(), "IO error on /a/file: something went wrong");
     }
 
-    // Test block exists
+    // Test getting bytes from a block
     #[tokio::test]
-    async fn test_block_exists() {
+    async fn test_get_bytes_from_block() {
         let path = get_testdata_path();
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
         let h =
             BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
                 .unwrap();
-        let exists = archive.block_exists(&h).await.unwrap();
-        assert!(exists);
+        let header = archive.block_header(&h).await.unwrap();
+        let bytes = archive.get_bytes_from_block(&h, 0, 80).await.unwrap();
+        assert_eq!(bytes, header.raw);
+        let bytes = archive.get_bytes_from_block(&h, 220, 7).await.unwrap();
+        assert_eq!(bytes.len(), 7);
     }
 
-    // Test unknown block does not exist
+    // Ranges that are not within the block are rejected without reading them
     #[tokio::test]
-    async fn test_unknown_block_exists() {
+    async fn test_invalid_range() {
         let path = get_testdata_path();
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
         let h =
-            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
                 .unwrap();
-        let exists = archive.block_exists(&h).await.unwrap();
-        assert!(!exists);
+        for (offset, length) in [(220, 8), (0, 1 << 40), (u64::MAX, 2), (300, 0)] {
+            match archive.get_bytes_from_block(&h, offset, length).await {
+                Ok(_) => panic!("Expected error but got Ok"),
+                Err(e) => match e {
+                    Error::InvalidRange { size, .. } => assert_eq!(size, 227), // Expected error
+                    _ => panic!("Unexpected error type: {e:?}"),
+                },
+            }
+        }
+        assert!(archive.get_block_range(&h, 0, 228).await.is_err());
+        let ranges = [(0, 80), (227, 1)];
+        assert!(archive.get_ranges_from_block(&h, &ranges).await.is_err());
     }
 
-    // A block that is stored in the wrong location wont exist
+    // Test reading a range of a block as a stream
     #[tokio::test]
-    async fn test_wrong_location_block_exists() {
+    async fn test_get_block_range() {
         let path = get_testdata_path();
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
         let h =
-            BlockHash::from_hex("000000001ee3392a6b6ba0bf2480a0f6bf9cdaaefa331bc0dfb243523af41a44")
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
                 .unwrap();
-        let exists = archive.block_exists(&h).await.unwrap();
-        assert!(!exists);
+        let expected = archive.get_bytes_from_block(&h, 81, 100).await.unwrap();
+        let mut reader = archive.get_block_range(&h, 81, 100).await.unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
     }
 
-    // Test storing a block by storing on in a temporary location and then checking it is stored correctly
+    // Test reading several ranges from a block
     #[tokio::test]
-    async fn test_store_block() {
-        let root_path = tempdir().unwrap();
-        let path = String::from(root_path.path().to_str().unwrap());
+    async fn test_get_ranges_from_block() {
+        let path = get_testdata_path();
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
         let h =
             BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
                 .unwrap();
-        let block = "This is a block".as_bytes().to_vec();
-        let block_cursor = Box::new(Cursor::new(block.clone()));
-        archive
-            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+        let ranges = [(81, 10), (0, 80), (227, 0)];
+        let results = archive.get_ranges_from_block(&h, &ranges).await.unwrap();
+        assert_eq!(results.len(), 3);
+        for (bytes, (offset, length)) in results.iter().zip(ranges.iter()) {
+            let expected = archive
+                .get_bytes_from_block(&h, *offset, *length)
+                .await
+                .unwrap();
+            assert_eq!(bytes, &expected);
+        }
+    }
+
+    // The layout is recorded in the manifest and detected when the archive is opened
+    #[tokio::test]
+    async fn test_layout_manifest() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new_with_layout(path.clone(), Layout::Flat)
             .await
             .unwrap();
-        let exists = archive.block_exists(&h).await.unwrap();
-        assert!(exists);
-        let mut stored_block = archive.get_block(&h).await.unwrap();
-        let mut buf = Vec::new();
-        stored_block.read_to_end(&mut buf).await.unwrap();
-        assert_eq!(buf, block);
+        let block = &make_chain(1)[0];
+        let h = block.header().unwrap().hash();
+        archive.store_block_full(block).await.unwrap();
+        let flat_path = root_path.path().join(h.to_string()).with_extension("bin");
+        assert!(flat_path.exists());
+
+        let archive = SimpleFileBasedBlockArchive::new(path.clone())
+            .await
+            .unwrap();
+        assert_eq!(archive.layout(), Layout::Flat);
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert_eq!(
+            futures::StreamExt::count(archive.block_list().await.unwrap()).await,
+            1
+        );
+        let result = SimpleFileBasedBlockArchive::new_with_layout(path, Layout::default()).await;
+        match result {
+            Err(Error::InvalidManifest { .. }) => {}
+            r => panic!("expected InvalidManifest, got {:?}", r.map(|_| ())),
+        }
+
+        // an archive without a manifest uses the default layout
+        let archive = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        assert_eq!(archive.layout(), Layout::default());
     }
 
-    // Test storing a block that already exists
+    // The height layout stores blocks by height and needs the parent of each block
     #[tokio::test]
-    async fn test_store_existing_block() {
+    async fn test_height_layout() {
         let root_path = tempdir().unwrap();
         let path = String::from(root_path.path().to_str().unwrap());
+        let layout = Layout::Height { blocks_per_dir: 2 };
+        let archive = SimpleFileBasedBlockArchive::new_with_layout(path.clone(), layout)
+            .await
+            .unwrap();
+        let blocks = make_chain(3);
+        let result = archive.store_block_full(&blocks[1]).await;
+        match result {
+            Err(Error::ParentNotFound { parent, .. }) => {
+                assert_eq!(parent, blocks[0].header().unwrap().hash())
+            }
+            r => panic!("expected ParentNotFound, got {:?}", r.map(|_| ())),
+        }
+        archive.store_block_full(&blocks[0]).await.unwrap();
+        archive.store_block_full(&blocks[1]).await.unwrap();
+        let h = blocks[2].header().unwrap().hash();
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> =
+            Box::new(Cursor::new(blocks[2].raw.clone()));
+        archive.store_block(&h, &mut reader).await.unwrap();
+        let expected = root_path.path().join("2").join(format!("2_{h}.bin"));
+        assert!(expected.exists());
+
+        // the heights are found again when the archive is opened
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let h =
-            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+        assert_eq!(archive.get_block_full(&h).await.unwrap().raw, blocks[2].raw);
+        archive.delete_block(&h).await.unwrap();
+        assert!(!archive.block_exists(&h).await.unwrap());
+    }
+
+    // An archive can be migrated between layouts in place
+    #[tokio::test]
+    async fn test_migrate_layout() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let mut archive = SimpleFileBasedBlockArchive::new(path.clone())
+            .await
+            .unwrap();
+        let blocks = make_chain(5);
+        for block in blocks.iter() {
+            archive.store_block_full(block).await.unwrap();
+        }
+        // blocks at heights 0 and 1 are in the same place with 2 and 3 blocks per directory
+        let layouts = [
+            (Layout::Height { blocks_per_dir: 2 }, 5),
+            (Layout::Height { blocks_per_dir: 3 }, 3),
+            (Layout::Flat, 5),
+            (Layout::FanOut { depth: 3, width: 1 }, 5),
+        ];
+        for (layout, moved) in layouts {
+            assert_eq!(archive.migrate_layout(layout).await.unwrap(), moved);
+            let archive = SimpleFileBasedBlockArchive::new(path.clone())
+                .await
                 .unwrap();
-        let block = "This is a block".as_bytes().to_vec();
-        let block_cursor = Box::new(Cursor::new(block.clone()));
+            assert_eq!(archive.layout(), layout);
+            assert_eq!(
+                futures::StreamExt::count(archive.block_list().await.unwrap()).await,
+                5
+            );
+            for block in blocks.iter() {
+                let h = block.header().unwrap().hash();
+                assert_eq!(archive.get_block_full(&h).await.unwrap().raw, block.raw);
+            }
+        }
+        // the files and directories of the old layouts have been removed
+        for entry in std::fs::read_dir(root_path.path()).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            assert!(name == crate::MANIFEST_FILE || name.len() == 1, "{name}");
+        }
+        assert_eq!(archive.migrate_layout(archive.layout()).await.unwrap(), 0);
+
+        // blocks whose parent is not in the archive have no height
+        let mut archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
         archive
-            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+            .delete_block(&blocks[2].header().unwrap().hash())
             .await
             .unwrap();
-        let exists = archive.block_exists(&h).await.unwrap();
-        assert!(exists);
-        let block = "This is a new block".as_bytes().to_vec();
-        let block_cursor = Box::new(Cursor::new(block.clone()));
-        let store = archive
-            .store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>))
+        let result = archive
+            .migrate_layout(Layout::Height { blocks_per_dir: 2 })
             .await;
-        match store {
-            Ok(_) => panic!("Expected error but got Ok"),
-            Err(e) => match e {
-                Error::BlockExists => {} // Expected error
-                _ => panic!("Unexpected error type: {e:?}"),
-            },
-        }
+        assert!(matches!(result, Err(Error::ParentNotFound { .. })));
+        assert_eq!(archive.layout(), Layout::FanOut { depth: 3, width: 1 });
     }
 
-    // Test getting the size of a block
+    // The statistics are kept up to date as blocks are stored and deleted
     #[tokio::test]
-    async fn test_block_size() {
-        let path = get_testdata_path();
+    async fn test_archive_stats() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let h =
+        let testdata = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let genesis =
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        let h1 =
             BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
                 .unwrap();
-        let size = archive.block_size(&h).await.unwrap();
-        assert_eq!(size, 227);
+        let h2 =
+            BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048")
+                .unwrap();
+        assert_eq!(
+            archive.archive_stats().await.unwrap(),
+            ArchiveStats::default()
+        );
+        archive
+            .store_block_full(&testdata.get_block_full(&genesis).await.unwrap())
+            .await
+            .unwrap();
+        for h in [h1, h2] {
+            let mut reader = testdata.get_block(&h).await.unwrap();
+            archive.store_block(&h, &mut reader).await.unwrap();
+        }
+        let stats = archive.archive_stats().await.unwrap();
+        assert_eq!(stats.block_count, 3);
+        assert_eq!(stats.total_size, 285 + 227 + 215);
+        assert_eq!(stats, scan_archive_stats(&archive).await.unwrap());
+        // a block that is not an extreme is removed incrementally
+        archive.delete_block(&h2).await.unwrap();
+        assert!(archive.stats.lock().unwrap().stats.is_some());
+        assert_eq!(
+            archive.archive_stats().await.unwrap(),
+            scan_archive_stats(&archive).await.unwrap()
+        );
+        // removing the largest block means the statistics are calculated again
+        archive.delete_block(&genesis).await.unwrap();
+        assert!(archive.stats.lock().unwrap().stats.is_none());
+        let stats = archive.archive_stats().await.unwrap();
+        assert_eq!(stats.block_count, 1);
+        assert_eq!(stats.max_size_block, Some(h1));
     }
 
-    // Test getting the size of an unknown block
+    // Metadata is recorded when a block is stored, kept next to the block, moved by a migration
+    // and removed with the block
     #[tokio::test]
-    async fn test_unknown_block_size() {
-        let path = get_testdata_path();
-        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let h =
-            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
-                .unwrap();
-        let size = archive.block_size(&h).await;
-        match size {
-            Ok(_) => panic!("Expected error but got Ok"),
-            Err(e) => match e {
-                Error::BlockNotFound => {} // Expected error
-                _ => panic!("Unexpected error type: {e:?}"),
-            },
+    async fn test_block_metadata() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let mut archive = SimpleFileBasedBlockArchive::new(path)
+            .await
+            .unwrap()
+            .with_source("importer");
+        let blocks = make_chain(2);
+        for block in blocks.iter() {
+            archive.store_block_full(block).await.unwrap();
         }
+        let h = blocks[1].header().unwrap().hash();
+        // when and where the block came from is recorded when it is stored
+        let stored = archive.block_metadata(&h).await.unwrap();
+        assert_eq!(stored.source.as_deref(), Some("importer"));
+        assert!(stored.first_seen.is_some());
+        let mut metadata = BlockMetadata::seen_now("peer 127.0.0.1:8333");
+        metadata.height = Some(1);
+        metadata
+            .custom
+            .insert(String::from("note"), String::from("test"));
+        archive.set_block_metadata(&h, &metadata).await.unwrap();
+        // the checksum recorded when the block was stored is kept
+        metadata.checksum = Some(crate::block_checksum(&blocks[1].raw));
+        assert_eq!(archive.block_metadata(&h).await.unwrap(), metadata);
+
+        archive.migrate_layout(Layout::Flat).await.unwrap();
+        assert_eq!(archive.block_metadata(&h).await.unwrap(), metadata);
+        let meta_path =
+            SimpleFileBasedBlockArchive::metadata_path(&archive.block_path(&h).unwrap());
+        assert!(meta_path.exists());
+
+        archive.delete_block(&h).await.unwrap();
+        assert!(!meta_path.exists());
+        assert!(matches!(
+            archive.block_metadata(&h).await,
+            Err(Error::BlockNotFound(_))
+        ));
+        assert!(matches!(
+            archive.set_block_metadata(&h, &metadata).await,
+            Err(Error::BlockNotFound(_))
+        ));
     }
 
-    // Testing getting a header
+    // A block that has been stored is not lost when its metadata cannot be written
     #[tokio::test]
-    async fn test_block_header() {
-        let path = get_testdata_path();
+    async fn test_store_without_metadata() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
         let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let h =
-            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+        let block = make_chain(1).remove(0);
+        let h = block.header().unwrap().hash();
+        // a directory in the place of the metadata file makes the write fail
+        let meta_path = archive
+            .get_path_from_hash(&h)
+            .unwrap()
+            .with_extension("meta");
+        std::fs::create_dir_all(meta_path.join("blocker")).unwrap();
+        archive.store_block_full(&block).await.unwrap();
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert_eq!(archive.get_block_full(&h).await.unwrap().raw, block.raw);
+    }
+
+    // Checksums are recorded when blocks are stored and checked when they are read
+    #[tokio::test]
+    async fn test_verify_on_read() {
+        let root_path = tempdir().unwrap();
+        let path = String::from(root_path.path().to_str().unwrap());
+        let archive = SimpleFileBasedBlockArchive::new(path.clone())
+            .await
+            .unwrap();
+        let blocks = make_chain(2);
+        archive.store_block_full(&blocks[0]).await.unwrap();
+        let h0 = blocks[0].header().unwrap().hash();
+        let h1 = blocks[1].header().unwrap().hash();
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> =
+            Box::new(Cursor::new(blocks[1].raw.to_vec()));
+        archive.store_block(&h1, &mut reader).await.unwrap();
+        for block in blocks.iter() {
+            let h = block.header().unwrap().hash();
+            let metadata = archive.block_metadata(&h).await.unwrap();
+            assert_eq!(metadata.checksum, Some(crate::block_checksum(&block.raw)));
+            assert!(archive.verify_block(&h).await.unwrap());
+        }
+        // blocks without a checksum are not checked
+        let testdata = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap()
+            .with_verify_on_read(true);
+        let genesis =
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
                 .unwrap();
-        let header = archive.block_header(&h).await.unwrap();
-        assert_eq!(header.version(), 2);
+        assert!(!testdata.verify_block(&genesis).await.unwrap());
+        assert!(testdata.get_block_full(&genesis).await.is_ok());
+
+        // corrupt a block without changing its size
+        let block_path = archive.block_path(&h1).unwrap();
+        let mut raw = std::fs::read(&block_path).unwrap();
+        raw[4] ^= 0xff;
+        std::fs::write(&block_path, &raw).unwrap();
+        // reads are not checked unless verification is enabled
+        assert!(archive.get_block_full(&h1).await.is_ok());
+        assert!(matches!(
+            archive.verify_block(&h1).await,
+            Err(Error::CorruptData { .. })
+        ));
+
+        let archive = SimpleFileBasedBlockArchive::new(path)
+            .await
+            .unwrap()
+            .with_verify_on_read(true);
+        assert!(matches!(
+            archive.get_block_full(&h1).await,
+            Err(Error::CorruptData { .. })
+        ));
+        let mut reader = archive.get_block(&h1).await.unwrap();
+        let mut buf = Vec::new();
+        let e = reader.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(archive.get_block_full(&h0).await.is_ok());
+        let mut reader = archive.get_block(&h0).await.unwrap();
+        reader.read_to_end(&mut Vec::new()).await.unwrap();
+    }
+
+    // An archive records its network and rejects blocks from other networks
+    #[tokio::test]
+    async fn test_network() {
+        let root = tempdir().unwrap();
+        let root_path = root.path().to_str().unwrap().to_string();
+        let regtest =
+            SimpleFileBasedBlockArchive::open_network(root_path.clone(), BlockchainId::Regtest)
+                .await
+                .unwrap();
+        let regtest_genesis = Block::get_genesis(BlockchainId::Regtest).unwrap();
+        let main_genesis = Block::get_genesis(BlockchainId::Main).unwrap();
         assert_eq!(
-            header.prev_hash(),
-            BlockHash::from_hex("0000000000000135aeabf9666fc9f1d5b8573685db070a5f1dfdd78f728a167a")
-                .unwrap()
+            regtest.genesis(),
+            Some(regtest_genesis.header().unwrap().hash())
         );
-        let m_root =
-            BlockHash::from_hex("949904a56c861ecde4b43c9fc4ad612b82d10e38bdd164ea820b8cd0e6a39178")
+        assert_eq!(regtest.root_path, root.path().join("regtest"));
+        regtest.store_block_full(&regtest_genesis).await.unwrap();
+        assert!(matches!(
+            regtest.store_block_full(&main_genesis).await,
+            Err(Error::WrongNetwork { .. })
+        ));
+        // a block following the mainnet genesis block
+        let mut raw = vec![1, 0, 0, 0];
+        raw.extend_from_slice(&main_genesis.header().unwrap().hash().raw);
+        raw.extend_from_slice(&regtest_genesis.raw[36..80]);
+        raw.push(0);
+        let block = Block::new(Bytes::from(raw)).unwrap();
+        assert!(matches!(
+            regtest.store_block_full(&block).await,
+            Err(Error::WrongNetwork { .. })
+        ));
+
+        // regtest blocks are easier than mainnet allows
+        let main = SimpleFileBasedBlockArchive::open_network(root_path.clone(), BlockchainId::Main)
+            .await
+            .unwrap();
+        let mut raw = regtest_genesis.raw[..80].to_vec();
+        raw[4] = 1;
+        raw.push(0);
+        let h = Block::new(Bytes::from(raw.clone()))
+            .unwrap()
+            .header()
+            .unwrap()
+            .hash();
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(raw));
+        assert!(matches!(
+            main.store_block(&h, &mut reader).await,
+            Err(Error::WrongNetwork { .. })
+        ));
+        // a block without a complete header cannot be checked
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(vec![1; 40]));
+        assert!(matches!(
+            main.store_block(&h, &mut reader).await,
+            Err(Error::CorruptData { .. })
+        ));
+        assert!(!main.block_exists(&h).await.unwrap());
+        let testdata = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        for h in testdata.list_blocks().await.unwrap() {
+            let mut reader = testdata.get_block(&h).await.unwrap();
+            main.store_block(&h, &mut reader).await.unwrap();
+        }
+        assert_eq!(main.list_blocks().await.unwrap().len(), 3);
+
+        // the network is kept by migrations and checked when the archive is opened
+        let mut main =
+            SimpleFileBasedBlockArchive::new(main.root_path.to_str().unwrap().to_string())
+                .await
                 .unwrap();
-        assert_eq!(header.merkle_root(), m_root);
+        main.migrate_layout(Layout::Flat).await.unwrap();
+        let main_path = main.root_path.to_str().unwrap().to_string();
+        let main = SimpleFileBasedBlockArchive::new(main_path.clone())
+            .await
+            .unwrap();
+        assert_eq!(main.genesis(), Some(main_genesis.header().unwrap().hash()));
+        assert!(matches!(
+            SimpleFileBasedBlockArchive::new_with_network(main_path, BlockchainId::Test).await,
+            Err(Error::InvalidManifest { .. })
+        ));
     }
 
-    // test getting a header for an unknown block
+    // Memory mapped reads return the same data as file reads and keep a bounded pool of mappings
+    #[cfg(feature = "mmap")]
     #[tokio::test]
-    async fn test_unknown_block_header() {
-        let path = get_testdata_path();
-        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
-        let h =
-            BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1")
+    async fn test_mmap() {
+        let archive = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let mapped = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap()
+            .with_mmap(2);
+        let hashes = archive.list_blocks().await.unwrap();
+        assert_eq!(hashes.len(), 3);
+        for h in hashes.iter() {
+            let block = mapped.get_block_full(h).await.unwrap();
+            assert_eq!(block.raw, archive.get_block_full(h).await.unwrap().raw);
+            assert_eq!(
+                mapped.block_header(h).await.unwrap(),
+                archive.block_header(h).await.unwrap()
+            );
+            let ranges = [(0, 4), (80, 1), (block.raw.len() as u64 - 10, 10)];
+            assert_eq!(
+                mapped.get_ranges_from_block(h, &ranges).await.unwrap(),
+                archive.get_ranges_from_block(h, &ranges).await.unwrap()
+            );
+            assert_eq!(
+                mapped.get_bytes_from_block(h, 1, 2).await.unwrap(),
+                block.raw.slice(1..3)
+            );
+            assert!(matches!(
+                mapped
+                    .get_bytes_from_block(h, 1, block.raw.len() as u64)
+                    .await,
+                Err(Error::InvalidRange { .. })
+            ));
+        }
+        assert_eq!(mapped.mappings.as_ref().unwrap().lock().unwrap().len(), 2);
+        let missing =
+            BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000")
                 .unwrap();
-        let header = archive.block_header(&h).await;
-        match header {
-            Ok(_) => panic!("Expected error but got Ok"),
-            Err(e) => match e {
-                Error::BlockNotFound => {} // Expected error
-                _ => panic!("Unexpected error type: {e:?}"),
-            },
+        assert!(matches!(
+            mapped.get_block_full(&missing).await,
+            Err(Error::BlockNotFound(_))
+        ));
+    }
+
+    // Reads through io_uring return the same data as reads through Tokio
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[tokio::test]
+    async fn test_io_uring() {
+        let archive = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let uring = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap()
+            .with_io_uring(8)
+            .unwrap()
+            .with_verify_on_read(true);
+        for h in archive.list_blocks().await.unwrap().iter() {
+            let size = archive.block_size(h).await.unwrap() as u64;
+            let mut expected = Vec::new();
+            archive
+                .get_block(h)
+                .await
+                .unwrap()
+                .read_to_end(&mut expected)
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            uring
+                .get_block(h)
+                .await
+                .unwrap()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            assert_eq!(buf, expected);
+            assert_eq!(
+                uring.block_header(h).await.unwrap(),
+                archive.block_header(h).await.unwrap()
+            );
+            assert_eq!(
+                uring.block_tx_count(h).await.unwrap(),
+                archive.block_tx_count(h).await.unwrap()
+            );
+            let ranges = [(0, 4), (80, 1), (size - 10, 10), (size, 0)];
+            assert_eq!(
+                uring.get_ranges_from_block(h, &ranges).await.unwrap(),
+                archive.get_ranges_from_block(h, &ranges).await.unwrap()
+            );
+            let mut buf = Vec::new();
+            uring
+                .get_block_range(h, 5, 20)
+                .await
+                .unwrap()
+                .read_to_end(&mut buf)
+                .await
+                .unwrap();
+            assert_eq!(buf, expected[5..25]);
+            assert!(matches!(
+                uring.get_bytes_from_block(h, 1, size).await,
+                Err(Error::InvalidRange { .. })
+            ));
         }
+        let missing =
+            BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000")
+                .unwrap();
+        assert!(matches!(
+            uring.block_header(&missing).await,
+            Err(Error::BlockNotFound(_))
+        ));
     }
 }
@@ -0,0 +1,696 @@
+use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromChannel};
+use crate::events::{BlockEvent, BlockEventPublisher, BlockEventStream};
+use crate::sfb_archive::MAX_BLOCKS;
+use crate::{
+    BlockArchiveReader, BlockArchiveWriter, BlockFilter, BlockMetadata, Error, Layout, Result,
+    SimpleFileBasedBlockArchive,
+};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc::channel;
+
+/// How a [ShardedBlockArchive] chooses the shard that a new block is stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShardPlacement {
+    /// Each block has a preferred shard which is calculated from its hash, using rendezvous
+    /// hashing so that adding a shard only moves the blocks that belong on the new shard.
+    #[default]
+    ByHash,
+    /// Blocks are stored in the shard with the most available space.
+    ///
+    /// Shards that are on the same filesystem share their available space.
+    ByFreeSpace,
+}
+
+/// A [BlockArchive](crate::BlockArchive) that spreads blocks across several [SimpleFileBasedBlockArchive]s, for
+/// example one on each disk of a JBOD array.
+///
+/// Each block is stored in one shard, chosen according to the [ShardPlacement]. Reads look for
+/// the block in every shard, starting with the shard that the block would be stored in, so blocks
+/// that are stored in a different shard are still found. [BlockArchiveReader::block_list] lists the
+/// blocks in all the shards.
+///
+/// A shard can be added with [ShardedBlockArchive::add_shard] while the archive is in use, after
+/// which [ShardedBlockArchive::rebalance] moves blocks to the new shard. The archive can be used
+/// while it is being rebalanced, a block is copied to its new shard before it is removed from its
+/// old shard so that it can always be found.
+///
+/// Example code:
+///     let roots = vec![String::from("/mnt/disk1/blocks"), String::from("/mnt/disk2/blocks")];
+///     let archive = ShardedBlockArchive::new(roots).await?;
+///     archive.add_shard(String::from("/mnt/disk3/blocks")).await?;
+///     let report = archive.rebalance().await?;
+#[derive(Debug)]
+pub struct ShardedBlockArchive {
+    // Shards are only ever added, so the index of a shard does not change.
+    shards: RwLock<Vec<Arc<SimpleFileBasedBlockArchive>>>,
+    placement: ShardPlacement,
+    events: BlockEventPublisher,
+}
+
+/// The result of [ShardedBlockArchive::rebalance].
+#[derive(Debug, Default)]
+pub struct RebalanceReport {
+    /// Blocks that were moved, as (block, old shard index, new shard index).
+    pub moved: Vec<(BlockHash, usize, usize)>,
+    /// The number of bytes moved.
+    pub bytes_moved: u64,
+    /// Failures to move a block, as (block, error).
+    pub errors: Vec<(BlockHash, Error)>,
+}
+
+impl ShardedBlockArchive {
+    /// Create a new sharded archive with the given shard root paths.
+    ///
+    /// The order of the shards must not be changed once blocks have been stored using
+    /// [ShardPlacement::ByHash], otherwise the blocks must be rebalanced.
+    ///
+    /// Panics if there are no shards.
+    pub async fn new(roots: Vec<String>) -> Result<ShardedBlockArchive> {
+        assert!(!roots.is_empty(), "at least one shard is required");
+        let mut shards = Vec::with_capacity(roots.len());
+        for root in roots {
+            shards.push(Arc::new(SimpleFileBasedBlockArchive::new(root).await?));
+        }
+        Ok(ShardedBlockArchive {
+            shards: RwLock::new(shards),
+            placement: ShardPlacement::default(),
+            events: BlockEventPublisher::default(),
+        })
+    }
+
+    /// Set the way that shards are chosen for new blocks.
+    pub fn with_placement(mut self, placement: ShardPlacement) -> ShardedBlockArchive {
+        self.placement = placement;
+        self
+    }
+
+    /// The root paths of the shards.
+    pub fn shard_roots(&self) -> Vec<PathBuf> {
+        self.shards().iter().map(|s| s.root_path.clone()).collect()
+    }
+
+    /// Add a shard to the archive, returning its index.
+    ///
+    /// New blocks may be stored in the shard immediately. Existing blocks are not moved until
+    /// [ShardedBlockArchive::rebalance] is called.
+    pub async fn add_shard(&self, root: String) -> Result<usize> {
+        let shard = Arc::new(SimpleFileBasedBlockArchive::new(root).await?);
+        let mut shards = self.shards.write().unwrap();
+        shards.push(shard);
+        Ok(shards.len() - 1)
+    }
+
+    /// Move blocks between shards so that they match the placement.
+    ///
+    /// With [ShardPlacement::ByHash] every block is moved to its preferred shard. With
+    /// [ShardPlacement::ByFreeSpace] blocks are moved from the shards with the least available
+    /// space to the shards with the most available space, until moving another block would not
+    /// reduce the difference.
+    ///
+    /// Errors moving individual blocks are recorded in [RebalanceReport::errors], errors listing
+    /// the shards are returned.
+    pub async fn rebalance(&self) -> Result<RebalanceReport> {
+        let shards = self.shards();
+        let mut contents = Vec::with_capacity(shards.len());
+        for shard in shards.iter() {
+            let mut blocks = Vec::new();
+            for block_hash in shard.list_blocks().await? {
+                match shard.block_size(&block_hash).await {
+                    Ok(size) => blocks.push((block_hash, size)),
+                    // removed since it was listed
+                    Err(Error::BlockNotFound(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            contents.push(blocks);
+        }
+        let moves = match self.placement {
+            ShardPlacement::ByHash => Self::plan_by_hash(shards.len(), contents),
+            ShardPlacement::ByFreeSpace => {
+                let mut available = Vec::with_capacity(shards.len());
+                for shard in shards.iter() {
+                    available.push(Self::available_space(shard)?);
+                }
+                Self::plan_by_free_space(available, contents)
+            }
+        };
+        let mut report = RebalanceReport::default();
+        for (block_hash, size, from, to) in moves {
+            match Self::move_block(&shards[from], &shards[to], &block_hash).await {
+                Ok(_) => {
+                    report.moved.push((block_hash, from, to));
+                    report.bytes_moved += size as u64;
+                }
+                Err(e) => report.errors.push((block_hash, e)),
+            }
+        }
+        Ok(report)
+    }
+
+    // Plan the moves for ByHash placement, as (block, size, from, to).
+    fn plan_by_hash(
+        shard_count: usize,
+        contents: Vec<Vec<(BlockHash, usize)>>,
+    ) -> Vec<(BlockHash, usize, usize, usize)> {
+        let mut moves = Vec::new();
+        for (from, blocks) in contents.into_iter().enumerate() {
+            for (block_hash, size) in blocks {
+                let to = Self::preferred_shard(&block_hash, shard_count);
+                if to != from {
+                    moves.push((block_hash, size, from, to));
+                }
+            }
+        }
+        moves
+    }
+
+    // Plan the moves for ByFreeSpace placement, as (block, size, from, to). Moving a block
+    // changes the difference in available space between two shards by twice its size, so it only
+    // helps if the block is smaller than the difference.
+    fn plan_by_free_space(
+        mut available: Vec<u64>,
+        mut contents: Vec<Vec<(BlockHash, usize)>>,
+    ) -> Vec<(BlockHash, usize, usize, usize)> {
+        let mut moves = Vec::new();
+        loop {
+            let fullest = (0..available.len()).min_by_key(|i| available[*i]).unwrap();
+            let emptiest = (0..available.len()).max_by_key(|i| available[*i]).unwrap();
+            let difference = available[emptiest] - available[fullest];
+            let Some((block_hash, size)) = contents[fullest].pop() else {
+                break;
+            };
+            if size as u64 >= difference {
+                break;
+            }
+            available[fullest] += size as u64;
+            available[emptiest] -= size as u64;
+            moves.push((block_hash, size, fullest, emptiest));
+        }
+        moves
+    }
+
+    // Copy a block to its new shard and then remove it from its old shard.
+    async fn move_block(
+        from: &SimpleFileBasedBlockArchive,
+        to: &SimpleFileBasedBlockArchive,
+        block_hash: &BlockHash,
+    ) -> Result<()> {
+        let mut reader = from.get_block(block_hash).await?;
+        match to.store_block(block_hash, &mut reader).await {
+            // an earlier move may have been interrupted, keep the copy if it is complete
+            Err(Error::BlockExists(_))
+                if to.block_size(block_hash).await? == from.block_size(block_hash).await? => {}
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        let metadata = from.block_metadata(block_hash).await?;
+        if !metadata.is_empty() {
+            to.set_block_metadata(block_hash, &metadata).await?;
+        }
+        if let Some(filter) = from.block_filter(block_hash).await? {
+            to.set_block_filter(block_hash, &filter).await?;
+        }
+        from.delete_block(block_hash).await
+    }
+
+    // The preferred shard for a block, using rendezvous hashing: the shard with the highest
+    // score for the block. Shards are identified by their index so the scores for the existing
+    // shards do not change when a shard is added.
+    fn preferred_shard(block_hash: &BlockHash, shard_count: usize) -> usize {
+        (0..shard_count)
+            .max_by_key(|i| {
+                let mut hasher = Sha256::new();
+                hasher.update(block_hash.raw);
+                hasher.update((*i as u32).to_le_bytes());
+                let digest = hasher.finalize();
+                u64::from_le_bytes(digest[..8].try_into().unwrap())
+            })
+            .unwrap()
+    }
+
+    // A snapshot of the shards. The lock is never held across an await.
+    fn shards(&self) -> Vec<Arc<SimpleFileBasedBlockArchive>> {
+        self.shards.read().unwrap().clone()
+    }
+
+    fn available_space(shard: &SimpleFileBasedBlockArchive) -> Result<u64> {
+        fs4::available_space(&shard.root_path).map_err(|e| Error::io(&shard.root_path, e))
+    }
+
+    // Choose the shard that a new block is stored in.
+    fn select_shard(
+        &self,
+        block_hash: &BlockHash,
+        shards: &[Arc<SimpleFileBasedBlockArchive>],
+    ) -> Result<usize> {
+        match self.placement {
+            ShardPlacement::ByHash => Ok(Self::preferred_shard(block_hash, shards.len())),
+            ShardPlacement::ByFreeSpace => {
+                let mut best = (0, 0);
+                for (i, shard) in shards.iter().enumerate() {
+                    let available = Self::available_space(shard)?;
+                    if i == 0 || available > best.1 {
+                        best = (i, available);
+                    }
+                }
+                Ok(best.0)
+            }
+        }
+    }
+
+    // The shards in the order that they should be searched for a block.
+    fn search_order(&self, block_hash: &BlockHash) -> Vec<Arc<SimpleFileBasedBlockArchive>> {
+        let mut shards = self.shards();
+        if self.placement == ShardPlacement::ByHash {
+            let preferred = Self::preferred_shard(block_hash, shards.len());
+            shards.swap(0, preferred);
+        }
+        shards
+    }
+
+    // Perform a read on each shard in turn until one has the block.
+    async fn read<'a, T, F>(&self, block_hash: &'a BlockHash, op: F) -> Result<T>
+    where
+        F: Fn(Arc<SimpleFileBasedBlockArchive>) -> BoxFuture<'a, Result<T>>,
+    {
+        for shard in self.search_order(block_hash) {
+            match op(shard).await {
+                Err(Error::BlockNotFound(_)) => {}
+                result => return result,
+            }
+        }
+        Err(Error::BlockNotFound(*block_hash))
+    }
+
+    // List the blocks in all the shards in the background, sending results to the channel. A
+    // block that is being moved may be in two shards, it is only sent once.
+    async fn block_list_bgrnd(
+        roots: Vec<(PathBuf, Layout)>,
+        transmit: tokio::sync::mpsc::Sender<BlockHash>,
+    ) -> Result<()> {
+        let mut seen = HashSet::new();
+        for (root, layout) in roots {
+            let (tx, mut rx) = channel(1024);
+            let list = SimpleFileBasedBlockArchive::block_list_bgrnd(root, layout, tx);
+            let forward = async {
+                while let Some(block_hash) = rx.recv().await {
+                    if seen.insert(block_hash) && transmit.send(block_hash).await.is_err() {
+                        // the receiver has dropped
+                        return false;
+                    }
+                }
+                true
+            };
+            let (result, more) = tokio::join!(list, forward);
+            result?;
+            if !more {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockArchiveReader for ShardedBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.get_block(block_hash).await })
+        })
+        .await
+    }
+
+    async fn get_block_full(&self, block_hash: &BlockHash) -> Result<Block> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.get_block_full(block_hash).await })
+        })
+        .await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        for shard in self.search_order(block_hash) {
+            if shard.block_exists(block_hash).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.block_size(block_hash).await })
+        })
+        .await
+    }
+
+    async fn block_tx_count(&self, block_hash: &BlockHash) -> Result<i64> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.block_tx_count(block_hash).await })
+        })
+        .await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.block_header(block_hash).await })
+        })
+        .await
+    }
+
+    async fn get_bytes_from_block(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Bytes> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.get_bytes_from_block(block_hash, offset, length).await })
+        })
+        .await
+    }
+
+    async fn get_block_range(
+        &self,
+        block_hash: &BlockHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.get_block_range(block_hash, offset, length).await })
+        })
+        .await
+    }
+
+    async fn get_ranges_from_block(
+        &self,
+        block_hash: &BlockHash,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<Bytes>> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.get_ranges_from_block(block_hash, ranges).await })
+        })
+        .await
+    }
+
+    async fn block_metadata(&self, block_hash: &BlockHash) -> Result<BlockMetadata> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.block_metadata(block_hash).await })
+        })
+        .await
+    }
+
+    async fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.block_filter(block_hash).await })
+        })
+        .await
+    }
+
+    async fn block_list(&self) -> Result<Pin<Box<dyn BlockHashListStream<Item = BlockHash>>>> {
+        let (tx, rx) = channel(MAX_BLOCKS);
+        let roots = self
+            .shards()
+            .iter()
+            .map(|s| (s.root_path.clone(), s.layout()))
+            .collect();
+        let handle = tokio::spawn(Self::block_list_bgrnd(roots, tx));
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    /// Subscribe to changes made through the sharded archive.
+    ///
+    /// Changes made directly to the shards, and blocks moved by
+    /// [ShardedBlockArchive::rebalance], are not reported.
+    async fn subscribe(&self) -> Result<Pin<Box<dyn BlockEventStream<Item = BlockEvent>>>> {
+        Ok(Box::pin(self.events.subscribe()))
+    }
+}
+
+#[async_trait]
+impl BlockArchiveWriter for ShardedBlockArchive {
+    async fn store_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        if self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists(*block_hash));
+        }
+        let shards = self.shards();
+        let shard = &shards[self.select_shard(block_hash, &shards)?];
+        shard.store_block(block_hash, block).await?;
+        let size = shard.block_size(block_hash).await?;
+        self.events.publish(BlockEvent::Stored {
+            hash: *block_hash,
+            size,
+        });
+        Ok(())
+    }
+
+    async fn store_block_full(&self, block: &Block) -> Result<()> {
+        let block_hash = block.header()?.hash();
+        if self.block_exists(&block_hash).await? {
+            return Err(Error::BlockExists(block_hash));
+        }
+        let shards = self.shards();
+        let shard = &shards[self.select_shard(&block_hash, &shards)?];
+        shard.store_block_full(block).await?;
+        self.events.publish(BlockEvent::Stored {
+            hash: block_hash,
+            size: block.raw.len(),
+        });
+        Ok(())
+    }
+
+    async fn delete_block(&self, block_hash: &BlockHash) -> Result<()> {
+        // a block that is being moved may be in two shards
+        let mut found = false;
+        for shard in self.shards() {
+            match shard.delete_block(block_hash).await {
+                Ok(_) => found = true,
+                Err(Error::BlockNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !found {
+            return Err(Error::BlockNotFound(*block_hash));
+        }
+        self.events
+            .publish(BlockEvent::Deleted { hash: *block_hash });
+        Ok(())
+    }
+
+    async fn replace_block(
+        &self,
+        block_hash: &BlockHash,
+        block: &mut Box<dyn AsyncRead + Unpin + Send>,
+    ) -> Result<()> {
+        let mut holder = None;
+        for shard in self.search_order(block_hash) {
+            if shard.block_exists(block_hash).await? {
+                holder = Some(shard);
+                break;
+            }
+        }
+        let Some(shard) = holder else {
+            return self.store_block(block_hash, block).await;
+        };
+        shard.replace_block(block_hash, block).await?;
+        let size = shard.block_size(block_hash).await?;
+        self.events.publish(BlockEvent::Stored {
+            hash: *block_hash,
+            size,
+        });
+        Ok(())
+    }
+
+    async fn set_block_metadata(
+        &self,
+        block_hash: &BlockHash,
+        metadata: &BlockMetadata,
+    ) -> Result<()> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.set_block_metadata(block_hash, metadata).await })
+        })
+        .await
+    }
+
+    async fn set_block_filter(&self, block_hash: &BlockHash, filter: &BlockFilter) -> Result<()> {
+        self.read(block_hash, |s| {
+            Box::pin(async move { s.set_block_filter(block_hash, filter).await })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sync_archives, SyncOptions};
+    use futures::StreamExt;
+    use tempfile::{tempdir, TempDir};
+
+    fn get_testdata_path() -> String {
+        String::from("testdata/blockarchive")
+    }
+
+    // Blocks with distinct hashes and no transactions.
+    fn make_blocks(count: u8) -> Vec<Block> {
+        (0..count)
+            .map(|i| {
+                let mut raw = vec![i; 80];
+                raw.push(0);
+                Block::new(Bytes::from(raw)).unwrap()
+            })
+            .collect()
+    }
+
+    fn make_dirs(count: usize) -> (Vec<TempDir>, Vec<String>) {
+        let dirs: Vec<TempDir> = (0..count).map(|_| tempdir().unwrap()).collect();
+        let roots = dirs
+            .iter()
+            .map(|d| d.path().to_str().unwrap().to_string())
+            .collect();
+        (dirs, roots)
+    }
+
+    async fn shard_contains(root: &str, block_hash: &BlockHash) -> bool {
+        let shard = SimpleFileBasedBlockArchive::new(root.to_string())
+            .await
+            .unwrap();
+        shard.block_exists(block_hash).await.unwrap()
+    }
+
+    // Blocks are spread across the shards by hash and can be read back
+    #[tokio::test]
+    async fn test_store_by_hash() {
+        let (_dirs, roots) = make_dirs(3);
+        let archive = ShardedBlockArchive::new(roots.clone()).await.unwrap();
+        let blocks = make_blocks(30);
+        for block in blocks.iter() {
+            archive.store_block_full(block).await.unwrap();
+        }
+        let mut used = HashSet::new();
+        for block in blocks.iter() {
+            let h = block.header().unwrap().hash();
+            let preferred = ShardedBlockArchive::preferred_shard(&h, 3);
+            assert!(shard_contains(&roots[preferred], &h).await);
+            used.insert(preferred);
+            assert_eq!(archive.get_block_full(&h).await.unwrap().raw, block.raw);
+        }
+        assert_eq!(used.len(), 3);
+        assert!(archive
+            .store_block_full(&blocks[0])
+            .await
+            .unwrap_err()
+            .is_exists());
+
+        let source = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let report = sync_archives(&source, &archive, &SyncOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(report.copied.len(), 3);
+        assert_eq!(archive.block_list().await.unwrap().count().await, 33);
+    }
+
+    // A block in more than one shard is listed once and found in any shard
+    #[tokio::test]
+    async fn test_block_in_other_shard() {
+        let (_dirs, roots) = make_dirs(2);
+        let archive = ShardedBlockArchive::new(roots.clone()).await.unwrap();
+        let block = &make_blocks(1)[0];
+        let h = block.header().unwrap().hash();
+        for root in roots.iter() {
+            let shard = SimpleFileBasedBlockArchive::new(root.clone())
+                .await
+                .unwrap();
+            shard.store_block_full(block).await.unwrap();
+        }
+        assert_eq!(archive.block_list().await.unwrap().count().await, 1);
+        let preferred = ShardedBlockArchive::preferred_shard(&h, 2);
+        let shard = SimpleFileBasedBlockArchive::new(roots[preferred].clone())
+            .await
+            .unwrap();
+        shard.delete_block(&h).await.unwrap();
+        assert_eq!(archive.block_size(&h).await.unwrap(), 81);
+        archive.delete_block(&h).await.unwrap();
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(archive.delete_block(&h).await.unwrap_err().is_not_found());
+    }
+
+    // Adding a shard and rebalancing moves only the blocks that belong on the new shard
+    #[tokio::test]
+    async fn test_add_shard_and_rebalance() {
+        let (_dirs, roots) = make_dirs(3);
+        let archive = ShardedBlockArchive::new(roots[..2].to_vec()).await.unwrap();
+        let blocks = make_blocks(30);
+        let metadata = BlockMetadata::seen_now("test");
+        for block in blocks.iter() {
+            archive.store_block_full(block).await.unwrap();
+            let h = block.header().unwrap().hash();
+            archive.set_block_metadata(&h, &metadata).await.unwrap();
+        }
+        assert_eq!(archive.add_shard(roots[2].clone()).await.unwrap(), 2);
+        assert_eq!(archive.shard_roots().len(), 3);
+        let report = archive.rebalance().await.unwrap();
+        assert!(report.errors.is_empty());
+        assert!(!report.moved.is_empty());
+        assert_eq!(report.bytes_moved, 81 * report.moved.len() as u64);
+        for (_, _, to) in report.moved.iter() {
+            assert_eq!(*to, 2);
+        }
+        for block in blocks.iter() {
+            let h = block.header().unwrap().hash();
+            let preferred = ShardedBlockArchive::preferred_shard(&h, 3);
+            assert!(shard_contains(&roots[preferred], &h).await);
+            assert!(archive.block_exists(&h).await.unwrap());
+            let moved = archive.block_metadata(&h).await.unwrap();
+            assert_eq!(moved.source, metadata.source);
+            assert_eq!(moved.checksum, Some(crate::block_checksum(&block.raw)));
+        }
+        assert_eq!(archive.block_list().await.unwrap().count().await, 30);
+        let report = archive.rebalance().await.unwrap();
+        assert!(report.moved.is_empty());
+    }
+
+    // Placement by free space moves blocks towards the shards with the most space
+    #[tokio::test]
+    async fn test_free_space() {
+        let (_dirs, roots) = make_dirs(2);
+        let archive = ShardedBlockArchive::new(roots)
+            .await
+            .unwrap()
+            .with_placement(ShardPlacement::ByFreeSpace);
+        let block = &make_blocks(1)[0];
+        archive.store_block_full(block).await.unwrap();
+        let h = block.header().unwrap().hash();
+        assert!(archive.block_exists(&h).await.unwrap());
+        // the shards share a filesystem so there is nothing to balance
+        let report = archive.rebalance().await.unwrap();
+        assert!(report.moved.is_empty());
+
+        let contents = vec![
+            make_blocks(4)
+                .iter()
+                .map(|b| (b.header().unwrap().hash(), 100))
+                .collect(),
+            vec![],
+        ];
+        let moves = ShardedBlockArchive::plan_by_free_space(vec![1000, 1350], contents);
+        assert_eq!(moves.len(), 2);
+        for (_, _, from, to) in moves {
+            assert_eq!((from, to), (0, 1));
+        }
+    }
+}
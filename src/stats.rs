@@ -0,0 +1,177 @@
+use crate::{BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::BlockHash;
+use std::collections::BTreeMap;
+use tokio_stream::StreamExt;
+
+/// Statistics about the blocks in an archive, returned by
+/// [BlockArchiveReader::archive_stats](crate::BlockArchiveReader::archive_stats).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    /// The number of blocks.
+    pub block_count: u64,
+    /// The total size of the blocks, in bytes.
+    pub total_size: u64,
+    /// The size of the largest block, in bytes.
+    pub max_size: u64,
+    /// The largest block.
+    pub max_size_block: Option<BlockHash>,
+    /// The total number of transactions in the blocks.
+    pub total_tx_count: u64,
+    /// The earliest header timestamp of the blocks.
+    pub earliest_time: Option<u32>,
+    /// The latest header timestamp of the blocks.
+    pub latest_time: Option<u32>,
+    /// The distribution of block sizes. The key `k` counts the blocks with a size of at least
+    /// 2^k bytes and less than 2^(k+1) bytes.
+    pub size_histogram: BTreeMap<u32, u64>,
+}
+
+impl ArchiveStats {
+    /// The average size of the blocks, in bytes.
+    pub fn average_size(&self) -> Option<u64> {
+        self.total_size.checked_div(self.block_count)
+    }
+
+    /// Add a block to the statistics.
+    pub fn add_block(&mut self, hash: &BlockHash, size: u64, tx_count: u64, timestamp: u32) {
+        self.block_count += 1;
+        self.total_size += size;
+        if self.max_size_block.is_none() || size > self.max_size {
+            self.max_size = size;
+            self.max_size_block = Some(*hash);
+        }
+        self.total_tx_count += tx_count;
+        self.earliest_time = Some(self.earliest_time.map_or(timestamp, |t| t.min(timestamp)));
+        self.latest_time = Some(self.latest_time.map_or(timestamp, |t| t.max(timestamp)));
+        *self
+            .size_histogram
+            .entry(Self::size_bucket(size))
+            .or_default() += 1;
+    }
+
+    /// Remove a block from the statistics.
+    ///
+    /// Returns false if the statistics can no longer be maintained incrementally, because the
+    /// block was the largest block or had the earliest or latest timestamp, or because the
+    /// statistics did not include the block, in which case the statistics must be recalculated.
+    pub fn remove_block(
+        &mut self,
+        hash: &BlockHash,
+        size: u64,
+        tx_count: u64,
+        timestamp: u32,
+    ) -> bool {
+        let bucket = Self::size_bucket(size);
+        // statistics that don't include the block are stale, for example because the block was
+        // removed twice
+        let stale = self.block_count == 0
+            || self.total_size < size
+            || self.total_tx_count < tx_count
+            || !self.size_histogram.contains_key(&bucket);
+        self.block_count = self.block_count.saturating_sub(1);
+        self.total_size = self.total_size.saturating_sub(size);
+        self.total_tx_count = self.total_tx_count.saturating_sub(tx_count);
+        if let Some(count) = self.size_histogram.get_mut(&bucket) {
+            *count -= 1;
+            if *count == 0 {
+                self.size_histogram.remove(&bucket);
+            }
+        }
+        !stale
+            && self.max_size_block != Some(*hash)
+            && self.earliest_time != Some(timestamp)
+            && self.latest_time != Some(timestamp)
+    }
+
+    // The histogram bucket for a block size.
+    fn size_bucket(size: u64) -> u32 {
+        size.checked_ilog2().unwrap_or(0)
+    }
+}
+
+/// Calculate the statistics of an archive by reading the size, transaction count and header of
+/// every block.
+pub async fn scan_archive_stats<A>(archive: &A) -> Result<ArchiveStats>
+where
+    A: BlockArchiveReader + ?Sized,
+{
+    let mut hashes = Vec::new();
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        hashes.push(block_hash);
+    }
+    let mut stats = ArchiveStats::default();
+    for block_hash in hashes {
+        let read = async {
+            let size = archive.block_size(&block_hash).await?;
+            let tx_count = archive.block_tx_count(&block_hash).await?;
+            let header = archive.block_header(&block_hash).await?;
+            Ok((size, tx_count, header.timestamp()))
+        };
+        match read.await {
+            Ok((size, tx_count, timestamp)) => {
+                stats.add_block(&block_hash, size as u64, tx_count as u64, timestamp)
+            }
+            // removed since it was listed
+            Err(Error::BlockNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleFileBasedBlockArchive;
+    use hex::FromHex;
+
+    // The statistics of the test data are calculated by a scan
+    #[tokio::test]
+    async fn test_scan_archive_stats() {
+        let archive = SimpleFileBasedBlockArchive::new(String::from("testdata/blockarchive"))
+            .await
+            .unwrap();
+        let stats = scan_archive_stats(&archive).await.unwrap();
+        assert_eq!(stats.block_count, 3);
+        assert_eq!(stats.total_size, 285 + 227 + 215);
+        assert_eq!(stats.average_size(), Some(242));
+        assert_eq!(stats.max_size, 285);
+        let genesis =
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        assert_eq!(stats.max_size_block, Some(genesis));
+        assert_eq!(stats.total_tx_count, 3);
+        assert_eq!(stats.earliest_time, Some(1231006505));
+        assert_eq!(stats.size_histogram, BTreeMap::from([(7, 2), (8, 1)]));
+    }
+
+    // Removing a block that is not an extreme keeps the statistics valid
+    #[test]
+    fn test_remove_block() {
+        let h1 =
+            BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        let h2 =
+            BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000002")
+                .unwrap();
+        let h3 =
+            BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000003")
+                .unwrap();
+        let mut stats = ArchiveStats::default();
+        assert_eq!(stats.average_size(), None);
+        stats.add_block(&h1, 1000, 5, 100);
+        stats.add_block(&h2, 300, 2, 200);
+        stats.add_block(&h3, 200, 1, 150);
+        assert!(stats.remove_block(&h3, 200, 1, 150));
+        assert_eq!(stats.block_count, 2);
+        assert_eq!(stats.total_size, 1300);
+        assert_eq!(stats.total_tx_count, 7);
+        assert_eq!(stats.size_histogram, BTreeMap::from([(8, 1), (9, 1)]));
+        assert!(!stats.remove_block(&h1, 1000, 5, 100));
+        // removing a block that is not in the statistics does not underflow
+        assert!(!stats.remove_block(&h1, 1000, 5, 100));
+        assert!(!stats.remove_block(&h1, 1000, 5, 100));
+        assert_eq!((stats.block_count, stats.total_size), (0, 0));
+    }
+}
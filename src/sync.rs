@@ -0,0 +1,288 @@
+use crate::checksum::{digest_reader, HashingReader};
+use crate::{BlockArchive, BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::BlockHash;
+use futures::stream::{self, StreamExt};
+use std::collections::BTreeMap;
+use tokio::io::AsyncRead;
+
+/// The default number of blocks that are copied concurrently by [sync_archives].
+pub const DEFAULT_SYNC_CONCURRENCY: usize = 4;
+
+/// The differences between two archives, as found by [diff_archives].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ArchiveDiff {
+    /// Blocks that are in the source but not in the destination.
+    pub missing: Vec<BlockHash>,
+    /// Blocks that are in both archives but with a different size, as (block, source size,
+    /// destination size).
+    pub size_mismatch: Vec<(BlockHash, usize, usize)>,
+    /// Blocks that are in the destination but not in the source.
+    pub extra: Vec<BlockHash>,
+    /// The number of blocks that are in both archives with the same size.
+    pub matching: usize,
+}
+
+impl ArchiveDiff {
+    /// Returns true if the destination has every block in the source, with the same size.
+    pub fn is_in_sync(&self) -> bool {
+        self.missing.is_empty() && self.size_mismatch.is_empty()
+    }
+}
+
+/// Options for [sync_archives].
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// The maximum number of blocks that are copied at the same time.
+    pub concurrency: usize,
+    /// Only report which blocks would be copied, do not copy them.
+    pub dry_run: bool,
+    /// Read each block back from the destination after it has been copied and check that the
+    /// bytes are the same as the bytes read from the source.
+    pub verify: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            concurrency: DEFAULT_SYNC_CONCURRENCY,
+            dry_run: false,
+            verify: true,
+        }
+    }
+}
+
+/// The result of [sync_archives].
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The differences between the archives before the sync.
+    pub diff: ArchiveDiff,
+    /// Blocks that were copied to the destination, or would be copied in a dry run.
+    pub copied: Vec<BlockHash>,
+    /// The number of bytes copied to the destination.
+    pub bytes_copied: u64,
+    /// Blocks that could not be copied.
+    pub errors: Vec<(BlockHash, Error)>,
+}
+
+/// Compare the contents of two archives using [BlockArchiveReader::block_list] and
+/// [BlockArchiveReader::block_size].
+///
+/// The lists in the result are sorted by block hash.
+pub async fn diff_archives<S, D>(source: &S, destination: &D) -> Result<ArchiveDiff>
+where
+    S: BlockArchiveReader + ?Sized,
+    D: BlockArchiveReader + ?Sized,
+{
+    let source_sizes = list_sizes(source).await?;
+    let mut destination_sizes = list_sizes(destination).await?;
+    let mut diff = ArchiveDiff::default();
+    for (block_hash, source_size) in source_sizes {
+        match destination_sizes.remove(&block_hash) {
+            None => diff.missing.push(block_hash),
+            Some(size) if size != source_size => {
+                diff.size_mismatch.push((block_hash, source_size, size))
+            }
+            Some(_) => diff.matching += 1,
+        }
+    }
+    diff.extra = destination_sizes.into_keys().collect();
+    Ok(diff)
+}
+
+// The size of every block in the archive.
+async fn list_sizes<A>(archive: &A) -> Result<BTreeMap<BlockHash, usize>>
+where
+    A: BlockArchiveReader + ?Sized,
+{
+    let mut hashes = Vec::new();
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        hashes.push(block_hash);
+    }
+    let mut sizes = BTreeMap::new();
+    for block_hash in hashes {
+        match archive.block_size(&block_hash).await {
+            Ok(size) => {
+                sizes.insert(block_hash, size);
+            }
+            // removed since it was listed
+            Err(Error::BlockNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sizes)
+}
+
+/// Copy the blocks that are in the source archive but not in the destination archive.
+///
+/// Blocks are streamed from [BlockArchiveReader::get_block] to
+/// [BlockArchiveWriter::store_block](crate::BlockArchiveWriter::store_block), up to
+/// [SyncOptions::concurrency] at a time. Blocks whose size differs between the archives are
+/// reported in [SyncReport::diff] but are not copied.
+///
+/// A block is either stored completely or not at all, so an interrupted sync can be resumed by
+/// running it again, only the blocks that are still missing are copied. A block that is stored
+/// in the destination by another process during the sync is not reported as an error.
+///
+/// If verification is enabled and the copy in the destination does not match the bytes read
+/// from the source then the copy is removed and a [Error::CorruptData] is reported for the
+/// block.
+///
+/// Errors copying individual blocks are recorded in [SyncReport::errors], errors listing the
+/// archives are returned.
+pub async fn sync_archives<S, D>(
+    source: &S,
+    destination: &D,
+    options: &SyncOptions,
+) -> Result<SyncReport>
+where
+    S: BlockArchiveReader + ?Sized,
+    D: BlockArchive + ?Sized,
+{
+    let diff = diff_archives(source, destination).await?;
+    let mut report = SyncReport::default();
+    if options.dry_run {
+        report.copied = diff.missing.clone();
+        report.diff = diff;
+        return Ok(report);
+    }
+    let mut copies = stream::iter(diff.missing.iter())
+        .map(|block_hash| async move {
+            let result = copy_block(source, destination, block_hash, options.verify).await;
+            (*block_hash, result)
+        })
+        .buffer_unordered(options.concurrency.max(1));
+    while let Some((block_hash, result)) = copies.next().await {
+        match result {
+            Ok(size) => {
+                report.copied.push(block_hash);
+                report.bytes_copied += size;
+            }
+            Err(Error::BlockExists(_)) => {}
+            Err(e) => report.errors.push((block_hash, e)),
+        }
+    }
+    drop(copies);
+    report.copied.sort();
+    report.diff = diff;
+    Ok(report)
+}
+
+// Copy a block from one archive to another, returning the number of bytes copied.
+async fn copy_block<S, D>(
+    source: &S,
+    destination: &D,
+    block_hash: &BlockHash,
+    verify: bool,
+) -> Result<u64>
+where
+    S: BlockArchiveReader + ?Sized,
+    D: BlockArchive + ?Sized,
+{
+    let reader = HashingReader::new(source.get_block(block_hash).await?);
+    let digest = reader.handle();
+    let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(reader);
+    destination.store_block(block_hash, &mut reader).await?;
+    let (source_digest, size) = digest.lock().unwrap().finish();
+    if verify {
+        let mut reader = destination.get_block(block_hash).await?;
+        let (destination_digest, destination_size) = digest_reader(&mut reader).await?;
+        if destination_digest != source_digest || destination_size != size {
+            destination.delete_block(block_hash).await?;
+            return Err(Error::CorruptData {
+                hash: *block_hash,
+                reason: String::from("copy does not match the source"),
+            });
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlockArchiveWriter;
+    use crate::SimpleFileBasedBlockArchive;
+    use bitcoinsv::bitcoin::{Block, BlockchainId};
+    use bytes::Bytes;
+    use hex::FromHex;
+    use tempfile::tempdir;
+
+    fn get_testdata_path() -> String {
+        String::from("testdata/blockarchive")
+    }
+
+    async fn make_archive(path: &std::path::Path) -> SimpleFileBasedBlockArchive {
+        SimpleFileBasedBlockArchive::new(path.to_str().unwrap().to_string())
+            .await
+            .unwrap()
+    }
+
+    // The diff reports missing, extra and mismatched blocks
+    #[tokio::test]
+    async fn test_diff() {
+        let source = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let destination = make_archive(dir.path()).await;
+        let genesis = Block::get_genesis(BlockchainId::Main).unwrap();
+        destination.store_block_full(&genesis).await.unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let raw = source.get_bytes_from_block(&h, 0, 100).await.unwrap();
+        let mut short: Box<dyn AsyncRead + Unpin + Send> = Box::new(std::io::Cursor::new(raw));
+        destination.store_block(&h, &mut short).await.unwrap();
+        let other = Block::new(Bytes::from(vec![0u8; 81])).unwrap();
+        destination.store_block_full(&other).await.unwrap();
+
+        let diff = diff_archives(&source, &destination).await.unwrap();
+        assert_eq!(diff.matching, 1);
+        assert_eq!(diff.size_mismatch, vec![(h, 227, 100)]);
+        assert_eq!(diff.extra, vec![other.header().unwrap().hash()]);
+        assert_eq!(diff.missing.len(), 1);
+        assert!(!diff.is_in_sync());
+    }
+
+    // A sync copies every missing block and a second sync copies nothing
+    #[tokio::test]
+    async fn test_sync() {
+        let source = SimpleFileBasedBlockArchive::new(get_testdata_path())
+            .await
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let destination = make_archive(dir.path()).await;
+
+        let options = SyncOptions {
+            dry_run: true,
+            ..SyncOptions::default()
+        };
+        let report = sync_archives(&source, &destination, &options)
+            .await
+            .unwrap();
+        assert_eq!(report.copied.len(), 3);
+        assert_eq!(report.bytes_copied, 0);
+        assert_eq!(destination.block_list().await.unwrap().count().await, 0);
+
+        let report = sync_archives(&source, &destination, &SyncOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(report.copied, report.diff.missing);
+        assert_eq!(report.copied.len(), 3);
+        assert!(report.errors.is_empty());
+        assert_eq!(report.bytes_copied, 285 + 227 + 215);
+        for block_hash in report.copied.iter() {
+            let expected = source.get_block_full(block_hash).await.unwrap();
+            let actual = destination.get_block_full(block_hash).await.unwrap();
+            assert_eq!(expected.raw, actual.raw);
+        }
+
+        let report = sync_archives(&source, &destination, &SyncOptions::default())
+            .await
+            .unwrap();
+        assert!(report.copied.is_empty());
+        assert!(report.diff.is_in_sync());
+        assert_eq!(report.diff.matching, 3);
+    }
+}
@@ -0,0 +1,99 @@
+// Blocks and transactions shared by the tests.
+use crate::pow::meets_target;
+use bitcoinsv::bitcoin::{Block, BlockHash, BlockHeader, Outpoint};
+use bytes::Bytes;
+
+// A chain of blocks with no transactions, starting after the zero hash.
+pub(crate) fn make_chain(length: usize) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for i in 0..length {
+        let mut raw = vec![1, 0, 0, 0];
+        match blocks.last() {
+            Some(parent) => raw.extend_from_slice(&parent.header().unwrap().hash().raw),
+            None => raw.extend_from_slice(&[0; 32]),
+        }
+        raw.extend_from_slice(&[i as u8; 44]);
+        raw.push(0);
+        blocks.push(Block::new(Bytes::from(raw)).unwrap());
+    }
+    blocks
+}
+
+// A transaction with the given inputs and output scripts, each output has a value of 1.
+pub(crate) fn make_tx(inputs: &[Outpoint], outputs: &[&[u8]]) -> Vec<u8> {
+    make_tagged_tx(inputs, outputs, 0x51)
+}
+
+// A transaction like make_tx() whose inputs are unlocked with the tag, so that transactions
+// with the same inputs and outputs can be told apart.
+pub(crate) fn make_tagged_tx(inputs: &[Outpoint], outputs: &[&[u8]], tag: u8) -> Vec<u8> {
+    let mut raw = vec![1, 0, 0, 0, inputs.len() as u8];
+    for input in inputs {
+        raw.extend_from_slice(&input.raw);
+        raw.extend_from_slice(&[1, tag, 0xff, 0xff, 0xff, 0xff]);
+    }
+    raw.push(outputs.len() as u8);
+    for script in outputs {
+        raw.extend_from_slice(&1u64.to_le_bytes());
+        raw.push(script.len() as u8);
+        raw.extend_from_slice(script);
+    }
+    raw.extend_from_slice(&[0; 4]);
+    raw
+}
+
+// A coinbase transaction paying to the script.
+#[cfg(feature = "index")]
+pub(crate) fn coinbase(script: &[u8], tag: u8) -> Vec<u8> {
+    let null = crate::filters::outpoint(&bitcoinsv::bitcoin::Hash::ZERO, u32::MAX);
+    make_tagged_tx(&[null], &[script], tag)
+}
+
+// A block with the given transactions, whose header is not mined.
+pub(crate) fn make_block(parent: &BlockHash, txs: &[Vec<u8>]) -> Block {
+    let mut raw = vec![1, 0, 0, 0];
+    raw.extend_from_slice(&parent.raw);
+    raw.extend_from_slice(&[0; 44]);
+    raw.push(txs.len() as u8);
+    for tx in txs {
+        raw.extend_from_slice(tx);
+    }
+    Block::new(Bytes::from(raw)).unwrap()
+}
+
+// Build a header and search for a nonce that meets, or fails, the target.
+pub(crate) fn mine_header(
+    parent: &BlockHash,
+    timestamp: u32,
+    bits: u32,
+    meet_target: bool,
+) -> BlockHeader {
+    let mut raw = vec![1, 0, 0, 0];
+    raw.extend_from_slice(&parent.raw);
+    raw.extend_from_slice(&[7; 32]);
+    raw.extend_from_slice(&timestamp.to_le_bytes());
+    raw.extend_from_slice(&bits.to_le_bytes());
+    for nonce in 0u32.. {
+        raw.truncate(76);
+        raw.extend_from_slice(&nonce.to_le_bytes());
+        let header = BlockHeader {
+            raw: Bytes::from(raw.clone()),
+        };
+        if meets_target(&header.hash(), bits) == meet_target {
+            return header;
+        }
+    }
+    unreachable!()
+}
+
+// Mine a regtest block with the given transactions.
+#[cfg(feature = "index")]
+pub(crate) fn mine(parent: &BlockHash, timestamp: u32, txs: &[Vec<u8>]) -> Block {
+    let header = mine_header(parent, timestamp, 0x207fffff, true);
+    let mut raw = header.raw.to_vec();
+    raw.push(txs.len() as u8);
+    for tx in txs {
+        raw.extend_from_slice(tx);
+    }
+    Block::new(Bytes::from(raw)).unwrap()
+}
@@ -0,0 +1,337 @@
+use crate::{Error, Result};
+use bitcoinsv::bitcoin::{
+    BlockHash, BlockHeader, Encodable, Hash, Outpoint, Script, Tx, TxHash, TxInput, TxOutput,
+};
+use bytes::Bytes;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+/// A transaction read from a block by a [BlockTxReader].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTx {
+    /// The hash of the transaction.
+    pub hash: TxHash,
+    /// The position of the transaction in the block, the coinbase is 0.
+    pub index: u64,
+    /// The offset of the transaction from the start of the block.
+    pub offset: u64,
+    /// The size of the transaction in bytes.
+    pub size: u64,
+    /// The transaction. The scripts refer to a single buffer holding the transaction.
+    pub tx: Tx,
+}
+
+impl BlockTx {
+    /// Returns true if the transaction is a coinbase: it has a single input which spends the
+    /// null outpoint.
+    pub fn is_coinbase(&self) -> bool {
+        match self.tx.inputs.as_slice() {
+            [input] => input.outpoint.tx_hash() == Hash::ZERO && input.outpoint.index() == u32::MAX,
+            _ => false,
+        }
+    }
+}
+
+/// Reads the transactions of a block from a stream one at a time, so that large blocks can be
+/// processed without loading them into memory.
+///
+/// Example code:
+///     let reader = archive.get_block(&hash).await?;
+///     let mut txs = BlockTxReader::new(reader, hash).await?;
+///     while let Some(tx) = txs.next_tx().await? {
+///         println!("{} {} bytes", tx.hash, tx.size);
+///     }
+pub struct BlockTxReader<R> {
+    reader: CountingReader<R>,
+    header: BlockHeader,
+    tx_count: u64,
+    txs_read: u64,
+}
+
+impl<R: AsyncRead + Unpin> BlockTxReader<R> {
+    /// Read the header and transaction count of the block with the given hash.
+    ///
+    /// Returns [Error::CorruptData] if the stream ends before the transaction count.
+    pub async fn new(mut reader: R, block_hash: BlockHash) -> Result<BlockTxReader<R>> {
+        let mut raw = vec![0; BlockHeader::SIZE as usize];
+        match reader.read_exact(&mut raw).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::CorruptData {
+                    hash: block_hash,
+                    reason: String::from("block is too short to contain a header"),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let header = BlockHeader::from_binary(&mut Bytes::from(raw))?;
+        Self::with_header(header, reader).await
+    }
+
+    /// Read the transaction count of a block whose header has already been read from the
+    /// stream.
+    pub async fn with_header(header: BlockHeader, reader: R) -> Result<BlockTxReader<R>> {
+        let mut reader = CountingReader {
+            inner: reader,
+            count: BlockHeader::SIZE,
+        };
+        let mut raw = Vec::new();
+        let tx_count = match read_varint(&mut reader, &mut raw).await {
+            Ok(tx_count) => tx_count,
+            Err(e) => return Err(corrupt(&header, "the transaction count", e)),
+        };
+        Ok(BlockTxReader {
+            reader,
+            header,
+            tx_count,
+            txs_read: 0,
+        })
+    }
+
+    /// The header of the block.
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    /// The number of transactions in the block, as given at the start of the block.
+    pub fn tx_count(&self) -> u64 {
+        self.tx_count
+    }
+
+    /// The number of transactions that have been read.
+    pub fn txs_read(&self) -> u64 {
+        self.txs_read
+    }
+
+    /// The number of bytes that have been read from the stream, including the header.
+    pub fn offset(&self) -> u64 {
+        self.reader.count
+    }
+
+    /// Read the next transaction, or return None once all the transactions in the block have
+    /// been read.
+    ///
+    /// Returns [Error::CorruptData] if the transaction cannot be parsed or the block ends
+    /// before all the transactions have been read.
+    pub async fn next_tx(&mut self) -> Result<Option<BlockTx>> {
+        if self.txs_read == self.tx_count {
+            return Ok(None);
+        }
+        let offset = self.reader.count;
+        let mut raw = Vec::new();
+        let fields = match read_tx(&mut self.reader, &mut raw).await {
+            Ok(fields) => fields,
+            Err(e) => {
+                let what = format!("transaction {} at offset {offset}", self.txs_read);
+                return Err(corrupt(&self.header, &what, e));
+            }
+        };
+        let raw = Bytes::from(raw);
+        let script = |(start, end): (usize, usize)| Script {
+            raw: raw.slice(start..end),
+        };
+        let inputs = fields
+            .inputs
+            .into_iter()
+            .map(|(outpoint, range, sequence)| TxInput {
+                outpoint: Outpoint {
+                    raw: raw.slice(outpoint..outpoint + Outpoint::SIZE as usize),
+                },
+                script: script(range),
+                sequence,
+            })
+            .collect();
+        let outputs = fields
+            .outputs
+            .into_iter()
+            .map(|(value, range)| TxOutput {
+                value,
+                script: script(range),
+            })
+            .collect();
+        let tx = BlockTx {
+            hash: Hash::sha256d(&raw),
+            index: self.txs_read,
+            offset,
+            size: raw.len() as u64,
+            tx: Tx {
+                version: fields.version,
+                inputs,
+                outputs,
+                lock_time: fields.lock_time,
+            },
+        };
+        self.txs_read += 1;
+        Ok(Some(tx))
+    }
+
+    /// Get the stream, for example to read any bytes after the transactions.
+    pub fn into_inner(self) -> R {
+        self.reader.inner
+    }
+}
+
+// The fields of a transaction, with the positions of the outpoints and scripts in the raw
+// transaction.
+struct TxFields {
+    version: u32,
+    inputs: Vec<(usize, (usize, usize), u32)>,
+    outputs: Vec<(u64, (usize, usize))>,
+    lock_time: u32,
+}
+
+// Read a transaction, appending its bytes to raw.
+async fn read_tx<R>(reader: &mut R, raw: &mut Vec<u8>) -> std::io::Result<TxFields>
+where
+    R: AsyncRead + Unpin,
+{
+    let version = u32::from_le_bytes(read_array(reader, raw).await?);
+    let input_count = read_varint(reader, raw).await?;
+    // the counts are not trusted for allocations, a corrupt count would run out of data first
+    let mut inputs = Vec::with_capacity(input_count.min(1024) as usize);
+    for _ in 0..input_count {
+        let outpoint = raw.len();
+        read_array::<_, 36>(reader, raw).await?;
+        let script = read_script(reader, raw).await?;
+        let sequence = u32::from_le_bytes(read_array(reader, raw).await?);
+        inputs.push((outpoint, script, sequence));
+    }
+    let output_count = read_varint(reader, raw).await?;
+    let mut outputs = Vec::with_capacity(output_count.min(1024) as usize);
+    for _ in 0..output_count {
+        let value = u64::from_le_bytes(read_array(reader, raw).await?);
+        let script = read_script(reader, raw).await?;
+        outputs.push((value, script));
+    }
+    let lock_time = u32::from_le_bytes(read_array(reader, raw).await?);
+    Ok(TxFields {
+        version,
+        inputs,
+        outputs,
+        lock_time,
+    })
+}
+
+async fn read_array<R, const N: usize>(
+    reader: &mut R,
+    raw: &mut Vec<u8>,
+) -> std::io::Result<[u8; N]>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf).await?;
+    raw.extend_from_slice(&buf);
+    Ok(buf)
+}
+
+async fn read_varint<R>(reader: &mut R, raw: &mut Vec<u8>) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let [n0] = read_array(reader, raw).await?;
+    Ok(match n0 {
+        0xff => u64::from_le_bytes(read_array(reader, raw).await?),
+        0xfe => u32::from_le_bytes(read_array(reader, raw).await?) as u64,
+        0xfd => u16::from_le_bytes(read_array(reader, raw).await?) as u64,
+        _ => n0 as u64,
+    })
+}
+
+// Read a script with its length, returning the position of the script in raw.
+async fn read_script<R>(reader: &mut R, raw: &mut Vec<u8>) -> std::io::Result<(usize, usize)>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = read_varint(reader, raw).await?;
+    let start = raw.len();
+    // read_to_end only grows the buffer as data arrives, so a corrupt length cannot cause a huge
+    // allocation
+    let read = (&mut *reader).take(length).read_to_end(raw).await?;
+    if (read as u64) < length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((start, raw.len()))
+}
+
+fn corrupt(header: &BlockHeader, what: &str, e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::CorruptData {
+            hash: header.hash(),
+            reason: format!("the block ends in {what}"),
+        },
+        _ => e.into(),
+    }
+}
+
+// A reader which counts the bytes read from it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.count += (buf.filled().len() - start) as u64;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockArchiveReader, SimpleFileBasedBlockArchive};
+    use hex::FromHex;
+
+    // The transactions read from a stream are the same as those parsed from the full block
+    #[tokio::test]
+    async fn test_block_tx_reader() {
+        let archive = SimpleFileBasedBlockArchive::new(String::from("testdata/blockarchive"))
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let block = archive.get_block_full(&h).await.unwrap();
+        let expected = block.tx_iter().collect::<Vec<_>>();
+        let mut txs = BlockTxReader::new(archive.get_block(&h).await.unwrap(), h)
+            .await
+            .unwrap();
+        assert_eq!(txs.header().hash(), h);
+        assert_eq!(txs.tx_count(), block.num_tx as u64);
+        let mut count = 0;
+        while let Some(tx) = txs.next_tx().await.unwrap() {
+            assert_eq!(tx.tx, expected[count]);
+            assert_eq!(tx.hash, expected[count].hash());
+            assert_eq!(tx.is_coinbase(), count == 0);
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+        assert_eq!(txs.offset(), block.raw.len() as u64);
+
+        // a truncated block
+        let raw = &block.raw[..block.raw.len() - 1];
+        let mut txs = BlockTxReader::new(raw, h).await.unwrap();
+        let mut result = Ok(None);
+        for _ in 0..=expected.len() {
+            result = txs.next_tx().await;
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(result, Err(Error::CorruptData { .. })));
+
+        // a block without a complete header is reported with the hash it was read as
+        let result = BlockTxReader::new(&block.raw[..10], h).await;
+        assert!(matches!(result, Err(Error::CorruptData { hash, .. }) if hash == h));
+    }
+}
@@ -0,0 +1,434 @@
+use crate::{BlockArchiveReader, Error, Result, SimpleFileBasedBlockArchive};
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable, FromHex};
+use serde::{Deserialize, Serialize};
+use std::fs::TryLockError;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// The directory, within the root of a file based archive, which holds the data of uploads that
+/// have not been committed.
+pub const UPLOADS_DIR: &str = "uploads";
+
+/// The progress of an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadStatus {
+    /// The hash of the block being uploaded.
+    pub block_hash: BlockHash,
+    /// The size of the block, as given when the upload was started.
+    pub expected_size: u64,
+    /// The number of bytes received, from the start of the block.
+    pub received: u64,
+}
+
+impl UploadStatus {
+    /// Returns true if all the bytes of the block have been received.
+    pub fn is_complete(&self) -> bool {
+        self.received == self.expected_size
+    }
+}
+
+// The state of an upload which is recorded next to its data.
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadState {
+    expected_size: u64,
+}
+
+/// A resumable upload of a block to a [SimpleFileBasedBlockArchive], started with
+/// [SimpleFileBasedBlockArchive::begin_store].
+///
+/// The block is sent in chunks with [UploadSession::append]. The data that has been received is
+/// kept in the [UPLOADS_DIR] directory of the archive, so an upload that is interrupted, even by
+/// the process stopping, can be continued by calling
+/// [SimpleFileBasedBlockArchive::begin_store] again and appending from
+/// [UploadStatus::received]. The block is not in the archive until [UploadSession::commit] is
+/// called.
+///
+/// Example code:
+///     let mut session = archive.begin_store(&hash, size).await?;
+///     let mut offset = session.status().await?.received;
+///     while offset < size {
+///         let chunk = next_chunk(offset).await?;
+///         offset = session.append(offset, &chunk).await?.received;
+///     }
+///     session.commit().await?;
+#[derive(Debug)]
+pub struct UploadSession<'a> {
+    archive: &'a SimpleFileBasedBlockArchive,
+    block_hash: BlockHash,
+    expected_size: u64,
+    data_path: PathBuf,
+}
+
+impl SimpleFileBasedBlockArchive {
+    /// Start an upload of a block of `expected_size` bytes, or continue an earlier upload of the
+    /// block.
+    ///
+    /// Returns [Error::BlockExists] if the block is already in the archive, or
+    /// [Error::InvalidUpload] if an upload of the block with a different size is in progress.
+    pub async fn begin_store(
+        &self,
+        block_hash: &BlockHash,
+        expected_size: u64,
+    ) -> Result<UploadSession<'_>> {
+        self.check_writable()?;
+        if self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists(*block_hash));
+        }
+        let dir = self.root_path.join(UPLOADS_DIR);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| Error::io(&dir, e))?;
+        let session = UploadSession {
+            archive: self,
+            block_hash: *block_hash,
+            expected_size,
+            data_path: dir.join(block_hash.to_string()).with_extension("part"),
+        };
+        match read_state(&session.state_path()).await? {
+            Some(state) if state.expected_size == expected_size => {}
+            Some(state) => {
+                return Err(Error::InvalidUpload {
+                    hash: *block_hash,
+                    reason: format!("an upload of {} bytes is in progress", state.expected_size),
+                })
+            }
+            None => {
+                // create the data file before the state, so that a recorded upload has data
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&session.data_path)
+                    .await
+                    .map_err(|e| Error::io(&session.data_path, e))?;
+                let state = UploadState { expected_size };
+                let data = serde_json::to_vec(&state).map_err(std::io::Error::other)?;
+                let state_path = session.state_path();
+                let tmp_path = state_path.with_extension("upload.tmp");
+                let write = async {
+                    tokio::fs::write(&tmp_path, data).await?;
+                    tokio::fs::rename(&tmp_path, &state_path).await
+                };
+                write.await.map_err(|e| Error::io(&state_path, e))?;
+            }
+        }
+        Ok(session)
+    }
+
+    /// Get the status of the uploads which have been started and not committed or aborted.
+    pub async fn uploads(&self) -> Result<Vec<UploadStatus>> {
+        let dir = self.root_path.join(UPLOADS_DIR);
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::io(&dir, e)),
+        };
+        let mut uploads = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(|e| Error::io(&dir, e))? {
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != "upload") {
+                continue;
+            }
+            let Some(block_hash) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| BlockHash::from_hex(s).ok())
+            else {
+                continue;
+            };
+            let Some(state) = read_state(&path).await? else {
+                continue;
+            };
+            let data_path = path.with_extension("part");
+            uploads.push(UploadStatus {
+                block_hash,
+                expected_size: state.expected_size,
+                received: data_size(&data_path).await?,
+            });
+        }
+        uploads.sort_by_key(|u| u.block_hash);
+        Ok(uploads)
+    }
+}
+
+impl UploadSession<'_> {
+    /// The hash of the block being uploaded.
+    pub fn block_hash(&self) -> &BlockHash {
+        &self.block_hash
+    }
+
+    /// Get the number of bytes that have been received.
+    pub async fn status(&self) -> Result<UploadStatus> {
+        Ok(UploadStatus {
+            block_hash: self.block_hash,
+            expected_size: self.expected_size,
+            received: data_size(&self.data_path).await?,
+        })
+    }
+
+    /// Add a chunk of the block, starting at `offset`.
+    ///
+    /// The chunk must start at or before the end of the data that has been received. Bytes which
+    /// have already been received are skipped, so a chunk can be sent again if it is not known
+    /// whether an earlier attempt succeeded. The data is synced to disk before this returns.
+    ///
+    /// Returns [Error::InvalidUpload] if the chunk would leave a gap or goes beyond the expected
+    /// size of the block.
+    pub async fn append(&mut self, offset: u64, chunk: &[u8]) -> Result<UploadStatus> {
+        let mut file = self.lock_data().await?;
+        let data_error = |e| Error::io(&self.data_path, e);
+        let received = file.metadata().await.map_err(data_error)?.len();
+        let Some(end) = offset.checked_add(chunk.len() as u64) else {
+            return Err(self.invalid(format!(
+                "a chunk of {} bytes at offset {offset} ends beyond the largest offset",
+                chunk.len()
+            )));
+        };
+        if offset > received {
+            return Err(self.invalid(format!(
+                "a chunk at offset {offset} leaves a gap after the {received} bytes received"
+            )));
+        }
+        if end > self.expected_size {
+            return Err(self.invalid(format!(
+                "a chunk ending at {end} is beyond the expected size of {} bytes",
+                self.expected_size
+            )));
+        }
+        if end > received {
+            file.seek(std::io::SeekFrom::Start(received))
+                .await
+                .map_err(data_error)?;
+            file.write_all(&chunk[(received - offset) as usize..])
+                .await
+                .map_err(data_error)?;
+            file.sync_data().await.map_err(data_error)?;
+        }
+        Ok(UploadStatus {
+            block_hash: self.block_hash,
+            expected_size: self.expected_size,
+            received: received.max(end),
+        })
+    }
+
+    /// Store the uploaded block in the archive and end the session.
+    ///
+    /// Returns [Error::InvalidUpload] if the upload is not complete, in which case it can be
+    /// continued with [SimpleFileBasedBlockArchive::begin_store], or [Error::HashMismatch] if the
+    /// data is not the block that was expected, in which case the upload is aborted.
+    pub async fn commit(self) -> Result<()> {
+        let mut file = self.lock_data().await?;
+        let data_error = |e| Error::io(&self.data_path, e);
+        let received = file.metadata().await.map_err(data_error)?.len();
+        if received != self.expected_size {
+            return Err(self.invalid(format!(
+                "{received} of {} bytes have been received",
+                self.expected_size
+            )));
+        }
+        let mut header = vec![0; BlockHeader::SIZE as usize];
+        file.seek(std::io::SeekFrom::Start(0))
+            .await
+            .map_err(data_error)?;
+        let header = match file.read_exact(&mut header).await {
+            Ok(_) => BlockHeader::from_binary(&mut &header[..])?,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                self.remove_files().await?;
+                return Err(Error::CorruptData {
+                    hash: self.block_hash,
+                    reason: String::from("block is too short to contain a header"),
+                });
+            }
+            Err(e) => return Err(data_error(e)),
+        };
+        if header.hash() != self.block_hash {
+            self.remove_files().await?;
+            return Err(Error::HashMismatch {
+                expected: self.block_hash,
+                actual: header.hash(),
+            });
+        }
+        self.archive
+            .store_uploaded_block(&self.block_hash, &header, &self.data_path)
+            .await?;
+        self.remove_files().await
+    }
+
+    /// End the session and remove the data that has been received.
+    pub async fn abort(self) -> Result<()> {
+        let _file = self.lock_data().await?;
+        self.remove_files().await
+    }
+
+    // The path of the file which records the state of the upload.
+    fn state_path(&self) -> PathBuf {
+        self.data_path.with_extension("upload")
+    }
+
+    fn invalid(&self, reason: String) -> Error {
+        Error::InvalidUpload {
+            hash: self.block_hash,
+            reason,
+        }
+    }
+
+    // Open and lock the data file, so that only one writer changes it at a time, even if the
+    // writers are in different processes.
+    async fn lock_data(&self) -> Result<File> {
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.data_path)
+            .await
+        {
+            Ok(file) => file.into_std().await,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(self.invalid(String::from("the upload has ended")))
+            }
+            Err(e) => return Err(Error::io(&self.data_path, e)),
+        };
+        match file.try_lock() {
+            Ok(_) => Ok(File::from_std(file)),
+            Err(TryLockError::WouldBlock) => {
+                Err(self.invalid(String::from("another writer is using the upload")))
+            }
+            Err(TryLockError::Error(e)) => Err(Error::io(&self.data_path, e)),
+        }
+    }
+
+    // Remove the state of the upload, then its data.
+    async fn remove_files(&self) -> Result<()> {
+        for path in [self.state_path(), self.data_path.clone()] {
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::io(&path, e))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Read the state file of an upload, if there is one.
+async fn read_state(path: &Path) -> Result<Option<UploadState>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data).map(Some).map_err(|e| {
+            Error::io(
+                path,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::io(path, e)),
+    }
+}
+
+// The number of bytes in the data file of an upload.
+async fn data_size(path: &Path) -> Result<u64> {
+    match tokio::fs::metadata(path).await {
+        Ok(m) => Ok(m.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(Error::io(path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // An upload can be continued by a new instance and the block is only visible after commit
+    #[tokio::test]
+    async fn test_upload() {
+        let source = SimpleFileBasedBlockArchive::new(String::from("testdata/blockarchive"))
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let raw = source.get_block_full(&h).await.unwrap().raw;
+        let size = raw.len() as u64;
+
+        let root = tempdir().unwrap();
+        let path = root.path().to_str().unwrap().to_string();
+        let archive = SimpleFileBasedBlockArchive::new(path.clone())
+            .await
+            .unwrap();
+        let mut session = archive.begin_store(&h, size).await.unwrap();
+        assert_eq!(session.append(0, &raw[..100]).await.unwrap().received, 100);
+        assert!(matches!(
+            session.append(150, &raw[150..]).await,
+            Err(Error::InvalidUpload { .. })
+        ));
+        assert!(matches!(
+            session.append(u64::MAX, &raw[..1]).await,
+            Err(Error::InvalidUpload { .. })
+        ));
+        assert!(matches!(
+            archive.begin_store(&h, size + 1).await,
+            Err(Error::InvalidUpload { .. })
+        ));
+        drop(session);
+
+        // continue after a restart, resending part of the data
+        let archive = SimpleFileBasedBlockArchive::new(path).await.unwrap();
+        let uploads = archive.uploads().await.unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].received, 100);
+        let mut session = archive.begin_store(&h, size).await.unwrap();
+        let status = session.append(50, &raw[50..200]).await.unwrap();
+        assert_eq!(status.received, 200);
+        assert!(matches!(
+            session.append(200, &raw[200..]).await,
+            Ok(UploadStatus { received, .. }) if received == size
+        ));
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert_eq!(
+            futures::StreamExt::count(archive.block_list().await.unwrap()).await,
+            0
+        );
+        assert!(session.status().await.unwrap().is_complete());
+        session.commit().await.unwrap();
+
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert_eq!(archive.get_block_full(&h).await.unwrap().raw, raw);
+        assert!(archive.verify_block(&h).await.unwrap());
+        assert!(archive.uploads().await.unwrap().is_empty());
+        assert!(matches!(
+            archive.begin_store(&h, size).await,
+            Err(Error::BlockExists(_))
+        ));
+    }
+
+    // A commit fails if the upload is incomplete or the data is a different block
+    #[tokio::test]
+    async fn test_upload_commit_errors() {
+        let root = tempdir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let mut session = archive.begin_store(&h, 100).await.unwrap();
+        session.append(0, &[1; 90]).await.unwrap();
+        assert!(matches!(
+            session.commit().await,
+            Err(Error::InvalidUpload { .. })
+        ));
+        let mut session = archive.begin_store(&h, 100).await.unwrap();
+        session.append(90, &[1; 10]).await.unwrap();
+        assert!(matches!(
+            session.commit().await,
+            Err(Error::HashMismatch { .. })
+        ));
+        assert!(archive.uploads().await.unwrap().is_empty());
+
+        let session = archive.begin_store(&h, 100).await.unwrap();
+        session.abort().await.unwrap();
+        assert!(archive.uploads().await.unwrap().is_empty());
+        assert!(!archive.block_exists(&h).await.unwrap());
+    }
+}
@@ -0,0 +1,403 @@
+use bytes::Bytes;
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::File;
+use std::future::Future;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::oneshot;
+
+// The size of the reads used to stream a block.
+const STREAM_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// An io_uring instance, driven by a dedicated thread.
+//
+// Requests are sent to the thread over a channel. The thread submits the operations of all the
+// requests that are waiting in one batch, up to the queue depth, and replies when all the
+// operations of a request have completed. The thread stops when the Uring is dropped.
+#[derive(Debug)]
+pub(crate) struct Uring {
+    sender: mpsc::Sender<Job>,
+}
+
+type Reply<T> = oneshot::Sender<std::io::Result<T>>;
+
+enum Job {
+    Open {
+        path: CString,
+        reply: Reply<File>,
+    },
+    Read {
+        file: Arc<File>,
+        reads: Vec<PendingRead>,
+        remaining: usize,
+        error: Option<std::io::Error>,
+        reply: Reply<Vec<Bytes>>,
+    },
+}
+
+struct PendingRead {
+    offset: u64,
+    buf: Vec<u8>,
+    filled: usize,
+}
+
+impl Uring {
+    // Create the ring with space for `queue_depth` operations and start its thread.
+    pub fn new(queue_depth: u32) -> std::io::Result<Uring> {
+        let ring = IoUring::new(queue_depth.max(1))?;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(String::from("blockarchive-uring"))
+            .spawn(move || run(ring, receiver))?;
+        Ok(Uring { sender })
+    }
+
+    // Open a file for reading.
+    pub async fn open(&self, path: &Path) -> std::io::Result<File> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let (reply, receiver) = oneshot::channel();
+        self.send(Job::Open { path, reply });
+        receive(receiver).await
+    }
+
+    // Read several ranges of a file, given as (offset, length). The reads are submitted together
+    // and it is an error if the file ends before the end of any of the ranges.
+    pub async fn read(
+        &self,
+        file: Arc<File>,
+        ranges: &[(u64, u64)],
+    ) -> std::io::Result<Vec<Bytes>> {
+        receive(self.start_read(file, ranges)).await
+    }
+
+    fn start_read(
+        &self,
+        file: Arc<File>,
+        ranges: &[(u64, u64)],
+    ) -> oneshot::Receiver<std::io::Result<Vec<Bytes>>> {
+        let reads = ranges
+            .iter()
+            .map(|(offset, length)| PendingRead {
+                offset: *offset,
+                buf: vec![0; *length as usize],
+                filled: 0,
+            })
+            .collect::<Vec<_>>();
+        let (reply, receiver) = oneshot::channel();
+        self.send(Job::Read {
+            file,
+            remaining: reads.len(),
+            reads,
+            error: None,
+            reply,
+        });
+        receiver
+    }
+
+    // If the thread has stopped then the reply sender is dropped, which the receiver reports.
+    fn send(&self, job: Job) {
+        let _ = self.sender.send(job);
+    }
+}
+
+async fn receive<T>(receiver: oneshot::Receiver<std::io::Result<T>>) -> std::io::Result<T> {
+    receiver.await.unwrap_or_else(|_| Err(stopped()))
+}
+
+fn stopped() -> std::io::Error {
+    std::io::Error::other("the io_uring thread has stopped")
+}
+
+// The body of the io_uring thread.
+fn run(mut ring: IoUring, receiver: mpsc::Receiver<Job>) {
+    let depth = ring.params().sq_entries() as usize;
+    // jobs which have operations in progress, indexed by the upper half of the user data
+    let mut jobs: Vec<Option<Job>> = Vec::new();
+    let mut free: Vec<usize> = Vec::new();
+    // operations which have not been submitted yet
+    let mut backlog: VecDeque<squeue::Entry> = VecDeque::new();
+    let mut in_flight = 0;
+    loop {
+        if in_flight == 0 && backlog.is_empty() {
+            match receiver.recv() {
+                Ok(job) => start_job(job, &mut jobs, &mut free, &mut backlog),
+                Err(_) => return,
+            }
+        }
+        while let Ok(job) = receiver.try_recv() {
+            start_job(job, &mut jobs, &mut free, &mut backlog);
+        }
+        {
+            let mut submission = ring.submission();
+            while in_flight < depth {
+                let Some(entry) = backlog.front() else { break };
+                // SAFETY: the buffers and paths used by the entry are owned by its job, which is
+                // not dropped until all of its operations have completed
+                if unsafe { submission.push(entry) }.is_err() {
+                    break;
+                }
+                backlog.pop_front();
+                in_flight += 1;
+            }
+        }
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINTR) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+            Err(e) => {
+                fail_jobs(jobs, &e);
+                return;
+            }
+        }
+        let completed = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect::<Vec<_>>();
+        for (user_data, result) in completed {
+            in_flight -= 1;
+            let index = (user_data >> 32) as usize;
+            let part = (user_data & 0xffff_ffff) as usize;
+            if complete(&mut jobs[index], part, result, user_data, &mut backlog) {
+                jobs[index] = None;
+                free.push(index);
+            }
+        }
+    }
+}
+
+// Reply to every job with an error when the ring can no longer be used. Operations may still be
+// in progress, so the buffers, paths and files that they use are never freed.
+fn fail_jobs(jobs: Vec<Option<Job>>, cause: &std::io::Error) {
+    let error = || std::io::Error::new(cause.kind(), format!("io_uring submit failed: {cause}"));
+    for job in jobs.into_iter().flatten() {
+        match job {
+            Job::Open { path, reply } => {
+                let _ = reply.send(Err(error()));
+                std::mem::forget(path);
+            }
+            Job::Read {
+                file, reads, reply, ..
+            } => {
+                let _ = reply.send(Err(error()));
+                std::mem::forget((file, reads));
+            }
+        }
+    }
+}
+
+// Store a new job and queue its operations.
+fn start_job(
+    mut job: Job,
+    jobs: &mut Vec<Option<Job>>,
+    free: &mut Vec<usize>,
+    backlog: &mut VecDeque<squeue::Entry>,
+) {
+    if let Job::Read {
+        remaining: 0,
+        reply,
+        ..
+    } = job
+    {
+        let _ = reply.send(Ok(Vec::new()));
+        return;
+    }
+    let index = free.pop().unwrap_or_else(|| {
+        jobs.push(None);
+        jobs.len() - 1
+    });
+    let base = (index as u64) << 32;
+    match &mut job {
+        Job::Open { path, .. } => {
+            let entry = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), path.as_ptr())
+                .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+                .build()
+                .user_data(base);
+            backlog.push_back(entry);
+        }
+        Job::Read { file, reads, .. } => {
+            for (part, read) in reads.iter_mut().enumerate() {
+                backlog.push_back(read_entry(file, read, base | part as u64));
+            }
+        }
+    }
+    jobs[index] = Some(job);
+}
+
+// The operation which reads the rest of a range.
+fn read_entry(file: &File, read: &mut PendingRead, user_data: u64) -> squeue::Entry {
+    let rest = &mut read.buf[read.filled..];
+    let length = rest.len().min(u32::MAX as usize) as u32;
+    opcode::Read::new(types::Fd(file.as_raw_fd()), rest.as_mut_ptr(), length)
+        .offset(read.offset + read.filled as u64)
+        .build()
+        .user_data(user_data)
+}
+
+// Handle the completion of an operation. Returns true when the job is finished.
+fn complete(
+    job: &mut Option<Job>,
+    part: usize,
+    result: i32,
+    user_data: u64,
+    backlog: &mut VecDeque<squeue::Entry>,
+) -> bool {
+    match job {
+        Some(Job::Open { .. }) => {
+            let Some(Job::Open { reply, .. }) = job.take() else {
+                unreachable!()
+            };
+            let file = match result {
+                // SAFETY: the kernel returned a new file descriptor which nothing else owns
+                fd if fd >= 0 => Ok(unsafe { File::from_raw_fd(fd) }),
+                e => Err(std::io::Error::from_raw_os_error(-e)),
+            };
+            let _ = reply.send(file);
+            true
+        }
+        Some(Job::Read {
+            file,
+            reads,
+            remaining,
+            error,
+            ..
+        }) => {
+            let read = &mut reads[part];
+            match result {
+                e if e == -libc::EINTR || e == -libc::EAGAIN => {
+                    backlog.push_back(read_entry(file, read, user_data));
+                    return false;
+                }
+                e if e < 0 => {
+                    error.get_or_insert(std::io::Error::from_raw_os_error(-e));
+                }
+                0 if read.filled < read.buf.len() => {
+                    error.get_or_insert(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                n => {
+                    read.filled += n as usize;
+                    if read.filled < read.buf.len() {
+                        backlog.push_back(read_entry(file, read, user_data));
+                        return false;
+                    }
+                }
+            }
+            *remaining -= 1;
+            if *remaining > 0 {
+                return false;
+            }
+            let Some(Job::Read {
+                reads,
+                error,
+                reply,
+                ..
+            }) = job.take()
+            else {
+                unreachable!()
+            };
+            let _ = reply.send(match error {
+                Some(e) => Err(e),
+                None => Ok(reads.into_iter().map(|r| Bytes::from(r.buf)).collect()),
+            });
+            true
+        }
+        None => false,
+    }
+}
+
+// A reader for a range of a file, which reads it in chunks through the ring.
+pub(crate) struct UringReader {
+    uring: Arc<Uring>,
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+    chunk: Bytes,
+    pending: Option<oneshot::Receiver<std::io::Result<Vec<Bytes>>>>,
+}
+
+impl UringReader {
+    pub fn new(uring: Arc<Uring>, file: File, offset: u64, end: u64) -> UringReader {
+        UringReader {
+            uring,
+            file: Arc::new(file),
+            offset,
+            end,
+            chunk: Bytes::new(),
+            pending: None,
+        }
+    }
+}
+
+impl AsyncRead for UringReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.chunk.is_empty() && self.offset < self.end && buf.remaining() > 0 {
+            if self.pending.is_none() {
+                let length = STREAM_CHUNK_SIZE.min(self.end - self.offset);
+                let pending = self
+                    .uring
+                    .start_read(self.file.clone(), &[(self.offset, length)]);
+                self.pending = Some(pending);
+            }
+            let result = ready!(Pin::new(self.pending.as_mut().unwrap()).poll(cx));
+            self.pending = None;
+            let mut chunks = result.unwrap_or_else(|_| Err(stopped()))?;
+            let chunk = chunks.remove(0);
+            self.offset += chunk.len() as u64;
+            self.chunk = chunk;
+        }
+        let n = self.chunk.len().min(buf.remaining());
+        let data = self.chunk.split_to(n);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    // Batched reads return each range and fail if a range is past the end of the file
+    #[tokio::test]
+    async fn test_uring_read() {
+        let uring = Arc::new(Uring::new(4).unwrap());
+        let data = (0..3_000_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, &data).unwrap();
+        let file = Arc::new(uring.open(&path).await.unwrap());
+        let ranges = (0..10).map(|i| (i * 1000, 10)).collect::<Vec<_>>();
+        let results = uring.read(file.clone(), &ranges).await.unwrap();
+        for ((offset, length), bytes) in ranges.iter().zip(results.iter()) {
+            assert_eq!(
+                &bytes[..],
+                &data[*offset as usize..(*offset + *length) as usize]
+            );
+        }
+        let e = uring
+            .read(file, &[(0, 10), (2_999_995, 10)])
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+        let e = uring.open(&dir.path().join("missing")).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+        let file = uring.open(&path).await.unwrap();
+        let mut reader = UringReader::new(uring, file, 10, data.len() as u64);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data[10..]);
+    }
+}
@@ -0,0 +1,904 @@
+use crate::filters::{is_unspendable, outpoint};
+use crate::headers::load_header_chain;
+use crate::output_index::index_error;
+use crate::tx_reader::BlockTxReader;
+use crate::{BlockArchiveReader, Error, Result};
+use bitcoinsv::bitcoin::{BlockHash, BlockchainId, Hash, Outpoint};
+use bytes::Bytes;
+use redb::{
+    Database, ReadableDatabase, ReadableTable, ReadableTableMetadata, TableDefinition,
+    WriteTransaction,
+};
+use sha2::{Digest, Sha256};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// outpoint -> the unspent output
+const COINS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("coins");
+// height -> the hash of the block applied at that height, starting with the genesis block
+const CHAIN: TableDefinition<u64, &[u8]> = TableDefinition::new("chain");
+// height -> the changes needed to undo the block applied at that height
+const UNDO: TableDefinition<u64, &[u8]> = TableDefinition::new("undo");
+
+/// The first bytes of a UTXO snapshot file.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"UTXO";
+/// The version of the UTXO snapshot format.
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+// Used to give temporary snapshot files unique names.
+static NEXT_TMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// An unspent transaction output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    /// The height of the block that created the output.
+    pub height: u64,
+    /// True if the output was created by a coinbase transaction.
+    pub coinbase: bool,
+    /// The value of the output in satoshis.
+    pub value: u64,
+    /// The locking script of the output.
+    pub script: Bytes,
+}
+
+impl Coin {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(17 + self.script.len());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.push(self.coinbase as u8);
+        buf.extend_from_slice(&self.value.to_le_bytes());
+        buf.extend_from_slice(&self.script);
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<Coin> {
+        Some(Coin {
+            height: u64::from_le_bytes(data.get(0..8)?.try_into().ok()?),
+            coinbase: *data.get(8)? != 0,
+            value: u64::from_le_bytes(data.get(9..17)?.try_into().ok()?),
+            script: Bytes::copy_from_slice(data.get(17..)?),
+        })
+    }
+}
+
+// The first height at which the Genesis upgrade applies, from the chain parameters of the node.
+fn genesis_upgrade_height(blockchain: BlockchainId) -> u64 {
+    match blockchain {
+        BlockchainId::Main => 620_538,
+        BlockchainId::Test => 1_344_302,
+        BlockchainId::Stn => 100,
+        BlockchainId::Regtest => 10_000,
+    }
+}
+
+// A change made to the set by a block, in the order of the transactions in the block.
+enum Change {
+    Create { outpoint: Bytes, coin: Vec<u8> },
+    Spend { outpoint: Outpoint },
+}
+
+// The changes needed to undo a block: the outputs it created, the outputs it spent, and the
+// outputs that it replaced by creating an output with the same outpoint.
+#[derive(Debug, Default)]
+struct Undo {
+    created: Vec<Vec<u8>>,
+    spent: Vec<(Vec<u8>, Vec<u8>)>,
+    replaced: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Undo {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.created.len() as u32).to_le_bytes());
+        for outpoint in self.created.iter() {
+            buf.extend_from_slice(outpoint);
+        }
+        for coins in [&self.spent, &self.replaced] {
+            buf.extend_from_slice(&(coins.len() as u32).to_le_bytes());
+            for (outpoint, coin) in coins.iter() {
+                buf.extend_from_slice(outpoint);
+                buf.extend_from_slice(&(coin.len() as u32).to_le_bytes());
+                buf.extend_from_slice(coin);
+            }
+        }
+        buf
+    }
+
+    fn decode(mut data: &[u8]) -> Option<Undo> {
+        let data = &mut data;
+        let mut undo = Undo::default();
+        for _ in 0..take_u32(data)? {
+            undo.created
+                .push(take(data, Outpoint::SIZE as usize)?.to_vec());
+        }
+        for coins in [&mut undo.spent, &mut undo.replaced] {
+            for _ in 0..take_u32(data)? {
+                let outpoint = take(data, Outpoint::SIZE as usize)?.to_vec();
+                let size = take_u32(data)? as usize;
+                coins.push((outpoint, take(data, size)?.to_vec()));
+            }
+        }
+        Some(undo)
+    }
+}
+
+// Take bytes from the front of the data.
+fn take<'a>(data: &mut &'a [u8], size: usize) -> Option<&'a [u8]> {
+    let (value, rest) = data.split_at_checked(size)?;
+    *data = rest;
+    Some(value)
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?))
+}
+
+/// The result of [UtxoSet::replay].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// The number of blocks that were undone because they are no longer in the best chain or
+    /// are above the requested height.
+    pub rolled_back: usize,
+    /// The number of blocks that were applied.
+    pub applied: usize,
+    /// The hash and height of the last block applied.
+    pub tip: (BlockHash, u64),
+}
+
+/// A description of a UTXO snapshot file, see [UtxoSet::write_snapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoSnapshot {
+    /// The hash of the last block applied to the set.
+    pub block_hash: BlockHash,
+    /// The height of the last block applied to the set.
+    pub height: u64,
+    /// The number of unspent outputs.
+    pub coins: u64,
+    /// The double SHA-256 hash of the serialized outputs, which are in outpoint order, so two
+    /// sets at the same block have the same commitment.
+    pub commitment: Hash,
+}
+
+/// The set of unspent transaction outputs (UTXOs) at a block, built by replaying the blocks of
+/// the best chain in an archive.
+///
+/// The set is stored in a [redb](https://docs.rs/redb) database file, and is available with the
+/// "index" feature. The changes made by each block are kept so that blocks can be undone when
+/// the best chain changes, or to go back to an earlier height. Blocks are applied without
+/// checking the scripts or the rules for spending, only that the outputs being spent exist.
+/// Outputs that can never be spent are not kept: those whose script starts with OP_FALSE
+/// OP_RETURN, and before the Genesis upgrade those whose script starts with OP_RETURN.
+///
+/// Example code:
+///     let utxos = UtxoSet::open("utxo.redb")?;
+///     let snapshot = utxos.snapshot_at(&archive, BlockchainId::Main, 800_000, "utxo-800000.bin").await?;
+///     println!("{} outputs, commitment {}", snapshot.coins, snapshot.commitment);
+#[derive(Clone)]
+pub struct UtxoSet {
+    path: PathBuf,
+    db: Arc<Database>,
+    // The height of the Genesis upgrade, if it is not the height for the blockchain.
+    genesis_height: Option<u64>,
+}
+
+impl UtxoSet {
+    /// Open the set in the file at the given path, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<UtxoSet> {
+        let path = path.as_ref().to_path_buf();
+        let db = Database::create(&path).map_err(|e| index_error(&path, e))?;
+        let set = UtxoSet {
+            path,
+            db: Arc::new(db),
+            genesis_height: None,
+        };
+        // create the tables, so that reads do not fail before the first block is applied
+        set.write(|txn| {
+            txn.open_table(COINS)?;
+            txn.open_table(CHAIN)?;
+            txn.open_table(UNDO)?;
+            Ok(Ok(()))
+        })?;
+        Ok(set)
+    }
+
+    /// Apply the Genesis upgrade from the given height rather than the height for the
+    /// blockchain, for example for a regtest node started with `-genesisactivationheight`.
+    pub fn with_genesis_height(mut self, height: u64) -> UtxoSet {
+        self.genesis_height = Some(height);
+        self
+    }
+
+    /// The path of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The hash and height of the last block applied to the set, the genesis block if no block
+    /// has been applied, or None if the set has never been replayed.
+    pub fn tip(&self) -> Result<Option<(BlockHash, u64)>> {
+        let tip = self.read(|txn| {
+            let table = txn.open_table(CHAIN)?;
+            let last = table.last()?;
+            Ok(last.map(|(k, v)| (k.value(), v.value().to_vec())))
+        })?;
+        match tip {
+            Some((height, hash)) => Ok(Some((self.decode_hash(&hash)?, height))),
+            None => Ok(None),
+        }
+    }
+
+    /// The hash of the block applied at a height, or None if there is none.
+    pub fn block_hash(&self, height: u64) -> Result<Option<BlockHash>> {
+        let hash = self.read(|txn| {
+            let value = txn.open_table(CHAIN)?.get(height)?;
+            Ok(value.map(|v| v.value().to_vec()))
+        })?;
+        hash.map(|h| self.decode_hash(&h)).transpose()
+    }
+
+    /// Get the unspent output at an outpoint, or None if it does not exist or has been spent.
+    pub fn get(&self, outpoint: &Outpoint) -> Result<Option<Coin>> {
+        let coin = self.read(|txn| {
+            let value = txn.open_table(COINS)?.get(&outpoint.raw[..])?;
+            Ok(value.map(|v| v.value().to_vec()))
+        })?;
+        match coin {
+            Some(coin) => Coin::decode(&coin)
+                .map(Some)
+                .ok_or_else(|| self.invalid("invalid unspent output")),
+            None => Ok(None),
+        }
+    }
+
+    /// The number of unspent outputs.
+    pub fn coin_count(&self) -> Result<u64> {
+        self.read(|txn| Ok(txn.open_table(COINS)?.len()?))
+    }
+
+    /// Bring the set to a height in the best chain of the archive, or to the tip of the best
+    /// chain if no height is given.
+    ///
+    /// The best chain is the chain of headers with the most work that pass the rules of the
+    /// blockchain, see [HeaderChain](crate::HeaderChain). Blocks that have been applied but are
+    /// not in the best chain, or are above the height, are undone first. Then the blocks of the
+    /// best chain are applied in height order, streaming each one from
+    /// [BlockArchiveReader::get_block].
+    ///
+    /// Returns [Error::HeightNotFound] if the best chain does not reach the height,
+    /// [Error::ParentNotFound] if the best chain does not start at the genesis block, and
+    /// [Error::PrevoutNotFound] if a block spends an output that is not in the set.
+    pub async fn replay<A>(
+        &self,
+        archive: &A,
+        blockchain: BlockchainId,
+        height: Option<u64>,
+    ) -> Result<ReplayReport>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let (chain, _) = load_header_chain(archive, blockchain).await?;
+        let best = chain.best_chain();
+        if best[0] != chain.genesis_hash() {
+            let parent = archive.block_header(&best[0]).await?.prev_hash();
+            return Err(Error::ParentNotFound {
+                hash: best[0],
+                parent,
+            });
+        }
+        let target = height.unwrap_or(best.len() as u64 - 1);
+        if target >= best.len() as u64 {
+            return Err(Error::HeightNotFound(target));
+        }
+        match self.block_hash(0)? {
+            Some(genesis) if genesis != best[0] => {
+                return Err(Error::WrongNetwork {
+                    hash: best[0],
+                    genesis,
+                })
+            }
+            Some(_) => {}
+            None => {
+                let genesis = best[0];
+                self.write(move |txn| {
+                    txn.open_table(CHAIN)?.insert(0, &genesis.raw[..])?;
+                    Ok(Ok(()))
+                })?;
+            }
+        }
+
+        let mut rolled_back = 0;
+        while let Some((hash, height)) = self.tip()? {
+            if height <= target && best.get(height as usize) == Some(&hash) {
+                break;
+            }
+            self.rollback().await?;
+            rolled_back += 1;
+        }
+        let start = self.tip()?.map_or(1, |(_, height)| height + 1);
+        let genesis_height = self
+            .genesis_height
+            .unwrap_or_else(|| genesis_upgrade_height(blockchain));
+        for height in start..=target {
+            self.apply_block(archive, &best[height as usize], height, genesis_height)
+                .await?;
+        }
+        Ok(ReplayReport {
+            rolled_back,
+            applied: (start..=target).count(),
+            tip: (best[target as usize], target),
+        })
+    }
+
+    /// Undo the last block applied to the set. Returns the hash of the block, or None if no
+    /// block has been applied.
+    pub async fn rollback(&self) -> Result<Option<BlockHash>> {
+        let Some((block_hash, height)) = self.tip()? else {
+            return Ok(None);
+        };
+        if height == 0 {
+            return Ok(None);
+        }
+        let path = self.path.clone();
+        self.write_blocking(move |txn| {
+            let undo = txn
+                .open_table(UNDO)?
+                .remove(height)?
+                .map(|v| Undo::decode(v.value()));
+            let Some(Some(undo)) = undo else {
+                return Ok(Err(Error::IndexError {
+                    path,
+                    reason: format!("no undo data for block {block_hash} at height {height}"),
+                }));
+            };
+            let mut coins = txn.open_table(COINS)?;
+            for (outpoint, coin) in undo.spent.iter() {
+                coins.insert(&outpoint[..], &coin[..])?;
+            }
+            // outputs that were created and spent in the block are restored above and removed
+            // here
+            for outpoint in undo.created.iter() {
+                coins.remove(&outpoint[..])?;
+            }
+            for (outpoint, coin) in undo.replaced.iter() {
+                coins.insert(&outpoint[..], &coin[..])?;
+            }
+            txn.open_table(CHAIN)?.remove(height)?;
+            Ok(Ok(()))
+        })
+        .await?;
+        Ok(Some(block_hash))
+    }
+
+    /// Undo blocks until the last block applied is at the given height. Returns the number of
+    /// blocks that were undone.
+    pub async fn rollback_to(&self, height: u64) -> Result<usize> {
+        let mut rolled_back = 0;
+        while let Some((_, tip)) = self.tip()? {
+            if tip <= height || self.rollback().await?.is_none() {
+                break;
+            }
+            rolled_back += 1;
+        }
+        Ok(rolled_back)
+    }
+
+    /// Replay the archive to a height and write a snapshot of the set at that height, see
+    /// [UtxoSet::replay] and [UtxoSet::write_snapshot]. The set is left at the height.
+    pub async fn snapshot_at<A>(
+        &self,
+        archive: &A,
+        blockchain: BlockchainId,
+        height: u64,
+        path: impl AsRef<Path>,
+    ) -> Result<UtxoSnapshot>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        self.replay(archive, blockchain, Some(height)).await?;
+        self.write_snapshot(path).await
+    }
+
+    /// Write the set to a snapshot file.
+    ///
+    /// The file contains [SNAPSHOT_MAGIC], the [SNAPSHOT_VERSION] byte, the block hash, the
+    /// height and the number of outputs as 64 bit little endian integers, then for each output
+    /// in outpoint order the outpoint, the size of the output as a 32 bit integer and the
+    /// output: its height, a coinbase flag byte, its value and its script. It ends with the
+    /// commitment, see [UtxoSnapshot::commitment]. The file is written to a temporary file and
+    /// then renamed, so a snapshot file is always complete.
+    ///
+    /// Returns [Error::HeightNotFound] if the set has never been replayed.
+    pub async fn write_snapshot(&self, path: impl AsRef<Path>) -> Result<UtxoSnapshot> {
+        let path = path.as_ref().to_path_buf();
+        let db = self.db.clone();
+        let db_path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut tmp_path = path.clone().into_os_string();
+            tmp_path.push(format!(
+                ".{}.{}.tmp",
+                std::process::id(),
+                NEXT_TMP_FILE.fetch_add(1, Ordering::Relaxed)
+            ));
+            let tmp_path = PathBuf::from(tmp_path);
+            let result = write_snapshot_file(&db, &db_path, &tmp_path).and_then(|snapshot| {
+                std::fs::rename(&tmp_path, &path).map_err(|e| Error::io(&path, e))?;
+                Ok(snapshot)
+            });
+            if result.is_err() {
+                let _ = std::fs::remove_file(&tmp_path);
+            }
+            result
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    // Apply a block at a height, the block must follow the tip.
+    async fn apply_block<A>(
+        &self,
+        archive: &A,
+        block_hash: &BlockHash,
+        height: u64,
+        genesis_height: u64,
+    ) -> Result<()>
+    where
+        A: BlockArchiveReader + ?Sized,
+    {
+        let mut txs = BlockTxReader::new(archive.get_block(block_hash).await?, *block_hash).await?;
+        let mut changes = Vec::new();
+        while let Some(tx) = txs.next_tx().await? {
+            let coinbase = tx.is_coinbase();
+            if !coinbase {
+                for input in tx.tx.inputs.iter() {
+                    changes.push(Change::Spend {
+                        outpoint: input.outpoint.clone(),
+                    });
+                }
+            }
+            for (index, output) in tx.tx.outputs.iter().enumerate() {
+                if is_unspendable(&output.script.raw, height < genesis_height) {
+                    continue;
+                }
+                let coin = Coin {
+                    height,
+                    coinbase,
+                    value: output.value,
+                    script: output.script.raw.clone(),
+                };
+                changes.push(Change::Create {
+                    outpoint: outpoint(&tx.hash, index as u32).raw,
+                    coin: coin.encode(),
+                });
+            }
+        }
+        let block_hash = *block_hash;
+        self.write_blocking(move |txn| {
+            let mut coins = txn.open_table(COINS)?;
+            let mut undo = Undo::default();
+            for change in changes {
+                match change {
+                    Change::Create { outpoint, coin } => {
+                        let replaced = coins
+                            .insert(&outpoint[..], &coin[..])?
+                            .map(|v| v.value().to_vec());
+                        if let Some(replaced) = replaced {
+                            undo.replaced.push((outpoint.to_vec(), replaced));
+                        }
+                        undo.created.push(outpoint.to_vec());
+                    }
+                    Change::Spend { outpoint } => {
+                        let spent = coins.remove(&outpoint.raw[..])?.map(|v| v.value().to_vec());
+                        match spent {
+                            Some(coin) => undo.spent.push((outpoint.raw.to_vec(), coin)),
+                            None => {
+                                return Ok(Err(Error::PrevoutNotFound {
+                                    hash: block_hash,
+                                    tx: outpoint.tx_hash(),
+                                    index: outpoint.index(),
+                                }))
+                            }
+                        }
+                    }
+                }
+            }
+            txn.open_table(UNDO)?.insert(height, &undo.encode()[..])?;
+            txn.open_table(CHAIN)?.insert(height, &block_hash.raw[..])?;
+            Ok(Ok(()))
+        })
+        .await
+    }
+
+    fn decode_hash(&self, raw: &[u8]) -> Result<BlockHash> {
+        Ok(BlockHash {
+            raw: raw
+                .try_into()
+                .map_err(|_| self.invalid("invalid block hash"))?,
+        })
+    }
+
+    fn invalid(&self, reason: &str) -> Error {
+        Error::IndexError {
+            path: self.path.clone(),
+            reason: reason.to_string(),
+        }
+    }
+
+    fn read<T>(
+        &self,
+        f: impl FnOnce(&redb::ReadTransaction) -> std::result::Result<T, redb::Error>,
+    ) -> Result<T> {
+        let result = self
+            .db
+            .begin_read()
+            .map_err(redb::Error::from)
+            .and_then(|txn| f(&txn));
+        result.map_err(|e| index_error(&self.path, e))
+    }
+
+    fn write<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&WriteTransaction) -> std::result::Result<Result<()>, redb::Error>,
+    {
+        checked_write(&self.db, &self.path, f)
+    }
+
+    // Perform a write on a blocking thread, writes can be large and wait for other writes.
+    async fn write_blocking<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&WriteTransaction) -> std::result::Result<Result<()>, redb::Error>
+            + Send
+            + 'static,
+    {
+        let db = self.db.clone();
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || checked_write(&db, &path, f))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+}
+
+// Perform a write transaction, committing it only if the function succeeds and does not return
+// an error of its own.
+fn checked_write<F>(db: &Database, path: &Path, f: F) -> Result<()>
+where
+    F: FnOnce(&WriteTransaction) -> std::result::Result<Result<()>, redb::Error>,
+{
+    let txn = db.begin_write().map_err(|e| index_error(path, e))?;
+    // dropping the transaction without committing it aborts it
+    f(&txn).map_err(|e| index_error(path, e))??;
+    txn.commit().map_err(|e| index_error(path, e))
+}
+
+// Write the tip and the coins to a snapshot file, both read in one transaction so that they
+// agree when blocks are applied at the same time.
+fn write_snapshot_file(db: &Database, db_path: &Path, path: &Path) -> Result<UtxoSnapshot> {
+    let io_error = |e| Error::io(path, e);
+    let txn = db.begin_read().map_err(|e| index_error(db_path, e))?;
+    let chain = txn.open_table(CHAIN).map_err(|e| index_error(db_path, e))?;
+    let tip = chain.last().map_err(|e| index_error(db_path, e))?;
+    let (height, block_hash) = tip.ok_or(Error::HeightNotFound(0))?;
+    let height = height.value();
+    let block_hash = BlockHash {
+        raw: block_hash
+            .value()
+            .try_into()
+            .map_err(|_| Error::IndexError {
+                path: db_path.to_path_buf(),
+                reason: String::from("invalid block hash"),
+            })?,
+    };
+    let coins = txn.open_table(COINS).map_err(|e| index_error(db_path, e))?;
+    let count = coins.len().map_err(|e| index_error(db_path, e))?;
+    let mut file = BufWriter::new(std::fs::File::create(path).map_err(io_error)?);
+    let mut header = Vec::with_capacity(53);
+    header.extend_from_slice(&SNAPSHOT_MAGIC);
+    header.push(SNAPSHOT_VERSION);
+    header.extend_from_slice(&block_hash.raw);
+    header.extend_from_slice(&height.to_le_bytes());
+    header.extend_from_slice(&count.to_le_bytes());
+    file.write_all(&header).map_err(io_error)?;
+    let mut hasher = Sha256::new();
+    for entry in coins.iter().map_err(|e| index_error(db_path, e))? {
+        let (outpoint, coin) = entry.map_err(|e| index_error(db_path, e))?;
+        let mut record = outpoint.value().to_vec();
+        record.extend_from_slice(&(coin.value().len() as u32).to_le_bytes());
+        record.extend_from_slice(coin.value());
+        hasher.update(&record);
+        file.write_all(&record).map_err(io_error)?;
+    }
+    let commitment = Hash {
+        raw: Sha256::digest(hasher.finalize()).into(),
+    };
+    file.write_all(&commitment.raw).map_err(io_error)?;
+    let file = file.into_inner().map_err(|e| io_error(e.into_error()))?;
+    file.sync_all().map_err(io_error)?;
+    Ok(UtxoSnapshot {
+        block_hash,
+        height,
+        coins: count,
+        commitment,
+    })
+}
+
+/// Check a snapshot file written by [UtxoSet::write_snapshot] against its commitment, and
+/// return its description.
+///
+/// Returns [Error::CorruptData] if the file is not a complete snapshot or does not match its
+/// commitment.
+pub async fn verify_snapshot(path: impl AsRef<Path>) -> Result<UtxoSnapshot> {
+    let path = path.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || verify_snapshot_file(&path))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+fn verify_snapshot_file(path: &Path) -> Result<UtxoSnapshot> {
+    let mut file = BufReader::new(std::fs::File::open(path).map_err(|e| Error::io(path, e))?);
+    let mut header = [0u8; 53];
+    let read_error = |hash: BlockHash, e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::CorruptData {
+            hash,
+            reason: format!("snapshot {} is incomplete", path.display()),
+        },
+        _ => Error::io(path, e),
+    };
+    file.read_exact(&mut header)
+        .map_err(|e| read_error(BlockHash::ZERO, e))?;
+    let block_hash = BlockHash {
+        raw: header[5..37].try_into().unwrap(),
+    };
+    let corrupt = |reason: String| Error::CorruptData {
+        hash: block_hash,
+        reason,
+    };
+    if header[0..4] != SNAPSHOT_MAGIC || header[4] != SNAPSHOT_VERSION {
+        return Err(corrupt(format!(
+            "{} is not a version {SNAPSHOT_VERSION} UTXO snapshot",
+            path.display()
+        )));
+    }
+    let height = u64::from_le_bytes(header[37..45].try_into().unwrap());
+    let coins = u64::from_le_bytes(header[45..53].try_into().unwrap());
+    let mut hasher = Sha256::new();
+    let mut record = vec![0; Outpoint::SIZE as usize + 4];
+    let mut coin = Vec::new();
+    for _ in 0..coins {
+        file.read_exact(&mut record)
+            .map_err(|e| read_error(block_hash, e))?;
+        let size = u32::from_le_bytes(record[Outpoint::SIZE as usize..].try_into().unwrap());
+        // the size is not trusted with an allocation, the buffer grows as the data is read
+        coin.clear();
+        let read = file
+            .by_ref()
+            .take(size as u64)
+            .read_to_end(&mut coin)
+            .map_err(|e| read_error(block_hash, e))?;
+        if read != size as usize {
+            return Err(read_error(
+                block_hash,
+                std::io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+        hasher.update(&record);
+        hasher.update(&coin);
+    }
+    let mut raw = [0u8; 32];
+    file.read_exact(&mut raw)
+        .map_err(|e| read_error(block_hash, e))?;
+    let commitment = Hash {
+        raw: Sha256::digest(hasher.finalize()).into(),
+    };
+    if commitment.raw != raw {
+        return Err(corrupt(format!(
+            "snapshot {} does not match its commitment",
+            path.display()
+        )));
+    }
+    if file.read(&mut [0u8; 1]).map_err(|e| Error::io(path, e))? != 0 {
+        return Err(corrupt(format!(
+            "snapshot {} has data after its commitment",
+            path.display()
+        )));
+    }
+    Ok(UtxoSnapshot {
+        block_hash,
+        height,
+        coins,
+        commitment,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::OP_RETURN;
+    use crate::test_util::{coinbase, make_tagged_tx, mine};
+    use crate::{BlockArchiveWriter, SimpleFileBasedBlockArchive};
+    use bitcoinsv::bitcoin::BlockHeader;
+    use tempfile::tempdir;
+
+    // The set follows the best chain, rolls back to earlier heights and writes snapshots which
+    // match their commitments
+    #[tokio::test]
+    async fn test_replay() {
+        let dir = tempdir().unwrap();
+        let blocks = dir.path().join("blocks");
+        std::fs::create_dir(&blocks).unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(blocks.to_str().unwrap().into())
+            .await
+            .unwrap();
+        let genesis = BlockHeader::get_genesis(BlockchainId::Regtest);
+        let time = genesis.timestamp();
+
+        let coinbase1 = coinbase(b"alice", 1);
+        let block1 = mine(
+            &genesis.hash(),
+            time + 600,
+            std::slice::from_ref(&coinbase1),
+        );
+        let h1 = block1.header().unwrap().hash();
+        let paid = outpoint(&Hash::sha256d(&coinbase1), 0);
+        let spend = make_tagged_tx(std::slice::from_ref(&paid), &[b"bob", &[0, OP_RETURN]], 0);
+        let block2 = mine(&h1, time + 1200, &[coinbase(b"carol", 2), spend.clone()]);
+        let h2 = block2.header().unwrap().hash();
+        for block in [&block1, &block2] {
+            archive.store_block_full(block).await.unwrap();
+        }
+
+        let utxos = UtxoSet::open(dir.path().join("utxo.redb")).unwrap();
+        let report = utxos
+            .replay(&archive, BlockchainId::Regtest, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ReplayReport {
+                rolled_back: 0,
+                applied: 2,
+                tip: (h2, 2)
+            }
+        );
+        assert_eq!(utxos.coin_count().unwrap(), 2);
+        assert_eq!(utxos.get(&paid).unwrap(), None);
+        let bob = outpoint(&Hash::sha256d(&spend), 0);
+        assert_eq!(
+            utxos.get(&bob).unwrap(),
+            Some(Coin {
+                height: 2,
+                coinbase: false,
+                value: 1,
+                script: Bytes::from_static(b"bob")
+            })
+        );
+
+        let snapshot1 = utxos
+            .snapshot_at(
+                &archive,
+                BlockchainId::Regtest,
+                1,
+                dir.path().join("one.bin"),
+            )
+            .await
+            .unwrap();
+        assert_eq!((snapshot1.block_hash, snapshot1.coins), (h1, 1));
+        assert_eq!(utxos.get(&paid).unwrap().unwrap().height, 1);
+        assert_eq!(
+            verify_snapshot(dir.path().join("one.bin")).await.unwrap(),
+            snapshot1
+        );
+        assert!(matches!(
+            utxos.replay(&archive, BlockchainId::Regtest, Some(3)).await,
+            Err(Error::HeightNotFound(3))
+        ));
+
+        let report = utxos
+            .replay(&archive, BlockchainId::Regtest, None)
+            .await
+            .unwrap();
+        assert_eq!((report.applied, report.tip), (1, (h2, 2)));
+
+        // a longer fork from block 1 becomes the best chain
+        let block2b = mine(&h1, time + 1300, &[coinbase(b"dave", 3)]);
+        let h2b = block2b.header().unwrap().hash();
+        let block3b = mine(&h2b, time + 1900, &[coinbase(b"erin", 4)]);
+        let h3b = block3b.header().unwrap().hash();
+        for block in [&block2b, &block3b] {
+            archive.store_block_full(block).await.unwrap();
+        }
+        let report = utxos
+            .replay(&archive, BlockchainId::Regtest, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ReplayReport {
+                rolled_back: 1,
+                applied: 2,
+                tip: (h3b, 3)
+            }
+        );
+        assert_eq!(utxos.get(&bob).unwrap(), None);
+        assert!(utxos.get(&paid).unwrap().is_some());
+        assert_eq!(utxos.coin_count().unwrap(), 3);
+
+        // the set at a height is the same after a rollback
+        assert_eq!(utxos.rollback_to(1).await.unwrap(), 2);
+        let snapshot = utxos
+            .write_snapshot(dir.path().join("again.bin"))
+            .await
+            .unwrap();
+        assert_eq!(snapshot, snapshot1);
+        assert_eq!(
+            std::fs::read(dir.path().join("again.bin")).unwrap(),
+            std::fs::read(dir.path().join("one.bin")).unwrap()
+        );
+
+        let mut raw = std::fs::read(dir.path().join("one.bin")).unwrap();
+        raw[60] ^= 1;
+        std::fs::write(dir.path().join("one.bin"), &raw).unwrap();
+        assert!(matches!(
+            verify_snapshot(dir.path().join("one.bin")).await,
+            Err(Error::CorruptData { .. })
+        ));
+        // the size of an output beyond the end of the file
+        raw[89..93].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(dir.path().join("one.bin"), &raw).unwrap();
+        assert!(matches!(
+            verify_snapshot(dir.path().join("one.bin")).await,
+            Err(Error::CorruptData { .. })
+        ));
+    }
+
+    // Outputs whose script starts with OP_RETURN are kept from the Genesis upgrade and can be
+    // spent
+    #[tokio::test]
+    async fn test_genesis_upgrade() {
+        let dir = tempdir().unwrap();
+        let blocks = dir.path().join("blocks");
+        std::fs::create_dir(&blocks).unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(blocks.to_str().unwrap().into())
+            .await
+            .unwrap();
+        let genesis = BlockHeader::get_genesis(BlockchainId::Regtest);
+        let time = genesis.timestamp();
+
+        let coinbase1 = coinbase(&[OP_RETURN], 1);
+        let block1 = mine(
+            &genesis.hash(),
+            time + 600,
+            std::slice::from_ref(&coinbase1),
+        );
+        let h1 = block1.header().unwrap().hash();
+        let coinbase2 = coinbase(&[OP_RETURN], 2);
+        let block2 = mine(&h1, time + 1200, std::slice::from_ref(&coinbase2));
+        let h2 = block2.header().unwrap().hash();
+        let paid = outpoint(&Hash::sha256d(&coinbase2), 0);
+        let spend = make_tagged_tx(std::slice::from_ref(&paid), &[b"bob", &[0, OP_RETURN]], 0);
+        let block3 = mine(&h2, time + 1800, &[coinbase(b"carol", 3), spend.clone()]);
+        for block in [&block1, &block2, &block3] {
+            archive.store_block_full(block).await.unwrap();
+        }
+
+        let utxos = UtxoSet::open(dir.path().join("utxo.redb"))
+            .unwrap()
+            .with_genesis_height(2);
+        utxos
+            .replay(&archive, BlockchainId::Regtest, Some(2))
+            .await
+            .unwrap();
+        // the output of block 1 is before the upgrade and is not kept
+        assert_eq!(utxos.coin_count().unwrap(), 1);
+        assert_eq!(utxos.get(&paid).unwrap().unwrap().height, 2);
+
+        utxos
+            .replay(&archive, BlockchainId::Regtest, None)
+            .await
+            .unwrap();
+        assert_eq!(utxos.get(&paid).unwrap(), None);
+        let bob = outpoint(&Hash::sha256d(&spend), 0);
+        assert!(utxos.get(&bob).unwrap().is_some());
+        assert_eq!(utxos.coin_count().unwrap(), 2);
+    }
+}
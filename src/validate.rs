@@ -0,0 +1,333 @@
+use crate::pow::{meets_target, target_from_bits};
+use crate::tx_reader::BlockTxReader;
+use crate::{Error, Result};
+use bitcoinsv::bitcoin::{
+    calculate_merkle_root, BlockHash, BlockHeader, Encodable, MerkleRoot, TxHash,
+};
+use bytes::Bytes;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The result of checking that a block is well formed, see
+/// [BlockArchiveReader::validate_block](crate::BlockArchiveReader::validate_block).
+///
+/// The checks are structural, transactions are not checked against the outputs they spend and
+/// scripts are not run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockValidation {
+    /// The hash of the block that was checked.
+    pub block_hash: BlockHash,
+    /// The number of bytes in the block.
+    pub size: u64,
+    /// The number of transactions given at the start of the block, if it could be read.
+    pub tx_count: Option<u64>,
+    /// The number of transactions that were parsed.
+    pub transactions: u64,
+    /// The merkle root calculated from the transactions, if they could all be parsed.
+    pub merkle_root: Option<MerkleRoot>,
+    /// The problems that were found, empty if the block is well formed.
+    pub problems: Vec<ValidationProblem>,
+}
+
+impl BlockValidation {
+    /// Returns true if no problems were found.
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A problem found when checking that a block is well formed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationProblem {
+    /// The block is too short to contain a header.
+    HeaderTooShort,
+    /// The header is not the header of the block that was requested.
+    HashMismatch { actual: BlockHash },
+    /// The block ends before the transaction count.
+    MissingTxCount,
+    /// The block does not contain any transactions.
+    NoTransactions,
+    /// The block ends before the number of transactions given by the transaction count.
+    TxCountMismatch { declared: u64, found: u64 },
+    /// A transaction cannot be parsed.
+    InvalidTransaction {
+        index: u64,
+        offset: u64,
+        reason: String,
+    },
+    /// There are bytes after the last transaction.
+    TrailingBytes { offset: u64, count: u64 },
+    /// The merkle root of the transactions does not match the header.
+    MerkleRootMismatch {
+        header: MerkleRoot,
+        calculated: MerkleRoot,
+    },
+    /// The first transaction is not a coinbase.
+    FirstNotCoinbase,
+    /// A transaction other than the first is a coinbase.
+    ExtraCoinbase { index: u64 },
+    /// The difficulty target in the header is not valid.
+    InvalidBits { bits: u32 },
+    /// The hash of the header is above the difficulty target.
+    InsufficientWork { bits: u32 },
+}
+
+impl fmt::Display for ValidationProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationProblem::HeaderTooShort => write!(f, "too short to contain a header"),
+            ValidationProblem::HashMismatch { actual } => {
+                write!(f, "the header is for block {actual}")
+            }
+            ValidationProblem::MissingTxCount => write!(f, "no transaction count"),
+            ValidationProblem::NoTransactions => write!(f, "no transactions"),
+            ValidationProblem::TxCountMismatch { declared, found } => write!(
+                f,
+                "{found} transactions found but the transaction count is {declared}"
+            ),
+            ValidationProblem::InvalidTransaction {
+                index,
+                offset,
+                reason,
+            } => write!(f, "transaction {index} at offset {offset}: {reason}"),
+            ValidationProblem::TrailingBytes { offset, count } => {
+                write!(f, "{count} bytes after the transactions at offset {offset}")
+            }
+            ValidationProblem::MerkleRootMismatch { header, calculated } => write!(
+                f,
+                "the merkle root is {calculated} but the header has {header}"
+            ),
+            ValidationProblem::FirstNotCoinbase => {
+                write!(f, "the first transaction is not a coinbase")
+            }
+            ValidationProblem::ExtraCoinbase { index } => {
+                write!(f, "transaction {index} is a coinbase")
+            }
+            ValidationProblem::InvalidBits { bits } => write!(f, "invalid bits {bits:#010x}"),
+            ValidationProblem::InsufficientWork { bits } => {
+                write!(f, "the hash is above the target of bits {bits:#010x}")
+            }
+        }
+    }
+}
+
+// Check that there is enough data for the header and tx count of a block, Block::new() panics
+// if there is not.
+pub(crate) fn has_header_and_tx_count(raw: &[u8]) -> bool {
+    let tx_count_size = match raw.get(BlockHeader::SIZE as usize) {
+        Some(0xff) => 9,
+        Some(0xfe) => 5,
+        Some(0xfd) => 3,
+        _ => 1,
+    };
+    raw.len() >= BlockHeader::SIZE as usize + tx_count_size
+}
+
+// Check that the block in a stream is well formed.
+pub(crate) async fn validate_reader<R>(
+    block_hash: &BlockHash,
+    mut reader: R,
+) -> Result<BlockValidation>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut validation = BlockValidation {
+        block_hash: *block_hash,
+        size: 0,
+        tx_count: None,
+        transactions: 0,
+        merkle_root: None,
+        problems: Vec::new(),
+    };
+    let mut raw = vec![0; BlockHeader::SIZE as usize];
+    match reader.read_exact(&mut raw).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            // the size is not known, but whatever there is, is less than a header
+            validation.problems.push(ValidationProblem::HeaderTooShort);
+            return Ok(validation);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let header = BlockHeader::from_binary(&mut Bytes::from(raw))?;
+    if header.hash() != *block_hash {
+        validation.problems.push(ValidationProblem::HashMismatch {
+            actual: header.hash(),
+        });
+    }
+    match target_from_bits(header.bits()) {
+        None => validation.problems.push(ValidationProblem::InvalidBits {
+            bits: header.bits(),
+        }),
+        Some(_) if !meets_target(&header.hash(), header.bits()) => {
+            validation
+                .problems
+                .push(ValidationProblem::InsufficientWork {
+                    bits: header.bits(),
+                })
+        }
+        Some(_) => {}
+    }
+
+    let mut txs = match BlockTxReader::with_header(header.clone(), reader).await {
+        Ok(txs) => txs,
+        Err(Error::CorruptData { .. }) => {
+            validation.size = BlockHeader::SIZE;
+            validation.problems.push(ValidationProblem::MissingTxCount);
+            return Ok(validation);
+        }
+        Err(e) => return Err(e),
+    };
+    validation.tx_count = Some(txs.tx_count());
+    if txs.tx_count() == 0 {
+        validation.problems.push(ValidationProblem::NoTransactions);
+    }
+    let mut hashes: Vec<TxHash> = Vec::new();
+    let mut complete = true;
+    loop {
+        let offset = txs.offset();
+        match txs.next_tx().await {
+            Ok(Some(tx)) => {
+                match (tx.index, tx.is_coinbase()) {
+                    (0, false) => validation
+                        .problems
+                        .push(ValidationProblem::FirstNotCoinbase),
+                    (index, true) if index > 0 => validation
+                        .problems
+                        .push(ValidationProblem::ExtraCoinbase { index }),
+                    _ => {}
+                }
+                hashes.push(tx.hash);
+            }
+            Ok(None) => break,
+            // the block ends between transactions
+            Err(Error::CorruptData { .. }) if txs.offset() == offset => {
+                validation
+                    .problems
+                    .push(ValidationProblem::TxCountMismatch {
+                        declared: txs.tx_count(),
+                        found: txs.txs_read(),
+                    });
+                complete = false;
+                break;
+            }
+            Err(Error::CorruptData { reason, .. }) => {
+                validation
+                    .problems
+                    .push(ValidationProblem::InvalidTransaction {
+                        index: txs.txs_read(),
+                        offset,
+                        reason,
+                    });
+                complete = false;
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    validation.transactions = txs.txs_read();
+    validation.size = txs.offset();
+
+    if complete {
+        let mut reader = txs.into_inner();
+        let trailing = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+        if trailing > 0 {
+            validation.problems.push(ValidationProblem::TrailingBytes {
+                offset: validation.size,
+                count: trailing,
+            });
+            validation.size += trailing;
+        }
+        if let Ok(merkle_root) = calculate_merkle_root(&hashes) {
+            if merkle_root != header.merkle_root() {
+                validation
+                    .problems
+                    .push(ValidationProblem::MerkleRootMismatch {
+                        header: header.merkle_root(),
+                        calculated: merkle_root,
+                    });
+            }
+            validation.merkle_root = Some(merkle_root);
+        }
+    }
+    Ok(validation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockArchiveReader, SimpleFileBasedBlockArchive};
+    use hex::FromHex;
+
+    // The blocks in the test data are well formed and damage to a block is reported
+    #[tokio::test]
+    async fn test_validate_block() {
+        let archive = SimpleFileBasedBlockArchive::new(String::from("testdata/blockarchive"))
+            .await
+            .unwrap();
+        for h in archive.list_blocks().await.unwrap() {
+            let validation = archive.validate_block(&h).await.unwrap();
+            assert!(validation.is_valid(), "{h}: {:?}", validation.problems);
+            assert_eq!(
+                validation.size,
+                archive.block_size(&h).await.unwrap() as u64
+            );
+            assert_eq!(validation.tx_count, Some(validation.transactions));
+        }
+
+        let h =
+            BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f")
+                .unwrap();
+        let raw = archive.get_block_full(&h).await.unwrap().raw.to_vec();
+
+        let mut extra = raw.clone();
+        extra.extend_from_slice(&[0; 3]);
+        let validation = validate_reader(&h, &extra[..]).await.unwrap();
+        assert_eq!(
+            validation.problems,
+            vec![ValidationProblem::TrailingBytes {
+                offset: raw.len() as u64,
+                count: 3
+            }]
+        );
+
+        // change the lock time of the last transaction
+        let mut changed = raw.clone();
+        let last = changed.len() - 1;
+        changed[last] ^= 1;
+        let validation = validate_reader(&h, &changed[..]).await.unwrap();
+        assert!(matches!(
+            validation.problems[..],
+            [ValidationProblem::MerkleRootMismatch { .. }]
+        ));
+
+        let validation = validate_reader(&h, &raw[..raw.len() - 1]).await.unwrap();
+        assert!(matches!(
+            validation.problems[..],
+            [ValidationProblem::InvalidTransaction { .. }]
+        ));
+        let validation = validate_reader(&h, &raw[..40]).await.unwrap();
+        assert_eq!(validation.problems, vec![ValidationProblem::HeaderTooShort]);
+
+        // increase the tx count
+        let mut more = raw.clone();
+        more[80] += 1;
+        let validation = validate_reader(&h, &more[..]).await.unwrap();
+        assert_eq!(
+            validation.problems,
+            vec![ValidationProblem::TxCountMismatch {
+                declared: more[80] as u64,
+                found: raw[80] as u64
+            }]
+        );
+
+        let other =
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        let validation = validate_reader(&other, &raw[..]).await.unwrap();
+        assert_eq!(
+            validation.problems,
+            vec![ValidationProblem::HashMismatch { actual: h }]
+        );
+    }
+}